@@ -90,3 +90,15 @@ pub fn init_slog_logging(use_syslog: bool, no_decoration: bool) -> Result<Global
 pub fn path_split(full_path: &str) -> Option<(&str, &str)> {
     full_path.rsplit_once('/')
 }
+
+/// Human readable size like 1.5G, the units are powers of 1024
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, units[unit])
+}
@@ -31,6 +31,26 @@ pub struct Config {
     pub vpc_db_path: PathBuf,
     pub vpc_search_path: PathBuf,
     pub listen_ip: Option<IpAddr>,
+    /// Url or path of the catalog used to find images which are not installed
+    pub image_catalog: Option<String>,
+    /// Object storage images are published to and imported from with s3:// urls
+    pub s3: Option<S3Config>,
+}
+
+/// An S3 compatible object storage like Garage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Config {
+    /// Url of the S3 api like http://localhost:3900
+    pub endpoint: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+/// Garage calls its region garage unless configured otherwise
+fn default_s3_region() -> String {
+    String::from("garage")
 }
 
 impl Default for Config {
@@ -41,6 +61,8 @@ impl Default for Config {
             vpc_db_path: PathBuf::from(VPC_DB_PATH),
             vpc_search_path: PathBuf::from(VPC_SEARCH_FILE_PATH),
             listen_ip: None,
+            image_catalog: None,
+            s3: None,
         }
     }
 }
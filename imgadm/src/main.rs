@@ -1,3 +1,217 @@
-fn main() {
-    println!("Hello, world!");
+use clap::{Parser, Subcommand};
+use common::{error, format_size, info, init_slog_logging};
+use miette::Result;
+use opczone::image::installed::{self, InstalledImage};
+use opczone::image::oci::OCI_TRANSPORT;
+use opczone::image::store::S3_SCHEME;
+use opczone::image::ImageManifest;
+use opczone::image::{
+    export_image_as_dataset_format, import_dataset_image, import_oci_image, import_s3_image,
+};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    commands: Commands,
+}
+
+/// All image commands
+#[derive(Subcommand)]
+enum Commands {
+    /// List the installed images
+    List,
+    /// Show everything known about an installed image
+    Show {
+        /// uuid or name of the image, a version or tag can follow the name like
+        /// zones/web@1 or zones/web:stable
+        image: String,
+    },
+    /// Import an image so zones can be installed from it
+    Import {
+        /// Where to import the image from. Either a <uuid>.zfs.gz file written by export,
+        /// oci:<layout>[:<tag>] for an OCI image layout like skopeo copy writes them or
+        /// s3://<bucket>/<key> for an image published to the configured object storage
+        source: String,
+
+        #[arg(short, long)]
+        /// Register the image under this name instead of the name in the image
+        name: Option<String>,
+    },
+    /// Export an installed image as <uuid>.zfs.gz with its manifest
+    Export {
+        /// uuid or name of the image, a version or tag can follow the name like
+        /// zones/web@1 or zones/web:stable
+        image: String,
+
+        #[arg(short, long, default_value = ".")]
+        /// Directory to write the image to
+        output_dir: PathBuf,
+    },
+    /// Delete an image no zone or image is cloned from
+    Delete {
+        /// uuid or name of the image, a version or tag can follow the name like
+        /// zones/web@1 or zones/web:stable
+        image: String,
+    },
+    /// Delete all images no zone uses and no other image is based on
+    Vacuum {
+        #[arg(short = 'n', long)]
+        /// Only print the images that would be deleted
+        dry_run: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    let _logger_guard = init_slog_logging(false, false)?;
+
+    let cli: Cli = Cli::parse();
+
+    match cli.commands {
+        Commands::List => {
+            println!(
+                "{:<36} {:<30} {:<12} {:>10} {:<36} {:>6} TAGS",
+                "UUID", "NAME", "VERSION", "SIZE", "ORIGIN", "CLONES"
+            );
+            for image in installed::list()? {
+                println!(
+                    "{:<36} {:<30} {:<12} {:>10} {:<36} {:>6} {}",
+                    image.uuid,
+                    image.name,
+                    image.version.as_deref().unwrap_or("-"),
+                    image.size.map_or("-".to_string(), format_size),
+                    image.origin().map_or("-".to_string(), |o| o.to_string()),
+                    image.clones.len(),
+                    tags(&image)
+                );
+            }
+        }
+        Commands::Show { image } => {
+            let images = installed::find(&image)?;
+            show(&images);
+        }
+        Commands::Import { source, name } => {
+            let uuid = if source.starts_with(OCI_TRANSPORT) {
+                import_oci_image(&source, name.as_deref())?
+            } else if source.starts_with(&format!("{}://", S3_SCHEME)) {
+                import_s3_image(&source, name.as_deref())?
+            } else {
+                import_dataset_image(Path::new(&source), name.as_deref())?
+            };
+            info!("Imported {} as image {}", source, uuid);
+            println!("{}", uuid);
+        }
+        Commands::Export { image, output_dir } => {
+            let uuid = installed::find(&image)?[0].uuid;
+            let file = export_image_as_dataset_format(uuid, &output_dir)?;
+            if let Some(mut manifest) = ImageManifest::load(&uuid)? {
+                manifest.add_file(&file)?;
+                manifest.store()?;
+                manifest.export_to(&output_dir)?;
+            }
+            println!("{}", file.display());
+        }
+        Commands::Delete { image } => {
+            let uuid = installed::find(&image)?[0].uuid;
+            installed::delete(&uuid)?;
+            println!("Deleted {}", uuid);
+        }
+        Commands::Vacuum { dry_run } => {
+            let images = installed::list()?;
+            let mut failed = 0;
+            for uuid in installed::unused(&images) {
+                let names: Vec<String> = images
+                    .iter()
+                    .filter(|i| i.uuid == uuid)
+                    .map(|i| i.reference())
+                    .collect();
+                if dry_run {
+                    println!("Would delete {} ({})", uuid, names.join(", "));
+                    continue;
+                }
+
+                match installed::delete(&uuid) {
+                    Ok(_) => println!("Deleted {} ({})", uuid, names.join(", ")),
+                    Err(err) => {
+                        error!("could not delete {} ({}): {}", uuid, names.join(", "), err);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                miette::bail!("{} images could not be deleted", failed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print an image registered under one or more names
+fn show(images: &[InstalledImage]) {
+    let field = |label: &str, value: &str| println!("{:<11} {}", format!("{}:", label), value);
+
+    let image = &images[0];
+    let names: Vec<String> = images.iter().map(|i| i.reference()).collect();
+    field("uuid", &image.uuid.to_string());
+    field("names", &names.join(", "));
+    field("tags", &tags(image));
+    field("dataset", &image.dataset);
+    field(
+        "size",
+        &image
+            .size
+            .map_or("dataset missing".to_string(), format_size),
+    );
+    field(
+        "origin",
+        &image.origin().map_or("-".to_string(), |o| o.to_string()),
+    );
+    field("clones", &image.clones.len().to_string());
+    for clone in &image.clones {
+        println!("    {}", clone);
+    }
+
+    let manifest = match &image.manifest {
+        Some(manifest) => manifest,
+        None => return,
+    };
+    field("brand", &manifest.brand.to_string());
+    if let Some(author) = &manifest.author {
+        field("author", author);
+    }
+    if let Some(source) = &manifest.source {
+        field("source", source);
+    }
+    if let Some(container) = &manifest.container {
+        field("entrypoint", &container.entrypoint.join(" "));
+        field("cmd", &container.cmd.join(" "));
+        if let Some(user) = &container.user {
+            field("user", user);
+        }
+        if let Some(workdir) = &container.working_dir {
+            field("workdir", workdir);
+        }
+    }
+    field("packages", &manifest.packages.len().to_string());
+    for volume in &manifest.volumes {
+        field(
+            "volume",
+            &format!("{} at {}", volume.name, volume.mountpoint),
+        );
+    }
+    for service in &manifest.services {
+        field("service", service);
+    }
+}
+
+fn tags(image: &InstalledImage) -> String {
+    if !image.registered {
+        "(unregistered)".to_string()
+    } else if image.tags.is_empty() {
+        "-".to_string()
+    } else {
+        image.tags.join(",")
+    }
 }
@@ -1,23 +1,53 @@
 use clap::{Parser, Subcommand, ValueEnum};
-use common::{debug, info, init_slog_logging};
+use common::{debug, format_size, info, init_slog_logging, warn};
 use miette::{Context, IntoDiagnostic, Result};
 use opczone::brand::Brand;
 use opczone::build::bundle::{BuildBundleType, Bundle};
-use opczone::get_zone_dataset;
-use opczone::image::{export_image_as_dataset_format, export_zone_as_oci_format};
+use opczone::build::cache::{self, CacheEntry};
+use opczone::build::download;
+use opczone::build::lint;
+use opczone::build::plan::Plan;
+use opczone::build::source::BundleSource;
+use opczone::build::stage::{self, CopySource, CopySources};
+use opczone::build::vm;
+use opczone::build::{build_vars_from_env, Action, BUILD_VARS_FILENAME};
+use opczone::image::reference::ImageReference;
+use opczone::image::resolve::{CandidateOrigin, Resolver};
+use opczone::image::{distribution, installed, manifest, store};
+use opczone::image::{
+    export_image_as_dataset_format, export_image_as_oci_format, publish_image,
+    publish_image_to_store, ImageManifest, PublishFormat,
+};
 use opczone::machine::AddNicPayload;
+use opczone::smf::Svcs;
 use opczone::{brand::build_zonecontrol_gz_path, machine::define_vm};
+use opczone::{get_zone_dataset, get_zonepath_parent_ds};
 use std::fmt::Display;
 use std::fs::{DirBuilder, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 
 const RUNNER_BRAND_PATH: &str = "/usr/lib/brand/opczimage/build_runner";
 const RUNNER_IN_ZONE_PATH_RELATIVE: &str = "build_runner";
 const RUNNER_IN_ZONE_PATH_ABSOLUTE: &str = "/build_runner";
 const ZONEADM: &str = "/usr/sbin/zoneadm";
+const ZONECFG: &str = "/usr/sbin/zonecfg";
 const ZLOGIN: &str = "/usr/sbin/zlogin";
+const SVCADM: &str = "/usr/sbin/svcadm";
+const MANIFEST_IMPORT_FMRI: &str = "svc:/system/manifest-import:default";
+/// Password of the user publish logs into registries as
+const REGISTRY_PASSWORD_ENV: &str = "OPC_REGISTRY_PASSWORD";
+/// Token publish sends to registries if no user is given
+const REGISTRY_TOKEN_ENV: &str = "OPC_REGISTRY_TOKEN";
+/// Stage bundles get generated in this directory of the staging directory
+const STAGES_DIR: &str = "stages";
+/// The cloud-init seed of vm builds is written to this directory of the zone path
+const VM_SEED_DIR: &str = "seed";
+/// define_vm places all zones here
+const ZONES_PATH: &str = "/zones";
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -88,13 +118,104 @@ enum Commands {
         /// To which image format to export into
         image_export_type: ExportType,
 
-        /// Tell the Cli the location of the build bundle. Assumes CWD as default
+        #[arg(long = "var", value_parser = parse_build_var)]
+        /// Variables for templates in the form key=value. Can be given multiple times
+        vars: Vec<(String, String)>,
+
+        #[arg(long, default_value_t = 300)]
+        /// Seconds to wait for the services enabled by the build to come online
+        service_timeout: u64,
+
+        #[arg(long)]
+        /// Run all actions even if they are cached. The cache still gets updated
+        no_cache: bool,
+
+        #[arg(long, default_value_t = 3600)]
+        /// Seconds to wait for a vm image to apply its actions and power off
+        vm_timeout: u64,
+
+        #[arg(long, default_value = download::DEFAULT_DOWNLOAD_CACHE_DIR)]
+        /// Directory downloads are kept in between builds
+        download_cache: PathBuf,
+
+        /// Tell the Cli the location of the build bundle. Assumes CWD as default.
+        /// Can be a directory, a .tar, .tar.gz or .tar.zst archive, a file:// or https:// url
+        /// to an archive optionally pinned with #sha256=<hex> or git+https://...#ref
         build_bundle: Option<String>,
     },
+    /// Check a build bundle for problems without building it
+    Check {
+        /// Directory of the build bundle. Assumes CWD as default
+        build_bundle: Option<PathBuf>,
+    },
+    /// Show what a build would do without creating a zone. Fails if an input is missing
+    Plan {
+        #[arg(long = "var", value_parser = parse_build_var)]
+        /// Variables for templates in the form key=value. Can be given multiple times
+        vars: Vec<(String, String)>,
+
+        /// Location of the build bundle. Accepts the same sources as build
+        build_bundle: Option<String>,
+    },
+    /// Push an installed image to a registry
     Publish {
-        /// Tell the utility where to publish the image to. Use oci:// for OCI registry endpoints
+        #[arg(short = 'e', long, default_value = "oci")]
+        /// Push an OCI image or an artifact wrapping the zfs stream of the image
+        image_export_type: ExportType,
+
+        #[arg(short, long)]
+        /// User to log into the registry as, the password is read from OPC_REGISTRY_PASSWORD.
+        /// Without a user the token in OPC_REGISTRY_TOKEN is used if it is set
+        username: Option<String>,
+
+        #[arg(long)]
+        /// Talk plain http to the registry, for registries run locally
+        insecure: bool,
+
+        /// uuid or name of the installed image, a version or tag can follow the name
+        image: String,
+
+        /// Tell the utility where to publish the image to. Use oci://<registry>/<repository>[:<tag>]
+        /// for OCI registry endpoints, the tag defaults to the version of the image. Use
+        /// s3://<bucket>/<prefix> for the object storage configured in /etc/opc/config.toml
         endpoint: Url,
     },
+    /// Manage the snapshots build uses to skip actions that did not change
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// List the cached layers
+    List {
+        #[arg(short, long)]
+        /// Dataset the build zones live in. Defaults to the dataset mounted at /zones
+        dataset: Option<String>,
+    },
+    /// Destroy cached layers. Without filters all layers are destroyed
+    Prune {
+        #[arg(short, long)]
+        /// Dataset the build zones live in. Defaults to the dataset mounted at /zones
+        dataset: Option<String>,
+
+        #[arg(short, long)]
+        /// Only destroy the layers of this image
+        image: Option<String>,
+
+        #[arg(long)]
+        /// Only destroy layers older than this many days
+        older_than: Option<u64>,
+    },
+}
+
+fn parse_build_var(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid variable {}, expected key=value", s)),
+    }
 }
 
 fn main() -> Result<()> {
@@ -158,182 +279,744 @@ fn main() -> Result<()> {
             gateway,
             build_bundle,
             image_export_type,
+            service_timeout,
+            no_cache,
+            vm_timeout,
+            download_cache,
+            vars,
         } => {
-            let bundle_path = std::fs::canonicalize(if let Some(build_bundle) = build_bundle {
-                Path::new(build_bundle.as_str()).to_path_buf()
-            } else {
-                Path::new(".").to_path_buf()
-            })
-            .into_diagnostic()?;
-
-            let bundle = Bundle::new(&bundle_path)?;
-
-            let mut cfg = opczone::machine::CreatePayload {
-                brand: match bundle.get_audit_info().kind() {
-                    BuildBundleType::BaseImage => Brand::Image,
-                    BuildBundleType::Image => Brand::Image,
-                    BuildBundleType::Bhyve => Brand::Bhyve,
-                    BuildBundleType::Propolis => Brand::Propolis,
-                    BuildBundleType::NativeBhyve => Brand::NativeBhyve,
-                },
-                max_physical_memory: Some(ram),
+            // Archives, urls and git sources get unpacked here. The directory lives until the build is done
+            let staging_dir = tempfile::Builder::new()
+                .prefix("imgbuild-")
+                .tempdir()
+                .into_diagnostic()?;
+            let bundle = load_bundle(build_bundle, vars, staging_dir.path())?;
+            let is_vm = bundle.get_audit_info().is_vm();
+            if is_vm && matches!(image_export_type, ExportType::OCI) {
+                miette::bail!("vm images can only be exported as dataset image");
+            }
+            let opts = BuildZoneOptions {
+                nictag,
                 quota,
                 ram,
-                zfs_io_priority: 30,
-                ..Default::default()
+                ip,
+                gateway,
+                service_timeout,
+                no_cache,
+                vm_timeout,
+                download_cache,
             };
 
-            if let Some(ref nictag) = nictag {
-                let mut nics = if let Some(nics) = cfg.nics {
-                    nics
-                } else {
-                    vec![]
-                };
-
-                nics.push(AddNicPayload {
-                    nic_tag: Some(nictag.clone()),
-                    ip: ip.clone(),
-                    gateway: gateway.clone(),
-                    ..Default::default()
-                });
-
-                cfg.nics = Some(nics)
+            //Stages are built in order so each can copy from the ones before it
+            let stages_dir = staging_dir.path().join(STAGES_DIR);
+            let mut stages = CopySources::new();
+            let mut stage_zones = StageZones::default();
+            for stage in &bundle.document.stages {
+                info!("building stage {}", stage.name);
+                let stage_bundle =
+                    stage::stage_bundle(&bundle, &stage.name, &stages_dir.join(&stage.name))?;
+                let built = build_zone(&opts, &stage_bundle, &stages)?;
+                stage_zones.0.push(built.zone.name().to_string());
+
+                /*
+                 * A ready zone keeps its root mounted for copy-from but runs nothing.
+                 * This frees the address of the zone for the next stage.
+                 */
+                let stage_zone = built.zone.name();
+                opczone::run(&[ZONEADM, "-z", stage_zone, "halt"], None)?;
+                opczone::run(&[ZONEADM, "-z", stage_zone, "ready"], None)?;
+
+                stages.insert(
+                    stage.name.clone(),
+                    CopySource {
+                        root: built.zone.path().join("root"),
+                        identity: built.last_hash,
+                    },
+                );
             }
 
-            let quota = cfg.quota.clone();
+            let BuiltZone { zone, origin, .. } = if is_vm {
+                build_vm(&opts, &bundle)?
+            } else {
+                build_zone(&opts, &bundle, &stages)?
+            };
+            let zonename = zone.name().to_string();
 
-            let conf = define_vm(cfg)?;
-            let zonename = conf.uuid.to_string();
+            let output_dir = std::env::current_dir().into_diagnostic()?;
 
-            let mut zoneadm = zone::Adm::new(&zonename);
+            //Packages of vm images are installed inside the guest where pkg can not see them
+            let mut image_manifest = ImageManifest::from_bundle(&bundle, origin)?;
+            if !is_vm {
+                image_manifest.packages = manifest::installed_packages(&zone.path().join("root"))?;
+            }
 
-            // We use opczone::run here to install the zone because the zone package gets all output before
-            // returning it to stdout. opczone::run shows progress immediatly
-            opczone::run(
-                &[
-                    ZONEADM,
-                    "-z",
-                    &zonename,
-                    "install",
-                    "-q",
-                    &quota.to_string(),
-                    "-b",
-                    bundle_path.to_str().expect("non UTF-8 paths can not be used by this program please put the bundle somewhere where there is UTF-8"),
-                ],
-                None,
+            let image_uuid = opczone::image::convert_zone_to_image(
+                &zonename,
+                &bundle.document.name,
+                Some(&image_manifest.version),
             )?;
+            image_manifest.uuid = image_uuid;
 
-            let zone = opczone::get_zone(&zonename)?;
-            debug!("trying to get zonepath of {}", zonename);
-            let zone_path = zone.path();
-            debug!("Zone path: {}", zone_path.display());
-
-            //Add Volume root to delegated dataset
-            let zone_ds_name = get_zone_dataset(&zone_path.as_os_str().to_string_lossy())?;
-            let mut zonecfg_zone = zone::Config::new(&zonename);
-            zonecfg_zone.add_dataset(&zone::Dataset {
-                name: format!("{}/vroot", zone_ds_name),
-            });
-            let out = zonecfg_zone.run_blocking().into_diagnostic()?;
-            info!("Updating zone config: {}", out);
-
-            //Boot Zone
-            zoneadm.boot_blocking().into_diagnostic()?;
-
-            //Copy Builder into zone
-            let gz_runner_in_zone_path = zone_path.join("root").join(RUNNER_IN_ZONE_PATH_RELATIVE);
-
-            info!("copying build_runner into zone {}", zone.name());
-            debug!(
-                "{} -> {}",
-                RUNNER_BRAND_PATH,
-                gz_runner_in_zone_path.display()
-            );
-            fs_extra::file::copy(
-                RUNNER_BRAND_PATH,
-                &gz_runner_in_zone_path,
-                &fs_extra::file::CopyOptions {
-                    skip_exist: true,
-                    ..Default::default()
-                },
-            )
-            .into_diagnostic()?;
+            match image_export_type {
+                ExportType::Dataset => {
+                    let file = export_image_as_dataset_format(image_uuid, &output_dir)?;
+                    image_manifest.add_file(&file)?;
+                }
+                ExportType::OCI => {
+                    let config = bundle.document.oci.clone().unwrap_or_default();
+                    let layer = export_image_as_oci_format(&output_dir, &config, &image_manifest)?;
+                    image_manifest.add_file(&layer.path)?;
+                }
+            }
+            image_manifest.store()?;
+            let manifest_path = image_manifest.export_to(&output_dir)?;
+            info!("wrote image manifest {}", manifest_path.display());
 
-            let mut instructions = libsysconfig::InstructionsSet::new();
-            if nictag.is_some() {
-                let ipv4_config = if let Some(ref ip) = ip {
-                    let ip = if !ip.contains("/") {
-                        ip.clone() + "/24"
-                    } else {
-                        ip.clone()
-                    };
-                    Some(libsysconfig::NetworkConfig::Static(ip.clone()))
-                } else {
-                    Some(libsysconfig::NetworkConfig::DHCP)
-                };
+            stage_zones.destroy()?;
+        }
+        Commands::Check { build_bundle } => {
+            let bundle_path = build_bundle.unwrap_or(Path::new(".").to_path_buf());
+            if let Some(report) = lint::check_bundle(&bundle_path)? {
+                return Err(report.into());
+            }
+            println!("{} has no problems", bundle_path.display());
+        }
+        Commands::Plan { vars, build_bundle } => {
+            let staging_dir = tempfile::Builder::new()
+                .prefix("imgbuild-")
+                .tempdir()
+                .into_diagnostic()?;
+            let bundle = load_bundle(build_bundle, vars, staging_dir.path())?;
+
+            // The zone gets its name when it is created so we can only show where it will live
+            let zone_root = Path::new(ZONES_PATH).join("<build-zone>").join("root");
+
+            let mut plans = vec![];
+            for stage in &bundle.document.stages {
+                let stage_bundle = stage::stage_bundle(
+                    &bundle,
+                    &stage.name,
+                    &staging_dir.path().join(STAGES_DIR).join(&stage.name),
+                )?;
+                let base_image = find_base_image(&stage_bundle)?;
+                plans.push(Plan::new(&stage_bundle, &zone_root, base_image));
+            }
+            let base_image = find_base_image(&bundle)?;
+            plans.push(Plan::new(&bundle, &zone_root, base_image));
 
-                for nic in conf.nics {
-                    let nic_config = libsysconfig::Instruction::ConfigureNetworkAdapter {
-                        device: nic.interface.clone(),
-                        name: None,
-                        ipv4: ipv4_config.clone(),
-                        ipv6: Some(libsysconfig::NetworkConfig::DHCPStateless),
-                        primary: true,
-                        temporary: true,
-                    };
-
-                    instructions.push(nic_config);
+            for (index, plan) in plans.iter().enumerate() {
+                if index > 0 {
+                    println!();
                 }
+                print!("{}", plan);
+            }
 
-                if let Some(ref gateway) = gateway {
-                    instructions.push(libsysconfig::Instruction::AddRoute {
-                        name: "default".into(),
-                        route_match: "default".into(),
-                        gateway: gateway.clone(),
-                    });
+            if let Some(plan) = plans.iter().find(|plan| !plan.is_complete()) {
+                let missing = plan.missing_inputs().len();
+                if let (Some(base_on), None) = (&plan.base_on, &plan.base_image) {
+                    miette::bail!(
+                        "base image {} of {} is not installed and {} inputs are missing",
+                        base_on,
+                        plan.name,
+                        missing
+                    );
+                }
+                miette::bail!("{} inputs of {} are missing", missing, plan.name);
+            }
+        }
+        Commands::Publish {
+            image_export_type,
+            username,
+            insecure,
+            image,
+            endpoint,
+        } => {
+            let uuid = installed::find(&image)?[0].uuid;
+            let format = match image_export_type {
+                ExportType::Dataset => PublishFormat::Dataset,
+                ExportType::OCI => PublishFormat::Oci,
+            };
+            if endpoint.scheme() == store::S3_SCHEME {
+                let location: store::S3Location = endpoint.as_str().parse()?;
+                let url = publish_image_to_store(&uuid, &location, format)?;
+                println!("{}", url);
+            } else {
+                let mut endpoint = distribution::Endpoint::from_url(&endpoint)?;
+                endpoint.insecure = insecure;
+                let credentials = match username {
+                    Some(username) => Some(distribution::Credentials::Basic {
+                        username,
+                        password: std::env::var(REGISTRY_PASSWORD_ENV)
+                            .into_diagnostic()
+                            .wrap_err(format!("{} is not set", REGISTRY_PASSWORD_ENV))?,
+                    }),
+                    None => std::env::var(REGISTRY_TOKEN_ENV)
+                        .ok()
+                        .map(distribution::Credentials::Token),
+                };
+                let digest = publish_image(&uuid, endpoint, credentials, format)?;
+                println!("{}", digest);
+            }
+        }
+        Commands::Cache { command } => match command {
+            CacheCommands::List { dataset } => {
+                let dataset = cache_dataset(dataset)?;
+                println!(
+                    "{:<40} {:>4} {:>10} {:>8}  SNAPSHOT",
+                    "IMAGE", "STEP", "SIZE", "AGE"
+                );
+                for entry in cache::list(&dataset)? {
+                    println!(
+                        "{:<40} {:>4} {:>10} {:>8}  {}",
+                        entry.image,
+                        entry.step,
+                        format_size(entry.used),
+                        format_age(&entry),
+                        entry.snapshot
+                    );
+                }
+            }
+            CacheCommands::Prune {
+                dataset,
+                image,
+                older_than,
+            } => {
+                let dataset = cache_dataset(dataset)?;
+                let older_than = older_than.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+                for entry in cache::list(&dataset)? {
+                    if image.as_ref().is_some_and(|image| image != &entry.image) {
+                        continue;
+                    }
+                    if older_than.is_some_and(|older_than| entry.age() < older_than) {
+                        continue;
+                    }
+                    cache::destroy(&entry)?;
                 }
+            }
+        },
+    }
+    Ok(())
+}
 
-                instructions.push(libsysconfig::Instruction::SetupDNS {
-                    domain: None,
-                    search: None,
-                    nameservers: vec!["9.9.9.9".into(), "1.1.1.1".into()],
-                });
+/// Settings of the zones a build creates. Stages and the final image share them
+struct BuildZoneOptions {
+    nictag: Option<String>,
+    quota: u32,
+    ram: u32,
+    ip: Option<String>,
+    gateway: Option<String>,
+    service_timeout: u64,
+    no_cache: bool,
+    vm_timeout: u64,
+    download_cache: PathBuf,
+}
+
+/// A zone with all actions of a bundle applied
+struct BuiltZone {
+    zone: zone::Zone,
+    /// The image the zone was created from
+    origin: Option<uuid::Uuid>,
+    /// Hash of the last cache layer. Changes whenever the content of the zone does
+    last_hash: String,
+}
+
+/// The zones of the stages built so far. Only the last stage becomes an image, the
+/// others are destroyed when the build is done or failed.
+#[derive(Default)]
+struct StageZones(Vec<String>);
+
+impl StageZones {
+    /// Destroy the zones and fail on the first one which can not be destroyed
+    fn destroy(mut self) -> Result<()> {
+        while let Some(zonename) = self.0.pop() {
+            destroy_zone(&zonename)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StageZones {
+    fn drop(&mut self) {
+        for zonename in self.0.drain(..).rev() {
+            if let Err(err) = destroy_zone(&zonename) {
+                warn!("could not remove stage zone {}: {}", zonename, err);
             }
+        }
+    }
+}
 
-            let zonecontrol_path = build_zonecontrol_gz_path(&zonename);
+/// Create a zone for bundle and run all of its actions. stages are the stages built
+/// before this one which copy-from can read from.
+fn build_zone(opts: &BuildZoneOptions, bundle: &Bundle, stages: &CopySources) -> Result<BuiltZone> {
+    let bundle_path = std::fs::canonicalize(bundle.get_path()).into_diagnostic()?;
+
+    let base_image = find_base_image(bundle)?;
+    let copy_sources = copy_sources(bundle, stages)?;
+    let identities = copy_sources
+        .iter()
+        .map(|(name, source)| (name.clone(), source.identity.clone()))
+        .collect();
+    let action_hashes = cache::action_hashes(bundle, base_image.as_ref(), &identities)?;
+
+    let mut cfg = opczone::machine::CreatePayload {
+        brand: bundle.get_audit_info().kind().brand(),
+        max_physical_memory: Some(opts.ram),
+        quota: opts.quota,
+        ram: opts.ram,
+        zfs_io_priority: 30,
+        ..Default::default()
+    };
+
+    if let Some(ref nictag) = opts.nictag {
+        let mut nics = if let Some(nics) = cfg.nics {
+            nics
+        } else {
+            vec![]
+        };
+
+        nics.push(AddNicPayload {
+            nic_tag: Some(nictag.clone()),
+            ip: opts.ip.clone(),
+            gateway: opts.gateway.clone(),
+            ..Default::default()
+        });
+
+        cfg.nics = Some(nics)
+    }
+
+    let quota = cfg.quota.clone();
+
+    let conf = define_vm(cfg)?;
+    let zonename = conf.uuid.to_string();
+
+    let mut zoneadm = zone::Adm::new(&zonename);
+
+    let zone = opczone::get_zone(&zonename)?;
+    debug!("trying to get zonepath of {}", zonename);
+    let zone_path = zone.path();
+    debug!("Zone path: {}", zone_path.display());
+
+    //Find the last action we have a snapshot of and start the zone from there
+    let cached = if opts.no_cache {
+        None
+    } else {
+        let cache_entries = cache::list(&get_zonepath_parent_ds(&zone_path.to_string_lossy())?)?;
+        cache::find_deepest(&cache_entries, &action_hashes)
+            .map(|(step, entry)| (step, entry.snapshot.clone()))
+    };
+
+    let quota = quota.to_string();
+    let mut install_args = vec![
+        ZONEADM,
+        "-z",
+        &zonename,
+        "install",
+        "-q",
+        &quota,
+        "-b",
+        bundle_path.to_str().expect("non UTF-8 paths can not be used by this program please put the bundle somewhere where there is UTF-8"),
+    ];
+    if let Some((step, snapshot)) = &cached {
+        info!(
+            "using cached layer {} skipping {} of {} actions",
+            snapshot,
+            step + 1,
+            action_hashes.len()
+        );
+        install_args.push("-c");
+        install_args.push(snapshot);
+    }
 
-            if !instructions.is_empty() {
-                let mut file = File::options()
-                    .write(true)
-                    .create_new(true)
-                    .open(zonecontrol_path.join("sysconfig.json"))
-                    .into_diagnostic()?;
-                serde_json::to_writer(&mut file, &instructions).into_diagnostic()?;
+    // We use opczone::run here to install the zone because the zone package gets all output before
+    // returning it to stdout. opczone::run shows progress immediatly
+    opczone::run(&install_args, None)?;
+
+    //Add Volume root to delegated dataset
+    let zone_ds_name = get_zone_dataset(&zone_path.as_os_str().to_string_lossy())?;
+
+    /*
+     * The layer of the last action is only cached once the services the build
+     * enabled are healthy. Otherwise the next build would reuse a broken image.
+     */
+    let last_step = action_hashes.len().checked_sub(1);
+
+    //Base images get their first action run by the installer
+    let first_step = match cached {
+        Some((step, _)) => step + 1,
+        None if bundle.get_audit_info().is_base_image() => {
+            if last_step != Some(0) {
+                cache::snapshot(&zone_ds_name, &action_hashes[0], &bundle.document.name, 0)?;
             }
+            1
+        }
+        None => 0,
+    };
+    let mut zonecfg_zone = zone::Config::new(&zonename);
+    zonecfg_zone.add_dataset(&zone::Dataset {
+        name: format!("{}/vroot", zone_ds_name),
+    });
+    let out = zonecfg_zone.run_blocking().into_diagnostic()?;
+    info!("Updating zone config: {}", out);
+
+    //Boot Zone
+    zoneadm.boot_blocking().into_diagnostic()?;
+
+    //Copy Builder into zone
+    let gz_runner_in_zone_path = zone_path.join("root").join(RUNNER_IN_ZONE_PATH_RELATIVE);
+
+    info!("copying build_runner into zone {}", zone.name());
+    debug!(
+        "{} -> {}",
+        RUNNER_BRAND_PATH,
+        gz_runner_in_zone_path.display()
+    );
+    fs_extra::file::copy(
+        RUNNER_BRAND_PATH,
+        &gz_runner_in_zone_path,
+        &fs_extra::file::CopyOptions {
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .into_diagnostic()?;
+
+    let mut instructions = libsysconfig::InstructionsSet::new();
+    if opts.nictag.is_some() {
+        let ipv4_config = if let Some(ref ip) = opts.ip {
+            let ip = if !ip.contains("/") {
+                ip.clone() + "/24"
+            } else {
+                ip.clone()
+            };
+            Some(libsysconfig::NetworkConfig::Static(ip.clone()))
+        } else {
+            Some(libsysconfig::NetworkConfig::DHCP)
+        };
+
+        for nic in conf.nics {
+            let nic_config = libsysconfig::Instruction::ConfigureNetworkAdapter {
+                device: nic.interface.clone(),
+                name: None,
+                ipv4: ipv4_config.clone(),
+                ipv6: Some(libsysconfig::NetworkConfig::DHCPStateless),
+                primary: true,
+                temporary: true,
+            };
+
+            instructions.push(nic_config);
+        }
+
+        if let Some(ref gateway) = opts.gateway {
+            instructions.push(libsysconfig::Instruction::AddRoute {
+                name: "default".into(),
+                route_match: "default".into(),
+                gateway: gateway.clone(),
+            });
+        }
+
+        instructions.push(libsysconfig::Instruction::SetupDNS {
+            domain: None,
+            search: None,
+            nameservers: vec!["9.9.9.9".into(), "1.1.1.1".into()],
+        });
+    }
+
+    let zonecontrol_path = build_zonecontrol_gz_path(&zonename);
+
+    if !instructions.is_empty() {
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(zonecontrol_path.join("sysconfig.json"))
+            .into_diagnostic()?;
+        serde_json::to_writer(&mut file, &instructions).into_diagnostic()?;
+    }
+
+    //Hand the template variables to the runner
+    if !bundle.build_vars.is_empty() {
+        let file = File::create(zonecontrol_path.join(BUILD_VARS_FILENAME)).into_diagnostic()?;
+        serde_json::to_writer(file, &bundle.build_vars).into_diagnostic()?;
+    }
+
+    //Run Builder inside zone with zlogin one action at a time and snapshot the zone
+    //after each action so the next build can start from there
+    //we again use opczone::run to get all the output
+    let mut sysconfig_applied = false;
+    for (step, hash) in action_hashes.iter().enumerate().skip(first_step) {
+        //copy-from reads from other zones, downloads share the cache of the host and
+        //devfsadm does not work inside a zone so those run here in the global zone
+        let action = &bundle.document.actions[step];
+        if let Action::CopyFrom(copy) = action {
+            info!("Running {}", action);
+            let source = &copy_sources[&copy.from];
+            stage::copy_from(source, &copy.src, &zone_path.join("root"), &copy.dst)?;
+        } else if let Action::Download(d) = action {
+            info!("Running {}", action);
+            let file = download::fetch(d, &opts.download_cache)?;
+            download::install(d, &file, &zone_path.join("root"))?;
+        } else if let Action::Devfsadm = action {
+            info!("Running {}", action);
+            opczone::build::devfsadm(&zone_path.join("root"))?;
+        } else {
+            let step_arg = step.to_string();
+            let mut runner_args = vec![
+                ZLOGIN,
+                "-Q",
+                &zonename,
+                RUNNER_IN_ZONE_PATH_ABSOLUTE,
+                "--step",
+                &step_arg,
+            ];
+            if sysconfig_applied {
+                runner_args.push("--skip-sysconfig");
+            }
+            opczone::run(&runner_args, None)?;
+            sysconfig_applied = true;
+        }
+
+        if Some(step) != last_step {
+            cache::snapshot(&zone_ds_name, hash, &bundle.document.name, step)?;
+        }
+    }
+
+    //Make sure the services the build enabled are healthy before we snapshot the zone
+    let enabled_services = bundle.document.enabled_services();
+    if !enabled_services.is_empty() {
+        let svcs = Svcs::zone(&zonename);
+        let timeout = Duration::from_secs(opts.service_timeout);
 
-            //Run Builder inside zone with zlogin
-            //we again use opczone::run to get all the output
+        if bundle.document.installs_smf_manifests() {
+            info!("importing service manifests in zone {}", zone.name());
             opczone::run(
-                &[ZLOGIN, "-Q", &zonename, RUNNER_IN_ZONE_PATH_ABSOLUTE],
+                &[SVCADM, "-z", &zonename, "restart", MANIFEST_IMPORT_FMRI],
                 None,
             )?;
+            svcs.wait_for_online(MANIFEST_IMPORT_FMRI, timeout)?;
+        }
 
-            //Cleanup Bundle
-            let bundle_zonecontrol_path = zonecontrol_path.join("build_bundle");
-            let cleanup_items = vec![bundle_zonecontrol_path.as_path(), &gz_runner_in_zone_path];
-            fs_extra::remove_items(&cleanup_items).into_diagnostic()?;
+        for fmri in enabled_services {
+            info!("waiting for service {} to come online", fmri);
+            svcs.wait_for_online(&fmri, timeout)?;
+        }
+    }
 
-            let output_dir = std::env::current_dir().into_diagnostic()?;
+    if let Some(last_step) = last_step {
+        if cached.as_ref().is_none_or(|(step, _)| *step < last_step) {
+            cache::snapshot(
+                &zone_ds_name,
+                &action_hashes[last_step],
+                &bundle.document.name,
+                last_step,
+            )?;
+        }
+    }
 
-            let image_uuid =
-                opczone::image::convert_zone_to_image(&zonename, &bundle.document.name)?;
+    //Cleanup Bundle
+    let bundle_zonecontrol_path = zonecontrol_path.join("build_bundle");
+    let cleanup_items = vec![bundle_zonecontrol_path.as_path(), &gz_runner_in_zone_path];
+    fs_extra::remove_items(&cleanup_items).into_diagnostic()?;
 
-            match image_export_type {
-                ExportType::Dataset => export_image_as_dataset_format(image_uuid, output_dir)?,
-                ExportType::OCI => export_zone_as_oci_format(zone, output_dir)?,
+    Ok(BuiltZone {
+        zone,
+        origin: base_image,
+        last_hash: action_hashes.last().cloned().unwrap_or_default(),
+    })
+}
+
+/// Create a bhyve zone with the disks of the vm-specs of bundle and let cloud-init apply
+/// the actions in the guest. The vm powers off when it is done.
+fn build_vm(opts: &BuildZoneOptions, bundle: &Bundle) -> Result<BuiltZone> {
+    let spec = bundle
+        .document
+        .vm_specs
+        .as_ref()
+        .ok_or(vm::VmError::NoVmSpecs)?;
+
+    /*
+     * The opcbhyve brand can not boot guests yet. The disks are the same for
+     * every bhyve brand so the native one builds the image.
+     */
+    if bundle.get_audit_info().kind() == BuildBundleType::Propolis {
+        miette::bail!("building propolis images is not supported yet, use (bhyve)vm-specs");
+    }
+
+    // Translate the actions before anything gets created so unsupported ones fail early
+    let user_data = vm::user_data(bundle)?;
+    let boot_image = vm::boot_image(bundle, spec)?;
+    let network_config = opts
+        .ip
+        .as_ref()
+        .map(|ip| vm::network_config(ip, opts.gateway.as_deref()));
+
+    let ram = match spec.get_memory()? {
+        Some(memory) => memory.as_mebibytes(),
+        None => opts.ram as u64,
+    };
+
+    let mut cfg = opczone::machine::CreatePayload {
+        brand: Brand::NativeBhyve,
+        ram: ram as u32,
+        vcpus: spec.cpus.unwrap_or(1) as u32,
+        quota: opts.quota,
+        zfs_io_priority: 30,
+        ..Default::default()
+    };
+
+    if let Some(ref nictag) = opts.nictag {
+        cfg.nics = Some(vec![AddNicPayload {
+            nic_tag: Some(nictag.clone()),
+            ip: opts.ip.clone(),
+            gateway: opts.gateway.clone(),
+            ..Default::default()
+        }]);
+    }
+
+    let conf = define_vm(cfg)?;
+    let zonename = conf.uuid.to_string();
+    opczone::run(&[ZONEADM, "-z", &zonename, "install"], None)?;
+
+    let zone = opczone::get_zone(&zonename)?;
+    let zone_path = zone.path();
+    let zone_ds_name = get_zone_dataset(&zone_path.to_string_lossy())?;
+
+    let disks = vm::disks(&zone_ds_name, spec)?;
+    vm::create_disks(&disks)?;
+
+    let installer = if vm::is_installer(&boot_image) {
+        Some(boot_image)
+    } else {
+        vm::write_boot_image(&boot_image, &disks[0])?;
+        None
+    };
+
+    let seed_dir = zone_path.join(VM_SEED_DIR);
+    let seed_iso = vm::write_seed(&seed_dir, &zonename, &user_data, network_config)?;
+    vm::configure_zone(
+        &zonename,
+        spec,
+        ram,
+        &disks,
+        &seed_iso,
+        installer.as_deref(),
+    )?;
+
+    let mut zoneadm = zone::Adm::new(&zonename);
+    let timeout = Duration::from_secs(opts.vm_timeout);
+
+    //Unattended installers power off once the system is on the disk
+    if installer.is_some() {
+        info!("booting installer in vm {}", zonename);
+        zoneadm.boot_blocking().into_diagnostic()?;
+        vm::wait_for_poweroff(&zonename, timeout)?;
+        vm::boot_from_disk(&zonename, &seed_iso)?;
+    }
+
+    info!("applying actions in vm {}", zonename);
+    zoneadm.boot_blocking().into_diagnostic()?;
+    vm::wait_for_poweroff(&zonename, timeout)?;
+
+    fs_extra::dir::remove(&seed_dir).into_diagnostic()?;
+
+    Ok(BuiltZone {
+        zone,
+        origin: None,
+        last_hash: String::new(),
+    })
+}
+
+/// Where each copy-from of bundle reads from. Names which are not a stage built before
+/// have to be installed images.
+fn copy_sources(bundle: &Bundle, stages: &CopySources) -> Result<CopySources> {
+    let mut sources = CopySources::new();
+    for action in &bundle.document.actions {
+        if let Action::CopyFrom(copy) = action {
+            if sources.contains_key(&copy.from) {
+                continue;
             }
+            let source = match stages.get(&copy.from) {
+                Some(source) => source.clone(),
+                None => stage::image_copy_source(&copy.from, &cache_dataset(None)?, stages)?,
+            };
+            sources.insert(copy.from.clone(), source);
         }
-        Commands::Publish { endpoint } => {}
     }
+    Ok(sources)
+}
+
+/// Remove a zone which is not needed anymore together with its datasets
+fn destroy_zone(zonename: &str) -> Result<()> {
+    info!("removing stage zone {}", zonename);
+    let zone = opczone::get_zone(zonename)?;
+    if matches!(zone.state(), zone::State::Running | zone::State::Ready) {
+        opczone::run(&[ZONEADM, "-z", zonename, "halt"], None)?;
+    }
+    opczone::run(&[ZONEADM, "-z", zonename, "uninstall", "-F"], None)?;
+    opczone::run(&[ZONECFG, "-z", zonename, "delete", "-F"], None)?;
     Ok(())
 }
+
+/// Load the bundle from any source. Sources which need unpacking are staged in staging_dir.
+/// Variables from the command line win over the ones from the environment.
+fn load_bundle(
+    build_bundle: Option<String>,
+    vars: Vec<(String, String)>,
+    staging_dir: &Path,
+) -> Result<Bundle> {
+    let source = if let Some(build_bundle) = build_bundle {
+        BundleSource::from_str(&build_bundle)?
+    } else {
+        BundleSource::Directory(Path::new(".").to_path_buf())
+    };
+
+    let mut bundle = Bundle::from_source(&source, staging_dir)?;
+
+    let mut build_vars = build_vars_from_env();
+    build_vars.extend(vars);
+    bundle.build_vars = build_vars;
+
+    Ok(bundle)
+}
+
+/// The installed image base-on resolves to. If the image is not installed but the image
+/// catalog of the host has it the build fails and tells where to import it from.
+fn find_base_image(bundle: &Bundle) -> Result<Option<uuid::Uuid>> {
+    let base_on = match &bundle.document.base_on {
+        Some(base_on) => base_on,
+        None => return Ok(None),
+    };
+
+    let reference: ImageReference = base_on.parse()?;
+    let resolver = Resolver {
+        catalog: config::open().into_diagnostic()?.image_catalog,
+        ..Default::default()
+    };
+    let image = match resolver.resolve_base(&reference)? {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+    match &image.origin {
+        CandidateOrigin::Local => Ok(Some(image.uuid)),
+        CandidateOrigin::Catalog { url, .. } => miette::bail!(
+            "base image {} is not installed, the image catalog has {}. Import it from {} first",
+            base_on,
+            image,
+            url
+        ),
+    }
+}
+
+fn cache_dataset(dataset: Option<String>) -> Result<String> {
+    match dataset {
+        Some(dataset) => Ok(dataset),
+        None => Ok(get_zone_dataset(ZONES_PATH)?),
+    }
+}
+
+fn format_age(entry: &CacheEntry) -> String {
+    let secs = entry.age().as_secs();
+    match secs {
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
@@ -1,25 +1,40 @@
 use clap::Parser;
 use common::init_slog_logging;
-use miette::{IntoDiagnostic, Result};
+use miette::{IntoDiagnostic, Result, WrapErr};
 use opczone::brand::ZONECONTROL_NGZ_PATH;
 use opczone::build::bundle::{BuildBundleKind, Bundle, BUILD_BUNDLE_IMAGE_PATH};
-use opczone::build::run_action;
+use opczone::build::{run_action, Action, BUILD_VARS_FILENAME};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
-struct Cli {}
+struct Cli {
+    /// Only run the action with this index. imgbuild runs the actions one by one to
+    /// snapshot the zone after each of them
+    #[arg(long)]
+    step: Option<usize>,
+
+    /// Do not apply the system configuration. It only needs to be applied once per boot
+    #[arg(long)]
+    skip_sysconfig: bool,
+}
 
 fn main() -> Result<()> {
     let _log_guard = init_slog_logging(false, true)?;
 
-    let _cli: Cli = Cli::parse();
+    let cli: Cli = Cli::parse();
 
     // First try find the bundle we have
     let build_bundle = search_build_bundle()?;
 
     // Load build Instructions
-    let bundle = load_build_bundle(&build_bundle)?;
+    let mut bundle = load_build_bundle(&build_bundle)?;
+
+    let build_vars_path = Path::new(ZONECONTROL_NGZ_PATH).join(BUILD_VARS_FILENAME);
+    if build_vars_path.exists() {
+        let build_vars_file = File::open(&build_vars_path).into_diagnostic()?;
+        bundle.build_vars = serde_json::from_reader(build_vars_file).into_diagnostic()?;
+    }
     //let bundle_audit = bundle.get_audit_info();
 
     println!(
@@ -28,28 +43,47 @@ fn main() -> Result<()> {
         bundle.document.author.clone().unwrap_or("Anonymous".into())
     );
 
-    let actions = bundle.document.actions.clone();
+    let actions: Vec<(usize, Action)> = if let Some(step) = cli.step {
+        let action = bundle
+            .document
+            .actions
+            .get(step)
+            .ok_or(miette::miette!("build bundle has no action {}", step))?;
+        vec![(step, action.clone())]
+    } else {
+        bundle
+            .document
+            .actions
+            .clone()
+            .into_iter()
+            .enumerate()
+            .collect()
+    };
 
     let zonename = zone::current_blocking().into_diagnostic()?;
 
-    let sysconfig_path = Path::new(ZONECONTROL_NGZ_PATH).join("sysconfig.json");
+    if !cli.skip_sysconfig {
+        let sysconfig_path = Path::new(ZONECONTROL_NGZ_PATH).join("sysconfig.json");
 
-    let sysconfig_file = File::open(&sysconfig_path).into_diagnostic()?;
+        let sysconfig_file = File::open(&sysconfig_path).into_diagnostic()?;
 
-    let set: libsysconfig::InstructionsSet =
-        serde_json::from_reader(sysconfig_file).into_diagnostic()?;
+        let set: libsysconfig::InstructionsSet =
+            serde_json::from_reader(sysconfig_file).into_diagnostic()?;
 
-    let mut img = libsysconfig::Image::new();
+        let mut img = libsysconfig::Image::new();
 
-    // For some Reason we get problems that the network is not online fast enough
-    // So we insert 1 second delay between comands to settle things.
-    // TODO: expose this feature to the configudarion
-    img.insert_delay(1);
+        // For some Reason we get problems that the network is not online fast enough
+        // So we insert 1 second delay between comands to settle things.
+        // TODO: expose this feature to the configudarion
+        img.insert_delay(1);
 
-    img.apply_instructions(set).into_diagnostic()?;
+        img.apply_instructions(set).into_diagnostic()?;
+    }
 
-    for action in actions {
-        run_action("/", &zonename, &bundle, action)?;
+    for (step, action) in actions {
+        let description = action.to_string();
+        run_action("/", &zonename, &bundle, action)
+            .wrap_err_with(|| format!("action [{}] failed: {}", step, description))?;
     }
 
     Ok(())
@@ -3,7 +3,7 @@ use common::init_slog_logging;
 use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
 use opczone::{
     brand::Brand,
-    build::{bundle::Bundle, run_action},
+    build::{bundle::Bundle, cache, run_action},
     get_zonepath_parent_ds,
     vmext::get_brand_config,
 };
@@ -35,6 +35,10 @@ struct Cli {
 
     #[arg(short = 'b')]
     build_bundle: Option<PathBuf>,
+
+    /// Build cache snapshot to start the build from instead of the base image
+    #[arg(short = 'c', requires = "build_bundle")]
+    cache_snapshot: Option<String>,
 }
 
 fn main() -> Result<()> {
@@ -58,6 +62,7 @@ fn main() -> Result<()> {
         cli.quota,
         cli.image_uuid,
         cli.build_bundle.clone(),
+        cli.cache_snapshot.clone(),
         cli.brand.clone(),
     )?;
 
@@ -67,8 +72,22 @@ fn main() -> Result<()> {
         let mut bundle = Bundle::new(&build_bundle).map_err(|err| miette!("{:?}", err))?;
         let bundle_audit = bundle.get_audit_info();
 
+        if bundle_audit.is_base_image() && !bundle_audit.is_safe_to_run_in_gz() {
+            let violations = bundle_audit
+                .violations()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join("\n");
+            bail!(
+                "refusing to build base image in the global zone, the bundle has paths leaving the image or bundle:\n{}",
+                violations
+            )
+        }
+
         //Install a base image by running the first IPS action in the GZ
-        if bundle_audit.is_base_image() {
+        //A cache layer already contains at least the first action
+        if bundle_audit.is_base_image() && cli.cache_snapshot.is_none() {
             //Run first IPS action to install base image
             if let Some(ips_action) = bundle.pop_action() {
                 run_action(&cli.zonepath, &cli.zonename, &bundle, ips_action)?;
@@ -90,6 +109,7 @@ fn setup_dataset(
     zonequota: i32,
     image: Option<uuid::Uuid>,
     build_bundle: Option<PathBuf>,
+    cache_snapshot: Option<String>,
     brand: Brand,
 ) -> Result<()> {
     let parent_dataset = get_zonepath_parent_ds(zonepath)?;
@@ -114,6 +134,8 @@ fn setup_dataset(
             .build()
             .into_diagnostic()?;
         zfs_clone(&clone_request).into_diagnostic()?;
+    } else if let Some(cache_snapshot) = cache_snapshot {
+        cache::clone_layer(&cache_snapshot, &zone_dataset_name, &quota_arg)?;
     } else if let Some(bundle_path) = build_bundle {
         let bundle = Bundle::new(&bundle_path).map_err(|err| miette!("{:?}", err))?;
         let audit_info = bundle.get_audit_info();
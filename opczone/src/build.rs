@@ -1,11 +1,17 @@
 use self::bundle::{Bundle, BundleError};
-use crate::{dataset_create_with, get_zone_vroot_dataset, smf::SMFError, OPCZoneError, UtilError};
-use common::info;
+use self::paths::PathError;
+use crate::{
+    dataset_create_with, get_zone_vroot_dataset, smf::SMFError, ExecOptions, ExitStatus,
+    OPCZoneError, UtilError,
+};
+use common::{info, warn};
 use miette::{Diagnostic, IntoDiagnostic};
+use serde::{Serialize, Serializer};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     path::{Path, PathBuf, StripPrefixError},
     str::FromStr,
+    time::Duration,
 };
 use tera::Context;
 use thiserror::Error;
@@ -45,6 +51,44 @@ pub enum BuildError {
     ParseIntError(#[from] std::num::ParseIntError),
     #[error("invalid disk type")]
     InvalidDiskKind,
+    #[error("unknown hypervisor {0}, use bhyve, native-bhyve or propolis")]
+    InvalidHypervisor(String),
+    #[error("group {1} of user {0} does not exist in the image")]
+    GroupNotFound(String, String),
+    #[error("{0} {1} is already used by {2}")]
+    IdAlreadyUsed(String, u32, String),
+    #[error("no free id left in {0}")]
+    NoFreeId(String),
+    #[error("CA certificate {0} for publisher {1} not found in the files directory of the bundle")]
+    CaCertificateNotFound(String, String),
+    #[error("publisher {0} is not configured in the image, add it with set-publisher before approving its CA")]
+    PublisherNotConfigured(String),
+    #[error("template {0} references variable {1} which is not defined. Define it in the vars block of build.kdl, pass it with --var or set it in the environment with the IMGBUILD_VAR_ prefix")]
+    TemplateVariableMissing(String, String),
+    #[error("could not render template {0}: {1}")]
+    TemplateRenderError(String, String),
+    #[error("onu repository {0} does not exist")]
+    OnuRepositoryNotFound(String),
+    #[error("no SMF seed repository found at {0}, is the image installed?")]
+    SmfSeedNotFound(String),
+    #[error(transparent)]
+    PathError(#[from] PathError),
+    #[error("copy-from {0} {1} can only run from imgbuild in the global zone")]
+    CopyFromOutsideGlobalZone(String, String),
+    #[error("run can only be used inside the build zone, base images have to start with ips")]
+    RunInGlobalZone,
+    #[error("run needs either a command or a script")]
+    EitherCommandOrScript,
+    #[error("user {0} does not exist in the image")]
+    UserNotFound(String),
+    #[error("{0} {1}:\n{2}")]
+    RunFailed(String, ExitStatus, String),
+    #[error("download {0} can only run from imgbuild in the global zone")]
+    DownloadOutsideGlobalZone(String),
+    #[error("devfsadm can only run from imgbuild in the global zone")]
+    DevfsadmOutsideGlobalZone,
+    #[error("onu can not update the global zone itself, it only runs inside the build zone")]
+    OnuInGlobalZone,
 }
 
 type BResult<T> = miette::Result<T, BuildError>;
@@ -54,7 +98,89 @@ type BResult<T> = miette::Result<T, BuildError>;
  */
 const ROOT: u32 = 0;
 
+const PKG: &str = "/usr/bin/pkg";
+
+/*
+ * pkg talks to remote repositories and can hang there forever. Installs can
+ * legitimately take a long time so we are generous but not unlimited.
+ */
+const PKG_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
+/*
+ * pkg(1) exit code for "nothing to do"
+ */
+const PKG_EXIT_NOP: i32 = 4;
+
+/*
+ * Installing a single optional dependency in the fallback should be quick. When it
+ * is not something else is wrong and waiting for PKG_TIMEOUT per package would stall
+ * the build for hours.
+ */
+const PKG_OPTIONAL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/*
+ * The solver explains its decision at length, keep enough of it to still see the
+ * first line.
+ */
+const PKG_OPTIONAL_TAIL_LINES: usize = 1000;
+
+/*
+ * pkg(1) exits with 1 for any error. These are the messages of the solver when it can
+ * not find a version of the package which fits the constraints of the image.
+ */
+const PKG_UNSATISFIABLE_MESSAGES: &[&str] = &[
+    "No matching version of",
+    "No solution was found to satisfy constraints",
+];
+
+/// Environment variables with this prefix are passed to templates. IMGBUILD_VAR_FOO_BAR
+/// becomes foo_bar
+pub const BUILD_VAR_ENV_PREFIX: &str = "IMGBUILD_VAR_";
+
+/// File in the zonecontrol directory in which imgbuild hands the build variables to the runner
+pub const BUILD_VARS_FILENAME: &str = "build_vars.json";
+
+const ONU_DEFAULT_PUBLISHER: &str = "on-nightly";
+
+const DEVFSADM: &str = "/usr/sbin/devfsadm";
+const SVCCFG: &str = "/usr/sbin/svccfg";
+
+const SMF_MANIFEST_DIR: &str = "lib/svc/manifest";
+const SMF_PROFILE_DIR: &str = "var/svc/profile";
+const SMF_REPOSITORY: &str = "etc/svc/repository.db";
+const SMF_DTD: &str = "usr/share/lib/xml/dtd/service_bundle.dtd.1";
+const SMF_SEED_GLOBAL: &str = "lib/svc/seed/global.db";
+const SMF_SEED_NONGLOBAL: &str = "lib/svc/seed/nonglobal.db";
+const SMF_DEFAULT_PROFILES: [&str; 2] = ["generic.xml", "site.xml"];
+
+/*
+ * Importing all manifests of a full install takes a couple of minutes on slow disks
+ */
+const SVCCFG_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/*
+ * Where approved publisher CAs are kept inside the image
+ */
+const PKG_CA_DIR: &str = "etc/certs/ips";
+
+const SH: &str = "/bin/sh";
+
+/*
+ * Compiling larger software can take a while. Commands that need longer have
+ * to set their own timeout.
+ */
+const RUN_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
 pub mod bundle;
+pub mod cache;
+pub mod download;
+pub mod lint;
+pub mod paths;
+pub mod plan;
+pub mod source;
+pub mod stage;
+pub mod users;
+pub mod vm;
 
 #[derive(knuffel::Decode, Clone, Debug, PartialEq)]
 pub struct Document {
@@ -68,18 +194,125 @@ pub struct Document {
     pub base_on: Option<String>,
     #[knuffel(child)]
     pub vm_specs: Option<VMImageSpec>,
+    #[knuffel(child)]
+    pub oci: Option<OciConfig>,
+    #[knuffel(child, default)]
+    pub vars: Vars,
+    #[knuffel(children(name = "stage"))]
+    pub stages: Vec<Stage>,
     #[knuffel(children)]
     pub actions: Vec<Action>,
 }
 
+/// A named stage of a multi-stage build. Stages are built into zones of their own
+/// before the final image and can be referenced by copy-from. They are never converted
+/// to images.
+#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+pub struct Stage {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(child, unwrap(argument))]
+    pub base_on: Option<String>,
+    #[knuffel(children)]
+    pub actions: Vec<Action>,
+}
+
+/// Variables for templates defined in build.kdl
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Default)]
+pub struct Vars {
+    #[knuffel(children)]
+    pub vars: Vec<Var>,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+pub struct Var {
+    #[knuffel(node_name)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+}
+
+/// How containers run the image when it is exported as OCI image
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Default)]
+pub struct OciConfig {
+    #[knuffel(child, unwrap(arguments), default)]
+    pub entrypoint: Vec<String>,
+    #[knuffel(child, unwrap(arguments), default)]
+    pub cmd: Vec<String>,
+    #[knuffel(children(name = "env"))]
+    pub env: Vec<RunEnv>,
+    #[knuffel(children(name = "port"))]
+    pub ports: Vec<OciPort>,
+    #[knuffel(child, unwrap(argument))]
+    pub user: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub workdir: Option<String>,
+}
+
+/// A port the image listens on: port 53 protocol="udp". Defaults to tcp
+#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+pub struct OciPort {
+    #[knuffel(argument)]
+    pub port: u16,
+    #[knuffel(property)]
+    pub protocol: Option<String>,
+}
+
+impl std::fmt::Display for OciPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.port,
+            self.protocol.as_deref().unwrap_or("tcp")
+        )
+    }
+}
+
+impl Document {
+    pub fn get_stage(&self, name: &str) -> Option<&Stage> {
+        self.stages.iter().find(|s| s.name == name)
+    }
+
+    /// Fmris of all services this build enables. They are expected to be online
+    /// before the image gets snapshotted.
+    pub fn enabled_services(&self) -> Vec<String> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Service(svc) if svc.enabled => Some(&svc.fmri),
+                Action::SmfManifest(manifest) if manifest.enabled => Some(&manifest.fmri),
+                _ => None,
+            })
+            .map(|fmri| {
+                let (service_name, instance_name) = split_service_fmri(fmri);
+                format!("svc:/{}:{}", service_name, instance_name)
+            })
+            .collect()
+    }
+
+    /// Whether the build installs manifests which only get imported by manifest-import
+    pub fn installs_smf_manifests(&self) -> bool {
+        self.actions
+            .iter()
+            .any(|action| matches!(action, Action::SmfManifest(_)))
+    }
+}
+
 #[derive(knuffel::Decode, Clone, Debug, PartialEq)]
 pub struct VMImageSpec {
+    /// Set with the type name like (bhyve)vm-specs. Defaults to bhyve
+    #[knuffel(type_name)]
+    pub hypervisor: Option<VMHypervisor>,
     #[knuffel(child, unwrap(argument))]
     pub cpus: Option<i32>,
     #[knuffel(child, unwrap(argument))]
     memory: Option<String>,
     #[knuffel(children(name = "disk"))]
     pub disks: Vec<VMImageDisk>,
+    /// Raw cloud image written to the first disk or installer .iso the vm boots from first
+    #[knuffel(child, unwrap(argument))]
+    pub boot_image: Option<String>,
 }
 
 impl VMImageSpec {
@@ -91,16 +324,54 @@ impl VMImageSpec {
             Ok(None)
         }
     }
+
+    pub fn get_hypervisor(&self) -> VMHypervisor {
+        self.hypervisor.clone().unwrap_or(VMHypervisor::Bhyve)
+    }
+}
+
+#[derive(knuffel::DecodeScalar, Clone, Debug, PartialEq)]
+pub enum VMHypervisor {
+    Bhyve,
+    NativeBhyve,
+    Propolis,
+}
+
+impl FromStr for VMHypervisor {
+    type Err = BuildError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bhyve" => Ok(Self::Bhyve),
+            "native-bhyve" => Ok(Self::NativeBhyve),
+            "propolis" => Ok(Self::Propolis),
+            x => Err(BuildError::InvalidHypervisor(x.to_string())),
+        }
+    }
 }
 
+/// A disk of a vm image. The size can be given as property or child: disk "vda" size="50G"
 #[derive(knuffel::Decode, Clone, Debug, PartialEq)]
 pub struct VMImageDisk {
     #[knuffel(type_name)]
-    disk_kind: VMDiskKind,
-    #[knuffel(child, unwrap(property))]
+    pub disk_kind: VMDiskKind,
+    #[knuffel(argument)]
+    pub name: Option<String>,
+    #[knuffel(property(name = "size"))]
+    size_property: Option<String>,
+    #[knuffel(child, unwrap(argument))]
     size: Option<String>,
 }
 
+impl VMImageDisk {
+    pub fn get_size(&self) -> BResult<Option<HumanReadableBytes>> {
+        match self.size.as_ref().or(self.size_property.as_ref()) {
+            Some(size) => Ok(Some(HumanReadableBytes::from_str(size)?)),
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(knuffel::DecodeScalar, Clone, Debug, PartialEq)]
 pub enum VMDiskKind {
     Vioscsi,
@@ -129,6 +400,23 @@ pub enum HumanReadableBytes {
     Peta(i32),
 }
 
+impl HumanReadableBytes {
+    pub fn as_bytes(&self) -> u64 {
+        match *self {
+            Self::Bytes(v) => v as u64,
+            Self::Kilo(v) => (v as u64) << 10,
+            Self::Mega(v) => (v as u64) << 20,
+            Self::Giga(v) => (v as u64) << 30,
+            Self::Tera(v) => (v as u64) << 40,
+            Self::Peta(v) => (v as u64) << 50,
+        }
+    }
+
+    pub fn as_mebibytes(&self) -> u64 {
+        self.as_bytes() >> 20
+    }
+}
+
 impl FromStr for HumanReadableBytes {
     type Err = BuildError;
 
@@ -153,23 +441,27 @@ impl FromStr for HumanReadableBytes {
     }
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub enum Action {
     Volume(Volume),
     Remove(#[knuffel(argument)] String),
     ExtractTarball(#[knuffel(argument)] String),
     AssembleFile(AssembleFile),
-    Group(#[knuffel(argument)] String),
-    User(
-        #[knuffel(argument)] String,
-        #[knuffel(argument)] Option<String>,
-    ),
+    Group(Group),
+    User(User),
     Symlink(Symlink),
     Dir(Dir),
     File(File),
     Perm(Dir),
     Ips(Ips),
     Service(Service),
+    SmfManifest(SmfManifest),
+    Onu(Onu),
+    Devfsadm,
+    SeedSmf(SeedSmf),
+    CopyFrom(CopyFrom),
+    Run(Run),
+    Download(Download),
 }
 
 impl std::fmt::Display for Action {
@@ -181,8 +473,8 @@ impl std::fmt::Display for Action {
             Action::AssembleFile(fil) => {
                 write!(f, "Action Assemble File: {}", fil.output.display())
             }
-            Action::Group(g) => write!(f, "Action Ensure Group: {}", g),
-            Action::User(u, _) => write!(f, "Action Ensure User: {}", u),
+            Action::Group(g) => write!(f, "Action Ensure Group: {}", g.name),
+            Action::User(u) => write!(f, "Action Ensure User: {}", u.name),
             Action::Symlink(l) => write!(
                 f,
                 "Action Ensure Symlink: {} -> {}",
@@ -194,22 +486,53 @@ impl std::fmt::Display for Action {
             Action::Perm(p) => write!(f, "Action Ensure Permissions: {}", p.path.display()),
             Action::Ips(_) => Ok(()),
             Action::Service(svc) => write!(f, "Applying settings for service: {}", svc.fmri),
+            Action::SmfManifest(m) => write!(f, "Action Install SMF Manifest: {}", m.fmri),
+            Action::Onu(onu) => write!(f, "Action Install onu packages from: {}", onu.repository),
+            Action::Devfsadm => write!(f, "Action Run devfsadm"),
+            Action::SeedSmf(_) => write!(f, "Action Seed SMF repository"),
+            Action::CopyFrom(c) => write!(
+                f,
+                "Action Copy From {}: {} -> {}",
+                c.from,
+                c.src.display(),
+                c.dst.display()
+            ),
+            Action::Run(run) => match (&run.command, &run.script) {
+                (Some(command), _) => write!(f, "Action Run: {}", command),
+                (None, Some(script)) => write!(f, "Action Run Script: {}", script),
+                (None, None) => write!(f, "Action Run"),
+            },
+            Action::Download(d) => {
+                write!(f, "Action Download: {} -> {}", d.url, d.path.display())
+            }
         }
     }
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct Ips {
     #[knuffel(children)]
     pub actions: Vec<IpsActions>,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct IpsProperties {
     #[knuffel(properties)]
+    #[serde(serialize_with = "serialize_sorted")]
     pub properties: HashMap<String, String>,
 }
 
+/*
+ * Serialized actions are hashed for the build cache, the order of a HashMap
+ * changes from run to run.
+ */
+fn serialize_sorted<S: Serializer>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
 impl std::fmt::Display for IpsProperties {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut v = vec![];
@@ -226,7 +549,7 @@ impl std::fmt::Display for IpsProperties {
     }
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct IpsPackageList {
     #[knuffel(arguments)]
     pub packages: Vec<String>,
@@ -239,7 +562,7 @@ impl std::fmt::Display for IpsPackageList {
     }
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub enum IpsActions {
     InitializeImage,
     InstallPackages(IpsPackageList),
@@ -272,7 +595,7 @@ impl std::fmt::Display for IpsActions {
     }
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct Mediator {
     #[knuffel(argument)]
     pub name: String,
@@ -282,7 +605,7 @@ pub struct Mediator {
     pub version: Option<String>,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct CaCertificates {
     #[knuffel(argument)]
     pub publisher: String,
@@ -290,7 +613,7 @@ pub struct CaCertificates {
     pub cert_file: String,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct IpsPublisher {
     #[knuffel(argument)]
     pub publisher: String,
@@ -298,7 +621,7 @@ pub struct IpsPublisher {
     pub uris: Vec<String>,
 }
 
-#[derive(knuffel::Decode, Clone, Default, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Default, Debug, PartialEq, Serialize)]
 pub struct CommonPerms {
     #[knuffel(child, unwrap(argument))]
     pub owner: Option<String>,
@@ -308,7 +631,7 @@ pub struct CommonPerms {
     pub mode: Option<u32>,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct Dir {
     #[knuffel(flatten(child))]
     pub common: CommonPerms,
@@ -316,7 +639,7 @@ pub struct Dir {
     pub path: PathBuf,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct File {
     #[knuffel(flatten(child))]
     pub common: CommonPerms,
@@ -330,7 +653,64 @@ pub struct File {
     pub path: PathBuf,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct CopyFrom {
+    /// Name of a stage of this bundle or of an installed image
+    #[knuffel(argument)]
+    pub from: String,
+    #[knuffel(argument)]
+    pub src: PathBuf,
+    #[knuffel(argument)]
+    pub dst: PathBuf,
+}
+
+/// Run a command or a script from the files directory inside the build zone
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct Run {
+    /// Command line passed to /bin/sh -c
+    #[knuffel(argument)]
+    pub command: Option<String>,
+    /// Script in the files directory fed to /bin/sh on stdin
+    #[knuffel(child, unwrap(argument))]
+    pub script: Option<String>,
+    #[knuffel(children(name = "env"))]
+    pub env: Vec<RunEnv>,
+    #[knuffel(child, unwrap(argument))]
+    pub cwd: Option<PathBuf>,
+    #[knuffel(child, unwrap(argument))]
+    pub user: Option<String>,
+    /// Seconds after which the command is killed
+    #[knuffel(child, unwrap(argument))]
+    pub timeout: Option<u64>,
+}
+
+/// Download a file into the image. The checksum is required so builds are reproducible
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct Download {
+    #[knuffel(argument)]
+    pub url: String,
+    /// Path of the file in the image or the directory to extract into
+    #[knuffel(argument)]
+    pub path: PathBuf,
+    #[knuffel(child, unwrap(argument))]
+    pub sha256: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub sha512: Option<String>,
+    #[knuffel(child)]
+    pub extract: bool,
+    #[knuffel(flatten(child))]
+    pub common: CommonPerms,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct RunEnv {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct Symlink {
     #[knuffel(argument)]
     pub link: PathBuf,
@@ -342,7 +722,7 @@ pub struct Symlink {
     pub group: Option<String>,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct Volume {
     #[knuffel(argument)]
     pub name: String,
@@ -352,7 +732,7 @@ pub struct Volume {
     pub properties: Vec<VolumeProperty>,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct VolumeProperty {
     #[knuffel(node_name)]
     pub name: String,
@@ -362,7 +742,7 @@ pub struct VolumeProperty {
     pub driver_name: String,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct AssembleFile {
     #[knuffel(flatten(child))]
     pub common: CommonPerms,
@@ -382,7 +762,41 @@ pub struct Smf {
     pub apply_site: bool,
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct Group {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(property)]
+    pub gid: Option<u32>,
+    #[knuffel(child, unwrap(arguments), default)]
+    pub members: Vec<String>,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct User {
+    #[knuffel(argument)]
+    pub name: String,
+    /// Already encrypted password or one of the special values like NP
+    #[knuffel(argument)]
+    pub password: Option<String>,
+    #[knuffel(property)]
+    pub uid: Option<u32>,
+    #[knuffel(property)]
+    pub gid: Option<u32>,
+    #[knuffel(property)]
+    pub home: Option<String>,
+    #[knuffel(property)]
+    pub shell: Option<String>,
+    #[knuffel(property)]
+    pub gecos: Option<String>,
+    /// Supplementary groups
+    #[knuffel(child, unwrap(arguments), default)]
+    pub groups: Vec<String>,
+    #[knuffel(child, unwrap(arguments), default)]
+    pub authorized_keys: Vec<String>,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct Service {
     #[knuffel(argument)]
     pub fmri: String,
@@ -392,21 +806,27 @@ pub struct Service {
     pub properties: Vec<ServiceProperty>,
 }
 
+/// Split a service fmri into service and instance name. Services without instance get the
+/// default instance.
+fn split_service_fmri(fmri: &str) -> (String, String) {
+    let fmri = fmri.strip_prefix("svc:/").unwrap_or(fmri);
+    if let Some((svc_name, inst_name)) = fmri.split_once(":") {
+        if inst_name.is_empty() {
+            (svc_name.to_string(), String::from("default"))
+        } else {
+            (svc_name.to_string(), inst_name.to_string())
+        }
+    } else {
+        (fmri.to_string(), String::from("default"))
+    }
+}
+
 impl Service {
     pub fn to_smf_site_service_defintion(
         &self,
         bundle_name: &str,
     ) -> miette::Result<crate::smf::ServiceBundle> {
-        let (service_name, instance_name) =
-            if let Some((svc_name, inst_name)) = self.fmri.split_once(":") {
-                if inst_name == "" {
-                    (svc_name.to_string(), String::from("default"))
-                } else {
-                    (svc_name.to_string(), inst_name.to_string())
-                }
-            } else {
-                (self.fmri.clone(), String::from("default"))
-            };
+        let (service_name, instance_name) = split_service_fmri(&self.fmri);
         let mut svc = crate::smf::Service::default();
         svc.name = service_name.clone();
         svc.version = 1;
@@ -440,6 +860,7 @@ impl Service {
                             pb_type: crate::smf::PropValType::AString,
                             value: prop.value.clone(),
                         }],
+                        ..Default::default()
                     },
                 );
             }
@@ -458,7 +879,7 @@ impl Service {
     }
 }
 
-#[derive(knuffel::Decode, Clone, Debug, PartialEq)]
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
 pub struct ServiceProperty {
     #[knuffel(argument)]
     pub name: String,
@@ -466,6 +887,300 @@ pub struct ServiceProperty {
     pub value: String,
 }
 
+/// Install packages from a repository built by an illumos-gate nightly like onu(1ONBLD)
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct Onu {
+    /// Path to the repository. Relative paths are looked up in the files directory of the bundle
+    #[knuffel(argument)]
+    pub repository: String,
+    #[knuffel(property, default = String::from(ONU_DEFAULT_PUBLISHER))]
+    pub publisher: String,
+    /// Packages like entire which pin the versions of the OS and need to go before updating
+    #[knuffel(child, unwrap(arguments), default)]
+    pub uninstall: Vec<String>,
+}
+
+/// Import all manifests into the SMF repository of the image so the first boot does not
+/// need to do it
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct SeedSmf {
+    /// Start from the seed repository of the global zone instead of the one for zones
+    #[knuffel(property, default = false)]
+    pub global: bool,
+    /// Profiles from /var/svc/profile applied after the import if they exist
+    #[knuffel(child, unwrap(arguments))]
+    pub profiles: Option<Vec<String>>,
+}
+
+/// Path in the image where site specific service manifests live. Manifests there
+/// get imported by svc:/system/manifest-import on boot.
+pub const SITE_MANIFEST_DIR: &str = "lib/svc/manifest/site";
+
+/// Default timeout for start, stop and refresh methods
+const DEFAULT_METHOD_TIMEOUT: i64 = 60;
+
+/// A complete service definition which gets turned into a SMF manifest
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct SmfManifest {
+    #[knuffel(argument)]
+    pub fmri: String,
+    #[knuffel(property, default = true)]
+    pub enabled: bool,
+    #[knuffel(child)]
+    pub start: SmfExecCommand,
+    #[knuffel(child)]
+    pub stop: Option<SmfExecCommand>,
+    #[knuffel(child)]
+    pub refresh: Option<SmfExecCommand>,
+    #[knuffel(child, unwrap(argument))]
+    pub user: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub group: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub working_dir: Option<String>,
+    /// contract, child or transient. See svc.startd(8)
+    #[knuffel(child, unwrap(argument))]
+    pub duration: Option<String>,
+    #[knuffel(children(name = "environment"))]
+    pub environment: Vec<SmfEnvironmentVariable>,
+    #[knuffel(children(name = "dependency"))]
+    pub dependencies: Vec<SmfDependency>,
+    #[knuffel(children(name = "property"))]
+    pub properties: Vec<SmfProperty>,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct SmfExecCommand {
+    #[knuffel(argument)]
+    pub exec: String,
+    #[knuffel(property, default = DEFAULT_METHOD_TIMEOUT)]
+    pub timeout: i64,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct SmfEnvironmentVariable {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct SmfDependency {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(arguments)]
+    pub fmris: Vec<String>,
+    #[knuffel(property, default = String::from("require_all"))]
+    pub grouping: String,
+    #[knuffel(property, default = String::from("none"))]
+    pub restart_on: String,
+}
+
+#[derive(knuffel::Decode, Clone, Debug, PartialEq, Serialize)]
+pub struct SmfProperty {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+    #[knuffel(property(name = "type"), default = String::from("astring"))]
+    pub prop_type: String,
+}
+
+impl SmfManifest {
+    pub fn manifest_name(&self) -> String {
+        let (service_name, _) = split_service_fmri(&self.fmri);
+        service_name.replace("/", "_")
+    }
+
+    pub fn to_smf_manifest(&self) -> BResult<crate::smf::ServiceBundle> {
+        use crate::smf;
+
+        let (service_name, instance_name) = split_service_fmri(&self.fmri);
+
+        let mut dependencies = vec![];
+        for dep in &self.dependencies {
+            let dependency_type = if dep.fmris.iter().all(|f| f.starts_with("file:")) {
+                smf::DependencyType::Path
+            } else {
+                smf::DependencyType::Service
+            };
+            dependencies.push(smf::Dependency {
+                name: dep.name.clone(),
+                grouping: smf::DependencyGrouping::from_str(&dep.grouping)?,
+                restart_on: smf::RestartOn::from_str(&dep.restart_on)?,
+                dependency_type,
+                service_fmris: dep.fmris.iter().map(|f| smf::ServiceFmri::new(f)).collect(),
+                ..Default::default()
+            });
+        }
+
+        let method_credential = self.user.as_ref().map(|user| smf::MethodCredential {
+            user: user.clone(),
+            group: self.group.clone(),
+            ..Default::default()
+        });
+
+        let method_environment = if self.environment.is_empty() {
+            None
+        } else {
+            Some(smf::MethodEnvironment {
+                variables: self
+                    .environment
+                    .iter()
+                    .map(|e| smf::EnvVar {
+                        name: e.name.clone(),
+                        value: e.value.clone(),
+                    })
+                    .collect(),
+            })
+        };
+
+        let method_context = if method_credential.is_some()
+            || method_environment.is_some()
+            || self.working_dir.is_some()
+        {
+            Some(smf::MethodContext {
+                working_directory: self.working_dir.clone(),
+                method_credential,
+                method_environment,
+                ..Default::default()
+            })
+        } else {
+            None
+        };
+
+        /*
+         * SMF requires a stop method. Killing the contract is what most
+         * daemons need so we default to that.
+         */
+        let stop = self.stop.clone().unwrap_or(SmfExecCommand {
+            exec: String::from(":kill"),
+            timeout: DEFAULT_METHOD_TIMEOUT,
+        });
+        let mut exec_methods = vec![
+            smf::ExecMethod::new("start", &self.start.exec, self.start.timeout),
+            smf::ExecMethod::new("stop", &stop.exec, stop.timeout),
+        ];
+        if let Some(refresh) = &self.refresh {
+            exec_methods.push(smf::ExecMethod::new(
+                "refresh",
+                &refresh.exec,
+                refresh.timeout,
+            ));
+        }
+
+        let mut property_groups: Vec<smf::PropertyGroup> = vec![];
+        if let Some(duration) = &self.duration {
+            property_groups.push(smf::PropertyGroup {
+                name: String::from("startd"),
+                pg_type: smf::PropertyGroupType::Framework,
+                values: vec![smf::PropVal::new(
+                    "duration",
+                    smf::PropValType::AString,
+                    duration,
+                )?],
+                ..Default::default()
+            });
+        }
+
+        for prop in &self.properties {
+            let (pg_name, prop_name) =
+                prop.name
+                    .split_once("/")
+                    .ok_or(BuildError::NoNameForPropertyGroupInService(
+                        prop.name.clone(),
+                        service_name.clone(),
+                    ))?;
+            let value = smf::PropVal::new(
+                prop_name,
+                smf::PropValType::from_str(&prop.prop_type)?,
+                &prop.value,
+            )?;
+            if let Some(pg) = property_groups.iter_mut().find(|pg| pg.name == pg_name) {
+                pg.values.push(value);
+            } else {
+                property_groups.push(smf::PropertyGroup {
+                    name: pg_name.to_string(),
+                    pg_type: smf::PropertyGroupType::Application,
+                    values: vec![value],
+                    ..Default::default()
+                });
+            }
+        }
+
+        let svc = smf::Service {
+            name: service_name.clone(),
+            version: 1,
+            service_type: smf::ServiceType::Service,
+            dependencies,
+            method_context,
+            exec_methods,
+            property_groups,
+            instances: vec![smf::Instance {
+                name: instance_name,
+                enabled: Some(self.enabled),
+                ..Default::default()
+            }],
+            stability: Some(smf::Stability {
+                value: smf::StabilityValue::Unstable,
+            }),
+            ..Default::default()
+        };
+
+        Ok(smf::ServiceBundle {
+            bundle_type: smf::BundleType::Manifest,
+            name: self.manifest_name(),
+            services: vec![svc],
+        })
+    }
+}
+
+/// Collect the build variables from the environment
+pub fn build_vars_from_env() -> HashMap<String, String> {
+    std::env::vars()
+        .filter_map(|(key, value)| {
+            key.strip_prefix(BUILD_VAR_ENV_PREFIX)
+                .filter(|name| !name.is_empty())
+                .map(|name| (name.to_lowercase(), value))
+        })
+        .collect()
+}
+
+/// Render a template with tera. Missing variables are reported with the name of the template.
+pub fn render_template(name: &str, template: &str, context: &Context) -> BResult<String> {
+    let mut tera = tera::Tera::default();
+    tera.autoescape_on(vec![]);
+    tera.add_raw_template(name, template)?;
+
+    tera.render(name, context).map_err(|err| {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+        let mut messages = vec![];
+        while let Some(e) = source {
+            let variable = match e.downcast_ref::<tera::Error>().map(|e| &e.kind) {
+                Some(tera::ErrorKind::Msg(message)) => missing_template_variable(message),
+                _ => None,
+            };
+            if let Some(variable) = variable {
+                return BuildError::TemplateVariableMissing(name.to_string(), variable.to_string());
+            }
+            messages.push(e.to_string());
+            source = e.source();
+        }
+        BuildError::TemplateRenderError(name.to_string(), messages.join(": "))
+    })
+}
+
+/*
+ * tera has no error kind for missing variables, they are a plain message like
+ * "Variable `port` not found in context while rendering 'myapp.toml'"
+ */
+fn missing_template_variable(message: &str) -> Option<&str> {
+    let (variable, rest) = message.strip_prefix("Variable `")?.split_once('`')?;
+    rest.starts_with(" not found in context")
+        .then_some(variable)
+}
+
 pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Action) -> BResult<()> {
     let root_string = if zonename == &zone::current_blocking()? {
         zonepath.clone().to_string()
@@ -504,11 +1219,9 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
             Ok(())
         }
         Action::Remove(path) => {
-            let rpath = Path::new(root);
-            let path = Path::new(&path).strip_prefix("/")?;
-
-            let paths = vec![rpath.join(path)];
-            fs_extra::remove_items(&paths)?;
+            // Remove the link, not what it points to
+            let items = vec![paths::resolve_parent(root, &path)?];
+            fs_extra::remove_items(&items)?;
 
             Ok(())
         }
@@ -528,7 +1241,7 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
         }
         Action::AssembleFile(assemble) => {
             let source_path = bundle.get_file(assemble.dir)?;
-            let output_path = Path::new(root).join(assemble.output.strip_prefix("/")?);
+            let output_path = paths::resolve(root, &assemble.output)?;
 
             let mut files: Vec<String> = Vec::new();
             let mut diri = std::fs::read_dir(source_path)?;
@@ -576,33 +1289,23 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
 
             Ok(())
         }
-        Action::Group(_group) => Err(BuildError::NotYetSupported(String::from("Group")).into()),
-        Action::User(user, pw) => {
-            /*
-             * Read the shadow file:
-             */
-            let path = Path::new(root).join("etc/shadow");
-
-            let orig = illumos_image_builder::ShadowFile::load(&path)?;
-            let mut copy = orig.clone();
+        Action::Group(group) => {
+            users::ensure_group(Path::new(root), &group)?;
 
-            if let Some(password) = pw {
-                copy.password_set(&user, &password)?;
-            }
-
-            if orig == copy {
-                info!("no change to shadow file; skipping write");
-            } else {
-                info!("updating shadow file");
-                copy.write(&path)?;
-                illumos_image_builder::ensure::perms(&path, ROOT, ROOT, 0o400)?;
-            }
+            Ok(())
+        }
+        Action::User(user) => {
+            let root = Path::new(root);
+            let entry = users::ensure_user(root, &user)?;
+            users::ensure_shadow_perms(root)?;
+            users::ensure_home(root, &entry, &user.authorized_keys)?;
 
             Ok(())
         }
         Action::Symlink(link) => {
-            let target_path = Path::new(root).join(&link.target);
-            let link_path = Path::new(root).join(&link.link);
+            // The target is what the image sees. It is stored as is and never followed here
+            let target_path = link.target.clone();
+            let link_path = paths::resolve_parent(root, &link.link)?;
 
             let owner = if let Some(user) = link.owner {
                 illumos_image_builder::translate_uid(&user)?
@@ -621,7 +1324,7 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
             Ok(())
         }
         Action::Dir(dir) => {
-            let target_path = Path::new(root).join(dir.path.strip_prefix("/")?);
+            let target_path = paths::resolve(root, &dir.path)?;
 
             let owner = if let Some(user) = dir.common.owner {
                 illumos_image_builder::translate_uid(&user)?
@@ -646,7 +1349,7 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
             Ok(())
         }
         Action::File(file) => {
-            let target_path = Path::new(root).join(file.path.strip_prefix("/")?);
+            let target_path = paths::resolve(root, &file.path)?;
 
             let owner = if let Some(user) = file.common.owner {
                 illumos_image_builder::translate_uid(&user)?
@@ -669,9 +1372,7 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
             if let Some(src) = file.src {
                 if file.is_template {
                     let template = bundle.get_template_string(&src)?;
-
-                    let mut tera = tera::Tera::new("")?;
-                    let res = tera.render_str(&template, &Context::new())?;
+                    let res = render_template(&src, &template, &bundle.template_context())?;
 
                     illumos_image_builder::ensure::filestr(
                         &res,
@@ -695,8 +1396,11 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
                 }
             } else if let Some(content) = file.content {
                 if file.is_template {
-                    let mut tera = tera::Tera::new("")?;
-                    let res = tera.render_str(&content, &Context::new())?;
+                    let res = render_template(
+                        &file.path.to_string_lossy(),
+                        &content,
+                        &bundle.template_context(),
+                    )?;
 
                     illumos_image_builder::ensure::filestr(
                         &res,
@@ -723,7 +1427,7 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
             Ok(())
         }
         Action::Perm(perm) => {
-            let target_path = Path::new(root).join(perm.path.strip_prefix("/")?);
+            let target_path = paths::resolve(root, &perm.path)?;
 
             let owner = if let Some(user) = perm.common.owner {
                 illumos_image_builder::translate_uid(&user)?
@@ -751,7 +1455,7 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
         }
         Action::Ips(ips_actions) => {
             for action in ips_actions.actions {
-                run_ips_action(root, action)?;
+                run_ips_action(root, bundle, action)?;
             }
             Ok(())
         }
@@ -770,32 +1474,193 @@ pub fn run_action(zonepath: &str, zonename: &str, bundle: &Bundle, action: Actio
 
             Ok(())
         }
+        Action::Onu(onu) => run_onu(root, bundle, &onu),
+        Action::Devfsadm => Err(BuildError::DevfsadmOutsideGlobalZone),
+        Action::SeedSmf(seed) => seed_smf(root, &seed),
+        Action::CopyFrom(copy) => Err(BuildError::CopyFromOutsideGlobalZone(
+            copy.from,
+            copy.src.display().to_string(),
+        )),
+        Action::Download(download) => Err(BuildError::DownloadOutsideGlobalZone(download.url)),
+        Action::Run(run) => {
+            // Anything else would run the command on the host
+            if root != "/" {
+                return Err(BuildError::RunInGlobalZone);
+            }
+            run_command(Path::new(root), bundle, &run)
+        }
+        Action::SmfManifest(manifest) => {
+            let bundle = manifest.to_smf_manifest()?;
+            let content = crate::smf::manifest_to_string(&bundle)?;
+
+            let manifest_dir = Path::new(root).join(SITE_MANIFEST_DIR);
+            illumos_image_builder::ensure::directory(&manifest_dir, ROOT, ROOT, 0o755)?;
+
+            let manifest_path = manifest_dir.join(format!("{}.xml", manifest.manifest_name()));
+            illumos_image_builder::ensure::filestr(
+                &content,
+                &manifest_path,
+                ROOT,
+                ROOT,
+                0o444,
+                illumos_image_builder::ensure::Create::Always,
+            )?;
+
+            Ok(())
+        }
+    }
+}
+
+fn run_command(root: &Path, bundle: &Bundle, run: &Run) -> BResult<()> {
+    let (args, stdin) = match (&run.command, &run.script) {
+        (Some(command), None) => (vec![SH, "-c", command.as_str()], None),
+        (None, Some(script)) => {
+            let content = std::fs::read_to_string(bundle.get_file(script)?)?;
+            (vec![SH, "-s"], Some(content))
+        }
+        _ => return Err(BuildError::EitherCommandOrScript),
+    };
+
+    let mut opts = ExecOptions {
+        env: run
+            .env
+            .iter()
+            .map(|e| (e.name.clone(), e.value.clone()))
+            .collect(),
+        stdin,
+        timeout: Some(run.timeout.map_or(RUN_TIMEOUT, Duration::from_secs)),
+        cwd: run.cwd.clone(),
+        ..Default::default()
+    };
+
+    if let Some(user) = &run.user {
+        let entry = users::get_user(root, user)?.ok_or(BuildError::UserNotFound(user.clone()))?;
+        opts.uid = Some(entry.uid);
+        opts.gid = Some(entry.gid);
+        /*
+         * Like login would. Set before the env of the action so it can
+         * override them.
+         */
+        opts.env.splice(
+            0..0,
+            [
+                (String::from("HOME"), entry.home.clone()),
+                (String::from("USER"), entry.name.clone()),
+                (String::from("LOGNAME"), entry.name.clone()),
+            ],
+        );
+    }
+
+    let output = crate::execute(&args, &opts)?;
+    if !output.success() {
+        let description = match (&run.command, &run.script) {
+            (Some(command), _) => command.clone(),
+            (None, script) => format!("script {}", script.clone().unwrap_or_default()),
+        };
+        let tail = if output.stderr.is_empty() {
+            output.stdout_string()
+        } else {
+            output.stderr_string()
+        };
+        return Err(BuildError::RunFailed(description, output.status, tail));
     }
+
+    Ok(())
 }
 
-pub fn run_ips_action(root: &str, action: IpsActions) -> BResult<()> {
+pub fn run_ips_action(root: &str, bundle: &Bundle, action: IpsActions) -> BResult<()> {
     info!("Running {}", action);
     match action {
-        IpsActions::InitializeImage => Ok(illumos_image_builder::pkg(&[
-            "image-create",
-            "-F",
-            "-z",
-            root,
-        ])?),
-        IpsActions::InstallPackages(pkgs) => Ok(illumos_image_builder::pkg_install(
-            root,
-            pkgs.packages
-                .iter()
-                .map(|s| s.as_str())
-                .collect::<Vec<&str>>()
-                .as_slice(),
-        )?),
+        IpsActions::InitializeImage => pkg(&["image-create", "-F", "-z", root]),
+        IpsActions::InstallPackages(pkgs) => {
+            let mut args = vec!["-R", root, "install"];
+            args.extend(pkgs.packages.iter().map(|s| s.as_str()));
+            let output = pkg_output(&args)?;
+            match output.status {
+                ExitStatus::Exited(0) => Ok(()),
+                ExitStatus::Exited(PKG_EXIT_NOP) => {
+                    info!("packages {} already installed", pkgs);
+                    Ok(())
+                }
+                _ => Err(output.into_error().into()),
+            }
+        }
         IpsActions::InstallOptionals => {
-            Err(BuildError::NotYetSupported(String::from("install optionals")).into())
+            let installed = pkg_capture(&["-R", root, "list", "-H"])?;
+            let installed = parse_installed_packages(&installed);
+
+            let dependencies = pkg_capture(&[
+                "-R",
+                root,
+                "contents",
+                "-H",
+                "-t",
+                "depend",
+                "-a",
+                "type=optional",
+                "-o",
+                "fmri",
+            ])?;
+            let optionals = parse_optional_dependencies(&dependencies, &installed);
+
+            if optionals.is_empty() {
+                info!("all optional dependencies are already installed");
+                return Ok(());
+            }
+
+            let mut args = vec!["-R", root, "install"];
+            args.extend(optionals.iter().map(|s| s.as_str()));
+            let output = pkg_output(&args)?;
+            if matches!(
+                output.status,
+                ExitStatus::Exited(0) | ExitStatus::Exited(PKG_EXIT_NOP)
+            ) {
+                return Ok(());
+            }
+
+            /*
+             * A single optional dependency which can not be installed, for example
+             * because an incorporation pins another version, fails the whole
+             * transaction. Retry one by one and skip those.
+             */
+            warn!("could not install all optional dependencies at once, retrying one by one");
+            let mut skipped = vec![];
+            for optional in &optionals {
+                let output = crate::execute(
+                    &[PKG, "-R", root, "install", optional],
+                    &ExecOptions {
+                        timeout: Some(PKG_OPTIONAL_TIMEOUT),
+                        tail_lines: PKG_OPTIONAL_TAIL_LINES,
+                        ..Default::default()
+                    },
+                )?;
+                match output.status {
+                    ExitStatus::Exited(0) | ExitStatus::Exited(PKG_EXIT_NOP) => {}
+                    _ if is_unsatisfiable(&output) => {
+                        warn!(
+                            "skipping optional dependency {}: {}",
+                            optional,
+                            output.into_error()
+                        );
+                        skipped.push(optional.as_str());
+                    }
+                    _ => return Err(output.into_error().into()),
+                }
+            }
+            if !skipped.is_empty() {
+                warn!(
+                    "skipped {} of {} optional dependencies: {}",
+                    skipped.len(),
+                    optionals.len(),
+                    skipped.join(", ")
+                );
+            }
+
+            Ok(())
         }
         IpsActions::SetProperty(ips_properties) => {
             for (prop_name, prop_value) in ips_properties.properties {
-                illumos_image_builder::pkg(&["-R", root, "set-property", &prop_name, &prop_value])?;
+                pkg(&["-R", root, "set-property", &prop_name, &prop_value])?;
             }
 
             Ok(())
@@ -818,15 +1683,57 @@ pub fn run_ips_action(root: &str, action: IpsActions) -> BResult<()> {
 
             args.push(pub_props.publisher);
 
-            Ok(illumos_image_builder::pkg(
-                args.iter()
-                    .map(|s| s.as_str())
-                    .collect::<Vec<&str>>()
-                    .as_slice(),
-            )?)
+            pkg(args
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .as_slice())
         }
-        IpsActions::ApprovePublisherCA(_) => {
-            Err(BuildError::NotYetSupported(String::from("approving CA")).into())
+        IpsActions::ApprovePublisherCA(ca) => {
+            let source_path = bundle
+                .get_file(ca.cert_file.trim_start_matches("/"))
+                .map_err(|_| {
+                    BuildError::CaCertificateNotFound(ca.cert_file.clone(), ca.publisher.clone())
+                })?;
+
+            /*
+             * pkg only approves CAs for publishers it knows about. Check first so
+             * users get told to order their set-publisher before this action.
+             */
+            let publisher_check =
+                pkg_output_quiet(&["-R", root, "publisher", "-H", &ca.publisher])?;
+            if !publisher_check.success() {
+                return Err(BuildError::PublisherNotConfigured(ca.publisher));
+            }
+
+            let file_name = source_path
+                .file_name()
+                .ok_or(BuildError::CaCertificateNotFound(
+                    ca.cert_file.clone(),
+                    ca.publisher.clone(),
+                ))?
+                .to_string_lossy()
+                .to_string();
+            let ca_dir = Path::new(root).join(PKG_CA_DIR);
+            illumos_image_builder::ensure::directory(&ca_dir, ROOT, ROOT, 0o755)?;
+            let ca_path = ca_dir.join(format!("{}_{}", ca.publisher, file_name));
+            illumos_image_builder::ensure::file(
+                &source_path,
+                &ca_path,
+                ROOT,
+                ROOT,
+                0o644,
+                illumos_image_builder::ensure::Create::Always,
+            )?;
+
+            pkg(&[
+                "-R",
+                root,
+                "set-publisher",
+                "--approve-ca-cert",
+                &ca_path.to_string_lossy(),
+                &ca.publisher,
+            ])
         }
         IpsActions::UninstallPackages(pkgs) => Ok(illumos_image_builder::pkg_uninstall(
             root,
@@ -850,7 +1757,7 @@ pub fn run_ips_action(root: &str, action: IpsActions) -> BResult<()> {
 
             Ok(())
         }
-        IpsActions::PurgeHistory => Ok(illumos_image_builder::pkg(&["-R", root, "purge-history"])?),
+        IpsActions::PurgeHistory => pkg(&["-R", root, "purge-history"]),
         IpsActions::SetMediator(mediator_props) => {
             let mut args = vec!["-R".to_owned(), root.to_string(), "set-mediator".to_owned()];
             if let Some(imple) = mediator_props.implementation {
@@ -865,14 +1772,264 @@ pub fn run_ips_action(root: &str, action: IpsActions) -> BResult<()> {
 
             args.push(mediator_props.name);
 
-            Ok(illumos_image_builder::pkg(
-                args.iter()
-                    .map(|s| s.as_str())
-                    .collect::<Vec<&str>>()
-                    .as_slice(),
-            )?)
+            pkg(args
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .as_slice())
+        }
+    }
+}
+
+/// Create the /dev structure of the image at root. devfsadm does not work inside a
+/// zone so imgbuild runs this in the global zone against the root of the build zone.
+pub fn devfsadm(root: &Path) -> BResult<()> {
+    if root == Path::new("/") {
+        return Err(BuildError::DevfsadmOutsideGlobalZone);
+    }
+
+    let root = root.to_string_lossy();
+    crate::run(&[DEVFSADM, "-r", &root], None)?;
+    Ok(())
+}
+
+fn run_onu(root: &str, bundle: &Bundle, onu: &Onu) -> BResult<()> {
+    if root == "/" && zone::current_blocking()? == "global" {
+        return Err(BuildError::OnuInGlobalZone);
+    }
+
+    let repository = if Path::new(&onu.repository).is_absolute() {
+        PathBuf::from(&onu.repository)
+    } else {
+        paths::resolve(bundle.get_files_path(), &onu.repository)?
+    };
+
+    if !repository.exists() {
+        return Err(BuildError::OnuRepositoryNotFound(
+            repository.display().to_string(),
+        ));
+    }
+
+    /*
+     * Like onu(1ONBLD) make all other publishers non-sticky so packages can move
+     * to the nightly publisher and rank the nightly publisher first.
+     */
+    let publishers = pkg_capture(&["-R", root, "publisher", "-H", "-F", "tsv"])?;
+    for publisher in publishers
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .filter(|name| !name.is_empty() && *name != onu.publisher)
+    {
+        pkg(&["-R", root, "set-publisher", "--non-sticky", publisher])?;
+    }
+
+    let origin = format!("file://{}", repository.display());
+    pkg(&[
+        "-R",
+        root,
+        "set-publisher",
+        "-P",
+        "-O",
+        &origin,
+        &onu.publisher,
+    ])?;
+
+    let installed = parse_installed_packages(&pkg_capture(&["-R", root, "list", "-H"])?);
+    let uninstall: Vec<&str> = onu
+        .uninstall
+        .iter()
+        .map(|s| s.as_str())
+        .filter(|p| installed.contains(*p))
+        .collect();
+    if !uninstall.is_empty() {
+        let mut args = vec!["-R", root, "uninstall"];
+        args.extend(uninstall);
+        pkg(&args)?;
+    }
+
+    let output = pkg_output(&["-R", root, "update"])?;
+    match output.status {
+        ExitStatus::Exited(0) => Ok(()),
+        ExitStatus::Exited(PKG_EXIT_NOP) => {
+            info!("image is already up to date with {}", onu.publisher);
+            Ok(())
+        }
+        _ => Err(output.into_error().into()),
+    }
+}
+
+fn seed_smf(root: &str, seed: &SeedSmf) -> BResult<()> {
+    let root_path = Path::new(root);
+    let profiles = seed
+        .profiles
+        .clone()
+        .unwrap_or(SMF_DEFAULT_PROFILES.iter().map(|p| p.to_string()).collect());
+
+    /*
+     * When we run inside the image svc.configd is live and we import into
+     * the running repository. Otherwise we seed a copy of the seed repository
+     * with a private configd and move it into place once done.
+     */
+    let offline = root_path != Path::new("/");
+
+    let mut env = vec![(String::from("SVCCFG_CHECKHASH"), String::from("1"))];
+    let repository = std::env::temp_dir().join(format!("seed-smf-{}.db", std::process::id()));
+    if offline {
+        let seed_repository = root_path.join(if seed.global {
+            SMF_SEED_GLOBAL
+        } else {
+            SMF_SEED_NONGLOBAL
+        });
+        if !seed_repository.exists() {
+            return Err(BuildError::SmfSeedNotFound(
+                seed_repository.display().to_string(),
+            ));
+        }
+        std::fs::copy(&seed_repository, &repository)?;
+
+        env.push((
+            String::from("SVCCFG_DTD"),
+            root_path.join(SMF_DTD).display().to_string(),
+        ));
+        env.push((
+            String::from("SVCCFG_REPOSITORY"),
+            repository.display().to_string(),
+        ));
+        env.push((String::from("PKG_INSTALL_ROOT"), root.to_string()));
+    }
+
+    let opts = ExecOptions {
+        env,
+        timeout: Some(SVCCFG_TIMEOUT),
+        ..Default::default()
+    };
+
+    let manifest_dir = root_path.join(SMF_MANIFEST_DIR).display().to_string();
+    let res = crate::execute_checked(
+        &[SVCCFG, "import", "-p", "/dev/stdout", &manifest_dir],
+        &opts,
+    )
+    .and_then(|_| {
+        for profile in &profiles {
+            let profile_path = root_path.join(SMF_PROFILE_DIR).join(profile);
+            if !profile_path.exists() {
+                info!("profile {} does not exist; skipping", profile);
+                continue;
+            }
+            let profile_path = profile_path.display().to_string();
+            crate::execute_checked(&[SVCCFG, "apply", &profile_path], &opts)?;
+        }
+        Ok(())
+    });
+
+    if !offline {
+        res?;
+        return Ok(());
+    }
+
+    let res = res.map_err(BuildError::from).and_then(|_| {
+        info!("installing seeded repository");
+        illumos_image_builder::ensure::file(
+            &repository,
+            &root_path.join(SMF_REPOSITORY),
+            ROOT,
+            ROOT,
+            0o600,
+            illumos_image_builder::ensure::Create::Always,
+        )?;
+        Ok(())
+    });
+
+    /*
+     * Failing to clean up the copy must not hide why seeding failed
+     */
+    if let Err(err) = std::fs::remove_file(&repository) {
+        warn!("could not remove {}: {}", repository.display(), err);
+    }
+
+    res
+}
+
+fn pkg_output(args: &[&str]) -> BResult<crate::ExecOutput> {
+    let mut pkg_args = vec![PKG];
+    pkg_args.extend_from_slice(args);
+    let opts = ExecOptions {
+        timeout: Some(PKG_TIMEOUT),
+        ..Default::default()
+    };
+    Ok(crate::execute(&pkg_args, &opts)?)
+}
+
+fn pkg(args: &[&str]) -> BResult<()> {
+    pkg_output(args)?.into_result()?;
+    Ok(())
+}
+
+/// Run pkg without logging and return the output for inspection
+fn pkg_output_quiet(args: &[&str]) -> BResult<crate::ExecOutput> {
+    let mut pkg_args = vec![PKG];
+    pkg_args.extend_from_slice(args);
+    let opts = ExecOptions {
+        timeout: Some(PKG_TIMEOUT),
+        capture_stdout: true,
+        live_log: false,
+        ..Default::default()
+    };
+    Ok(crate::execute(&pkg_args, &opts)?)
+}
+
+fn pkg_capture(args: &[&str]) -> BResult<String> {
+    Ok(pkg_output_quiet(args)?.into_result()?.stdout_string())
+}
+
+/// Strip scheme, publisher and version from a package fmri
+fn fmri_package_name(fmri: &str) -> &str {
+    let name = if let Some(name) = fmri.strip_prefix("pkg://") {
+        name.split_once("/").map(|(_, name)| name).unwrap_or(name)
+    } else {
+        fmri.strip_prefix("pkg:/").unwrap_or(fmri)
+    };
+    name.split_once("@").map(|(name, _)| name).unwrap_or(name)
+}
+
+/// Package names from the output of `pkg list -H`
+fn parse_installed_packages(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+/// Whether pkg failed because the solver found no version of the package which can be
+/// installed into the image. Timeouts, cancellation and all other errors are not.
+fn is_unsatisfiable(output: &crate::ExecOutput) -> bool {
+    output.status == ExitStatus::Exited(1)
+        && output
+            .stderr
+            .iter()
+            .chain(output.stdout.iter())
+            .any(|line| PKG_UNSATISFIABLE_MESSAGES.iter().any(|m| line.contains(m)))
+}
+
+/// Optional dependencies from the output of `pkg contents -t depend -o fmri` which
+/// are not installed yet. Versions are dropped so the incorporations decide which
+/// version gets installed.
+fn parse_optional_dependencies(output: &str, installed: &HashSet<String>) -> Vec<String> {
+    let mut optionals: Vec<String> = vec![];
+    for line in output.lines() {
+        let name = fmri_package_name(line.trim());
+        /*
+         * __TBD is a placeholder pkgdepend leaves for unresolved dependencies
+         */
+        if name.is_empty() || name.starts_with("__") || installed.contains(name) {
+            continue;
+        }
+        if !optionals.iter().any(|o| o == name) {
+            optionals.push(name.to_string());
         }
     }
+    optionals
 }
 
 #[cfg(test)]
@@ -898,6 +2055,9 @@ mod tests {
             version: 1,
             base_on: Some("img://openindiana.org/hipster".into()),
             vm_specs: None,
+            oci: None,
+            vars: Default::default(),
+            stages: vec![],
             actions: vec![
                 Action::Volume(Volume {
                     name: "data".into(),
@@ -1007,7 +2167,9 @@ mod tests {
                                 name: "default".into(),
                                 enabled: Some(true),
                                 property_groups: vec![],
+                                ..Default::default()
                             }],
+                            ..Default::default()
                         }],
                     };
                     assert_eq!(expected, smf_manifest);
@@ -1018,4 +2180,366 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_smf_manifest_conversion() -> miette::Result<()> {
+        use super::{Action, Document};
+        use crate::smf;
+        use miette::{IntoDiagnostic, WrapErr};
+
+        let file = "testdata/daemon/build.kdl";
+        let text = std::fs::read_to_string(file)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("cannot read {:?}", file))?;
+
+        let config = knuffel::parse::<Document>(file, &text)?;
+
+        assert_eq!(
+            vec![String::from("svc:/network/myapp:default")],
+            config.enabled_services()
+        );
+        assert!(config.installs_smf_manifests());
+
+        let manifest = match &config.actions[0] {
+            Action::SmfManifest(m) => m,
+            x => panic!("expected smf-manifest action got {}", x),
+        };
+        assert_eq!("network_myapp", manifest.manifest_name());
+
+        let bundle = manifest.to_smf_manifest()?;
+        assert_eq!(smf::BundleType::Manifest, bundle.bundle_type);
+
+        let svc = &bundle.services[0];
+        assert_eq!("network/myapp", svc.name);
+        assert_eq!("default", svc.instances[0].name);
+        assert_eq!(Some(true), svc.instances[0].enabled);
+
+        let exec: Vec<(&str, &str, i64)> = svc
+            .exec_methods
+            .iter()
+            .map(|m| (m.name.as_str(), m.exec.as_str(), m.timeout_seconds))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "start",
+                    "/opt/myapp/bin/myapp --config /etc/myapp.toml &",
+                    30
+                ),
+                ("stop", ":kill", 60),
+                ("refresh", ":kill -HUP", 60),
+            ],
+            exec
+        );
+
+        let ctx = svc.method_context.as_ref().expect("method context");
+        assert_eq!(Some("/var/lib/myapp".into()), ctx.working_directory);
+        let cred = ctx.method_credential.as_ref().expect("method credential");
+        assert_eq!("myapp", cred.user);
+        assert_eq!(Some("myapp".into()), cred.group);
+
+        assert_eq!(2, svc.dependencies.len());
+        assert_eq!(
+            smf::DependencyType::Service,
+            svc.dependencies[0].dependency_type
+        );
+        assert_eq!(
+            smf::DependencyType::Path,
+            svc.dependencies[1].dependency_type
+        );
+        assert_eq!(smf::RestartOn::Refresh, svc.dependencies[1].restart_on);
+
+        assert_eq!(1, svc.property_groups.len());
+        let pg = &svc.property_groups[0];
+        assert_eq!("config", pg.name);
+        assert_eq!(smf::PropValType::Count, pg.values[0].pb_type);
+        assert_eq!("name", pg.values[1].name);
+
+        // the result must render as a manifest svccfg can import
+        let xml = smf::manifest_to_string(&bundle)?;
+        assert!(xml.contains(r#"<service_bundle type="manifest" name="network_myapp">"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_optional_dependencies() {
+        use super::{parse_installed_packages, parse_optional_dependencies};
+
+        let installed = parse_installed_packages(
+            "compress/bzip2                1.0.8-2022.0.0.0          i--
+library/zlib (openindiana.org) 1.2.13-2022.0.0.0         i--
+",
+        );
+        let dependencies = "\
+pkg:/library/zlib@1.2.11
+pkg:/developer/debug/mdb@0.5.11
+pkg://openindiana.org/text/gnu-gettext@0.21
+pkg:/developer/debug/mdb@0.5.11
+__TBD
+";
+
+        assert_eq!(
+            vec![
+                String::from("developer/debug/mdb"),
+                String::from("text/gnu-gettext")
+            ],
+            parse_optional_dependencies(dependencies, &installed)
+        );
+    }
+
+    #[test]
+    fn test_is_unsatisfiable() {
+        use super::is_unsatisfiable;
+        use crate::{ExecOutput, ExitStatus};
+        use std::time::Duration;
+
+        let output = |status, stderr: &[&str]| ExecOutput {
+            command: String::from("pkg install"),
+            status,
+            stdout: vec![],
+            stderr: stderr.iter().map(|s| s.to_string()).collect(),
+            stdout_bytes: vec![],
+        };
+
+        assert!(is_unsatisfiable(&output(
+            ExitStatus::Exited(1),
+            &[
+                "pkg install: No matching version of developer/debug/mdb can be installed:",
+                "  Reject:  pkg://openindiana.org/developer/debug/mdb@0.5.11",
+            ]
+        )));
+        assert!(!is_unsatisfiable(&output(
+            ExitStatus::Exited(1),
+            &[
+                "pkg: 0/1 catalogs successfully updated:",
+                "Framework error: code: 28"
+            ]
+        )));
+        assert!(!is_unsatisfiable(&output(
+            ExitStatus::TimedOut(Duration::from_secs(1)),
+            &["No matching version of developer/debug/mdb can be installed:"]
+        )));
+        assert!(!is_unsatisfiable(&output(ExitStatus::Cancelled, &[])));
+    }
+
+    #[test]
+    fn test_template_context() -> miette::Result<()> {
+        use super::{bundle::Bundle, render_template, BuildError};
+        use std::collections::HashMap;
+        use std::path::Path;
+
+        let mut bundle = Bundle::new(Path::new("testdata/daemon"))?;
+        bundle.build_vars = HashMap::from([(String::from("port"), String::from("9090"))]);
+        let context = bundle.template_context();
+
+        let res = render_template(
+            "myapp.toml",
+            "name = \"{{ image.name }}\"\nport = {{ port }}\nlog = \"{{ log_level }}\"\n",
+            &context,
+        )?;
+        assert_eq!(
+            "name = \"services/myapp\"\nport = 9090\nlog = \"info\"\n",
+            res
+        );
+
+        let err = render_template("broken.toml", "{{ missing }}", &context).unwrap_err();
+        match err {
+            BuildError::TemplateVariableMissing(template, variable) => {
+                assert_eq!("broken.toml", template);
+                assert_eq!("missing", variable);
+            }
+            x => panic!("expected missing variable error got {}", x),
+        }
+
+        // Other errors about variables are not reported as missing
+        let err = render_template("math.toml", "{{ image.name + 1 }}", &context).unwrap_err();
+        assert!(matches!(err, BuildError::TemplateRenderError(..)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_inline_template() -> miette::Result<()> {
+        use super::{bundle::Bundle, run_action, Action, CommonPerms, File};
+        use miette::IntoDiagnostic;
+        use std::collections::HashMap;
+        use std::path::Path;
+
+        let mut bundle = Bundle::new(Path::new("testdata/daemon"))?;
+        bundle.build_vars = HashMap::from([(String::from("port"), String::from("9090"))]);
+
+        // Actions run against the zonepath itself when we are in the zone the build is for
+        let root = tempfile::tempdir().into_diagnostic()?;
+        std::fs::create_dir(root.path().join("etc")).into_diagnostic()?;
+        let zonename = zone::current_blocking().into_diagnostic()?;
+        run_action(
+            &root.path().to_string_lossy(),
+            &zonename,
+            &bundle,
+            Action::File(File {
+                common: CommonPerms {
+                    mode: Some(0o644),
+                    ..Default::default()
+                },
+                src: None,
+                content: Some(String::from("port = {{ port }}\n")),
+                is_template: true,
+                path: "/etc/myapp.toml".into(),
+            }),
+        )?;
+
+        assert_eq!(
+            "port = 9090\n",
+            std::fs::read_to_string(root.path().join("etc/myapp.toml")).into_diagnostic()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_system_actions() -> miette::Result<()> {
+        use super::{Action, Document, Onu, SeedSmf};
+
+        let text = r#"
+name "illumos/dev"
+onu "/ws/illumos-gate/packages/i386/nightly/repo.redist" {
+    uninstall "entire"
+}
+onu "repo.redist" publisher="my-nightly"
+devfsadm
+seed-smf
+seed-smf global=true {
+    profiles "generic.xml"
+}
+"#;
+        let doc = knuffel::parse::<Document>("onu.kdl", text)?;
+
+        assert_eq!(
+            vec![
+                Action::Onu(Onu {
+                    repository: "/ws/illumos-gate/packages/i386/nightly/repo.redist".into(),
+                    publisher: "on-nightly".into(),
+                    uninstall: vec!["entire".into()],
+                }),
+                Action::Onu(Onu {
+                    repository: "repo.redist".into(),
+                    publisher: "my-nightly".into(),
+                    uninstall: vec![],
+                }),
+                Action::Devfsadm,
+                Action::SeedSmf(SeedSmf {
+                    global: false,
+                    profiles: None,
+                }),
+                Action::SeedSmf(SeedSmf {
+                    global: true,
+                    profiles: Some(vec!["generic.xml".into()]),
+                }),
+            ],
+            doc.actions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_run() -> miette::Result<()> {
+        use super::{Action, Document, Run, RunEnv};
+
+        let text = r#"
+name "app"
+run "make install" {
+    cwd "/src/app"
+    user "builder"
+    env "CC" "gcc"
+    env "PREFIX" "/opt/app"
+    timeout 3600
+}
+run {
+    script "setup.sh"
+}
+"#;
+        let doc = knuffel::parse::<Document>("run.kdl", text)?;
+
+        assert_eq!(
+            vec![
+                Action::Run(Run {
+                    command: Some("make install".into()),
+                    script: None,
+                    env: vec![
+                        RunEnv {
+                            name: "CC".into(),
+                            value: "gcc".into(),
+                        },
+                        RunEnv {
+                            name: "PREFIX".into(),
+                            value: "/opt/app".into(),
+                        },
+                    ],
+                    cwd: Some("/src/app".into()),
+                    user: Some("builder".into()),
+                    timeout: Some(3600),
+                }),
+                Action::Run(Run {
+                    command: None,
+                    script: Some("setup.sh".into()),
+                    env: vec![],
+                    cwd: None,
+                    user: None,
+                    timeout: None,
+                }),
+            ],
+            doc.actions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_oci() -> miette::Result<()> {
+        use super::{Document, OciConfig, OciPort, RunEnv};
+
+        let text = r#"
+name "web"
+oci {
+    entrypoint "/usr/sbin/nginx" "-g" "daemon off;"
+    env "NGINX_PORT" "8080"
+    port 8080
+    port 53 protocol="udp"
+    user "webservd"
+    workdir "/var/www"
+}
+"#;
+        let doc = knuffel::parse::<Document>("oci.kdl", text)?;
+
+        let oci = doc.oci.expect("oci is set");
+        assert_eq!(
+            OciConfig {
+                entrypoint: vec!["/usr/sbin/nginx".into(), "-g".into(), "daemon off;".into()],
+                env: vec![RunEnv {
+                    name: "NGINX_PORT".into(),
+                    value: "8080".into(),
+                }],
+                ports: vec![
+                    OciPort {
+                        port: 8080,
+                        protocol: None,
+                    },
+                    OciPort {
+                        port: 53,
+                        protocol: Some("udp".into()),
+                    },
+                ],
+                user: Some("webservd".into()),
+                workdir: Some("/var/www".into()),
+                ..Default::default()
+            },
+            oci
+        );
+        assert_eq!("8080/tcp", oci.ports[0].to_string());
+        assert_eq!("53/udp", oci.ports[1].to_string());
+        assert!(doc.actions.is_empty());
+
+        Ok(())
+    }
 }
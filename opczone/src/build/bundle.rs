@@ -1,10 +1,19 @@
-use super::{Action, Document};
+use super::paths::{self, PathError};
+use super::{Action, Document, IpsActions, VMHypervisor};
+use crate::brand::Brand;
 use miette::Diagnostic;
-use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+};
 use thiserror::Error;
 
 pub const BUILD_BUNDLE_IMAGE_PATH: &str = "/.zonemeta/build_bundle";
 pub const BUILD_BUNDLE_BUILD_CONFIG_FILENAME: &str = "build.kdl";
+pub const BUILD_BUNDLE_FILES_DIR: &str = "files";
+pub const BUILD_BUNDLE_TEMPLATES_DIR: &str = "templates";
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum BundleError {
@@ -16,6 +25,20 @@ pub enum BundleError {
     FileDoesExistsErr(String),
     #[error(transparent)]
     KnuffelError(#[from] knuffel::Error),
+    #[error("invalid bundle source {0}: {1}")]
+    InvalidSource(String, String),
+    #[error(transparent)]
+    UrlError(#[from] url::ParseError),
+    #[error("could not fetch bundle: {0}")]
+    FetchError(#[from] reqwest::Error),
+    #[error("checksum mismatch for {0}: expected sha256 {1} got {2}")]
+    ChecksumMismatch(String, String, String),
+    #[error("no build.kdl found in {0}")]
+    NoBuildConfig(String),
+    #[error(transparent)]
+    ProcessError(#[from] crate::OPCZoneError),
+    #[error(transparent)]
+    PathError(#[from] PathError),
 }
 
 type Result<T> = miette::Result<T, BundleError>;
@@ -29,6 +52,33 @@ pub enum BuildBundleType {
     NativeBhyve,
 }
 
+impl BuildBundleType {
+    /// Brand of the zones deployed from images of this type
+    pub fn brand(&self) -> Brand {
+        match self {
+            BuildBundleType::BaseImage | BuildBundleType::Image => Brand::Image,
+            BuildBundleType::Bhyve => Brand::Bhyve,
+            BuildBundleType::Propolis => Brand::Propolis,
+            BuildBundleType::NativeBhyve => Brand::NativeBhyve,
+        }
+    }
+}
+
+/// A path in the bundle which points outside of the image root or the bundle
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathViolation {
+    /// Index of the action the path belongs to
+    pub action: usize,
+    pub path: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for PathViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "action {}: {}: {}", self.action, self.path, self.reason)
+    }
+}
+
 /// This Audit Info struct gets generated by calling the get_audit_info function of the build bundle
 /// It contains all information needed for Applications to make Security Relevant decisions
 /// For exmaple if the bundle is safe to be used as a base bundle build which runs the first IPS
@@ -36,11 +86,17 @@ pub enum BuildBundleType {
 #[derive(Debug)]
 pub struct BuildBundleAuditInfo {
     bundle_type: BuildBundleType,
+    violations: Vec<PathViolation>,
 }
 
 impl BuildBundleAuditInfo {
     pub fn is_safe_to_run_in_gz(&self) -> bool {
-        self.is_base_image()
+        self.is_base_image() && self.violations.is_empty()
+    }
+
+    /// Paths of the bundle which try to leave the image or the bundle
+    pub fn violations(&self) -> &[PathViolation] {
+        &self.violations
     }
 
     pub fn is_base_image(&self) -> bool {
@@ -50,6 +106,14 @@ impl BuildBundleAuditInfo {
         false
     }
 
+    /// Whether the bundle builds the disks of a virtual machine instead of a zone
+    pub fn is_vm(&self) -> bool {
+        matches!(
+            self.bundle_type,
+            BuildBundleType::Bhyve | BuildBundleType::NativeBhyve | BuildBundleType::Propolis
+        )
+    }
+
     pub fn kind(&self) -> BuildBundleType {
         self.bundle_type.clone()
     }
@@ -58,12 +122,17 @@ impl BuildBundleAuditInfo {
 #[derive(Debug)]
 pub enum BuildBundleKind {
     Directory,
+    Archive,
+    Remote,
+    Git,
 }
 
 #[derive(Debug)]
 pub struct Bundle {
     pub kind: BuildBundleKind,
     pub document: Document,
+    /// Variables passed from the command line and environment. They override the vars of the document
+    pub build_vars: HashMap<String, String>,
     template_search_path: PathBuf,
     source_path: PathBuf,
 }
@@ -79,27 +148,60 @@ impl Bundle {
         Ok(Self {
             kind: BuildBundleKind::Directory,
             document,
-            template_search_path: path.join("templates").to_path_buf(),
+            build_vars: HashMap::new(),
+            template_search_path: path.join(BUILD_BUNDLE_TEMPLATES_DIR).to_path_buf(),
             source_path: path.to_path_buf(),
         })
     }
 
     pub fn get_files_path(&self) -> PathBuf {
-        self.source_path.join("files")
+        self.source_path.join(BUILD_BUNDLE_FILES_DIR)
+    }
+
+    pub fn get_templates_path(&self) -> PathBuf {
+        self.template_search_path.clone()
     }
 
     pub fn get_template_string(&self, name: &str) -> Result<String> {
-        let file = self.template_search_path.join(name);
+        let file = paths::resolve(&self.template_search_path, name)?;
         let text = std::fs::read_to_string(&file)?;
         Ok(text)
     }
 
+    /// The context templates are rendered with. The metadata of the image is available as
+    /// `image.name`, `image.author`, `image.version` and `image.base_on`. Variables from the
+    /// vars block of build.kdl and the build variables are available by their name.
+    pub fn template_context(&self) -> tera::Context {
+        let mut context = tera::Context::new();
+
+        let mut image = HashMap::new();
+        image.insert("name", self.document.name.clone());
+        image.insert("version", self.document.version.to_string());
+        if let Some(author) = &self.document.author {
+            image.insert("author", author.clone());
+        }
+        if let Some(base_on) = &self.document.base_on {
+            image.insert("base_on", base_on.clone());
+        }
+        context.insert("image", &image);
+
+        for var in &self.document.vars.vars {
+            context.insert(&var.name, &var.value);
+        }
+
+        for (name, value) in &self.build_vars {
+            context.insert(name, value);
+        }
+
+        context
+    }
+
     pub fn get_path(&self) -> &Path {
         &self.source_path
     }
 
     pub fn get_file<P: AsRef<Path>>(&self, relative_file_path: P) -> Result<PathBuf> {
-        let full_path = self.get_files_path().join(relative_file_path.as_ref());
+        let full_path = paths::resolve(self.get_files_path(), relative_file_path.as_ref())?;
         if !full_path.exists() {
             return Err(BundleError::FileDoesExistsErr(
                 relative_file_path.as_ref().to_string_lossy().to_string(),
@@ -109,16 +211,146 @@ impl Bundle {
         Ok(full_path)
     }
 
+    /// Hex encoded sha256 over build.kdl, the files and templates of the bundle and the
+    /// build variables. Two bundles with the same hash build the same image.
+    pub fn content_hash(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hash_bundle_path(
+            &mut hasher,
+            &self.source_path,
+            Path::new(BUILD_BUNDLE_BUILD_CONFIG_FILENAME),
+        )?;
+        for dir in [BUILD_BUNDLE_FILES_DIR, BUILD_BUNDLE_TEMPLATES_DIR] {
+            if self.source_path.join(dir).exists() {
+                hash_bundle_path(&mut hasher, &self.source_path, Path::new(dir))?;
+            }
+        }
+
+        // HashMap order changes between runs
+        let vars: BTreeMap<_, _> = self.build_vars.iter().collect();
+        for (name, value) in vars {
+            hasher.update(name);
+            hasher.update([0]);
+            hasher.update(value);
+            hasher.update([0]);
+        }
+
+        Ok(hex::encode(hasher.finalize()))
+    }
+
     pub fn get_audit_info(&self) -> BuildBundleAuditInfo {
-        let t = if self.document.base_on.is_none()
-            && matches!(self.document.actions[0], Action::Ips(..))
+        let t = if let Some(vm_specs) = &self.document.vm_specs {
+            match vm_specs.get_hypervisor() {
+                VMHypervisor::Bhyve => BuildBundleType::Bhyve,
+                VMHypervisor::NativeBhyve => BuildBundleType::NativeBhyve,
+                VMHypervisor::Propolis => BuildBundleType::Propolis,
+            }
+        } else if self.document.base_on.is_none()
+            && matches!(self.document.actions.first(), Some(Action::Ips(..)))
         {
             BuildBundleType::BaseImage
         } else {
             BuildBundleType::Image
         };
 
-        BuildBundleAuditInfo { bundle_type: t }
+        BuildBundleAuditInfo {
+            bundle_type: t,
+            violations: self.path_violations(),
+        }
+    }
+
+    /*
+     * Targets are only checked for .. since the image does not exist yet.
+     * Symlinks inside the image are caught when the action resolves its path.
+     * Bundle files exist so we resolve them completely.
+     */
+    fn path_violations(&self) -> Vec<PathViolation> {
+        let mut violations = vec![];
+
+        for (index, action) in self.document.actions.iter().enumerate() {
+            let (targets, files, templates) = match action {
+                Action::Remove(path) => (vec![path.clone()], vec![], vec![]),
+                Action::ExtractTarball(tarball) => (vec![], vec![tarball.clone()], vec![]),
+                Action::Run(run) => (vec![], run.script.iter().cloned().collect(), vec![]),
+                Action::AssembleFile(assemble) => (
+                    vec![assemble.output.to_string_lossy().to_string()],
+                    vec![assemble.dir.to_string_lossy().to_string()],
+                    vec![],
+                ),
+                Action::Symlink(link) => (
+                    vec![link.link.to_string_lossy().to_string()],
+                    vec![],
+                    vec![],
+                ),
+                Action::Dir(dir) | Action::Perm(dir) => {
+                    (vec![dir.path.to_string_lossy().to_string()], vec![], vec![])
+                }
+                Action::File(file) => {
+                    let target = vec![file.path.to_string_lossy().to_string()];
+                    match &file.src {
+                        Some(src) if file.is_template => (target, vec![], vec![src.clone()]),
+                        Some(src) => (target, vec![src.clone()], vec![]),
+                        None => (target, vec![], vec![]),
+                    }
+                }
+                Action::User(user) => (user.home.iter().cloned().collect(), vec![], vec![]),
+                Action::Onu(onu) if !Path::new(&onu.repository).is_absolute() => {
+                    (vec![], vec![onu.repository.clone()], vec![])
+                }
+                Action::Volume(volume) => {
+                    (volume.mountpoint.iter().cloned().collect(), vec![], vec![])
+                }
+                Action::CopyFrom(copy) => {
+                    (vec![copy.dst.to_string_lossy().to_string()], vec![], vec![])
+                }
+                Action::Download(download) => (
+                    vec![download.path.to_string_lossy().to_string()],
+                    vec![],
+                    vec![],
+                ),
+                Action::Ips(ips) => (
+                    vec![],
+                    ips.actions
+                        .iter()
+                        .filter_map(|a| match a {
+                            IpsActions::ApprovePublisherCA(ca) => {
+                                Some(ca.cert_file.trim_start_matches('/').to_string())
+                            }
+                            _ => None,
+                        })
+                        .collect(),
+                    vec![],
+                ),
+                _ => (vec![], vec![], vec![]),
+            };
+
+            for target in targets {
+                if paths::escapes(&target) {
+                    violations.push(PathViolation {
+                        action: index,
+                        path: target,
+                        reason: String::from("escapes the image root"),
+                    });
+                }
+            }
+
+            let inputs = files.into_iter().map(|f| (self.get_files_path(), f)).chain(
+                templates
+                    .into_iter()
+                    .map(|t| (self.get_templates_path(), t)),
+            );
+            for (base, input) in inputs {
+                if let Err(err) = paths::resolve(&base, &input) {
+                    violations.push(PathViolation {
+                        action: index,
+                        path: input,
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }
+
+        violations
     }
 
     pub fn pop_action(&mut self) -> Option<Action> {
@@ -145,3 +377,108 @@ impl Bundle {
         self.save_to(path)
     }
 }
+
+/*
+ * Every entry contributes its path relative to the bundle so renaming a file
+ * changes the hash. Symlinks are hashed by their target, they may point
+ * outside of the bundle.
+ */
+fn hash_bundle_path(hasher: &mut Sha256, base: &Path, relative: &Path) -> Result<()> {
+    let path = base.join(relative);
+    let metadata = std::fs::symlink_metadata(&path)?;
+    hasher.update(relative.to_string_lossy().as_bytes());
+    hasher.update([0]);
+
+    if metadata.file_type().is_symlink() {
+        hasher.update(std::fs::read_link(&path)?.to_string_lossy().as_bytes());
+    } else if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(&path)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            hash_bundle_path(hasher, base, &relative.join(entry.file_name()))?;
+        }
+    } else {
+        std::io::copy(&mut File::open(&path)?, hasher)?;
+    }
+    hasher.update([0]);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bundle;
+    use miette::{IntoDiagnostic, Result};
+
+    #[test]
+    fn test_audit_path_violations() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::create_dir_all(dir.path().join("files")).into_diagnostic()?;
+        std::os::unix::fs::symlink("../../..", dir.path().join("files/up")).into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            r#"name "base"
+ips {
+    install-packages "system/library"
+}
+file "/etc/motd" {
+    content "hello"
+}
+symlink "/../outside" "/etc/passwd"
+file "/etc/shadow" {
+    src "up/etc/shadow"
+}
+user "web" home="/../../srv/web"
+onu "../repo.redist"
+onu "/ws/illumos-gate/packages/i386/nightly/repo.redist"
+"#,
+        )
+        .into_diagnostic()?;
+
+        let bundle = Bundle::new(dir.path())?;
+        let audit = bundle.get_audit_info();
+        assert!(audit.is_base_image());
+        assert!(!audit.is_safe_to_run_in_gz());
+
+        let violations = audit.violations();
+        assert_eq!(4, violations.len());
+        assert_eq!(2, violations[0].action);
+        assert_eq!("/../outside", violations[0].path);
+        assert_eq!(3, violations[1].action);
+        assert_eq!("up/etc/shadow", violations[1].path);
+        assert_eq!(4, violations[2].action);
+        assert_eq!("/../../srv/web", violations[2].path);
+        assert_eq!(5, violations[3].action);
+        assert_eq!("../repo.redist", violations[3].path);
+
+        assert!(bundle.get_file("up/etc/shadow").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_hash() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::create_dir_all(dir.path().join("files/etc")).into_diagnostic()?;
+        std::fs::write(dir.path().join("build.kdl"), "name \"hash\"\n").into_diagnostic()?;
+        std::fs::write(dir.path().join("files/etc/motd"), "hello").into_diagnostic()?;
+
+        let mut bundle = Bundle::new(dir.path())?;
+        let hash = bundle.content_hash()?;
+        assert_eq!(64, hash.len());
+        assert_eq!(hash, bundle.content_hash()?);
+
+        bundle.build_vars.insert("port".into(), "8080".into());
+        let with_vars = bundle.content_hash()?;
+        assert_ne!(hash, with_vars);
+
+        std::fs::rename(
+            dir.path().join("files/etc/motd"),
+            dir.path().join("files/etc/issue"),
+        )
+        .into_diagnostic()?;
+        assert_ne!(with_vars, bundle.content_hash()?);
+
+        Ok(())
+    }
+}
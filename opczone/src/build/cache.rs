@@ -0,0 +1,535 @@
+use super::bundle::{Bundle, BundleError};
+use super::{render_template, Action, BuildError, IpsActions};
+use crate::OPCZoneError;
+use common::{debug, info};
+use miette::Diagnostic;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+const ZFS: &str = "/usr/sbin/zfs";
+const ZFS_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Snapshots of the build cache are named <dataset>@buildcache-<hash>
+pub const CACHE_SNAPSHOT_PREFIX: &str = "buildcache-";
+
+/*
+ * ZFS user properties on the cache snapshots. They let us list the cache
+ * without keeping an index next to it which could go out of sync.
+ */
+const CACHE_HASH_PROPERTY: &str = "org.openflowlabs.imgbuild:hash";
+const CACHE_IMAGE_PROPERTY: &str = "org.openflowlabs.imgbuild:image";
+const CACHE_STEP_PROPERTY: &str = "org.openflowlabs.imgbuild:step";
+
+/*
+ * Bump this when the way actions are executed changes so old layers are not
+ * reused for builds that would now produce something else.
+ */
+const CACHE_VERSION: &str = "imgbuild-cache-v1";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum CacheError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    BundleError(#[from] BundleError),
+    #[error(transparent)]
+    BuildError(#[from] Box<BuildError>),
+    #[error(transparent)]
+    ExecError(#[from] OPCZoneError),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+    #[error("could not parse zfs output line: {0}")]
+    ParseError(String),
+    #[error("{0} is not a build cache snapshot")]
+    NotACacheSnapshot(String),
+}
+
+type Result<T> = miette::Result<T, CacheError>;
+
+/// One cached layer. The snapshot covers the zone dataset and all of its children
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub snapshot: String,
+    pub hash: String,
+    pub image: String,
+    /// Index of the action after which the snapshot was taken
+    pub step: usize,
+    /// Seconds since the epoch
+    pub created: u64,
+    /// Bytes used by the snapshot and the snapshots of all children
+    pub used: u64,
+}
+
+impl CacheEntry {
+    /// The dataset of the zone the snapshot was taken of
+    pub fn dataset(&self) -> &str {
+        self.snapshot
+            .split_once('@')
+            .map(|(ds, _)| ds)
+            .unwrap_or(&self.snapshot)
+    }
+
+    pub fn age(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Duration::from_secs(now.saturating_sub(self.created))
+    }
+}
+
+/// The hash the chain of an image starts with. Images based on another image start
+/// from the uuid of that image so rebuilding the base invalidates the cache.
+pub fn seed_hash(base_image: Option<&uuid::Uuid>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(CACHE_VERSION);
+    match base_image {
+        Some(uuid) => hasher.update(uuid.hyphenated().to_string()),
+        None => hasher.update("base"),
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Hash of every action of the bundle. Each hash covers the action definition, the
+/// bundle files it references and the hash of the action before it. sources maps the
+/// stages and images copy-from reads from to something identifying their content.
+pub fn action_hashes(
+    bundle: &Bundle,
+    base_image: Option<&uuid::Uuid>,
+    sources: &HashMap<String, String>,
+) -> Result<Vec<String>> {
+    let mut parent = seed_hash(base_image);
+    let mut hashes = vec![];
+    for action in &bundle.document.actions {
+        parent = hash_action(&parent, bundle, action, sources)?;
+        hashes.push(parent.clone());
+    }
+    Ok(hashes)
+}
+
+pub fn hash_action(
+    parent: &str,
+    bundle: &Bundle,
+    action: &Action,
+    sources: &HashMap<String, String>,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(parent);
+    hasher.update(serde_json::to_vec(action)?);
+
+    match action {
+        Action::ExtractTarball(tarball) => hash_file(&mut hasher, &bundle.get_file(tarball)?)?,
+        Action::Run(run) => {
+            if let Some(script) = &run.script {
+                hash_file(&mut hasher, &bundle.get_file(script)?)?;
+            }
+        }
+        Action::AssembleFile(assemble) => {
+            let dir = bundle.get_file(&assemble.dir)?;
+            let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries {
+                if entry.file_type()?.is_file() {
+                    hasher.update(entry.file_name().to_string_lossy().as_bytes());
+                    hash_file(&mut hasher, &entry.path())?;
+                }
+            }
+        }
+        Action::File(file) => {
+            /*
+             * Templates are hashed rendered so changing a variable only invalidates
+             * the actions which use it.
+             */
+            if file.is_template {
+                let (name, template) = if let Some(src) = &file.src {
+                    (src.clone(), bundle.get_template_string(src)?)
+                } else {
+                    (
+                        file.path.to_string_lossy().to_string(),
+                        file.content.clone().unwrap_or_default(),
+                    )
+                };
+                let rendered = render_template(&name, &template, &bundle.template_context())
+                    .map_err(Box::new)?;
+                hasher.update(rendered);
+            } else if let Some(src) = &file.src {
+                hash_file(&mut hasher, &bundle.get_file(src)?)?;
+            }
+        }
+        Action::Ips(ips) => {
+            for ips_action in &ips.actions {
+                if let IpsActions::ApprovePublisherCA(ca) = ips_action {
+                    hash_file(
+                        &mut hasher,
+                        &bundle.get_file(ca.cert_file.trim_start_matches('/'))?,
+                    )?;
+                }
+            }
+        }
+        Action::Onu(onu) => {
+            /*
+             * Repositories are far too large to hash. The catalog changes with
+             * every publication which is all we need to know.
+             */
+            let repository = if Path::new(&onu.repository).is_absolute() {
+                Path::new(&onu.repository).to_path_buf()
+            } else {
+                bundle.get_files_path().join(&onu.repository)
+            };
+            let catalog = repository
+                .join("publisher")
+                .join(&onu.publisher)
+                .join("catalog/catalog.attrs");
+            if catalog.exists() {
+                hash_file(&mut hasher, &catalog)?;
+            }
+        }
+        Action::CopyFrom(copy) => {
+            /*
+             * Hashing the copied files would mean reading whole stages. Stages are
+             * identified by their last cache layer and images by their uuid instead.
+             */
+            if let Some(identity) = sources.get(&copy.from) {
+                hasher.update(identity);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn hash_file(hasher: &mut Sha256, path: &Path) -> Result<()> {
+    let mut file = File::open(path)?;
+    std::io::copy(&mut file, hasher)?;
+    Ok(())
+}
+
+/// Find the cache entry of the last action that has been cached. Returns the index of
+/// the action and the entry to clone from.
+pub fn find_deepest<'a>(
+    entries: &'a [CacheEntry],
+    hashes: &[String],
+) -> Option<(usize, &'a CacheEntry)> {
+    hashes.iter().enumerate().rev().find_map(|(step, hash)| {
+        entries
+            .iter()
+            .filter(|e| &e.hash == hash)
+            .max_by_key(|e| e.created)
+            .map(|e| (step, e))
+    })
+}
+
+/// Snapshot the zone dataset and all of its children as cache layer for step
+pub fn snapshot(zone_dataset: &str, hash: &str, image: &str, step: usize) -> Result<String> {
+    let snapshot = format!("{}@{}{}", zone_dataset, CACHE_SNAPSHOT_PREFIX, hash);
+    let hash_prop = format!("{}={}", CACHE_HASH_PROPERTY, hash);
+    let image_prop = format!("{}={}", CACHE_IMAGE_PROPERTY, image);
+    let step_prop = format!("{}={}", CACHE_STEP_PROPERTY, step);
+
+    debug!("Snapshotting cache layer {}", &snapshot);
+    crate::run_with_timeout(
+        &[
+            ZFS,
+            "snapshot",
+            "-r",
+            "-o",
+            &hash_prop,
+            "-o",
+            &image_prop,
+            "-o",
+            &step_prop,
+            &snapshot,
+        ],
+        None,
+        ZFS_TIMEOUT,
+    )?;
+
+    Ok(snapshot)
+}
+
+/// All cache layers below dataset
+pub fn list(dataset: &str) -> Result<Vec<CacheEntry>> {
+    let props = format!(
+        "name,creation,used,{},{},{}",
+        CACHE_HASH_PROPERTY, CACHE_IMAGE_PROPERTY, CACHE_STEP_PROPERTY
+    );
+    let output = crate::run_capture_stdout(
+        &[
+            ZFS, "list", "-H", "-p", "-r", "-t", "snapshot", "-o", &props, dataset,
+        ],
+        None,
+    )?;
+    parse_cache_list(&output)
+}
+
+/// Parse the output of `zfs list -H -p -t snapshot -o name,creation,used,<hash>,<image>,<step>`
+///
+/// Cache snapshots are taken recursively so every layer shows up once for the zone
+/// dataset and once for each child. The children are folded into the entry of
+/// the zone dataset.
+pub fn parse_cache_list(output: &str) -> Result<Vec<CacheEntry>> {
+    let mut entries: Vec<CacheEntry> = vec![];
+    let mut children: Vec<CacheEntry> = vec![];
+
+    for line in output.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 6 {
+            return Err(CacheError::ParseError(line.to_string()));
+        }
+
+        // Snapshots without the hash property are not ours
+        if fields[3] == "-" {
+            continue;
+        }
+
+        let parse_num = |s: &str| {
+            s.parse::<u64>()
+                .map_err(|_| CacheError::ParseError(line.to_string()))
+        };
+
+        let entry = CacheEntry {
+            snapshot: fields[0].to_string(),
+            created: parse_num(fields[1])?,
+            used: parse_num(fields[2])?,
+            hash: fields[3].to_string(),
+            image: fields[4].to_string(),
+            step: parse_num(fields[5])? as usize,
+        };
+
+        // zfs list sorts parents before their children
+        let child_of = |e: &CacheEntry| {
+            e.hash == entry.hash && entry.dataset().starts_with(&format!("{}/", e.dataset()))
+        };
+        if entries.iter().any(child_of) {
+            children.push(entry);
+        } else {
+            entries.push(entry);
+        }
+    }
+
+    for child in children {
+        if let Some(parent) = entries.iter_mut().find(|e| {
+            e.hash == child.hash && child.dataset().starts_with(&format!("{}/", e.dataset()))
+        }) {
+            parent.used += child.used;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Clone a cache layer as datasets of a new zone. The zone dataset itself is
+/// created by zoneadm, only its children are cloned.
+pub fn clone_layer(snapshot: &str, zone_dataset: &str, quota: &str) -> Result<()> {
+    let (source_dataset, snap_name) = snapshot
+        .split_once('@')
+        .filter(|(_, name)| name.starts_with(CACHE_SNAPSHOT_PREFIX))
+        .ok_or(CacheError::NotACacheSnapshot(snapshot.to_string()))?;
+
+    let snapshots = crate::run_capture_stdout(
+        &[
+            ZFS,
+            "list",
+            "-H",
+            "-r",
+            "-t",
+            "snapshot",
+            "-o",
+            "name",
+            source_dataset,
+        ],
+        None,
+    )?;
+
+    let suffix = format!("@{}", snap_name);
+    for snap in snapshots.lines() {
+        let dataset = match snap.strip_suffix(&suffix) {
+            Some(dataset) => dataset,
+            None => continue,
+        };
+        let child = match dataset.strip_prefix(&format!("{}/", source_dataset)) {
+            Some(child) => child,
+            None => continue,
+        };
+
+        let target = format!("{}/{}", zone_dataset, child);
+        let mut props = local_properties(dataset)?;
+        match child {
+            "root" => {
+                props.insert("devices".into(), "off".into());
+                props.insert("quota".into(), quota.into());
+            }
+            "vroot" => {
+                props.insert("mountpoint".into(), "none".into());
+                props.insert("canmount".into(), "off".into());
+            }
+            _ => {}
+        }
+
+        let mut args = vec![ZFS.to_string(), "clone".to_string()];
+        for (name, value) in props {
+            args.push("-o".into());
+            args.push(format!("{}={}", name, value));
+        }
+        args.push(snap.to_string());
+        args.push(target.clone());
+
+        info!("Cloning cache layer {} -> {}", snap, &target);
+        crate::run_with_timeout(&args, None, ZFS_TIMEOUT)?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Clones do not inherit locally set properties like the mountpoints of volumes
+ * from their origin so we carry them over by hand.
+ */
+fn local_properties(dataset: &str) -> Result<HashMap<String, String>> {
+    let output = crate::run_capture_stdout(
+        &[
+            ZFS,
+            "get",
+            "-H",
+            "-s",
+            "local",
+            "-o",
+            "property,value",
+            "all",
+            dataset,
+        ],
+        None,
+    )?;
+
+    Ok(output
+        .lines()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
+}
+
+/// Destroy a cache layer. Layers still used by zones or images are destroyed once
+/// those are gone.
+pub fn destroy(entry: &CacheEntry) -> Result<()> {
+    info!("Destroying cache layer {}", &entry.snapshot);
+    crate::run_with_timeout(
+        &[ZFS, "destroy", "-d", "-r", &entry.snapshot],
+        None,
+        ZFS_TIMEOUT,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        action_hashes, find_deepest, hash_action, parse_cache_list, seed_hash, CacheEntry,
+    };
+    use crate::build::{bundle::Bundle, Action, Ips, IpsActions, IpsProperties};
+    use miette::Result;
+    use std::{collections::HashMap, path::Path};
+
+    #[test]
+    fn test_action_hashes() -> Result<()> {
+        let mut bundle = Bundle::new(Path::new("testdata/daemon"))?;
+        let hashes = action_hashes(&bundle, None, &HashMap::new())?;
+        assert_eq!(bundle.document.actions.len(), hashes.len());
+        assert_eq!(hashes, action_hashes(&bundle, None, &HashMap::new())?);
+
+        let base = uuid::Uuid::new_v4();
+        let based = action_hashes(&bundle, Some(&base), &HashMap::new())?;
+        assert_ne!(hashes[0], based[0]);
+        assert_ne!(seed_hash(None), seed_hash(Some(&base)));
+
+        // Changing a variable invalidates the templated file and everything after it
+        let template_step = bundle
+            .document
+            .actions
+            .iter()
+            .position(|a| matches!(a, crate::build::Action::File(f) if f.is_template))
+            .expect("testdata has a templated file");
+        bundle
+            .build_vars
+            .insert(String::from("port"), String::from("1234"));
+        let changed = action_hashes(&bundle, None, &HashMap::new())?;
+        assert_eq!(hashes[..template_step], changed[..template_step]);
+        assert_ne!(hashes[template_step], changed[template_step]);
+        assert_ne!(hashes.last(), changed.last());
+
+        // The order properties are stored in must not change the hash
+        let set_property = |keys: Vec<char>| {
+            Action::Ips(Ips {
+                actions: vec![IpsActions::SetProperty(IpsProperties {
+                    properties: keys
+                        .into_iter()
+                        .map(|k| (k.to_string(), k.to_string()))
+                        .collect(),
+                })],
+            })
+        };
+        let parent = seed_hash(None);
+        assert_eq!(
+            hash_action(
+                &parent,
+                &bundle,
+                &set_property(('a'..='z').collect()),
+                &HashMap::new()
+            )?,
+            hash_action(
+                &parent,
+                &bundle,
+                &set_property(('a'..='z').rev().collect()),
+                &HashMap::new()
+            )?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cache_list() -> Result<()> {
+        let output = "\
+rpool/zones/a@final\t1700000000\t0\t-\t-\t-
+rpool/zones/a@buildcache-aaa\t1700000100\t1024\taaa\tmy-image\t0
+rpool/zones/a/root@buildcache-aaa\t1700000100\t4096\taaa\tmy-image\t0
+rpool/zones/a/vroot@buildcache-aaa\t1700000100\t0\taaa\tmy-image\t0
+rpool/zones/a@buildcache-bbb\t1700000200\t10\tbbb\tmy-image\t1
+rpool/zones/a/root@buildcache-bbb\t1700000200\t20\tbbb\tmy-image\t1
+";
+        let entries = parse_cache_list(output)?;
+        assert_eq!(2, entries.len());
+        assert_eq!(
+            CacheEntry {
+                snapshot: "rpool/zones/a@buildcache-aaa".into(),
+                hash: "aaa".into(),
+                image: "my-image".into(),
+                step: 0,
+                created: 1700000100,
+                used: 5120,
+            },
+            entries[0]
+        );
+        assert_eq!("rpool/zones/a", entries[1].dataset());
+        assert_eq!(30, entries[1].used);
+        assert!(parse_cache_list("rpool/zones/a@x\t1").is_err());
+
+        let hashes = vec!["aaa".to_string(), "bbb".to_string(), "ccc".to_string()];
+        let (step, entry) = find_deepest(&entries, &hashes).expect("layer bbb is cached");
+        assert_eq!(1, step);
+        assert_eq!("bbb", entry.hash);
+        assert!(find_deepest(&entries, &["zzz".to_string()]).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use super::paths::{self, PathError};
+use super::source::ArchiveFormat;
+use super::{users, BuildError, Download};
+use common::{debug, info};
+use miette::Diagnostic;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+
+/// Where imgbuild keeps downloads between builds
+pub const DEFAULT_DOWNLOAD_CACHE_DIR: &str = "/var/cache/imgbuild/downloads";
+
+/*
+ * Vendor tarballs can be a few hundred MB. Slow mirrors get an hour.
+ */
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum DownloadError {
+    #[error("download {0} needs exactly one of sha256 or sha512")]
+    ChecksumMissing(String),
+    #[error("{0} is not a valid {1} checksum")]
+    InvalidChecksum(String, String),
+    #[error("{0} has {1} {2} but {3} was expected")]
+    ChecksumMismatch(String, String, String, String),
+    #[error(
+        "can not extract {0}, only .tar, .tar.gz, .tgz, .tar.zst and .tzst archives are supported"
+    )]
+    UnknownArchiveFormat(String),
+    #[error("group {0} does not exist in the image")]
+    GroupNotFound(String),
+    #[error(transparent)]
+    FetchError(#[from] reqwest::Error),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    PathError(#[from] PathError),
+    #[error(transparent)]
+    AnyHowError(#[from] anyhow::Error),
+    #[error(transparent)]
+    BuildError(Box<BuildError>),
+}
+
+impl From<BuildError> for DownloadError {
+    fn from(e: BuildError) -> Self {
+        DownloadError::BuildError(Box::new(e))
+    }
+}
+
+type Result<T> = miette::Result<T, DownloadError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Sha256(String),
+    Sha512(String),
+}
+
+impl Checksum {
+    /// The checksum a download declares. Hex digits are compared lower case
+    pub fn of(download: &Download) -> Result<Self> {
+        let checksum = match (&download.sha256, &download.sha512) {
+            (Some(sha256), None) => Checksum::Sha256(sha256.to_lowercase()),
+            (None, Some(sha512)) => Checksum::Sha512(sha512.to_lowercase()),
+            _ => return Err(DownloadError::ChecksumMissing(download.url.clone())),
+        };
+
+        let valid = checksum.hex().len() == checksum.hex_len()
+            && checksum.hex().chars().all(|c| c.is_ascii_hexdigit());
+        if !valid {
+            return Err(DownloadError::InvalidChecksum(
+                checksum.hex().to_string(),
+                checksum.algorithm().to_string(),
+            ));
+        }
+
+        Ok(checksum)
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Checksum::Sha256(_) => "sha256",
+            Checksum::Sha512(_) => "sha512",
+        }
+    }
+
+    pub fn hex(&self) -> &str {
+        match self {
+            Checksum::Sha256(hex) | Checksum::Sha512(hex) => hex,
+        }
+    }
+
+    fn hex_len(&self) -> usize {
+        match self {
+            Checksum::Sha256(_) => 64,
+            Checksum::Sha512(_) => 128,
+        }
+    }
+
+    /// Name of the file in the download cache
+    pub fn cache_name(&self) -> String {
+        format!("{}-{}", self.algorithm(), self.hex())
+    }
+
+    /// Hex encoded checksum of path with the algorithm of self
+    pub fn compute(&self, path: &Path) -> Result<String> {
+        let mut file = File::open(path)?;
+        Ok(match self {
+            Checksum::Sha256(_) => {
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                hex::encode(hasher.finalize())
+            }
+            Checksum::Sha512(_) => {
+                let mut hasher = Sha512::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                hex::encode(hasher.finalize())
+            }
+        })
+    }
+
+    fn verify(&self, name: &str, path: &Path) -> Result<()> {
+        let actual = self.compute(path)?;
+        if actual != self.hex() {
+            return Err(DownloadError::ChecksumMismatch(
+                name.to_string(),
+                self.algorithm().to_string(),
+                actual,
+                self.hex().to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm(), self.hex())
+    }
+}
+
+/// Path of the verified download in cache_dir. Files already in the cache are only
+/// verified, everything else is downloaded and only cached if the checksum matches.
+pub fn fetch(download: &Download, cache_dir: &Path) -> Result<PathBuf> {
+    let checksum = Checksum::of(download)?;
+    let cached = cache_dir.join(checksum.cache_name());
+
+    if cached.exists() {
+        match checksum.verify(&cached.display().to_string(), &cached) {
+            Ok(_) => {
+                info!("using cached download {} for {}", checksum, download.url);
+                return Ok(cached);
+            }
+            Err(e) => {
+                info!("cached download is corrupt, fetching again: {}", e);
+                std::fs::remove_file(&cached)?;
+            }
+        }
+    }
+
+    std::fs::create_dir_all(cache_dir)?;
+    let partial = cache_dir.join(format!(".{}.partial", checksum.cache_name()));
+
+    info!("downloading {}", download.url);
+    let client = reqwest::blocking::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()?;
+    let mut response = client.get(&download.url).send()?.error_for_status()?;
+    let mut file = File::create(&partial)?;
+    response.copy_to(&mut file)?;
+    drop(file);
+
+    if let Err(e) = checksum.verify(&download.url, &partial) {
+        std::fs::remove_file(&partial)?;
+        return Err(e);
+    }
+
+    // Other builds never see a half written file under the final name
+    std::fs::rename(&partial, &cached)?;
+    debug!("cached {} as {}", download.url, cached.display());
+    Ok(cached)
+}
+
+/// Put the downloaded file into the image at root. Archives which should be extracted
+/// are unpacked into the path of the download with the owners and modes of their
+/// entries, owner, group and mode then apply to the directory itself.
+pub fn install(download: &Download, file: &Path, root: &Path) -> Result<()> {
+    let owner = match &download.common.owner {
+        Some(owner) => {
+            users::get_user(root, owner)?
+                .ok_or(BuildError::UserNotFound(owner.clone()))?
+                .uid
+        }
+        None => 0,
+    };
+    let group = match &download.common.group {
+        Some(group) => {
+            users::get_group(root, group)?.ok_or(DownloadError::GroupNotFound(group.clone()))?
+        }
+        None => 0,
+    };
+
+    if download.extract {
+        // Query strings of signed urls would hide the extension
+        let name = url::Url::parse(&download.url)
+            .map(|u| u.path().to_string())
+            .unwrap_or(download.url.clone());
+        let format = ArchiveFormat::from_file_name(&name)
+            .ok_or(DownloadError::UnknownArchiveFormat(download.url.clone()))?;
+        let target = paths::resolve(root, &download.path)?;
+        let mode = download.common.mode.unwrap_or(0o755);
+        illumos_image_builder::ensure::directory(&target, owner, group, mode)?;
+        extract(file, format, &target)?;
+    } else {
+        let target = paths::resolve(root, &download.path)?;
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mode = download.common.mode.unwrap_or(0o644);
+        illumos_image_builder::ensure::file(
+            file,
+            &target,
+            owner,
+            group,
+            mode,
+            illumos_image_builder::ensure::Create::Always,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn extract(archive: &Path, format: ArchiveFormat, target: &Path) -> Result<()> {
+    info!(
+        "extracting {} archive {} to {}",
+        format,
+        archive.display(),
+        target.display()
+    );
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match format {
+        ArchiveFormat::Tar => Box::new(file),
+        ArchiveFormat::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(zstd::stream::read::Decoder::new(file)?),
+    };
+
+    /*
+     * unpack refuses entries and links which would end up outside of target.
+     */
+    crate::image::oci::unpack_archive(reader).unpack(target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch, Checksum, DownloadError};
+    use crate::build::{CommonPerms, Download};
+    use crate::mock_http::MockServer;
+    use miette::{IntoDiagnostic, Result};
+    use sha2::{Digest, Sha256};
+
+    fn download(url: &str, sha256: &str) -> Download {
+        Download {
+            url: url.to_string(),
+            path: "/opt/app".into(),
+            sha256: Some(sha256.to_string()),
+            sha512: None,
+            extract: false,
+            common: CommonPerms::default(),
+        }
+    }
+
+    #[test]
+    fn test_fetch() -> Result<()> {
+        const BODY: &[u8] = b"not really a tarball";
+        let sha256 = hex::encode(Sha256::digest(BODY));
+        let dir = tempfile::tempdir().into_diagnostic()?;
+
+        let server = MockServer::start(|_| (200, vec![], BODY.to_vec()));
+        let url = server.url("/app-1.0.tar.gz");
+        let cached = fetch(&download(&url, &sha256), dir.path())?;
+        assert_eq!(dir.path().join(format!("sha256-{}", sha256)), cached);
+        assert_eq!(BODY, std::fs::read(&cached).into_diagnostic()?.as_slice());
+
+        // Only the cache answers the second time
+        let again = fetch(&download(&url, &sha256.to_uppercase()), dir.path())?;
+        assert_eq!(cached, again);
+        assert_eq!(1, server.requests());
+
+        let server = MockServer::start(|_| (200, vec![], b"tampered".to_vec()));
+        let other = "0".repeat(64);
+        let url = server.url("/app-1.0.tar.gz");
+        let err = fetch(&download(&url, &other), dir.path()).unwrap_err();
+        assert!(matches!(err, DownloadError::ChecksumMismatch(..)));
+        assert_eq!(1, std::fs::read_dir(dir.path()).into_diagnostic()?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum() -> Result<()> {
+        let mut download = download("https://example.com/app.tar.gz", &"a".repeat(64));
+        assert_eq!(Checksum::Sha256("a".repeat(64)), Checksum::of(&download)?);
+
+        download.sha512 = Some("b".repeat(128));
+        assert!(Checksum::of(&download).is_err());
+
+        download.sha256 = None;
+        assert_eq!(Checksum::Sha512("b".repeat(128)), Checksum::of(&download)?);
+
+        download.sha512 = Some("xyz".into());
+        assert!(matches!(
+            Checksum::of(&download),
+            Err(DownloadError::InvalidChecksum(..))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,809 @@
+use super::bundle::{
+    BUILD_BUNDLE_BUILD_CONFIG_FILENAME, BUILD_BUNDLE_FILES_DIR, BUILD_BUNDLE_TEMPLATES_DIR,
+};
+use super::paths::{self, escapes};
+use super::{BuildError, Document};
+use kdl::{KdlDocument, KdlNode};
+use miette::{Diagnostic, LabeledSpan, NamedSource, SourceCode, SourceSpan};
+use std::{fmt::Display, path::Path};
+use thiserror::Error;
+
+/// Top level nodes of build.kdl which are not actions
+const DOCUMENT_NODES: [&str; 7] = [
+    "author", "name", "version", "base-on", "vm-specs", "oci", "vars",
+];
+
+/// Nodes of a stage which are not actions
+const STAGE_NODES: [&str; 1] = ["base-on"];
+
+const ACTION_NODES: [&str; 19] = [
+    "volume",
+    "remove",
+    "extract-tarball",
+    "assemble-file",
+    "group",
+    "user",
+    "symlink",
+    "dir",
+    "file",
+    "perm",
+    "ips",
+    "service",
+    "smf-manifest",
+    "onu",
+    "devfsadm",
+    "seed-smf",
+    "copy-from",
+    "run",
+    "download",
+];
+
+/// Actions cloud-init can not apply in vm images
+const VM_UNSUPPORTED_NODES: [&str; 8] = [
+    "service",
+    "smf-manifest",
+    "onu",
+    "devfsadm",
+    "seed-smf",
+    "copy-from",
+    "run",
+    "download",
+];
+
+const IPS_ACTION_NODES: [&str; 11] = [
+    "initialize-image",
+    "install-packages",
+    "install-optionals",
+    "set-property",
+    "set-publisher",
+    "approve-publisher-ca",
+    "uninstall-packages",
+    "set-variant",
+    "set-facet",
+    "purge-history",
+    "set-mediator",
+];
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum CheckError {
+    #[error("could not read {0}: {1}")]
+    IoError(String, #[source] std::io::Error),
+    #[error(transparent)]
+    KnuffelError(#[from] knuffel::Error),
+}
+
+/// A problem in build.kdl pointing at the node which causes it
+#[derive(Debug, Error)]
+#[error("{message}")]
+pub struct Lint {
+    pub message: String,
+    pub code: &'static str,
+    pub label: String,
+    pub help: Option<String>,
+    pub span: SourceSpan,
+    src: NamedSource,
+}
+
+impl Diagnostic for Lint {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(format!("imgbuild::check::{}", self.code)))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|h| Box::new(h) as Box<dyn Display + 'a>)
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.src)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        Some(Box::new(std::iter::once(LabeledSpan::new_with_span(
+            Some(self.label.clone()),
+            self.span,
+        ))))
+    }
+}
+
+/// All problems found in a bundle
+#[derive(Debug, Error)]
+#[error("found {} problems in {}", lints.len(), file)]
+pub struct CheckReport {
+    pub file: String,
+    pub lints: Vec<Lint>,
+}
+
+impl Diagnostic for CheckReport {
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        Some(Box::new(self.lints.iter().map(|l| l as &dyn Diagnostic)))
+    }
+}
+
+/*
+ * kdl brings its own version of miette. We only need offset and length of its
+ * spans so we convert them without naming the type.
+ */
+macro_rules! span {
+    ($span:expr) => {
+        SourceSpan::new($span.offset().into(), $span.len().into())
+    };
+}
+
+struct Linter<'a> {
+    bundle_path: &'a Path,
+    file: String,
+    text: String,
+    lints: Vec<Lint>,
+}
+
+impl Linter<'_> {
+    fn push(
+        &mut self,
+        code: &'static str,
+        span: SourceSpan,
+        message: String,
+        label: &str,
+        help: Option<String>,
+    ) {
+        self.lints.push(Lint {
+            message,
+            code,
+            label: label.to_string(),
+            help,
+            span,
+            src: NamedSource::new(&self.file, self.text.clone()),
+        });
+    }
+
+    fn check_document(&mut self, doc: &KdlDocument) {
+        let is_vm = doc.get("vm-specs").is_some();
+        let stages: Vec<&str> = doc
+            .nodes()
+            .iter()
+            .filter(|n| n.name().value() == "stage")
+            .filter_map(|n| argument(n, 0).map(|(a, _)| a))
+            .collect();
+        let mut built: Vec<&str> = vec![];
+
+        if let Some(specs) = doc.get("vm-specs") {
+            self.check_vm_specs(specs);
+        }
+
+        for node in doc.nodes() {
+            let name = node.name().value();
+            if DOCUMENT_NODES.contains(&name) {
+                continue;
+            }
+
+            if name == "stage" {
+                self.check_stage(node, &stages, &built);
+                if let Some((stage, _)) = argument(node, 0) {
+                    built.push(stage);
+                }
+                continue;
+            }
+
+            self.check_action(node, is_vm, &stages, &built);
+        }
+    }
+
+    fn check_vm_specs(&mut self, specs: &KdlNode) {
+        match child(specs, "boot-image") {
+            Some(image) => {
+                if let Some((path, _)) = argument(image, 0) {
+                    if !Path::new(path).is_absolute() {
+                        self.check_bundle_file(image, 0, BUILD_BUNDLE_FILES_DIR);
+                    }
+                }
+            }
+            None => self.push(
+                "vm_without_boot_image",
+                span!(specs.name().span()),
+                String::from("vm-specs has no boot-image"),
+                "no boot-image",
+                Some(String::from(
+                    "add the cloud image or installer iso the vm boots from like: boot-image \"debian-12.raw\"",
+                )),
+            ),
+        }
+    }
+
+    /// built are the stages defined before this one
+    fn check_stage(&mut self, node: &KdlNode, stages: &[&str], built: &[&str]) {
+        if let Some((name, span)) = argument(node, 0) {
+            if built.contains(&name) {
+                self.push(
+                    "duplicate_stage",
+                    span,
+                    format!("stage {} is defined more than once", name),
+                    "already defined",
+                    Some(String::from("give every stage a unique name")),
+                );
+            }
+        }
+
+        for action in children(node) {
+            let name = action.name().value();
+            if STAGE_NODES.contains(&name) {
+                continue;
+            }
+            if name == "stage" {
+                self.push(
+                    "nested_stage",
+                    span!(action.name().span()),
+                    String::from("stages can not be nested"),
+                    "nested stage",
+                    Some(String::from(
+                        "define the stage at the top level of build.kdl",
+                    )),
+                );
+                continue;
+            }
+            /*
+             * Stages are always built as zones, vm-specs only applies to the
+             * final image.
+             */
+            self.check_action(action, false, stages, built);
+        }
+    }
+
+    fn check_action(&mut self, node: &KdlNode, is_vm: bool, stages: &[&str], built: &[&str]) {
+        let name = node.name().value();
+        if is_vm && VM_UNSUPPORTED_NODES.contains(&name) {
+            self.push(
+                "unsupported_vm_action",
+                span!(node.name().span()),
+                format!("{} can not be applied to vm images", name),
+                "not supported in vms",
+                Some(String::from(
+                    "vm images get their actions applied by cloud-init, use file, dir or ips install-packages instead",
+                )),
+            );
+            return;
+        }
+        match name {
+            "file" => self.check_file(node),
+            "perm" => {
+                self.check_target(node);
+                if child(node, "mode").is_none() {
+                    let path = argument(node, 0).map(|(a, _)| a).unwrap_or_default();
+                    self.push(
+                        "perm_without_mode",
+                        span!(node.span()),
+                        BuildError::NoModeSpecified(path.to_string()).to_string(),
+                        "this perm",
+                        Some(String::from("add a mode child like: mode 0o644")),
+                    );
+                }
+            }
+            "dir" | "symlink" | "remove" | "assemble-file" => {
+                self.check_target(node);
+                if name == "assemble-file" {
+                    if let Some(dir) = child(node, "dir") {
+                        self.check_bundle_file(dir, 0, BUILD_BUNDLE_FILES_DIR);
+                    }
+                }
+            }
+            "extract-tarball" => self.check_bundle_file(node, 0, BUILD_BUNDLE_FILES_DIR),
+            "onu" => {
+                if let Some((repository, _)) = argument(node, 0) {
+                    if !Path::new(repository).is_absolute() {
+                        self.check_bundle_file(node, 0, BUILD_BUNDLE_FILES_DIR);
+                    }
+                }
+            }
+            "ips" => self.check_ips(node),
+            "copy-from" => {
+                self.check_target_at(node, 1);
+                self.check_target_at(node, 2);
+                if let Some((from, span)) = argument(node, 0) {
+                    if stages.contains(&from) && !built.contains(&from) {
+                        self.push(
+                            "copy_from_unbuilt_stage",
+                            span,
+                            format!("stage {} is not built yet when this copy-from runs", from),
+                            "not built yet",
+                            Some(String::from(
+                                "stages are built in the order they are defined, move the stage before this one",
+                            )),
+                        );
+                    }
+                }
+            }
+            "service" => self.check_service(node),
+            "run" => self.check_run(node),
+            "download" => self.check_download(node),
+            "volume" => {
+                if is_vm {
+                    self.push(
+                        "volume_outside_zone",
+                        span!(node.span()),
+                        String::from("volume is only supported when building zone images"),
+                        "this volume",
+                        Some(String::from(
+                            "define the disks of vm images in vm-specs instead",
+                        )),
+                    );
+                }
+                if let Some(mountpoint) = child(node, "mountpoint") {
+                    self.check_target(mountpoint);
+                }
+            }
+            x if ACTION_NODES.contains(&x) => {}
+            x => self.push(
+                "unknown_action",
+                span!(node.name().span()),
+                format!("unknown action {}", x),
+                "not an action",
+                Some(format!("known actions are: {}", ACTION_NODES.join(", "))),
+            ),
+        }
+    }
+
+    fn check_run(&mut self, node: &KdlNode) {
+        let script = child(node, "script");
+        match (argument(node, 0), script) {
+            (Some(_), Some(_)) => self.push(
+                "run_command_and_script",
+                span!(node.span()),
+                String::from("run has both a command and a script"),
+                "this run",
+                Some(String::from("remove one of them")),
+            ),
+            (None, None) => self.push(
+                "run_without_command",
+                span!(node.span()),
+                BuildError::EitherCommandOrScript.to_string(),
+                "this run",
+                Some(String::from(
+                    "add the command as argument like: run \"make install\" or a script child with a path in the bundle",
+                )),
+            ),
+            (None, Some(script)) => self.check_bundle_file(script, 0, BUILD_BUNDLE_FILES_DIR),
+            (Some(_), None) => {}
+        }
+
+        if let Some(cwd) = child(node, "cwd") {
+            self.check_target(cwd);
+        }
+    }
+
+    fn check_download(&mut self, node: &KdlNode) {
+        self.check_target_at(node, 1);
+
+        let checksums: Vec<(&KdlNode, usize)> = [("sha256", 64), ("sha512", 128)]
+            .into_iter()
+            .filter_map(|(name, len)| child(node, name).map(|c| (c, len)))
+            .collect();
+        if checksums.len() != 1 {
+            self.push(
+                "download_without_checksum",
+                span!(node.span()),
+                String::from("download needs exactly one of sha256 or sha512"),
+                "this download",
+                Some(String::from(
+                    "add the checksum of the file like: sha256 \"<hex>\"",
+                )),
+            );
+        }
+
+        for (checksum, len) in checksums {
+            if let Some((hex, span)) = argument(checksum, 0) {
+                if hex.len() != len || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    self.push(
+                        "invalid_checksum",
+                        span,
+                        format!(
+                            "{} is not a valid {} checksum",
+                            hex,
+                            checksum.name().value()
+                        ),
+                        "not a checksum",
+                        Some(format!("expected {} hex digits", len)),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_file(&mut self, node: &KdlNode) {
+        self.check_target(node);
+
+        let src = child(node, "src");
+        let content = child(node, "content");
+        match (src, content) {
+            (Some(_), Some(_)) => self.push(
+                "file_src_and_content",
+                span!(node.span()),
+                String::from("file has both src and content"),
+                "this file",
+                Some(String::from("remove one of them")),
+            ),
+            (None, None) => self.push(
+                "file_without_content",
+                span!(node.span()),
+                String::from("file has neither src nor content"),
+                "this file",
+                Some(String::from(
+                    "add src with a path in the bundle or content with the text of the file",
+                )),
+            ),
+            (Some(src), None) => {
+                let dir = if child(node, "is-template").is_some() {
+                    BUILD_BUNDLE_TEMPLATES_DIR
+                } else {
+                    BUILD_BUNDLE_FILES_DIR
+                };
+                self.check_bundle_file(src, 0, dir);
+            }
+            (None, Some(_)) => {}
+        }
+    }
+
+    fn check_ips(&mut self, node: &KdlNode) {
+        for ips_action in children(node) {
+            let name = ips_action.name().value();
+            if !IPS_ACTION_NODES.contains(&name) {
+                self.push(
+                    "unsupported_ips_action",
+                    span!(ips_action.name().span()),
+                    format!("unsupported ips action {}", name),
+                    "not supported",
+                    Some(format!(
+                        "supported ips actions are: {}",
+                        IPS_ACTION_NODES.join(", ")
+                    )),
+                );
+            } else if name == "approve-publisher-ca" {
+                self.check_bundle_file(ips_action, 1, BUILD_BUNDLE_FILES_DIR);
+            }
+        }
+    }
+
+    fn check_service(&mut self, node: &KdlNode) {
+        let fmri = argument(node, 0).map(|(a, _)| a).unwrap_or_default();
+        for property in children(node).filter(|n| n.name().value() == "property") {
+            if let Some((name, span)) = argument(property, 0) {
+                if !name.contains('/') {
+                    self.push(
+                        "property_without_group",
+                        span,
+                        BuildError::NoNameForPropertyGroupInService(
+                            name.to_string(),
+                            fmri.to_string(),
+                        )
+                        .to_string(),
+                        "missing property group",
+                        Some(format!(
+                            "prefix the property with its group like: config/{}",
+                            name
+                        )),
+                    );
+                }
+            }
+        }
+    }
+
+    /// The first argument of node is a path inside the image
+    fn check_target(&mut self, node: &KdlNode) {
+        self.check_target_at(node, 0)
+    }
+
+    fn check_target_at(&mut self, node: &KdlNode, index: usize) {
+        let (path, span) = match argument(node, index) {
+            Some(arg) => arg,
+            None => return,
+        };
+
+        if !path.starts_with('/') {
+            self.push(
+                "relative_target",
+                span,
+                format!("path {} in the image must be absolute", path),
+                "relative path",
+                Some(format!("use /{}", path)),
+            );
+        } else if escapes(path) {
+            self.push(
+                "target_escapes_root",
+                span,
+                format!("path {} points outside of the image", path),
+                "escapes the image root",
+                None,
+            );
+        }
+    }
+
+    /// The argument at index of node is a path relative to dir of the bundle
+    fn check_bundle_file(&mut self, node: &KdlNode, index: usize, dir: &str) {
+        let (path, span) = match argument(node, index) {
+            Some(arg) => arg,
+            None => return,
+        };
+
+        let relative = path.trim_start_matches('/');
+        let resolved = paths::resolve(self.bundle_path.join(dir), relative);
+        if escapes(relative) || resolved.is_err() {
+            self.push(
+                "file_escapes_bundle",
+                span,
+                format!(
+                    "{} points outside of the {} directory of the bundle",
+                    path, dir
+                ),
+                "escapes the bundle",
+                None,
+            );
+        } else if !resolved.map(|p| p.exists()).unwrap_or_default() {
+            self.push(
+                "missing_bundle_file",
+                span,
+                format!("{}/{} does not exist in the bundle", dir, relative),
+                "missing",
+                None,
+            );
+        }
+    }
+}
+
+fn argument(node: &KdlNode, index: usize) -> Option<(&str, SourceSpan)> {
+    node.entries()
+        .iter()
+        .filter(|e| e.name().is_none())
+        .nth(index)
+        .and_then(|e| e.value().as_string().map(|v| (v, span!(e.span()))))
+}
+
+fn child<'a>(node: &'a KdlNode, name: &str) -> Option<&'a KdlNode> {
+    node.children().and_then(|c| c.get(name))
+}
+
+fn children(node: &KdlNode) -> impl Iterator<Item = &KdlNode> {
+    node.children().into_iter().flat_map(|c| c.nodes().iter())
+}
+
+/// Check the bundle in bundle_path. Syntax errors and lints are returned as report,
+/// problems reading the bundle as error.
+pub fn check_bundle(bundle_path: &Path) -> Result<Option<CheckReport>, CheckError> {
+    let path = bundle_path.join(BUILD_BUNDLE_BUILD_CONFIG_FILENAME);
+    let file = path.to_string_lossy().to_string();
+    let text = std::fs::read_to_string(&path).map_err(|e| CheckError::IoError(file.clone(), e))?;
+
+    let mut linter = Linter {
+        bundle_path,
+        file: file.clone(),
+        text: text.clone(),
+        lints: vec![],
+    };
+
+    match text.parse::<KdlDocument>() {
+        Ok(doc) => linter.check_document(&doc),
+        Err(err) => linter.push(
+            "syntax",
+            span!(err.span),
+            err.to_string(),
+            err.label.unwrap_or("here"),
+            err.help.map(|h| h.to_string()),
+        ),
+    }
+
+    if !linter.lints.is_empty() {
+        return Ok(Some(CheckReport {
+            file,
+            lints: linter.lints,
+        }));
+    }
+
+    // Everything kdl can't tell us about like missing arguments or wrong types
+    knuffel::parse::<Document>(&file, &text)?;
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_bundle, ACTION_NODES};
+    use crate::build::{plan::ActionName, Document};
+    use miette::{IntoDiagnostic, Result};
+
+    #[test]
+    fn test_action_nodes() -> Result<()> {
+        let text = r#"name "nodes"
+volume "data"
+remove "/etc/motd"
+extract-tarball "files.tar"
+assemble-file "/etc/hosts" {
+    dir "hosts.d"
+}
+group "web"
+user "web"
+symlink "/etc/link" "/etc/motd"
+dir "/srv"
+file "/etc/motd"
+perm "/etc/shadow"
+ips
+service "svc:/network/web:default"
+smf-manifest "svc:/network/web:default" {
+    start "/usr/bin/web"
+}
+onu "repo.redist"
+devfsadm
+seed-smf
+copy-from "builder" "/out" "/srv"
+run "true"
+download "https://example.com/web.tar" "/srv"
+"#;
+        let document = knuffel::parse::<Document>("nodes.kdl", text)?;
+        let nodes: Vec<String> = document
+            .actions
+            .iter()
+            .map(|action| ActionName(action).to_string())
+            .collect();
+        assert_eq!(ACTION_NODES.to_vec(), nodes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_bundle() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::create_dir_all(dir.path().join("files")).into_diagnostic()?;
+        std::fs::write(dir.path().join("files/motd"), "hello").into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            r#"name "lint"
+base-on "zones/base"
+file "/etc/motd" {
+    src "motd"
+}
+file "/etc/issue" {
+    src "issue.tmpl"
+    is-template
+}
+file "/etc/both" {
+    src "motd"
+    content "hello"
+}
+file "/etc/none"
+perm "/etc/shadow"
+dir "/../outside"
+symlink "etc/relative" "/tmp"
+extract-tarball "../../secret.tar"
+ips {
+    install-package "vim"
+}
+service "network/myapp" {
+    property "port" "8080"
+}
+run "make" {
+    script "build.sh"
+}
+run {
+    cwd "src"
+}
+download "https://example.com/app.tar.gz" "opt/app"
+download "https://example.com/app.tar.gz" "/opt/app" {
+    sha256 "abc"
+}
+frobnicate "/etc"
+"#,
+        )
+        .into_diagnostic()?;
+
+        let report = check_bundle(dir.path())?.expect("bundle has problems");
+        let codes: Vec<&str> = report.lints.iter().map(|l| l.code).collect();
+        assert_eq!(
+            vec![
+                "missing_bundle_file",
+                "file_src_and_content",
+                "file_without_content",
+                "perm_without_mode",
+                "target_escapes_root",
+                "relative_target",
+                "file_escapes_bundle",
+                "unsupported_ips_action",
+                "property_without_group",
+                "run_command_and_script",
+                "run_without_command",
+                "relative_target",
+                "relative_target",
+                "download_without_checksum",
+                "invalid_checksum",
+                "unknown_action",
+            ],
+            codes
+        );
+
+        let missing = &report.lints[0];
+        assert_eq!(
+            "templates/issue.tmpl does not exist in the bundle",
+            missing.message
+        );
+        let text = std::fs::read_to_string(dir.path().join("build.kdl")).into_diagnostic()?;
+        let span = &text[missing.span.offset()..missing.span.offset() + missing.span.len()];
+        assert_eq!("\"issue.tmpl\"", span.trim());
+
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            "name \"lint\"\nfile \"/etc/motd\" {\n    src \"motd\"\n}\n",
+        )
+        .into_diagnostic()?;
+        assert!(check_bundle(dir.path())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_stages() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            r#"name "stages"
+base-on "zones/base"
+stage "builder" {
+    base-on "zones/devel"
+    copy-from "runtime" "/opt/runtime" "/opt/runtime"
+    frobnicate "/etc"
+}
+stage "runtime" {
+    copy-from "builder" "/src/out" "../outside"
+}
+stage "builder"
+copy-from "builder" "/src/out" "/opt/app"
+copy-from "zones/tools" "/usr/bin/jq" "/usr/bin/jq"
+"#,
+        )
+        .into_diagnostic()?;
+
+        let report = check_bundle(dir.path())?.expect("bundle has problems");
+        let codes: Vec<&str> = report.lints.iter().map(|l| l.code).collect();
+        assert_eq!(
+            vec![
+                "copy_from_unbuilt_stage",
+                "unknown_action",
+                "relative_target",
+                "duplicate_stage",
+            ],
+            codes
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_vm() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            r#"name "vm"
+vm-specs {
+    (virtio)disk "vda" size="20G"
+}
+ips {
+    install-packages "nginx"
+}
+service "network/nginx"
+run "nginx -t"
+dir "/srv/www"
+volume "/data"
+"#,
+        )
+        .into_diagnostic()?;
+
+        let report = check_bundle(dir.path())?.expect("bundle has problems");
+        let codes: Vec<&str> = report.lints.iter().map(|l| l.code).collect();
+        assert_eq!(
+            vec![
+                "vm_without_boot_image",
+                "unsupported_vm_action",
+                "unsupported_vm_action",
+                "volume_outside_zone"
+            ],
+            codes
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use miette::Diagnostic;
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+};
+use thiserror::Error;
+
+/*
+ * Same limit as MAXSYMLINKS on illumos. Anything deeper is a loop or an attack.
+ */
+const MAX_SYMLINKS: usize = 20;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum PathError {
+    #[error("path {0} escapes {1}")]
+    Escapes(String, String),
+    #[error("too many levels of symbolic links while resolving {0} in {1}")]
+    TooManySymlinks(String, String),
+    #[error("could not resolve {0}: {1}")]
+    IoError(String, #[source] std::io::Error),
+}
+
+type Result<T> = miette::Result<T, PathError>;
+
+/// Whether path climbs out of the directory it is relative to with ..
+/// Only looks at the path itself, symlinks are not followed.
+pub fn escapes<P: AsRef<Path>>(path: P) -> bool {
+    let mut depth = 0usize;
+    for component in path.as_ref().components() {
+        match component {
+            Component::ParentDir => {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Resolve path inside root like a chroot would. Absolute and relative paths are both
+/// taken as relative to root. Symlinks are followed, absolute link targets are
+/// resolved relative to root as well. Fails if .. in the path or in a link target
+/// climbs above root.
+pub fn resolve<R: AsRef<Path>, P: AsRef<Path>>(root: R, path: P) -> Result<PathBuf> {
+    resolve_with(root.as_ref(), path.as_ref(), true)
+}
+
+/// Like resolve but the last component is not followed if it is a symlink. Use this
+/// for paths which get replaced or removed like the link of a symlink action.
+pub fn resolve_parent<R: AsRef<Path>, P: AsRef<Path>>(root: R, path: P) -> Result<PathBuf> {
+    resolve_with(root.as_ref(), path.as_ref(), false)
+}
+
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+fn resolve_with(root: &Path, path: &Path, follow_last: bool) -> Result<PathBuf> {
+    let escapes_err = || PathError::Escapes(path.display().to_string(), root.display().to_string());
+
+    let mut pending = components(path);
+    let mut resolved: Vec<OsString> = vec![];
+    let mut links = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if component == ".." {
+            resolved.pop().ok_or_else(escapes_err)?;
+            continue;
+        }
+
+        if pending.is_empty() && !follow_last {
+            resolved.push(component);
+            break;
+        }
+
+        let candidate = resolved
+            .iter()
+            .fold(root.to_path_buf(), |p, c| p.join(c))
+            .join(&component);
+
+        let is_symlink = match std::fs::symlink_metadata(&candidate) {
+            Ok(meta) => meta.file_type().is_symlink(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(PathError::IoError(candidate.display().to_string(), e)),
+        };
+
+        if !is_symlink {
+            resolved.push(component);
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(PathError::TooManySymlinks(
+                path.display().to_string(),
+                root.display().to_string(),
+            ));
+        }
+
+        let target = std::fs::read_link(&candidate)
+            .map_err(|e| PathError::IoError(candidate.display().to_string(), e))?;
+        if target.is_absolute() {
+            resolved.clear();
+        }
+
+        let mut next = components(&target);
+        next.extend(pending);
+        pending = next;
+    }
+
+    Ok(resolved.iter().fold(root.to_path_buf(), |p, c| p.join(c)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escapes, resolve, resolve_parent, PathError};
+    use miette::{IntoDiagnostic, Result};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_escapes() {
+        assert!(escapes("../etc/passwd"));
+        assert!(escapes("/etc/../../etc/passwd"));
+        assert!(escapes("files/../../x"));
+        assert!(!escapes("/etc/../var/tmp"));
+        assert!(!escapes("templates/motd.tmpl"));
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("etc")).into_diagnostic()?;
+        std::fs::create_dir_all(root.join("usr/share")).into_diagnostic()?;
+
+        assert_eq!(root.join("etc/motd"), resolve(&root, "/etc/motd")?);
+        assert_eq!(root.join("etc/motd"), resolve(&root, "etc/../etc/motd")?);
+        assert!(matches!(
+            resolve(&root, "/../etc/passwd"),
+            Err(PathError::Escapes(..))
+        ));
+
+        // Absolute links stay inside the root
+        symlink("/usr/share", root.join("etc/share")).into_diagnostic()?;
+        assert_eq!(root.join("usr/share/x"), resolve(&root, "/etc/share/x")?);
+
+        // Relative links may not climb out
+        symlink("../../..", root.join("etc/up")).into_diagnostic()?;
+        assert!(matches!(
+            resolve(&root, "/etc/up/etc/passwd"),
+            Err(PathError::Escapes(..))
+        ));
+
+        // The link itself is fine as long as we don't follow it
+        assert_eq!(root.join("etc/up"), resolve_parent(&root, "/etc/up")?);
+        assert!(resolve(&root, "/etc/up").is_err());
+
+        symlink("b", root.join("a")).into_diagnostic()?;
+        symlink("a", root.join("b")).into_diagnostic()?;
+        assert!(matches!(
+            resolve(&root, "/a"),
+            Err(PathError::TooManySymlinks(..))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use super::bundle::{BuildBundleType, Bundle};
+use super::{users, Action, IpsActions, PKG_CA_DIR, SITE_MANIFEST_DIR};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+/// Where an action of the build gets executed
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunLocation {
+    GlobalZone,
+    Zone,
+}
+
+impl Display for RunLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunLocation::GlobalZone => write!(f, "global zone"),
+            RunLocation::Zone => write!(f, "zone"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputKind {
+    File,
+    Template,
+    Repository,
+}
+
+impl Display for InputKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputKind::File => write!(f, "file"),
+            InputKind::Template => write!(f, "template"),
+            InputKind::Repository => write!(f, "repository"),
+        }
+    }
+}
+
+/// Something from the bundle or the build host an action reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanInput {
+    pub kind: InputKind,
+    pub path: PathBuf,
+    pub exists: bool,
+}
+
+impl PlanInput {
+    fn new(kind: InputKind, path: PathBuf) -> Self {
+        Self {
+            exists: path.exists(),
+            kind,
+            path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanStep {
+    pub index: usize,
+    pub action: Action,
+    pub location: RunLocation,
+    /// Absolute paths the action writes to
+    pub targets: Vec<PathBuf>,
+    pub inputs: Vec<PlanInput>,
+}
+
+/// Everything a build of a bundle will do, resolved without touching the system
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub name: String,
+    pub bundle_type: BuildBundleType,
+    pub base_on: Option<String>,
+    /// The uuid of the image base_on resolves to. None if it could not be found
+    pub base_image: Option<uuid::Uuid>,
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    /// Plan the build of bundle into a zone whose root is mounted at zone_root
+    pub fn new(bundle: &Bundle, zone_root: &Path, base_image: Option<uuid::Uuid>) -> Self {
+        let audit_info = bundle.get_audit_info();
+
+        let steps = bundle
+            .document
+            .actions
+            .iter()
+            .enumerate()
+            .map(|(index, action)| {
+                let location = if (index == 0 && audit_info.is_base_image())
+                    || matches!(
+                        action,
+                        Action::CopyFrom(_) | Action::Download(_) | Action::Devfsadm
+                    ) {
+                    RunLocation::GlobalZone
+                } else {
+                    RunLocation::Zone
+                };
+
+                PlanStep {
+                    index,
+                    action: action.clone(),
+                    location,
+                    targets: action_targets(zone_root, action),
+                    inputs: action_inputs(bundle, action),
+                }
+            })
+            .collect();
+
+        Self {
+            name: bundle.document.name.clone(),
+            bundle_type: audit_info.kind(),
+            base_on: bundle.document.base_on.clone(),
+            base_image,
+            steps,
+        }
+    }
+
+    pub fn missing_inputs(&self) -> Vec<&PlanInput> {
+        self.steps
+            .iter()
+            .flat_map(|s| s.inputs.iter())
+            .filter(|i| !i.exists)
+            .collect()
+    }
+
+    /// A build can only start if the base image is known and all inputs exist
+    pub fn is_complete(&self) -> bool {
+        (self.base_on.is_none() || self.base_image.is_some()) && self.missing_inputs().is_empty()
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Image: {}", self.name)?;
+        writeln!(f, "Bundle type: {:?}", self.bundle_type)?;
+        match (&self.base_on, &self.base_image) {
+            (Some(base_on), Some(uuid)) => writeln!(f, "Base image: {} ({})", base_on, uuid)?,
+            (Some(base_on), None) => writeln!(f, "Base image: {} (NOT FOUND)", base_on)?,
+            (None, _) => writeln!(f, "Base image: none")?,
+        }
+
+        for step in &self.steps {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "[{}] {} in {}",
+                step.index,
+                ActionName(&step.action),
+                step.location
+            )?;
+            if let Action::Ips(ips) = &step.action {
+                for ips_action in &ips.actions {
+                    writeln!(f, "    {}", ips_action)?;
+                }
+            } else {
+                writeln!(f, "    {}", step.action)?;
+            }
+            for target in &step.targets {
+                writeln!(f, "    writes {}", target.display())?;
+            }
+            for input in &step.inputs {
+                writeln!(
+                    f,
+                    "    reads {} {}{}",
+                    input.kind,
+                    input.path.display(),
+                    if input.exists { "" } else { " (MISSING)" }
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints the kdl node name of an action. The linter checks its list of action
+/// nodes against this.
+pub(super) struct ActionName<'a>(pub(super) &'a Action);
+
+impl Display for ActionName<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self.0 {
+            Action::Volume(_) => "volume",
+            Action::Remove(_) => "remove",
+            Action::ExtractTarball(_) => "extract-tarball",
+            Action::AssembleFile(_) => "assemble-file",
+            Action::Group(_) => "group",
+            Action::User(_) => "user",
+            Action::Symlink(_) => "symlink",
+            Action::Dir(_) => "dir",
+            Action::File(_) => "file",
+            Action::Perm(_) => "perm",
+            Action::Ips(_) => "ips",
+            Action::Service(_) => "service",
+            Action::SmfManifest(_) => "smf-manifest",
+            Action::Onu(_) => "onu",
+            Action::Devfsadm => "devfsadm",
+            Action::SeedSmf(_) => "seed-smf",
+            Action::CopyFrom(_) => "copy-from",
+            Action::Run(_) => "run",
+            Action::Download(_) => "download",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn under_root<P: AsRef<Path>>(root: &Path, path: P) -> PathBuf {
+    let path = path.as_ref();
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn action_targets(root: &Path, action: &Action) -> Vec<PathBuf> {
+    match action {
+        Action::Volume(volume) => {
+            let mountpoint = volume
+                .mountpoint
+                .clone()
+                .unwrap_or(format!("/{}", volume.name));
+            vec![under_root(root, mountpoint)]
+        }
+        Action::Remove(path) => vec![under_root(root, path)],
+        // Commands can write anywhere
+        Action::ExtractTarball(_) | Action::Onu(_) | Action::Run(_) => vec![root.to_path_buf()],
+        Action::AssembleFile(assemble) => vec![under_root(root, &assemble.output)],
+        Action::Group(_) => vec![under_root(root, users::GROUP)],
+        Action::User(user) => {
+            let home =
+                user.home
+                    .clone()
+                    .unwrap_or(format!("{}/{}", users::DEFAULT_HOME_BASE, user.name));
+            let mut targets = vec![
+                under_root(root, users::PASSWD),
+                under_root(root, users::SHADOW),
+                under_root(root, home),
+            ];
+            if !user.groups.is_empty() {
+                targets.push(under_root(root, users::GROUP));
+            }
+            targets
+        }
+        Action::Symlink(link) => vec![under_root(root, &link.link)],
+        Action::Dir(dir) | Action::Perm(dir) => vec![under_root(root, &dir.path)],
+        Action::File(file) => vec![under_root(root, &file.path)],
+        Action::Ips(ips) => {
+            let mut targets = vec![root.to_path_buf()];
+            for ips_action in &ips.actions {
+                if let IpsActions::ApprovePublisherCA(_) = ips_action {
+                    targets.push(under_root(root, PKG_CA_DIR));
+                }
+            }
+            targets
+        }
+        Action::Service(svc) => vec![under_root(
+            root,
+            format!("var/svc/profile/{}.xml", svc.fmri.replace("/", "_")),
+        )],
+        Action::SmfManifest(manifest) => vec![under_root(
+            root,
+            Path::new(SITE_MANIFEST_DIR).join(format!("{}.xml", manifest.manifest_name())),
+        )],
+        Action::Devfsadm => vec![under_root(root, "dev"), under_root(root, "devices")],
+        Action::SeedSmf(_) => vec![under_root(root, "etc/svc/repository.db")],
+        Action::CopyFrom(copy) => vec![under_root(root, &copy.dst)],
+        Action::Download(download) => vec![under_root(root, &download.path)],
+    }
+}
+
+fn action_inputs(bundle: &Bundle, action: &Action) -> Vec<PlanInput> {
+    let files = bundle.get_files_path();
+    match action {
+        Action::ExtractTarball(tarball) => {
+            vec![PlanInput::new(InputKind::File, files.join(tarball))]
+        }
+        Action::Run(run) => run
+            .script
+            .iter()
+            .map(|script| PlanInput::new(InputKind::File, files.join(script)))
+            .collect(),
+        Action::AssembleFile(assemble) => {
+            vec![PlanInput::new(InputKind::File, files.join(&assemble.dir))]
+        }
+        Action::File(file) => match (&file.src, file.is_template) {
+            (Some(src), true) => vec![PlanInput::new(
+                InputKind::Template,
+                bundle.get_templates_path().join(src),
+            )],
+            (Some(src), false) => vec![PlanInput::new(InputKind::File, files.join(src))],
+            (None, _) => vec![],
+        },
+        Action::Ips(ips) => ips
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                IpsActions::ApprovePublisherCA(ca) => Some(PlanInput::new(
+                    InputKind::File,
+                    files.join(ca.cert_file.trim_start_matches('/')),
+                )),
+                _ => None,
+            })
+            .collect(),
+        Action::Onu(onu) => {
+            let repository = if Path::new(&onu.repository).is_absolute() {
+                PathBuf::from(&onu.repository)
+            } else {
+                files.join(&onu.repository)
+            };
+            vec![PlanInput::new(InputKind::Repository, repository)]
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputKind, Plan, RunLocation};
+    use crate::build::bundle::{BuildBundleType, Bundle};
+    use miette::{IntoDiagnostic, Result};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_plan() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::create_dir_all(dir.path().join("files")).into_diagnostic()?;
+        std::fs::write(dir.path().join("files/motd"), "hello").into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            r#"
+name "base"
+ips {
+    install-packages "system/library"
+}
+file "/etc/motd" {
+    src "motd"
+}
+file "/etc/issue" {
+    src "issue.tmpl"
+    is-template
+}
+symlink "/etc/localtime" "../usr/share/lib/zoneinfo/UTC"
+"#,
+        )
+        .into_diagnostic()?;
+
+        let bundle = Bundle::new(dir.path())?;
+        let plan = Plan::new(&bundle, Path::new("/zones/build/root"), None);
+
+        assert_eq!(BuildBundleType::BaseImage, plan.bundle_type);
+        assert_eq!(4, plan.steps.len());
+        assert_eq!(RunLocation::GlobalZone, plan.steps[0].location);
+        assert_eq!(RunLocation::Zone, plan.steps[1].location);
+        assert_eq!(
+            vec![PathBuf::from("/zones/build/root/etc/motd")],
+            plan.steps[1].targets
+        );
+        assert!(plan.steps[1].inputs[0].exists);
+        assert_eq!(
+            vec![PathBuf::from("/zones/build/root/etc/localtime")],
+            plan.steps[3].targets
+        );
+
+        let missing = plan.missing_inputs();
+        assert_eq!(1, missing.len());
+        assert_eq!(InputKind::Template, missing[0].kind);
+        assert!(missing[0].path.ends_with("templates/issue.tmpl"));
+        assert!(!plan.is_complete());
+
+        let output = plan.to_string();
+        assert!(output.contains("[0] ips in global zone"));
+        assert!(output.contains("issue.tmpl (MISSING)"));
+
+        Ok(())
+    }
+}
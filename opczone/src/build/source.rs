@@ -0,0 +1,606 @@
+use super::bundle::{BuildBundleKind, Bundle, BundleError, BUILD_BUNDLE_BUILD_CONFIG_FILENAME};
+use common::{debug, info};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use url::Url;
+
+type Result<T> = miette::Result<T, BundleError>;
+
+const GIT: &str = "git";
+
+/*
+ * Bundles are small but may come over slow links
+ */
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const GIT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Name of the directory inside the staging directory the bundle gets unpacked to
+const STAGING_BUNDLE_DIR: &str = "bundle";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Guess the format from the file name of the archive
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::TarZst)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveFormat::Tar => write!(f, "tar"),
+            ArchiveFormat::TarGz => write!(f, "tar.gz"),
+            ArchiveFormat::TarZst => write!(f, "tar.zst"),
+        }
+    }
+}
+
+/// Where a build bundle comes from. Everything but a local directory gets unpacked
+/// into a staging directory before it is used.
+///
+/// Accepted forms are:
+/// - `path/to/bundle` a local directory
+/// - `path/to/bundle.tar`, `.tar.gz` or `.tar.zst` a local archive
+/// - `file:///path/to/bundle.tar.gz` or `https://example.com/bundle.tar.zst` optionally
+///   pinned with `#sha256=<hex>`
+/// - `git+https://example.com/bundle.git#ref` where ref is a branch, tag or commit
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BundleSource {
+    Directory(PathBuf),
+    Archive(PathBuf, ArchiveFormat),
+    Url {
+        url: Url,
+        format: Option<ArchiveFormat>,
+        sha256: Option<String>,
+    },
+    Git {
+        url: String,
+        reference: Option<String>,
+    },
+}
+
+impl FromStr for BundleSource {
+    type Err = BundleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(git_url) = s.strip_prefix("git+") {
+            let (url, reference) = match git_url.split_once('#') {
+                Some((url, reference)) if !reference.is_empty() => {
+                    check_git_reference(url, reference)?;
+                    (url, Some(reference.to_string()))
+                }
+                Some((url, _)) => (url, None),
+                None => (git_url, None),
+            };
+            return Ok(Self::Git {
+                url: url.to_string(),
+                reference,
+            });
+        }
+
+        if s.starts_with("file://") || s.starts_with("https://") || s.starts_with("http://") {
+            let mut url = Url::parse(s)?;
+            let sha256 = match url.fragment() {
+                Some(fragment) => Some(
+                    fragment
+                        .strip_prefix("sha256=")
+                        .ok_or(BundleError::InvalidSource(
+                            s.to_string(),
+                            String::from("the only supported fragment is #sha256=<hex>"),
+                        ))?
+                        .to_lowercase(),
+                ),
+                None => None,
+            };
+            url.set_fragment(None);
+
+            /*
+             * A file url pointing to a directory is just a local bundle
+             */
+            if url.scheme() == "file" {
+                let path = url.to_file_path().map_err(|_| {
+                    BundleError::InvalidSource(s.to_string(), String::from("not a local path"))
+                })?;
+                if path.is_dir() {
+                    return Ok(Self::Directory(path));
+                }
+            }
+
+            let format = ArchiveFormat::from_file_name(url.path());
+            return Ok(Self::Url {
+                url,
+                format,
+                sha256,
+            });
+        }
+
+        let path = PathBuf::from(s);
+        if path.is_dir() {
+            return Ok(Self::Directory(path));
+        }
+
+        match ArchiveFormat::from_file_name(s) {
+            Some(format) => Ok(Self::Archive(path, format)),
+            None => Err(BundleError::InvalidSource(
+                s.to_string(),
+                String::from("expected a directory or a .tar, .tar.gz or .tar.zst archive"),
+            )),
+        }
+    }
+}
+
+impl Display for BundleSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BundleSource::Directory(path) => write!(f, "{}", path.display()),
+            BundleSource::Archive(path, _) => write!(f, "{}", path.display()),
+            BundleSource::Url { url, .. } => write!(f, "{}", url),
+            BundleSource::Git { url, reference } => match reference {
+                Some(reference) => write!(f, "git+{}#{}", url, reference),
+                None => write!(f, "git+{}", url),
+            },
+        }
+    }
+}
+
+impl BundleSource {
+    pub fn kind(&self) -> BuildBundleKind {
+        match self {
+            BundleSource::Directory(_) => BuildBundleKind::Directory,
+            BundleSource::Archive(..) => BuildBundleKind::Archive,
+            BundleSource::Url { .. } => BuildBundleKind::Remote,
+            BundleSource::Git { .. } => BuildBundleKind::Git,
+        }
+    }
+
+    /// Make the bundle available as a directory. Local directories are used as they
+    /// are, everything else gets unpacked into staging_dir. Returns the directory
+    /// which contains build.kdl.
+    pub fn stage(&self, staging_dir: &Path) -> Result<PathBuf> {
+        let target = staging_dir.join(STAGING_BUNDLE_DIR);
+
+        match self {
+            BundleSource::Directory(path) => return Ok(path.clone()),
+            BundleSource::Archive(path, format) => {
+                unpack_archive(path, *format, &target)?;
+            }
+            BundleSource::Url {
+                url,
+                format,
+                sha256,
+            } => {
+                let format = format.ok_or(BundleError::InvalidSource(
+                    url.to_string(),
+                    String::from("can not tell the archive format from the url"),
+                ))?;
+
+                let archive = if url.scheme() == "file" {
+                    url.to_file_path().map_err(|_| {
+                        BundleError::InvalidSource(
+                            url.to_string(),
+                            String::from("not a local path"),
+                        )
+                    })?
+                } else {
+                    let archive = staging_dir.join(format!("download.{}", format));
+                    download(url, &archive)?;
+                    archive
+                };
+
+                if let Some(expected) = sha256 {
+                    verify_sha256(url.as_str(), &archive, expected)?;
+                } else {
+                    debug!("no checksum pinned for {}", url);
+                }
+
+                unpack_archive(&archive, format, &target)?;
+            }
+            BundleSource::Git { url, reference } => {
+                clone_git(url, reference.as_deref(), &target)?;
+            }
+        }
+
+        find_bundle_root(&target)
+    }
+}
+
+impl Bundle {
+    /// Stage the bundle from source into staging_dir and load it
+    pub fn from_source(source: &BundleSource, staging_dir: &Path) -> Result<Self> {
+        let path = source.stage(staging_dir)?;
+        let mut bundle = Bundle::new(&path)?;
+        bundle.kind = source.kind();
+        Ok(bundle)
+    }
+}
+
+fn unpack_archive(archive: &Path, format: ArchiveFormat, target: &Path) -> Result<()> {
+    info!(
+        "unpacking {} archive {} to {}",
+        format,
+        archive.display(),
+        target.display()
+    );
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match format {
+        ArchiveFormat::Tar => Box::new(file),
+        ArchiveFormat::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(zstd::stream::read::Decoder::new(file)?),
+    };
+
+    std::fs::create_dir_all(target)?;
+    /*
+     * unpack refuses entries which would end up outside of target
+     */
+    tar::Archive::new(reader).unpack(target)?;
+    Ok(())
+}
+
+fn download(url: &Url, target: &Path) -> Result<()> {
+    info!("downloading bundle {}", url);
+    let client = reqwest::blocking::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()?;
+    let mut response = client.get(url.clone()).send()?.error_for_status()?;
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(target)?;
+    response.copy_to(&mut file)?;
+    Ok(())
+}
+
+/// Hex encoded sha256 of a file
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn verify_sha256(name: &str, path: &Path, expected: &str) -> Result<()> {
+    let actual = sha256_file(path)?;
+    if actual != expected {
+        return Err(BundleError::ChecksumMismatch(
+            name.to_string(),
+            expected.to_string(),
+            actual,
+        ));
+    }
+    Ok(())
+}
+
+/*
+ * git takes anything starting with - as an option even after the positional
+ * arguments started, so a reference like --upload-pack=... has to be refused.
+ */
+fn check_git_reference(url: &str, reference: &str) -> Result<()> {
+    if reference.starts_with('-') {
+        return Err(BundleError::InvalidSource(
+            format!("git+{}#{}", url, reference),
+            String::from("git references may not start with -"),
+        ));
+    }
+    Ok(())
+}
+
+fn clone_git(url: &str, reference: Option<&str>, target: &Path) -> Result<()> {
+    info!("cloning bundle from {}", url);
+    let opts = crate::ExecOptions {
+        timeout: Some(GIT_TIMEOUT),
+        ..Default::default()
+    };
+    let target_str = target.to_string_lossy();
+    crate::execute_checked(&[GIT, "clone", "--quiet", "--", url, &target_str], &opts)?;
+
+    if let Some(reference) = reference {
+        check_git_reference(url, reference)?;
+
+        /*
+         * The clone only has local branches for the default branch. Fetching the
+         * reference by name also gets other branches, tags and commits the server
+         * hands out. Abbreviated commits can not be fetched, those are looked up in
+         * what the clone already has.
+         */
+        let checkout = match crate::execute_checked(
+            &[
+                GIT,
+                "-C",
+                &target_str,
+                "fetch",
+                "--quiet",
+                "origin",
+                "--",
+                reference,
+            ],
+            &opts,
+        ) {
+            Ok(_) => "FETCH_HEAD",
+            Err(err) => {
+                debug!("could not fetch {} from {}: {}", reference, url, err);
+                reference
+            }
+        };
+
+        crate::execute_checked(
+            &[
+                GIT,
+                "-C",
+                &target_str,
+                "checkout",
+                "--quiet",
+                "--detach",
+                checkout,
+                "--",
+            ],
+            &opts,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Archives often contain a single top level directory. Look for build.kdl there as well.
+fn find_bundle_root(dir: &Path) -> Result<PathBuf> {
+    if dir.join(BUILD_BUNDLE_BUILD_CONFIG_FILENAME).exists() {
+        return Ok(dir.to_path_buf());
+    }
+
+    let entries = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect::<Vec<PathBuf>>();
+
+    if let [single] = entries.as_slice() {
+        if single.join(BUILD_BUNDLE_BUILD_CONFIG_FILENAME).exists() {
+            return Ok(single.clone());
+        }
+    }
+
+    Err(BundleError::NoBuildConfig(dir.display().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sha256_file, ArchiveFormat, BundleSource};
+    use crate::build::bundle::{BuildBundleKind, Bundle, BundleError};
+    use crate::mock_http::MockServer;
+    use miette::{IntoDiagnostic, Result};
+    use std::{fs, io::Write, path::Path, str::FromStr};
+
+    const BUILD_KDL: &str =
+        "name \"test/remote\"\nfile \"/etc/motd\" {\n    content \"hello\"\n}\n";
+
+    /// Pack a bundle with a top level directory like `tar -czf bundle.tar.gz bundle/` would
+    fn pack(dir: &Path, format: ArchiveFormat) -> Result<Vec<u8>> {
+        let bundle = dir.join("src");
+        fs::create_dir_all(bundle.join("files")).into_diagnostic()?;
+        fs::write(bundle.join("build.kdl"), BUILD_KDL).into_diagnostic()?;
+        fs::write(bundle.join("files/motd"), "hello").into_diagnostic()?;
+
+        let mut builder = tar::Builder::new(vec![]);
+        builder
+            .append_dir_all("bundle", &bundle)
+            .into_diagnostic()?;
+        let tar = builder.into_inner().into_diagnostic()?;
+
+        Ok(match format {
+            ArchiveFormat::Tar => tar,
+            ArchiveFormat::TarGz => {
+                let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                enc.write_all(&tar).into_diagnostic()?;
+                enc.finish().into_diagnostic()?
+            }
+            ArchiveFormat::TarZst => {
+                zstd::stream::encode_all(tar.as_slice(), 0).into_diagnostic()?
+            }
+        })
+    }
+
+    #[test]
+    fn test_parse_source() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let dir_str = dir.path().to_string_lossy().to_string();
+
+        assert_eq!(
+            BundleSource::Directory(dir.path().to_path_buf()),
+            BundleSource::from_str(&dir_str)?
+        );
+        assert_eq!(
+            BundleSource::Directory(dir.path().to_path_buf()),
+            BundleSource::from_str(&format!("file://{}", dir_str))?
+        );
+        assert_eq!(
+            BundleSource::Archive("b.tar.zst".into(), ArchiveFormat::TarZst),
+            BundleSource::from_str("b.tar.zst")?
+        );
+        assert_eq!(
+            BundleSource::Url {
+                url: url::Url::parse("https://example.com/b.tgz").into_diagnostic()?,
+                format: Some(ArchiveFormat::TarGz),
+                sha256: Some("abcd".into()),
+            },
+            BundleSource::from_str("https://example.com/b.tgz#sha256=ABCD")?
+        );
+        assert_eq!(
+            BundleSource::Git {
+                url: "https://example.com/b.git".into(),
+                reference: Some("v1.0".into()),
+            },
+            BundleSource::from_str("git+https://example.com/b.git#v1.0")?
+        );
+        assert!(matches!(
+            BundleSource::from_str("git+https://example.com/b.git#--upload-pack=touch"),
+            Err(BundleError::InvalidSource(..))
+        ));
+        assert!(matches!(
+            BundleSource::from_str("bundle.zip"),
+            Err(BundleError::InvalidSource(..))
+        ));
+        assert!(matches!(
+            BundleSource::from_str("https://example.com/b.tar#md5=abcd"),
+            Err(BundleError::InvalidSource(..))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_archives() -> Result<()> {
+        for format in [
+            ArchiveFormat::Tar,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst,
+        ] {
+            let dir = tempfile::tempdir().into_diagnostic()?;
+            let archive = dir.path().join(format!("bundle.{}", format));
+            fs::write(&archive, pack(dir.path(), format)?).into_diagnostic()?;
+
+            let staging = dir.path().join("staging");
+            let source = BundleSource::from_str(&archive.to_string_lossy())?;
+            let bundle = Bundle::from_source(&source, &staging)?;
+
+            assert!(matches!(bundle.kind, BuildBundleKind::Archive));
+            assert_eq!("test/remote", bundle.document.name);
+            assert_eq!(staging.join("bundle/bundle"), bundle.get_path());
+            assert!(bundle.get_file("motd").is_ok());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_file_url_with_checksum() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let archive = dir.path().join("bundle.tar.gz");
+        fs::write(&archive, pack(dir.path(), ArchiveFormat::TarGz)?).into_diagnostic()?;
+        let sha256 = sha256_file(&archive)?;
+
+        let source =
+            BundleSource::from_str(&format!("file://{}#sha256={}", archive.display(), sha256))?;
+        let bundle = Bundle::from_source(&source, &dir.path().join("good"))?;
+        assert_eq!("test/remote", bundle.document.name);
+
+        let source = BundleSource::from_str(&format!(
+            "file://{}#sha256={}",
+            archive.display(),
+            "0".repeat(64)
+        ))?;
+        assert!(matches!(
+            Bundle::from_source(&source, &dir.path().join("bad")),
+            Err(BundleError::ChecksumMismatch(..))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_http_url() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let body = pack(dir.path(), ArchiveFormat::TarZst)?;
+
+        let server = MockServer::start(move |_| (200, vec![], body.clone()));
+
+        let source = BundleSource::from_str(&server.url("/bundle.tar.zst"))?;
+        let bundle = Bundle::from_source(&source, dir.path())?;
+        assert!(matches!(bundle.kind, BuildBundleKind::Remote));
+        assert_eq!("test/remote", bundle.document.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_git() -> Result<()> {
+        if std::process::Command::new("git")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            eprintln!("git not found in PATH, skipping");
+            return Ok(());
+        }
+
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo).into_diagnostic()?;
+        let git = |args: &[&str]| -> Result<()> {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .status()
+                .into_diagnostic()?;
+            assert!(status.success());
+            Ok(())
+        };
+
+        git(&["init", "--quiet"])?;
+        fs::write(repo.join("build.kdl"), BUILD_KDL).into_diagnostic()?;
+        git(&["add", "build.kdl"])?;
+        git(&["commit", "--quiet", "-m", "v1"])?;
+        git(&["tag", "v1"])?;
+        fs::write(repo.join("build.kdl"), "name \"test/changed\"\n").into_diagnostic()?;
+        git(&["commit", "--quiet", "-am", "v2"])?;
+        git(&["checkout", "--quiet", "-b", "next", "v1"])?;
+        fs::write(repo.join("build.kdl"), "name \"test/next\"\n").into_diagnostic()?;
+        git(&["commit", "--quiet", "-am", "next"])?;
+        git(&["checkout", "--quiet", "-"])?;
+
+        let rev_parse = |reference: &str| -> Result<String> {
+            let output = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args(["rev-parse", reference])
+                .output()
+                .into_diagnostic()?;
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        let next = rev_parse("next")?;
+        let head = rev_parse("HEAD")?;
+
+        /*
+         * A tag, a branch and a commit which only exist on the remote and an
+         * abbreviated commit of the default branch
+         */
+        for (index, (reference, name)) in [
+            ("v1", "test/remote"),
+            ("next", "test/next"),
+            (next.as_str(), "test/next"),
+            (&head[..8], "test/changed"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let source =
+                BundleSource::from_str(&format!("git+file://{}#{}", repo.display(), reference))?;
+            let bundle =
+                Bundle::from_source(&source, &dir.path().join(format!("staging{}", index)))?;
+            assert!(matches!(bundle.kind, BuildBundleKind::Git));
+            assert_eq!(name, bundle.document.name, "{}", reference);
+        }
+
+        Ok(())
+    }
+}
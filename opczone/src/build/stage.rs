@@ -0,0 +1,387 @@
+use super::bundle::{Bundle, BundleError, BUILD_BUNDLE_BUILD_CONFIG_FILENAME};
+use super::paths::{self, PathError};
+use crate::OPCZoneError;
+use common::{info, warn};
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+use miette::Diagnostic;
+use std::{
+    collections::HashMap,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+const ZFS: &str = "/usr/sbin/zfs";
+
+/// Top level nodes every stage inherits from the document
+const INHERITED_NODES: [&str; 3] = ["author", "version", "vars"];
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum StageError {
+    #[error("stage {0} is not defined in {1}")]
+    StageNotFound(String, String),
+    #[error("could not parse {0}: {1}")]
+    ParseError(String, String),
+    #[error("copy-from source {0} is neither a stage nor an installed image. Stages built before this one: {1}")]
+    SourceNotFound(String, String),
+    #[error("copy-from source {0} has nothing at {1}")]
+    SourcePathNotFound(String, String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    FsError(#[from] fs_extra::error::Error),
+    #[error(transparent)]
+    BundleError(#[from] BundleError),
+    #[error(transparent)]
+    PathError(#[from] PathError),
+    #[error(transparent)]
+    ExecError(#[from] OPCZoneError),
+    #[error(transparent)]
+    ImageError(#[from] crate::image::ImageError),
+}
+
+type Result<T> = miette::Result<T, StageError>;
+
+/// Where copy-from finds the files of a stage or image
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopySource {
+    /// Root of the filesystem of the stage or image in the global zone
+    pub root: PathBuf,
+    /// Changes whenever the content of the source changes. Used for the build cache
+    pub identity: String,
+}
+
+/// Copy sources by the name copy-from refers to them with
+pub type CopySources = HashMap<String, CopySource>;
+
+/// Turn a stage of bundle into a bundle of its own in target_dir. The stage shares
+/// files and templates with the bundle and inherits author, version and vars. Its
+/// base-on and actions are the ones declared in the stage.
+pub fn stage_bundle(bundle: &Bundle, stage: &str, target_dir: &Path) -> Result<Bundle> {
+    let config_path = bundle.get_path().join(BUILD_BUNDLE_BUILD_CONFIG_FILENAME);
+    let text = std::fs::read_to_string(&config_path)?;
+    let doc: KdlDocument = text.parse().map_err(|e: kdl::KdlError| {
+        StageError::ParseError(config_path.display().to_string(), e.to_string())
+    })?;
+
+    let stage_node = doc
+        .nodes()
+        .iter()
+        .find(|n| {
+            n.name().value() == "stage"
+                && n.entries()
+                    .first()
+                    .and_then(|e| e.value().as_string())
+                    .is_some_and(|name| name == stage)
+        })
+        .ok_or(StageError::StageNotFound(
+            stage.to_string(),
+            config_path.display().to_string(),
+        ))?;
+
+    let mut stage_doc = KdlDocument::new();
+    let mut name_node = KdlNode::new("name");
+    name_node.push(KdlEntry::new(format!("{}-{}", bundle.document.name, stage)));
+    stage_doc.nodes_mut().push(name_node);
+
+    for node in doc.nodes() {
+        if INHERITED_NODES.contains(&node.name().value()) {
+            stage_doc.nodes_mut().push(node.clone());
+        }
+    }
+
+    if let Some(children) = stage_node.children() {
+        for node in children.nodes() {
+            stage_doc.nodes_mut().push(node.clone());
+        }
+    }
+
+    /*
+     * Nodes taken out of the stage keep their indentation, reformat so the
+     * generated build.kdl is readable when debugging a build.
+     */
+    stage_doc.fmt();
+
+    std::fs::create_dir_all(target_dir)?;
+    let options = fs_extra::dir::CopyOptions {
+        overwrite: true,
+        content_only: true,
+        ..Default::default()
+    };
+    fs_extra::dir::copy(bundle.get_path(), target_dir, &options)?;
+    std::fs::write(
+        target_dir.join(BUILD_BUNDLE_BUILD_CONFIG_FILENAME),
+        stage_doc.to_string(),
+    )?;
+
+    let mut stage_bundle = Bundle::new(target_dir)?;
+    stage_bundle.build_vars = bundle.build_vars.clone();
+    Ok(stage_bundle)
+}
+
+/// The copy source of an installed image. Files are read from the final snapshot
+/// of the image so running zones of the image don't change what gets copied.
+pub fn image_copy_source(
+    name: &str,
+    parent_dataset: &str,
+    stages: &CopySources,
+) -> Result<CopySource> {
+    let image_uuid = crate::image::find_image_by_name(name)?.ok_or_else(|| {
+        let mut known: Vec<&str> = stages.keys().map(|k| k.as_str()).collect();
+        known.sort();
+        StageError::SourceNotFound(name.to_string(), known.join(", "))
+    })?;
+
+    let root_dataset = format!("{}/{}/root", parent_dataset, image_uuid.hyphenated());
+    let mountpoint = crate::run_capture_stdout(
+        &[ZFS, "get", "-H", "-o", "value", "mountpoint", &root_dataset],
+        None,
+    )?;
+
+    Ok(CopySource {
+        root: Path::new(mountpoint.trim()).join(".zfs/snapshot/final"),
+        identity: image_uuid.hyphenated().to_string(),
+    })
+}
+
+/// Copy src from the root of a stage or image to dst in target_root. Directories are
+/// copied with their content. Ownership, modes and symlinks are kept as they are.
+pub fn copy_from(source: &CopySource, src: &Path, target_root: &Path, dst: &Path) -> Result<()> {
+    let source_path = paths::resolve(&source.root, src)?;
+    let meta = match std::fs::symlink_metadata(&source_path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(StageError::SourcePathNotFound(
+                source.root.display().to_string(),
+                src.display().to_string(),
+            ))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    info!(
+        "Copying {} -> {}",
+        source_path.display(),
+        target_root
+            .join(dst.strip_prefix("/").unwrap_or(dst))
+            .display()
+    );
+
+    /*
+     * A file copied onto an existing directory ends up inside of it, like cp does.
+     */
+    let dst = if !meta.is_dir() && paths::resolve(target_root, dst)?.is_dir() {
+        match src.file_name() {
+            Some(name) => dst.join(name),
+            None => dst.to_path_buf(),
+        }
+    } else {
+        dst.to_path_buf()
+    };
+
+    copy_entry(&source_path, &meta, target_root, &dst)
+}
+
+/*
+ * The copy runs in the global zone but the target is the root of a zone which
+ * earlier actions could fill with symlinks. Every entry is resolved inside
+ * target_root on its own so a link like /opt/app/conf -> /etc points into the
+ * zone and never at the global zone.
+ */
+fn copy_entry(
+    source_path: &Path,
+    meta: &std::fs::Metadata,
+    target_root: &Path,
+    dst: &Path,
+) -> Result<()> {
+    let file_type = meta.file_type();
+
+    if file_type.is_dir() {
+        let target_path = paths::resolve(target_root, dst)?;
+        std::fs::create_dir_all(&target_path)?;
+
+        for entry in std::fs::read_dir(source_path)? {
+            let entry = entry?;
+            copy_entry(
+                &entry.path(),
+                &entry.metadata()?,
+                target_root,
+                &dst.join(entry.file_name()),
+            )?;
+        }
+
+        // Set the modes last so read-only directories can still be filled
+        copy_attributes(meta, &target_path)?;
+        return Ok(());
+    }
+
+    let target_path = paths::resolve_parent(target_root, dst)?;
+    if let Some(parent) = target_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    remove_existing(&target_path)?;
+
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(source_path)?, &target_path)?;
+        lchown(meta, &target_path)?;
+    } else if file_type.is_file() {
+        std::fs::copy(source_path, &target_path)?;
+        copy_attributes(meta, &target_path)?;
+    } else {
+        warn!(
+            "Skipping {}, only files, directories and symlinks are copied",
+            source_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Make room for a file or symlink. Writing through an existing symlink would
+/// change whatever it points at. Directories are left alone and fail the copy.
+fn remove_existing(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => Ok(std::fs::remove_file(path)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/*
+ * chown clears setuid bits, so the mode comes after the owner. The handle for the
+ * times is opened before the mode can take our own read access away.
+ */
+fn copy_attributes(meta: &std::fs::Metadata, path: &Path) -> Result<()> {
+    lchown(meta, path)?;
+    let handle = std::fs::File::open(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(meta.mode()))?;
+    handle.set_modified(meta.modified()?)?;
+    Ok(())
+}
+
+fn lchown(meta: &std::fs::Metadata, path: &Path) -> Result<()> {
+    match std::os::unix::fs::lchown(path, Some(meta.uid()), Some(meta.gid())) {
+        // Like cp -p, keep going with our own ownership when we may not change it
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_from, stage_bundle, CopySource};
+    use crate::build::{bundle::Bundle, Action};
+    use miette::{IntoDiagnostic, Result};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+
+    #[test]
+    fn test_stage_bundle() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let source = dir.path().join("bundle");
+        std::fs::create_dir_all(source.join("files")).into_diagnostic()?;
+        std::fs::write(source.join("files/main.go"), "package main").into_diagnostic()?;
+        std::fs::write(
+            source.join("build.kdl"),
+            r#"name "myapp"
+author "John Doe"
+base-on "zones/base"
+vars {
+    version "1.0"
+}
+
+stage "builder" {
+    base-on "zones/devel"
+    ips {
+        install-packages "golang"
+    }
+    file "/src/main.go" {
+        src "main.go"
+    }
+}
+
+copy-from "builder" "/src/myapp" "/opt/myapp/bin/myapp"
+"#,
+        )
+        .into_diagnostic()?;
+
+        let bundle = Bundle::new(&source)?;
+        assert_eq!(1, bundle.document.stages.len());
+        assert_eq!("builder", bundle.document.stages[0].name);
+        assert_eq!(1, bundle.document.actions.len());
+        assert!(matches!(bundle.document.actions[0], Action::CopyFrom(..)));
+
+        let stage = stage_bundle(&bundle, "builder", &dir.path().join("stage"))?;
+        assert_eq!("myapp-builder", stage.document.name);
+        assert_eq!(Some("zones/devel".into()), stage.document.base_on);
+        assert_eq!(Some("John Doe".into()), stage.document.author);
+        assert_eq!("version", stage.document.vars.vars[0].name);
+        assert_eq!(2, stage.document.actions.len());
+        assert!(stage.get_file("main.go").is_ok());
+
+        assert!(stage_bundle(&bundle, "missing", &dir.path().join("missing")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_from_stays_in_target_root() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let source = CopySource {
+            root: dir.path().join("source"),
+            identity: "builder".into(),
+        };
+        let app = source.root.join("build/app");
+        std::fs::create_dir_all(app.join("conf")).into_diagnostic()?;
+        std::fs::write(app.join("conf/app.conf"), "port 80").into_diagnostic()?;
+        std::fs::write(app.join("app"), "#!/bin/sh").into_diagnostic()?;
+        std::fs::set_permissions(app.join("app"), std::fs::Permissions::from_mode(0o755))
+            .into_diagnostic()?;
+        symlink("app", app.join("current")).into_diagnostic()?;
+
+        // An earlier action left an absolute link in the destination
+        let target = dir.path().join("target");
+        std::fs::create_dir_all(target.join("opt/app")).into_diagnostic()?;
+        symlink("/srv/conf", target.join("opt/app/conf")).into_diagnostic()?;
+        std::fs::write(target.join("opt/app/current"), "old").into_diagnostic()?;
+
+        copy_from(
+            &source,
+            Path::new("/build/app"),
+            &target,
+            Path::new("/opt/app"),
+        )?;
+
+        assert_eq!(
+            "port 80",
+            std::fs::read_to_string(target.join("srv/conf/app.conf")).into_diagnostic()?
+        );
+        assert!(!Path::new("/srv/conf/app.conf").exists());
+        assert_eq!(
+            0o755,
+            std::fs::metadata(target.join("opt/app/app"))
+                .into_diagnostic()?
+                .permissions()
+                .mode()
+                & 0o777
+        );
+        assert_eq!(
+            Path::new("app"),
+            std::fs::read_link(target.join("opt/app/current")).into_diagnostic()?
+        );
+
+        // Single files land inside existing directories
+        copy_from(
+            &source,
+            Path::new("/build/app/app"),
+            &target,
+            Path::new("/srv"),
+        )?;
+        assert!(target.join("srv/app").is_file());
+
+        assert!(copy_from(&source, Path::new("/missing"), &target, Path::new("/opt")).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,605 @@
+use super::{paths, BResult, BuildError, Group, User, ROOT};
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+};
+
+pub(super) const PASSWD: &str = "etc/passwd";
+pub(super) const SHADOW: &str = "etc/shadow";
+pub(super) const GROUP: &str = "etc/group";
+
+const PASSWD_FIELDS: usize = 7;
+const SHADOW_FIELDS: usize = 9;
+const GROUP_FIELDS: usize = 4;
+
+/*
+ * Like useradd(8) and groupadd(8) we hand out ids starting at 100. Ids above
+ * 60000 are reserved for nobody, noaccess and nobody4.
+ */
+const FIRST_FREE_ID: u32 = 100;
+const LAST_FREE_ID: u32 = 60000;
+
+/*
+ * Defaults for new users. The primary group is staff like with useradd(8)
+ */
+const DEFAULT_GID: u32 = 10;
+const DEFAULT_SHELL: &str = "/bin/sh";
+pub(super) const DEFAULT_HOME_BASE: &str = "/home";
+
+/*
+ * Shadow password for new users without password. The account is locked.
+ */
+const LOCKED_PASSWORD: &str = "*LK*";
+
+/// The resolved passwd entry of a user after it has been ensured
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserEntry {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum DatabaseLine {
+    Entry(Vec<String>),
+    /// Comments, NIS compat entries and lines we don't understand are kept as they are
+    Other(String),
+}
+
+/// One of the colon separated user databases in /etc
+#[derive(Debug, Clone)]
+struct DatabaseFile {
+    path: PathBuf,
+    content: String,
+    lines: Vec<DatabaseLine>,
+}
+
+impl DatabaseFile {
+    fn load(root: &Path, name: &str, fields: usize) -> BResult<Self> {
+        let path = root.join(name);
+        let content = fs::read_to_string(&path)?;
+
+        let lines = content
+            .lines()
+            .map(|line| {
+                let entry: Vec<String> = line.split(':').map(String::from).collect();
+                if line.starts_with(['#', '+', '-']) || entry.len() != fields {
+                    DatabaseLine::Other(line.to_string())
+                } else {
+                    DatabaseLine::Entry(entry)
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            path,
+            content,
+            lines,
+        })
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &Vec<String>> {
+        self.lines.iter().filter_map(|line| match line {
+            DatabaseLine::Entry(entry) => Some(entry),
+            DatabaseLine::Other(_) => None,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<&Vec<String>> {
+        self.entries().find(|entry| entry[0] == name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Vec<String>> {
+        self.lines.iter_mut().find_map(|line| match line {
+            DatabaseLine::Entry(entry) if entry[0] == name => Some(entry),
+            _ => None,
+        })
+    }
+
+    fn push(&mut self, entry: Vec<String>) {
+        self.lines.push(DatabaseLine::Entry(entry));
+    }
+
+    /// Name of the entry which has id in field idx
+    fn id_owner(&self, id: u32, idx: usize) -> Option<&str> {
+        let id = id.to_string();
+        self.entries()
+            .find(|entry| entry[idx] == id)
+            .map(|entry| entry[0].as_str())
+    }
+
+    /// Make sure no other entry uses the id
+    fn check_id(&self, kind: &str, id: u32, idx: usize, name: &str) -> BResult<()> {
+        match self.id_owner(id, idx) {
+            Some(owner) if owner != name => Err(BuildError::IdAlreadyUsed(
+                kind.to_string(),
+                id,
+                owner.to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn next_free_id(&self, idx: usize) -> BResult<u32> {
+        let used: HashSet<u32> = self
+            .entries()
+            .filter_map(|entry| entry[idx].parse().ok())
+            .collect();
+
+        (FIRST_FREE_ID..LAST_FREE_ID)
+            .find(|id| !used.contains(id))
+            .ok_or(BuildError::NoFreeId(self.path.display().to_string()))
+    }
+
+    fn render(&self) -> String {
+        let mut content = self
+            .lines
+            .iter()
+            .map(|line| match line {
+                DatabaseLine::Entry(entry) => entry.join(":"),
+                DatabaseLine::Other(line) => line.clone(),
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        content.push('\n');
+        content
+    }
+
+    /// Write the file back if anything changed. Writing into the existing file keeps its mode.
+    fn save(&self) -> BResult<bool> {
+        let content = self.render();
+        if content == self.content {
+            return Ok(false);
+        }
+
+        fs::write(&self.path, content)?;
+        Ok(true)
+    }
+}
+
+fn add_members(entry: &mut [String], members: &[String]) {
+    let mut current: Vec<String> = entry[3]
+        .split(',')
+        .filter(|m| !m.is_empty())
+        .map(String::from)
+        .collect();
+
+    for member in members {
+        if !current.contains(member) {
+            current.push(member.clone());
+        }
+    }
+
+    entry[3] = current.join(",");
+}
+
+/// Ensure the group exists in /etc/group of the image at root. Existing groups get
+/// the gid if one is given and the members added. Returns the gid of the group.
+pub fn ensure_group(root: &Path, group: &Group) -> BResult<u32> {
+    let mut db = DatabaseFile::load(root, GROUP, GROUP_FIELDS)?;
+
+    if let Some(gid) = group.gid {
+        db.check_id("gid", gid, 2, &group.name)?;
+    }
+
+    let gid = if let Some(entry) = db.get_mut(&group.name) {
+        if let Some(gid) = group.gid {
+            entry[2] = gid.to_string();
+        }
+        add_members(entry, &group.members);
+        entry[2].parse()?
+    } else {
+        let gid = match group.gid {
+            Some(gid) => gid,
+            None => db.next_free_id(2)?,
+        };
+        db.push(vec![
+            group.name.clone(),
+            String::new(),
+            gid.to_string(),
+            group.members.join(","),
+        ]);
+        gid
+    };
+
+    if db.save()? {
+        common::info!("updated group {}", group.name);
+    } else {
+        common::info!("group {} already up to date", group.name);
+    }
+
+    Ok(gid)
+}
+
+/// Ensure the user exists in /etc/passwd and /etc/shadow of the image at root and
+/// is a member of its supplementary groups. Fields of existing users are only
+/// changed when they are given.
+pub fn ensure_user(root: &Path, user: &User) -> BResult<UserEntry> {
+    let mut passwd = DatabaseFile::load(root, PASSWD, PASSWD_FIELDS)?;
+    let mut shadow = DatabaseFile::load(root, SHADOW, SHADOW_FIELDS)?;
+    let mut groups = DatabaseFile::load(root, GROUP, GROUP_FIELDS)?;
+
+    if let Some(uid) = user.uid {
+        passwd.check_id("uid", uid, 2, &user.name)?;
+    }
+
+    if let Some(entry) = passwd.get_mut(&user.name) {
+        if let Some(uid) = user.uid {
+            entry[2] = uid.to_string();
+        }
+        if let Some(gid) = user.gid {
+            entry[3] = gid.to_string();
+        }
+        if let Some(gecos) = &user.gecos {
+            entry[4] = gecos.clone();
+        }
+        if let Some(home) = &user.home {
+            entry[5] = home.clone();
+        }
+        if let Some(shell) = &user.shell {
+            entry[6] = shell.clone();
+        }
+    } else {
+        let uid = match user.uid {
+            Some(uid) => uid,
+            None => passwd.next_free_id(2)?,
+        };
+        passwd.push(vec![
+            user.name.clone(),
+            String::from("x"),
+            uid.to_string(),
+            user.gid.unwrap_or(DEFAULT_GID).to_string(),
+            user.gecos.clone().unwrap_or_default(),
+            user.home
+                .clone()
+                .unwrap_or(format!("{}/{}", DEFAULT_HOME_BASE, user.name)),
+            user.shell.clone().unwrap_or(DEFAULT_SHELL.to_string()),
+        ]);
+    }
+
+    if let Some(entry) = shadow.get_mut(&user.name) {
+        if let Some(password) = &user.password {
+            entry[1] = password.clone();
+        }
+    } else {
+        let mut entry = vec![String::new(); SHADOW_FIELDS];
+        entry[0] = user.name.clone();
+        entry[1] = user.password.clone().unwrap_or(LOCKED_PASSWORD.to_string());
+        shadow.push(entry);
+    }
+
+    for group_name in &user.groups {
+        let entry = groups.get_mut(group_name).ok_or(BuildError::GroupNotFound(
+            user.name.clone(),
+            group_name.clone(),
+        ))?;
+        add_members(entry, std::slice::from_ref(&user.name));
+    }
+
+    let entry = passwd.get(&user.name).expect("user entry was just ensured");
+    let user_entry = UserEntry {
+        name: user.name.clone(),
+        uid: entry[2].parse()?,
+        gid: entry[3].parse()?,
+        home: entry[5].clone(),
+    };
+
+    let mut changed = passwd.save()?;
+    changed |= shadow.save()?;
+    changed |= groups.save()?;
+
+    if changed {
+        common::info!("updated user {}", user.name);
+    } else {
+        common::info!("user {} already up to date", user.name);
+    }
+
+    Ok(user_entry)
+}
+
+/// The passwd entry of a user in the image at root. None if the user does not exist
+pub fn get_user(root: &Path, name: &str) -> BResult<Option<UserEntry>> {
+    let passwd = DatabaseFile::load(root, PASSWD, PASSWD_FIELDS)?;
+    let entry = match passwd.get(name) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    Ok(Some(UserEntry {
+        name: name.to_string(),
+        uid: entry[2].parse()?,
+        gid: entry[3].parse()?,
+        home: entry[5].clone(),
+    }))
+}
+
+/// The gid of a group in the image at root. None if the group does not exist
+pub fn get_group(root: &Path, name: &str) -> BResult<Option<u32>> {
+    let db = DatabaseFile::load(root, GROUP, GROUP_FIELDS)?;
+    match db.get(name) {
+        Some(entry) => Ok(Some(entry[2].parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Create the home directory of the user if it is missing and install the
+/// authorized_keys file if keys are given.
+pub fn ensure_home(root: &Path, user: &UserEntry, authorized_keys: &[String]) -> BResult<()> {
+    let home = paths::resolve(root, &user.home)?;
+
+    /*
+     * Existing homes like /root or / are left alone. We only own what we create.
+     */
+    if !home.exists() {
+        /*
+         * Parents like /export/home do not exist in every image. They belong to
+         * root like the rest of the image, only the home itself goes to the user.
+         */
+        if let Some(parent) = home.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(parent)?;
+        }
+        illumos_image_builder::ensure::directory(&home, user.uid, user.gid, 0o755)?;
+    }
+
+    if authorized_keys.is_empty() {
+        return Ok(());
+    }
+
+    let ssh_dir = home.join(".ssh");
+    illumos_image_builder::ensure::directory(&ssh_dir, user.uid, user.gid, 0o700)?;
+
+    let mut keys = authorized_keys.join("\n");
+    keys.push('\n');
+    illumos_image_builder::ensure::filestr(
+        &keys,
+        &ssh_dir.join("authorized_keys"),
+        user.uid,
+        user.gid,
+        0o600,
+        illumos_image_builder::ensure::Create::Always,
+    )?;
+
+    Ok(())
+}
+
+/// Restore the ownership and mode the shadow file needs after editing
+pub fn ensure_shadow_perms(root: &Path) -> BResult<()> {
+    illumos_image_builder::ensure::perms(&root.join(SHADOW), ROOT, ROOT, 0o400)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure_group, ensure_home, ensure_user, UserEntry};
+    use crate::build::{BuildError, Group, User};
+    use miette::{IntoDiagnostic, Result};
+    use std::{
+        fs,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+    };
+    use tempfile::TempDir;
+
+    const PASSWD: &str = "\
+root:x:0:0:Super-User:/root:/usr/bin/bash
+daemon:x:1:1::/:
+nobody:x:60001:60001:NFS Anonymous Access User:/:
+";
+    const SHADOW: &str = "\
+root:$5$rounds=10000$abc$def:6445::::::
+daemon:NP:6445::::::
+nobody:*LK*:6445::::::
+";
+    const GROUP: &str = "\
+root::0:
+other::1:root
+staff::10:
+nobody::60001:
+";
+
+    fn image_root() -> Result<TempDir> {
+        let root = tempfile::tempdir().into_diagnostic()?;
+        fs::create_dir(root.path().join("etc")).into_diagnostic()?;
+        fs::write(root.path().join("etc/passwd"), PASSWD).into_diagnostic()?;
+        fs::write(root.path().join("etc/shadow"), SHADOW).into_diagnostic()?;
+        fs::write(root.path().join("etc/group"), GROUP).into_diagnostic()?;
+        Ok(root)
+    }
+
+    fn read(root: &TempDir, name: &str) -> Result<String> {
+        fs::read_to_string(root.path().join(name)).into_diagnostic()
+    }
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.into(),
+            password: None,
+            uid: None,
+            gid: None,
+            home: None,
+            shell: None,
+            gecos: None,
+            groups: vec![],
+            authorized_keys: vec![],
+        }
+    }
+
+    #[test]
+    fn test_ensure_group() -> Result<()> {
+        let root = image_root()?;
+
+        let web = Group {
+            name: "web".into(),
+            gid: None,
+            members: vec!["daemon".into()],
+        };
+        assert_eq!(100, ensure_group(root.path(), &web)?);
+        assert_eq!(100, ensure_group(root.path(), &web)?);
+
+        let other = Group {
+            name: "other".into(),
+            gid: None,
+            members: vec!["root".into(), "daemon".into()],
+        };
+        assert_eq!(1, ensure_group(root.path(), &other)?);
+
+        let db = Group {
+            name: "db".into(),
+            gid: Some(500),
+            members: vec![],
+        };
+        assert_eq!(500, ensure_group(root.path(), &db)?);
+
+        assert_eq!(
+            "root::0:\nother::1:root,daemon\nstaff::10:\nnobody::60001:\nweb::100:daemon\ndb::500:\n",
+            read(&root, "etc/group")?
+        );
+
+        let conflict = Group {
+            name: "conflict".into(),
+            gid: Some(10),
+            members: vec![],
+        };
+        assert!(matches!(
+            ensure_group(root.path(), &conflict),
+            Err(BuildError::IdAlreadyUsed(_, 10, _))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_user() -> Result<()> {
+        let root = image_root()?;
+
+        let myapp = User {
+            uid: Some(500),
+            home: Some("/var/lib/myapp".into()),
+            shell: Some("/usr/bin/bash".into()),
+            gecos: Some("My Application".into()),
+            groups: vec!["other".into()],
+            ..user("myapp")
+        };
+        let expected = UserEntry {
+            name: "myapp".into(),
+            uid: 500,
+            gid: 10,
+            home: "/var/lib/myapp".into(),
+        };
+        assert_eq!(expected, ensure_user(root.path(), &myapp)?);
+
+        let passwd = read(&root, "etc/passwd")?;
+        let shadow = read(&root, "etc/shadow")?;
+        let group = read(&root, "etc/group")?;
+        assert!(passwd.ends_with("myapp:x:500:10:My Application:/var/lib/myapp:/usr/bin/bash\n"));
+        assert!(shadow.ends_with("myapp:*LK*:::::::\n"));
+        assert!(group.contains("other::1:root,myapp\n"));
+
+        // Running the same action again must not change anything
+        assert_eq!(expected, ensure_user(root.path(), &myapp)?);
+        assert_eq!(passwd, read(&root, "etc/passwd")?);
+        assert_eq!(shadow, read(&root, "etc/shadow")?);
+        assert_eq!(group, read(&root, "etc/group")?);
+
+        // New users without uid get the next free one and the defaults
+        let worker = ensure_user(root.path(), &user("worker"))?;
+        assert_eq!(100, worker.uid);
+        assert_eq!("/home/worker", worker.home);
+
+        // Existing users only get the password changed
+        let root_user = User {
+            password: Some("NP".into()),
+            ..user("root")
+        };
+        ensure_user(root.path(), &root_user)?;
+        assert!(read(&root, "etc/shadow")?.starts_with("root:NP:6445::::::\n"));
+        assert!(
+            read(&root, "etc/passwd")?.starts_with("root:x:0:0:Super-User:/root:/usr/bin/bash\n")
+        );
+
+        let missing_group = User {
+            groups: vec!["nonexistent".into()],
+            ..user("other")
+        };
+        assert!(matches!(
+            ensure_user(root.path(), &missing_group),
+            Err(BuildError::GroupNotFound(_, _))
+        ));
+
+        let conflict = User {
+            uid: Some(0),
+            ..user("toor")
+        };
+        assert!(matches!(
+            ensure_user(root.path(), &conflict),
+            Err(BuildError::IdAlreadyUsed(_, 0, _))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_home() -> Result<()> {
+        let root = image_root()?;
+
+        /*
+         * Owned by whoever runs the test so it does not need to run as root.
+         */
+        let owner = fs::metadata(root.path()).into_diagnostic()?;
+        let alice = UserEntry {
+            name: "alice".into(),
+            uid: owner.uid(),
+            gid: owner.gid(),
+            home: "/export/home/alice".into(),
+        };
+        let keys = vec![
+            "ssh-ed25519 AAAA... alice@example.com".to_string(),
+            "ssh-ed25519 BBBB... alice@laptop".to_string(),
+        ];
+        ensure_home(root.path(), &alice, &keys)?;
+
+        let home = root.path().join("export/home/alice");
+        for (path, mode) in [
+            (home.clone(), 0o755),
+            (home.join(".ssh"), 0o700),
+            (home.join(".ssh/authorized_keys"), 0o600),
+        ] {
+            let meta = fs::metadata(&path).into_diagnostic()?;
+            assert_eq!(
+                mode,
+                meta.permissions().mode() & 0o777,
+                "{}",
+                path.display()
+            );
+            assert_eq!(alice.uid, meta.uid());
+            assert_eq!(alice.gid, meta.gid());
+        }
+        assert_eq!(
+            "ssh-ed25519 AAAA... alice@example.com\nssh-ed25519 BBBB... alice@laptop\n",
+            read(&root, "export/home/alice/.ssh/authorized_keys")?
+        );
+
+        // Running it again replaces the keys
+        ensure_home(root.path(), &alice, &keys[..1])?;
+        assert_eq!(
+            "ssh-ed25519 AAAA... alice@example.com\n",
+            read(&root, "export/home/alice/.ssh/authorized_keys")?
+        );
+
+        let escape = UserEntry {
+            home: "/../alice".into(),
+            ..alice
+        };
+        assert!(matches!(
+            ensure_home(root.path(), &escape, &[]),
+            Err(BuildError::PathError(_))
+        ));
+
+        Ok(())
+    }
+}
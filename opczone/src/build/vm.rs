@@ -0,0 +1,643 @@
+use super::bundle::{Bundle, BundleError};
+use super::{
+    render_template, Action, BuildError, CommonPerms, IpsActions, VMDiskKind, VMImageSpec,
+};
+use crate::{OPCZoneError, UtilError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::info;
+use miette::Diagnostic;
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use zone::ZoneError;
+
+const ZFS: &str = "/usr/sbin/zfs";
+const ZONECFG: &str = "/usr/sbin/zonecfg";
+const MKISOFS: &str = "/usr/bin/mkisofs";
+const DD: &str = "/usr/bin/dd";
+
+/*
+ * Writing a cloud image to a fresh zvol is limited by the disk. Give slow
+ * pools enough time for images of a few GB.
+ */
+const DISK_WRITE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const HALT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Size of disks which do not set one. Enough for the usual cloud images
+const DEFAULT_DISK_SIZE: &str = "10G";
+
+/// Files the actions need inside the guest get written here before runcmd runs
+const GUEST_STAGING_DIR: &str = "/var/tmp/imgbuild";
+/// Written by the last command of runcmd, the guest only powers off if it exists
+const GUEST_DONE_MARKER: &str = "/var/tmp/imgbuild.done";
+const DEFAULT_HOME_BASE: &str = "/home";
+
+pub const SEED_ISO_FILENAME: &str = "seed.iso";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum VmError {
+    #[error("{0} can not be applied to vm images yet")]
+    NotSupported(String),
+    #[error("vm images need a vm-specs node")]
+    NoVmSpecs,
+    #[error("vm-specs must define at least one disk")]
+    NoDisks,
+    #[error("vm-specs must define a boot-image, the vm has nothing to boot without one")]
+    NoBootImage,
+    #[error("boot image {0} does not exist")]
+    BootImageNotFound(String),
+    #[error("{0} disks are not supported by bhyve, use virtio")]
+    UnsupportedDiskKind(String),
+    #[error("boot image {0} is {1} bytes but disk {2} only has {3}")]
+    DiskTooSmall(String, u64, String, u64),
+    #[error("vm {0} did not power off within {1} seconds, it keeps running if an action failed")]
+    Timeout(String, u64),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    YamlError(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    BundleError(#[from] BundleError),
+    #[error(transparent)]
+    BuildError(#[from] Box<BuildError>),
+    #[error(transparent)]
+    ExecError(#[from] OPCZoneError),
+    #[error(transparent)]
+    UtilError(#[from] UtilError),
+    #[error(transparent)]
+    ZoneError(#[from] ZoneError),
+}
+
+impl From<BuildError> for VmError {
+    fn from(err: BuildError) -> Self {
+        Self::BuildError(Box::new(err))
+    }
+}
+
+type Result<T> = miette::Result<T, VmError>;
+
+/// The cloud-config cloud-init applies inside the guest
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct UserData {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub write_files: Vec<WriteFile>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub runcmd: Vec<Vec<String>>,
+    pub power_state: PowerState,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct WriteFile {
+    pub path: String,
+    pub content: String,
+    pub encoding: String,
+    pub permissions: String,
+}
+
+/// imgbuild waits for the vm to power off to know the actions ran. The condition only
+/// holds if all of them succeeded, a vm with failed actions keeps running.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PowerState {
+    pub mode: String,
+    pub message: String,
+    pub condition: Vec<String>,
+}
+
+impl Default for PowerState {
+    fn default() -> Self {
+        Self {
+            mode: String::from("poweroff"),
+            message: String::from("imgbuild actions applied"),
+            condition: cmd(&["rm", GUEST_DONE_MARKER]),
+        }
+    }
+}
+
+fn cmd(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+impl UserData {
+    /// The user-data document cloud-init reads
+    pub fn cloud_config(&self) -> Result<String> {
+        let content = serde_yaml::to_string(&self)?;
+        Ok(format!("#cloud-config\n{}", content))
+    }
+
+    /// Place content in the guest and return the path it ends up at
+    fn stage(&mut self, step: usize, name: &str, content: &[u8]) -> String {
+        let path = format!("{}/{}-{}", GUEST_STAGING_DIR, step, name);
+        self.write_files.push(WriteFile {
+            path: path.clone(),
+            content: STANDARD.encode(content),
+            encoding: String::from("b64"),
+            permissions: String::from("0600"),
+        });
+        path
+    }
+
+    fn perms(&mut self, path: &str, perms: &CommonPerms) {
+        if let Some(mode) = perms.mode {
+            self.runcmd
+                .push(cmd(&["chmod", &format!("{:o}", mode), path]));
+        }
+        match (&perms.owner, &perms.group) {
+            (Some(owner), Some(group)) => {
+                self.runcmd
+                    .push(cmd(&["chown", "-h", &format!("{}:{}", owner, group), path]))
+            }
+            (Some(owner), None) => self.runcmd.push(cmd(&["chown", "-h", owner, path])),
+            (None, Some(group)) => self.runcmd.push(cmd(&["chgrp", "-h", group, path])),
+            (None, None) => {}
+        }
+    }
+
+    fn install_file(&mut self, step: usize, path: &Path, content: &[u8], perms: &CommonPerms) {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let staged = self.stage(step, &name, content);
+        let target = path.to_string_lossy().to_string();
+        if let Some(parent) = path.parent() {
+            self.runcmd
+                .push(cmd(&["mkdir", "-p", &parent.to_string_lossy()]));
+        }
+        self.runcmd.push(cmd(&["cp", &staged, &target]));
+        self.perms(&target, perms);
+    }
+}
+
+/// Translate the actions of bundle into the cloud-config applying them in the guest.
+/// Packages are installed by cloud-init before the other actions run in their order.
+pub fn user_data(bundle: &Bundle) -> Result<UserData> {
+    let mut data = UserData::default();
+    // runcmd is one shell script, without this it goes on after a failed command
+    data.runcmd.push(cmd(&["set", "-e"]));
+
+    for (step, action) in bundle.document.actions.iter().enumerate() {
+        match action {
+            Action::Remove(path) => data.runcmd.push(cmd(&["rm", "-rf", path])),
+            Action::ExtractTarball(tarball) => {
+                let content = std::fs::read(bundle.get_file(tarball)?)?;
+                let staged = data.stage(step, "archive.tar", &content);
+                data.runcmd.push(cmd(&["tar", "-xf", &staged, "-C", "/"]));
+            }
+            Action::AssembleFile(assemble) => {
+                let dir = bundle.get_file(&assemble.dir)?;
+                let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+                entries.sort_by_key(|e| e.file_name());
+
+                // Same rules as in the zone: trimmed parts, one per line
+                let mut content = String::new();
+                for entry in entries {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    let matches_prefix = assemble
+                        .prefix
+                        .as_ref()
+                        .is_none_or(|prefix| name.starts_with(prefix));
+                    if !entry.file_type()?.is_file() || !matches_prefix {
+                        continue;
+                    }
+                    let part = std::fs::read_to_string(entry.path())?;
+                    if part.trim().is_empty() {
+                        continue;
+                    }
+                    content += part.trim();
+                    content += "\n";
+                }
+                data.install_file(step, &assemble.output, content.as_bytes(), &assemble.common);
+            }
+            Action::Group(group) => {
+                let mut args = cmd(&["groupadd"]);
+                if let Some(gid) = group.gid {
+                    args.extend(cmd(&["-g", &gid.to_string()]));
+                }
+                args.push(group.name.clone());
+                data.runcmd.push(args);
+                for member in &group.members {
+                    data.runcmd
+                        .push(cmd(&["usermod", "-a", "-G", &group.name, member]));
+                }
+            }
+            Action::User(user) => {
+                let home = user
+                    .home
+                    .clone()
+                    .unwrap_or(format!("{}/{}", DEFAULT_HOME_BASE, user.name));
+                let mut args = cmd(&["useradd", "-m", "-d", &home]);
+                if let Some(uid) = user.uid {
+                    args.extend(cmd(&["-u", &uid.to_string()]));
+                }
+                if let Some(gid) = user.gid {
+                    args.extend(cmd(&["-g", &gid.to_string()]));
+                }
+                if !user.groups.is_empty() {
+                    args.extend(cmd(&["-G", &user.groups.join(",")]));
+                }
+                if let Some(shell) = &user.shell {
+                    args.extend(cmd(&["-s", shell]));
+                }
+                if let Some(gecos) = &user.gecos {
+                    args.extend(cmd(&["-c", gecos]));
+                }
+                if let Some(password) = &user.password {
+                    args.extend(cmd(&["-p", password]));
+                }
+                args.push(user.name.clone());
+                data.runcmd.push(args);
+
+                if !user.authorized_keys.is_empty() {
+                    let ssh_dir = format!("{}/.ssh", home);
+                    let keys = format!("{}\n", user.authorized_keys.join("\n"));
+                    let staged = data.stage(step, "authorized_keys", keys.as_bytes());
+                    let authorized_keys = format!("{}/authorized_keys", ssh_dir);
+                    data.runcmd.push(cmd(&["mkdir", "-p", &ssh_dir]));
+                    data.runcmd.push(cmd(&["cp", &staged, &authorized_keys]));
+                    data.runcmd.push(cmd(&["chmod", "700", &ssh_dir]));
+                    data.runcmd.push(cmd(&["chmod", "600", &authorized_keys]));
+                    data.runcmd
+                        .push(cmd(&["chown", "-R", &user.name, &ssh_dir]));
+                }
+            }
+            Action::Symlink(link) => {
+                let target = link.target.to_string_lossy();
+                let path = link.link.to_string_lossy();
+                data.runcmd.push(cmd(&["ln", "-sfn", &target, &path]));
+                data.perms(
+                    &path,
+                    &CommonPerms {
+                        owner: link.owner.clone(),
+                        group: link.group.clone(),
+                        ..Default::default()
+                    },
+                );
+            }
+            Action::Dir(dir) => {
+                let path = dir.path.to_string_lossy();
+                data.runcmd.push(cmd(&["mkdir", "-p", &path]));
+                data.perms(&path, &dir.common);
+            }
+            Action::Perm(perm) => data.perms(&perm.path.to_string_lossy(), &perm.common),
+            Action::File(file) => {
+                let content = match (&file.src, &file.content, file.is_template) {
+                    (Some(src), _, true) => render_template(
+                        src,
+                        &bundle.get_template_string(src)?,
+                        &bundle.template_context(),
+                    )?
+                    .into_bytes(),
+                    (None, Some(content), true) => render_template(
+                        &file.path.to_string_lossy(),
+                        content,
+                        &bundle.template_context(),
+                    )?
+                    .into_bytes(),
+                    (Some(src), _, false) => std::fs::read(bundle.get_file(src)?)?,
+                    (None, Some(content), false) => content.clone().into_bytes(),
+                    (None, None, _) => return Err(BuildError::EitherContentOrSource.into()),
+                };
+                data.install_file(step, &file.path, &content, &file.common);
+            }
+            Action::Ips(ips) => {
+                for ips_action in &ips.actions {
+                    match ips_action {
+                        IpsActions::InstallPackages(list) => {
+                            data.packages.extend(list.packages.iter().cloned())
+                        }
+                        x => return Err(VmError::NotSupported(format!("ips action {}", x))),
+                    }
+                }
+            }
+            x => return Err(VmError::NotSupported(x.to_string())),
+        }
+    }
+
+    /*
+     * The image boots for the first time when a vm gets deployed from it.
+     * cloud-init has to run again then. A failed action stops runcmd before
+     * this so its logs are still there.
+     */
+    data.runcmd.push(cmd(&["rm", "-rf", GUEST_STAGING_DIR]));
+    data.runcmd.push(cmd(&["cloud-init", "clean", "--logs"]));
+    data.runcmd.push(cmd(&["touch", GUEST_DONE_MARKER]));
+
+    Ok(data)
+}
+
+/// A zvol backing a disk of the vm
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmDisk {
+    pub dataset: String,
+    pub size: u64,
+}
+
+impl VmDisk {
+    pub fn device(&self) -> String {
+        format!("/dev/zvol/rdsk/{}", self.dataset)
+    }
+}
+
+/// The zvols of the disks in spec. They are children of the zone dataset so the image
+/// gets them when the zone is converted.
+pub fn disks(zone_dataset: &str, spec: &VMImageSpec) -> Result<Vec<VmDisk>> {
+    if spec.disks.is_empty() {
+        return Err(VmError::NoDisks);
+    }
+
+    let mut disks = vec![];
+    for (index, disk) in spec.disks.iter().enumerate() {
+        if disk.disk_kind != VMDiskKind::Virtio {
+            return Err(VmError::UnsupportedDiskKind(format!(
+                "{:?}",
+                disk.disk_kind
+            )));
+        }
+
+        let size = match disk.get_size()? {
+            Some(size) => size,
+            None => DEFAULT_DISK_SIZE.parse()?,
+        };
+        let name = disk.name.clone().unwrap_or(format!("disk{}", index));
+        disks.push(VmDisk {
+            dataset: format!("{}/{}", zone_dataset, name),
+            size: size.as_bytes(),
+        });
+    }
+    Ok(disks)
+}
+
+pub fn create_disks(disks: &[VmDisk]) -> Result<()> {
+    for disk in disks {
+        info!("Creating disk {}", disk.dataset);
+        crate::run(
+            &[
+                ZFS,
+                "create",
+                "-s",
+                "-V",
+                &disk.size.to_string(),
+                &disk.dataset,
+            ],
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// The image the vm boots first. Relative paths are taken from the files of bundle
+pub fn boot_image(bundle: &Bundle, spec: &VMImageSpec) -> Result<PathBuf> {
+    let image = match &spec.boot_image {
+        Some(image) if Path::new(image).is_absolute() => PathBuf::from(image),
+        Some(image) => bundle.get_file(image)?,
+        None => return Err(VmError::NoBootImage),
+    };
+    if !image.is_file() {
+        return Err(VmError::BootImageNotFound(image.display().to_string()));
+    }
+    Ok(image)
+}
+
+/// Installer images get booted from cdrom. Everything else is a raw disk image
+pub fn is_installer(image: &Path) -> bool {
+    image
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("iso"))
+}
+
+/// Write a raw cloud image onto disk
+pub fn write_boot_image(image: &Path, disk: &VmDisk) -> Result<()> {
+    let image_size = std::fs::metadata(image)?.len();
+    if image_size > disk.size {
+        return Err(VmError::DiskTooSmall(
+            image.display().to_string(),
+            image_size,
+            disk.dataset.clone(),
+            disk.size,
+        ));
+    }
+
+    info!("Writing {} to {}", image.display(), disk.dataset);
+    crate::run_with_timeout(
+        &[
+            DD,
+            &format!("if={}", image.display()),
+            &format!("of={}", disk.device()),
+            "bs=1M",
+        ],
+        None,
+        DISK_WRITE_TIMEOUT,
+    )?;
+    Ok(())
+}
+
+/// Write the NoCloud seed cloud-init reads user_data from into seed_dir.
+/// Returns the path of the iso.
+pub fn write_seed(
+    seed_dir: &Path,
+    hostname: &str,
+    user_data: &UserData,
+    network_config: Option<String>,
+) -> Result<PathBuf> {
+    std::fs::create_dir_all(seed_dir)?;
+
+    let meta_data = seed_dir.join("meta-data");
+    std::fs::write(
+        &meta_data,
+        format!("instance-id: {}\nlocal-hostname: {}\n", hostname, hostname),
+    )?;
+    let user_data_path = seed_dir.join("user-data");
+    std::fs::write(&user_data_path, user_data.cloud_config()?)?;
+
+    let mut grafts = vec![
+        format!("user-data={}", user_data_path.display()),
+        format!("meta-data={}", meta_data.display()),
+    ];
+    if let Some(network_config) = network_config {
+        let network_config_path = seed_dir.join("network-config");
+        std::fs::write(&network_config_path, network_config)?;
+        grafts.push(format!("network-config={}", network_config_path.display()));
+    }
+
+    let iso = seed_dir.join(SEED_ISO_FILENAME);
+    let iso_arg = iso.to_string_lossy().to_string();
+    let mut args = vec![
+        MKISOFS,
+        "-graft-points",
+        "-dlrDJN",
+        "-relaxed-filenames",
+        "-o",
+        &iso_arg,
+        "-V",
+        "cidata",
+    ];
+    args.extend(grafts.iter().map(|g| g.as_str()));
+    crate::run(&args, None)?;
+
+    Ok(iso)
+}
+
+/// cloud-init network config for a static address. Without one the guest uses DHCP
+pub fn network_config(ip: &str, gateway: Option<&str>) -> String {
+    let mut config = format!(
+        "version: 2\nethernets:\n  primary:\n    match:\n      name: \"e*\"\n    dhcp4: false\n    addresses:\n      - {}\n",
+        ip
+    );
+    if let Some(gateway) = gateway {
+        config.push_str(&format!("    gateway4: {}\n", gateway));
+    }
+    config
+}
+
+/// Attach the disks to the bhyve zone and mount the directories of the iso files into it.
+/// With an installer the vm boots from it first, otherwise from the first disk with the seed.
+pub fn configure_zone(
+    zonename: &str,
+    spec: &VMImageSpec,
+    ram: u64,
+    disks: &[VmDisk],
+    seed_iso: &Path,
+    installer: Option<&Path>,
+) -> Result<()> {
+    let mut cfg = zone::Config::new(zonename);
+
+    for iso in [Some(seed_iso), installer].iter().flatten() {
+        if let Some(iso_dir) = iso.parent() {
+            let iso_dir = iso_dir.to_string_lossy().to_string();
+            cfg.add_fs(&zone::Fs::default())
+                .set_ty("lofs")
+                .set_dir(&iso_dir)
+                .set_special(&iso_dir)
+                .set_options([String::from("ro"), String::from("nodevices")]);
+        }
+    }
+
+    for (index, disk) in disks.iter().enumerate() {
+        cfg.add_device(&zone::Device::default())
+            .set_name(disk.device());
+        let name = if index == 0 {
+            String::from("bootdisk")
+        } else {
+            format!("disk{}", index - 1)
+        };
+        cfg.add_attr(&zone::Attr {
+            name,
+            value: zone::AttributeValue::String(disk.dataset.clone()),
+        });
+    }
+
+    let cdrom = installer.unwrap_or(seed_iso);
+    let mut attrs = vec![
+        ("ram", ram.to_string()),
+        ("vcpus", spec.cpus.unwrap_or(1).to_string()),
+        ("diskif", String::from("virtio")),
+        ("cdrom", cdrom.to_string_lossy().to_string()),
+    ];
+    if installer.is_some() {
+        attrs.push(("bootorder", String::from("cdrom")));
+    }
+    for (name, value) in attrs {
+        cfg.add_attr(&zone::Attr {
+            name: name.to_string(),
+            value: zone::AttributeValue::String(value),
+        });
+    }
+
+    cfg.run_blocking()?;
+    Ok(())
+}
+
+/// After the installer finished boot the installed system with the seed instead
+pub fn boot_from_disk(zonename: &str, seed_iso: &Path) -> Result<()> {
+    let script = format!(
+        "select attr name=cdrom; set value={}; end; remove attr name=bootorder; add attr; set name=bootorder; set type=string; set value=bootdisk; end",
+        seed_iso.display()
+    );
+    crate::run(&[ZONECFG, "-z", zonename, &script], None)?;
+    Ok(())
+}
+
+/// Wait until the guest powered off and the zone is back in the installed state
+pub fn wait_for_poweroff(zonename: &str, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        let zone = crate::get_zone(zonename)?;
+        if matches!(zone.state(), zone::State::Installed) {
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            return Err(VmError::Timeout(zonename.to_string(), timeout.as_secs()));
+        }
+        std::thread::sleep(HALT_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cmd, disks, user_data, VmDisk, GUEST_DONE_MARKER, GUEST_STAGING_DIR};
+    use crate::build::bundle::{BuildBundleType, Bundle};
+    use miette::Result;
+    use std::path::Path;
+
+    #[test]
+    fn test_user_data() -> Result<()> {
+        let bundle = Bundle::new(Path::new("testdata/vm"))?;
+        let audit = bundle.get_audit_info();
+        assert_eq!(BuildBundleType::Bhyve, audit.kind());
+        assert!(audit.is_vm());
+
+        let spec = bundle
+            .document
+            .vm_specs
+            .as_ref()
+            .expect("testdata has vm-specs");
+        assert_eq!(
+            vec![
+                VmDisk {
+                    dataset: String::from("rpool/zones/vm/vda"),
+                    size: 20 << 30,
+                },
+                VmDisk {
+                    dataset: String::from("rpool/zones/vm/disk1"),
+                    size: 10 << 30,
+                },
+            ],
+            disks("rpool/zones/vm", spec)?
+        );
+
+        let data = user_data(&bundle)?;
+        assert_eq!(vec![String::from("nginx")], data.packages);
+        assert_eq!(1, data.write_files.len());
+        assert_eq!(
+            format!("{}/3-motd", GUEST_STAGING_DIR),
+            data.write_files[0].path
+        );
+        assert!(data
+            .runcmd
+            .contains(&cmd(&["groupadd", "-g", "1100", "web"])));
+        assert!(data
+            .runcmd
+            .contains(&cmd(&["cp", &data.write_files[0].path, "/etc/motd"])));
+        assert!(data.runcmd.contains(&cmd(&["chmod", "644", "/etc/motd"])));
+        assert_eq!(Some(&cmd(&["set", "-e"])), data.runcmd.first());
+        assert_eq!(
+            vec![
+                cmd(&["cloud-init", "clean", "--logs"]),
+                cmd(&["touch", GUEST_DONE_MARKER]),
+            ],
+            data.runcmd[data.runcmd.len() - 2..]
+        );
+        assert_eq!(cmd(&["rm", GUEST_DONE_MARKER]), data.power_state.condition);
+
+        let text = data.cloud_config()?;
+        assert!(text.starts_with("#cloud-config\n"));
+        assert!(text.contains("mode: poweroff"));
+
+        Ok(())
+    }
+}
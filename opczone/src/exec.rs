@@ -0,0 +1,620 @@
+use crate::{build_cmd, build_env, OPCZoneError};
+use common::{debug, info, warn};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+type Result<T> = miette::Result<T, OPCZoneError>;
+
+/// How many lines of stdout and stderr we keep around by default to put into error messages.
+pub const DEFAULT_TAIL_LINES: usize = 50;
+
+/// How often we look at the child while waiting for it to exit.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long we wait for the output of a process to drain after it exited or got killed.
+/// Background processes it started can keep the pipes open, in that case we give up on
+/// the rest of the output.
+const READER_GRACE: Duration = Duration::from_secs(5);
+
+/// Handle that can be used from another thread to abort a running process.
+/// Cloning the token shares the cancellation state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// How a process ended. Callers can match on this instead of only knowing that "something failed"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process exited by itself with the given exit code
+    Exited(i32),
+    /// The process was terminated by a signal it did not get from us
+    Signaled(i32),
+    /// We killed the process because it ran longer than the timeout
+    TimedOut(Duration),
+    /// We killed the process because the CancelToken was triggered
+    Cancelled,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        matches!(self, ExitStatus::Exited(0))
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            ExitStatus::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(code) = status.code() {
+            ExitStatus::Exited(code)
+        } else if let Some(signal) = status.signal() {
+            ExitStatus::Signaled(signal)
+        } else {
+            // Neither exit code nor signal is only possible for stopped processes which we never wait for
+            ExitStatus::Signaled(0)
+        }
+    }
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::Signaled(signal) => write!(f, "was killed by signal {}", signal),
+            ExitStatus::TimedOut(timeout) => write!(f, "timed out after {:?}", timeout),
+            ExitStatus::Cancelled => write!(f, "was cancelled"),
+        }
+    }
+}
+
+/// Options for execute. Use struct update syntax with Default to only set what is needed:
+/// `ExecOptions { timeout: Some(Duration::from_secs(60)), ..Default::default() }`
+#[derive(Debug, Clone)]
+pub struct ExecOptions {
+    /// Environment variables to set in addition to the inherited ones
+    pub env: Vec<(String, String)>,
+    /// Content to write to the stdin of the process. If None stdin is /dev/null
+    pub stdin: Option<String>,
+    /// Kill the process if it did not exit after this duration
+    pub timeout: Option<Duration>,
+    /// Kill the process once this token is cancelled
+    pub cancel: Option<CancelToken>,
+    /// How many of the last lines of stdout and stderr to keep
+    pub tail_lines: usize,
+    /// Keep the full stdout instead of only the tail
+    pub capture_stdout: bool,
+    /// Log every line of output as it arrives
+    pub live_log: bool,
+    /// Working directory of the process. Inherited if None
+    pub cwd: Option<PathBuf>,
+    /// Run the process as this user and group instead of ours
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        Self {
+            env: vec![],
+            stdin: None,
+            timeout: None,
+            cancel: None,
+            tail_lines: DEFAULT_TAIL_LINES,
+            capture_stdout: false,
+            live_log: true,
+            cwd: None,
+            uid: None,
+            gid: None,
+        }
+    }
+}
+
+/// Result of a process execution. `stdout` is the full output if `capture_stdout` was set
+/// otherwise only the tail of it like `stderr`
+#[derive(Debug, Clone)]
+pub struct ExecOutput {
+    pub command: String,
+    pub status: ExitStatus,
+    pub stdout: Vec<String>,
+    pub stderr: Vec<String>,
+    /// stdout exactly as the process wrote it if `capture_stdout` was set
+    pub stdout_bytes: Vec<u8>,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+
+    pub fn stdout_string(&self) -> String {
+        lines_to_string(&self.stdout)
+    }
+
+    pub fn stderr_string(&self) -> String {
+        lines_to_string(&self.stderr)
+    }
+
+    /// Turn an unsuccessful output into an error carrying the captured tail of stderr.
+    /// Successful outputs are passed through
+    pub fn into_result(self) -> Result<Self> {
+        if self.success() {
+            return Ok(self);
+        }
+
+        Err(self.into_error())
+    }
+
+    pub fn into_error(self) -> OPCZoneError {
+        let output = if self.stderr.is_empty() {
+            lines_to_string(&self.stdout)
+        } else {
+            lines_to_string(&self.stderr)
+        };
+        OPCZoneError::ProcessFailed(self.command, self.status, output)
+    }
+}
+
+fn lines_to_string(lines: &[String]) -> String {
+    lines
+        .iter()
+        .map(|l| format!("{}\n", l))
+        .collect::<Vec<String>>()
+        .join("")
+}
+
+/// Keeps the last `limit` lines written into it. A limit of `None` keeps everything,
+/// including the bytes as they were read.
+#[derive(Debug, Default)]
+struct LineTail {
+    limit: Option<usize>,
+    lines: VecDeque<String>,
+    bytes: Vec<u8>,
+}
+
+impl LineTail {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            lines: VecDeque::new(),
+            bytes: vec![],
+        }
+    }
+
+    fn push_bytes(&mut self, buf: &[u8], line: String) {
+        if self.limit.is_none() {
+            self.bytes.extend_from_slice(buf);
+        }
+        self.push(line);
+    }
+
+    fn push(&mut self, line: String) {
+        if let Some(limit) = self.limit {
+            if limit == 0 {
+                return;
+            }
+            while self.lines.len() >= limit {
+                self.lines.pop_front();
+            }
+        }
+        self.lines.push_back(line);
+    }
+
+    fn into_parts(self) -> (Vec<String>, Vec<u8>) {
+        (self.lines.into_iter().collect(), self.bytes)
+    }
+}
+
+fn spawn_reader<T>(
+    name: &str,
+    stream: Option<T>,
+    mut tail: LineTail,
+    live_log: bool,
+) -> Option<JoinHandle<std::io::Result<LineTail>>>
+where
+    T: Read + Send + 'static,
+{
+    let name = name.to_string();
+    let stream = stream?;
+
+    Some(std::thread::spawn(move || {
+        let mut r = BufReader::new(stream);
+
+        loop {
+            let mut buf = Vec::new();
+
+            // Read bytes instead of a String so a process printing invalid UTF-8 does not abort us
+            match r.read_until(b'\n', &mut buf) {
+                Ok(0) => {
+                    /*
+                     * EOF.
+                     */
+                    return Ok(tail);
+                }
+                Ok(_) => {
+                    let raw = String::from_utf8_lossy(&buf);
+                    let line = raw.strip_suffix('\n').unwrap_or(raw.as_ref());
+                    let s = line.trim();
+
+                    if live_log && !s.is_empty() {
+                        info!(target: "illumos-rs", "{}| {}", name, s);
+                    }
+
+                    tail.push_bytes(&buf, line.to_string());
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }))
+}
+
+/// Wait for a reader until deadline. Output still open by then is dropped with a warning
+/// unless it is complete, captured output must not be cut off silently.
+fn join_reader(
+    name: &str,
+    command: &str,
+    handle: Option<JoinHandle<std::io::Result<LineTail>>>,
+    deadline: Instant,
+    complete: bool,
+) -> Result<LineTail> {
+    if let Some(h) = &handle {
+        while !h.is_finished() {
+            if Instant::now() >= deadline {
+                if complete {
+                    return Err(OPCZoneError::ProcessReadError(
+                        name.to_string(),
+                        command.to_string(),
+                        std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "still open after the process ended, output is incomplete",
+                        ),
+                    ));
+                }
+                warn!(target: "opczone", "{} of {} still open after it ended, dropping output", name, command);
+                return Ok(LineTail::default());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    match handle {
+        Some(handle) => match handle.join() {
+            Ok(Ok(tail)) => Ok(tail),
+            Ok(Err(e)) => Err(OPCZoneError::ProcessReadError(
+                name.to_string(),
+                command.to_string(),
+                e,
+            )),
+            Err(_) => Err(OPCZoneError::ProcessReadError(
+                name.to_string(),
+                command.to_string(),
+                std::io::Error::other("reader thread panicked"),
+            )),
+        },
+        None => Ok(LineTail::default()),
+    }
+}
+
+/// Run a process and wait for it to finish, stop it after the timeout or when cancelled.
+/// Output gets logged line by line while the process runs and the tail of it is returned.
+/// A process that ran but did not succeed is not an error here, look at `ExecOutput::status`
+/// or use `ExecOutput::into_result`
+pub fn execute<S: AsRef<str>>(args: &[S], opts: &ExecOptions) -> Result<ExecOutput> {
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+    let command = args.join(" ");
+    let env: Vec<(&str, &str)> = opts
+        .env
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    let env = if env.is_empty() {
+        None
+    } else {
+        build_env(Some(env.as_slice()))
+    };
+    let mut cmd = build_cmd(args.clone(), env);
+
+    if let Some(cwd) = &opts.cwd {
+        cmd.current_dir(cwd);
+    }
+    if let Some(gid) = opts.gid {
+        cmd.gid(gid);
+    }
+    if let Some(uid) = opts.uid {
+        cmd.uid(uid);
+    }
+
+    if opts.stdin.is_some() {
+        cmd.stdin(Stdio::piped());
+    } else {
+        cmd.stdin(Stdio::null());
+    }
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    // Its own process group so a timeout or cancel also stops what the process started
+    cmd.process_group(0);
+
+    let mut child = cmd.spawn().map_err(|e| {
+        OPCZoneError::ProcessErrorWithContext(format!("could not spawn process {}", args[0]), e)
+    })?;
+
+    if let Some(stdin) = opts.stdin.clone() {
+        if let Some(mut child_stdin) = child.stdin.take() {
+            std::thread::spawn(move || {
+                // The process may exit without reading all of its input, that is not our problem
+                if let Err(e) = child_stdin.write_all(stdin.as_bytes()) {
+                    debug!(target: "opczone", "could not write stdin: {}", e);
+                }
+            });
+        }
+    }
+
+    let stdout_limit = if opts.capture_stdout {
+        None
+    } else {
+        Some(opts.tail_lines)
+    };
+    let readout = spawn_reader(
+        "O",
+        child.stdout.take(),
+        LineTail::new(stdout_limit),
+        opts.live_log,
+    );
+    let readerr = spawn_reader(
+        "E",
+        child.stderr.take(),
+        LineTail::new(Some(opts.tail_lines)),
+        opts.live_log,
+    );
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(es) = child.try_wait()? {
+            break ExitStatus::from(es);
+        }
+
+        let stop = if opts.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            Some(ExitStatus::Cancelled)
+        } else {
+            match opts.timeout {
+                Some(timeout) if started.elapsed() >= timeout => {
+                    Some(ExitStatus::TimedOut(timeout))
+                }
+                _ => None,
+            }
+        };
+
+        if let Some(stop) = stop {
+            warn!(target: "opczone", "process {} {}, killing it", &command, &stop);
+            kill_process_group(&mut child);
+            child.wait()?;
+            break stop;
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    let drain_deadline = Instant::now() + READER_GRACE;
+    let (stdout, stdout_bytes) = join_reader(
+        "stdout",
+        &command,
+        readout,
+        drain_deadline,
+        opts.capture_stdout,
+    )?
+    .into_parts();
+    let (stderr, _) = join_reader("stderr", &command, readerr, drain_deadline, false)?.into_parts();
+
+    Ok(ExecOutput {
+        command,
+        status,
+        stdout,
+        stderr,
+        stdout_bytes,
+    })
+}
+
+/// Kill the process group the child leads, the child and everything it started which
+/// stayed in the group.
+fn kill_process_group(child: &mut Child) {
+    let pgid = child.id() as libc::pid_t;
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        // kill fails if the process exited in the meantime, wait reaps it either way
+        let _ = child.kill();
+    }
+}
+
+/// Like execute but fails if the process did not exit successfully.
+pub fn execute_checked<S: AsRef<str>>(args: &[S], opts: &ExecOptions) -> Result<ExecOutput> {
+    execute(args, opts)?.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, CancelToken, ExecOptions, ExitStatus, READER_GRACE};
+    use crate::OPCZoneError;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_exit_status_and_tail() -> miette::Result<()> {
+        let out = execute(
+            &[
+                "/bin/sh",
+                "-c",
+                "for i in 1 2 3 4 5; do echo $i; done; echo err >&2; exit 3",
+            ],
+            &ExecOptions {
+                tail_lines: 2,
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(ExitStatus::Exited(3), out.status);
+        assert_eq!(vec!["4".to_string(), "5".to_string()], out.stdout);
+        assert_eq!(vec!["err".to_string()], out.stderr);
+
+        match out.into_result() {
+            Err(OPCZoneError::ProcessFailed(_, status, stderr)) => {
+                assert_eq!(ExitStatus::Exited(3), status);
+                assert_eq!("err\n", stderr);
+            }
+            _ => panic!("expected a failed process"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_stdout_and_stdin() -> miette::Result<()> {
+        let out = execute(
+            &["/bin/cat"],
+            &ExecOptions {
+                stdin: Some("a\nb\nc\n".into()),
+                tail_lines: 1,
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )?;
+
+        assert!(out.success());
+        assert_eq!("a\nb\nc\n", out.stdout_string());
+        assert_eq!(b"a\nb\nc\n".to_vec(), out.stdout_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_capture_stdout() -> miette::Result<()> {
+        // Output is returned as is, without a newline added to the last line
+        assert_eq!(
+            "a\n\nb",
+            crate::run_capture_stdout(&["/usr/bin/printf", "a\\n\\nb"], None)?
+        );
+        assert_eq!("", crate::run_capture_stdout(&["/bin/true"], None)?);
+        assert!(matches!(
+            crate::run_capture_stdout(&["/usr/bin/printf", "\\377"], None),
+            Err(OPCZoneError::FromUTF8Error(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_stdout_held_open() {
+        // The background sleep keeps stdout open after the shell exited
+        let out = crate::run_capture_stdout(&["/bin/sh", "-c", "echo a; /bin/sleep 10 &"], None);
+        assert!(matches!(out, Err(OPCZoneError::ProcessReadError(..))));
+    }
+
+    #[test]
+    fn test_cwd_and_env() -> miette::Result<()> {
+        let out = execute(
+            &["/bin/sh", "-c", "pwd; echo $GREETING"],
+            &ExecOptions {
+                env: vec![("GREETING".into(), "hello".into())],
+                cwd: Some("/tmp".into()),
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )?;
+
+        assert!(out.success());
+        assert_eq!("/tmp\nhello\n", out.stdout_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout_kills_process() -> miette::Result<()> {
+        let timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        let out = execute(
+            &["/bin/sleep", "30"],
+            &ExecOptions {
+                timeout: Some(timeout),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(ExitStatus::TimedOut(timeout), out.status);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout_kills_process_group() -> miette::Result<()> {
+        let timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        let out = execute(
+            &["/bin/sh", "-c", "/bin/sleep 30 & /bin/sleep 30"],
+            &ExecOptions {
+                timeout: Some(timeout),
+                ..Default::default()
+            },
+        )?;
+
+        // The background sleep holds the pipes open until the whole group is killed
+        assert_eq!(ExitStatus::TimedOut(timeout), out.status);
+        assert!(started.elapsed() < READER_GRACE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_process_does_not_block() -> miette::Result<()> {
+        let started = Instant::now();
+        let out = execute(
+            &["/bin/sh", "-c", "/bin/sleep 30 & exit 0"],
+            &ExecOptions::default(),
+        )?;
+
+        assert_eq!(ExitStatus::Exited(0), out.status);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_kills_process() -> miette::Result<()> {
+        let token = CancelToken::new();
+        let remote = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            remote.cancel();
+        });
+
+        let out = execute(
+            &["/bin/sleep", "30"],
+            &ExecOptions {
+                cancel: Some(token),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(ExitStatus::Cancelled, out.status);
+        Ok(())
+    }
+}
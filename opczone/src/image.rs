@@ -1,23 +1,51 @@
 use crate::{get_zone_dataset, get_zonepath_parent_ds};
 use common::{debug, info};
 use miette::Diagnostic;
-use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::process::{Command, Stdio};
-use std::{fs::File, path::Path};
+use std::time::Duration;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
 use std::{thread, time};
 use thiserror::Error;
 
+pub mod distribution;
+pub mod installed;
+pub mod manifest;
+pub mod oci;
+pub mod reference;
+pub mod registry;
+pub mod resolve;
+pub mod store;
+
+use crate::build::OciConfig;
+use distribution::{Credentials, DistributionError, Endpoint};
+use installed::InstalledError;
+pub use manifest::ImageManifest;
+use manifest::{ManifestError, MANIFEST_EXPORT_SUFFIX};
+use oci::OciError;
+use reference::{ImageReference, ReferenceError};
+use registry::{Registry, RegistryError, LATEST_TAG};
+use resolve::{CatalogEntry, ResolveError, Resolver};
+use store::{ObjectStore, S3Location, StoreError};
+
 const ZONEADM: &str = "/usr/sbin/zoneadm";
 const ZFS: &str = "/usr/sbin/zfs";
 const GZIP: &str = "/usr/bin/gzip";
+/// Roots with this directory have packages installed with IPS
+const IPS_IMAGE_DIR: &str = "var/pkg";
 const ZONEIMAGE_DIR: &str = "/etc/zimages";
+/// Suffix of images exported as zfs stream, the file name starts with the uuid
+pub const DATASET_EXPORT_SUFFIX: &str = ".zfs.gz";
+/// Image datasets are created in the dataset mounted here like zones are
+const IMAGES_PATH: &str = "/zones";
 
-#[derive(Debug, Deserialize, Serialize)]
-struct ImageRegisterFile {
-    uuid: uuid::Uuid,
-}
-
-pub struct ImageManifest {}
+/// A zone that did not shut down cleanly after this time gets halted
+const ZONE_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const ZONE_HALT_TIMEOUT: Duration = Duration::from_secs(60);
+const ZFS_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum ImageError {
@@ -33,6 +61,9 @@ pub enum ImageError {
     #[error("Image export failed: {0}")]
     ImageExportFailed(String),
 
+    #[error("Image import failed: {0}")]
+    ImageImportFailed(String),
+
     #[error("Could not convert string to UTF-8")]
     UTF8Error(#[from] std::string::FromUtf8Error),
 
@@ -44,6 +75,57 @@ pub enum ImageError {
 
     #[error(transparent)]
     JSONError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    ReferenceError(#[from] ReferenceError),
+
+    #[error(transparent)]
+    ResolveError(#[from] ResolveError),
+
+    #[error(transparent)]
+    ManifestError(#[from] ManifestError),
+
+    #[error(transparent)]
+    OciError(#[from] OciError),
+
+    #[error("image is for {0}, only {1} images can be imported")]
+    UnsupportedPlatform(String, String),
+
+    #[error(transparent)]
+    RegistryError(#[from] RegistryError),
+
+    #[error(transparent)]
+    InstalledError(#[from] InstalledError),
+
+    #[error("{0} is not an exported image, expected <uuid>.zfs.gz")]
+    NotAnExport(String),
+
+    #[error("image {0} is already installed")]
+    AlreadyInstalled(String),
+
+    #[error("{0} has no manifest with the name of the image, import it with a name")]
+    NameRequired(String),
+
+    #[error("checksum of {0} is {1} but its manifest records {2}")]
+    ChecksumMismatch(String, String, String),
+
+    #[error("image {0} has no manifest to publish")]
+    ManifestMissing(String),
+
+    #[error(transparent)]
+    DistributionError(#[from] DistributionError),
+
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+}
+
+/// How publish_image pushes an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishFormat {
+    /// An OCI image like export_image_as_oci_format writes it
+    Oci,
+    /// An artifact wrapping the zfs stream export_image_as_dataset_format writes
+    Dataset,
 }
 
 pub type Result<T> = miette::Result<T, ImageError>;
@@ -63,7 +145,11 @@ impl std::fmt::Display for ImageType {
     }
 }
 
-pub fn convert_zone_to_image(zonename: &str, image_name: &str) -> Result<uuid::Uuid> {
+pub fn convert_zone_to_image(
+    zonename: &str,
+    image_name: &str,
+    image_version: Option<&str>,
+) -> Result<uuid::Uuid> {
     // Make sure zone is shutdown
     let zone = crate::get_zone(zonename)?;
     match zone.state() {
@@ -75,11 +161,22 @@ pub fn convert_zone_to_image(zonename: &str, image_name: &str) -> Result<uuid::U
         }
         zone::State::Running => {
             info!("Shutting down zone {}", zonename);
-            match crate::run(&[ZONEADM, "-z", zonename, "shutdown"], None) {
+            match crate::run_with_timeout(
+                &[ZONEADM, "-z", zonename, "shutdown"],
+                None,
+                ZONE_SHUTDOWN_TIMEOUT,
+            ) {
                 Ok(_) => {}
-                Err(_) => {
-                    info!("Unable to shutdown zone ignoring init and halting zone");
-                    crate::run(&[ZONEADM, "-z", zonename, "halt"], None)?;
+                Err(e) => {
+                    info!(
+                        "Unable to shutdown zone ({}) ignoring init and halting zone",
+                        e
+                    );
+                    crate::run_with_timeout(
+                        &[ZONEADM, "-z", zonename, "halt"],
+                        None,
+                        ZONE_HALT_TIMEOUT,
+                    )?;
                 }
             }
         }
@@ -94,7 +191,7 @@ pub fn convert_zone_to_image(zonename: &str, image_name: &str) -> Result<uuid::U
     let zds = get_zone_dataset(&zone.path().to_string_lossy())?;
     let snap_name = format!("{}@final", &zds);
     info!("Snaphotting {}", &zds);
-    crate::run(&[ZFS, "snap", "-r", &snap_name], None)?;
+    crate::run_with_timeout(&[ZFS, "snap", "-r", &snap_name], None, ZFS_TIMEOUT)?;
 
     let datasets = crate::run_capture_stdout(
         &[
@@ -111,102 +208,667 @@ pub fn convert_zone_to_image(zonename: &str, image_name: &str) -> Result<uuid::U
 
     let mut image_datasets: Vec<String> = vec![];
 
-    for ds in datasets.split_terminator("\n").collect::<Vec<&str>>() {
-        let target_ds_name = ds.replace(&zds, &image_base_ds).replace("@final", "");
+    for (ds, target_ds_name) in final_clones(&datasets, &zds, &image_base_ds) {
         image_datasets.insert(0, target_ds_name.clone());
         debug!("Cloning {} -> {}", ds, &target_ds_name);
-        crate::run(&[ZFS, "clone", ds, &target_ds_name], None)?;
+        crate::run_with_timeout(&[ZFS, "clone", ds, &target_ds_name], None, ZFS_TIMEOUT)?;
     }
 
     for ds in image_datasets {
-        crate::run(&[ZFS, "promote", &ds], None)?;
+        crate::run_with_timeout(&[ZFS, "promote", &ds], None, ZFS_TIMEOUT)?;
     }
 
-    register_image_with_name(image_name, &image_uuid)?;
+    register_image(image_name, image_version, &image_uuid)?;
 
     Ok(image_uuid)
 }
 
+/*
+ * Build cache layers are snapshots of the same datasets as the final snapshot.
+ * Only the final snapshots are cloned into the image.
+ */
+fn final_clones<'a>(snapshots: &'a str, zds: &str, image_ds: &str) -> Vec<(&'a str, String)> {
+    snapshots
+        .lines()
+        .filter_map(|snapshot| {
+            snapshot
+                .strip_suffix("@final")
+                .map(|ds| (snapshot, ds.replacen(zds, image_ds, 1)))
+        })
+        .collect()
+}
+
 pub fn register_image_with_name(name: &str, image_uuid: &uuid::Uuid) -> Result<()> {
-    let encoded_name = name.replace("/", "_");
-    let register_file_content = ImageRegisterFile {
-        uuid: image_uuid.clone(),
+    register_image(name, None, image_uuid)
+}
+
+/// Register the image under name and version and make it the latest image of name.
+/// Registering a version again points it to the new image.
+pub fn register_image(name: &str, version: Option<&str>, image_uuid: &uuid::Uuid) -> Result<()> {
+    let replaced = Registry::update(Path::new(ZONEIMAGE_DIR), |registry| {
+        registry.register(name, None, version, image_uuid)
+    })?;
+    if let Some(replaced) = replaced {
+        info!(
+            "{}@{} was image {} before, imgadm vacuum removes it once unused",
+            name,
+            version.unwrap_or_default(),
+            replaced
+        );
+    }
+    Ok(())
+}
+
+/// Point tag of name to the image
+pub fn tag_image(name: &str, tag: &str, image_uuid: &uuid::Uuid) -> Result<()> {
+    Ok(Registry::update(Path::new(ZONEIMAGE_DIR), |registry| {
+        registry.tag(name, tag, image_uuid)
+    })?)
+}
+
+/// Import the OCI image source points to as a new image. The layers are applied to
+/// the root dataset of the image which gets snapshotted like built images so zones
+/// can be installed from it. The image is registered under name, the name it was
+/// built with or the name of the layout, and its tag.
+pub fn import_oci_image(source: &str, name: Option<&str>) -> Result<uuid::Uuid> {
+    let reference: oci::LayoutReference = source.parse()?;
+    let image = oci::read_layout(&reference)?;
+
+    let host = oci::Platform::host();
+    if image.config.os != host.os || image.config.architecture != host.architecture {
+        return Err(ImageError::UnsupportedPlatform(
+            format!("{}/{}", image.config.os, image.config.architecture),
+            format!("{}/{}", host.os, host.architecture),
+        ));
+    }
+
+    let built_with = image.image_manifest()?;
+    let version = built_with.as_ref().map(|m| m.version.clone());
+    let tag = reference
+        .reference
+        .clone()
+        .or(image.reference().map(String::from))
+        .or(built_with.as_ref().map(|m| m.version.clone()))
+        .unwrap_or(String::from("latest"));
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => built_with
+            .as_ref()
+            .map(|m| m.name.clone())
+            .or(image
+                .manifest
+                .annotations
+                .get(oci::ANNOTATION_TITLE)
+                .cloned())
+            .unwrap_or_else(|| {
+                reference
+                    .path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            }),
+    };
+
+    let mut manifest = match built_with {
+        /*
+         * Images exported by imgbuild carry the manifest they were built with. It
+         * is kept for the provenance but the files belong to the exporting host.
+         */
+        Some(mut manifest) => {
+            manifest.name = name.clone();
+            manifest.version = tag.clone();
+            manifest.container = Some(image.config.config.clone());
+            manifest.files = vec![];
+            manifest
+        }
+        None => ImageManifest::from_oci_config(&name, &tag, &image.config),
+    };
+    manifest.uuid = uuid::Uuid::new_v4();
+    manifest.source = Some(reference.to_string());
+
+    let image_ds = format!(
+        "{}/{}",
+        get_zone_dataset(IMAGES_PATH)?,
+        manifest.uuid.as_hyphenated()
+    );
+    info!(
+        "Importing {} as {}:{} into {}",
+        reference, name, tag, image_ds
+    );
+    let applied = create_image_datasets(&image_ds).and_then(|root| {
+        for layer in &image.manifest.layers {
+            oci::apply_layer(&image.layout_dir, layer, &root)?;
+        }
+        if root.join(IPS_IMAGE_DIR).exists() {
+            manifest.packages = manifest::installed_packages(&root)?;
+        }
+        crate::run_with_timeout(
+            &[ZFS, "snap", "-r", &format!("{}@final", image_ds)],
+            None,
+            ZFS_TIMEOUT,
+        )?;
+        register_image(&name, version.as_deref(), &manifest.uuid)?;
+        if tag != LATEST_TAG && version.as_ref() != Some(&tag) {
+            tag_image(&name, &tag, &manifest.uuid)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = applied {
+        info!("Import failed, destroying {}", image_ds);
+        if let Err(destroy_err) = crate::run(&[ZFS, "destroy", "-r", &image_ds], None) {
+            info!("Could not destroy {}: {}", image_ds, destroy_err);
+        }
+        return Err(e);
+    }
+
+    manifest.store()?;
+
+    Ok(manifest.uuid)
+}
+
+/// Push the installed image to the repository of endpoint, tagged with the tag of the
+/// endpoint or the version of the image. Blobs the registry has already are not
+/// uploaded again. Returns the digest of the pushed manifest.
+pub fn publish_image(
+    image_uuid: &uuid::Uuid,
+    endpoint: Endpoint,
+    credentials: Option<Credentials>,
+    format: PublishFormat,
+) -> Result<String> {
+    let manifest = ImageManifest::load(image_uuid)?
+        .ok_or(ImageError::ManifestMissing(image_uuid.to_string()))?;
+    let tag = endpoint.tag.clone().unwrap_or(manifest.version.clone());
+    if !distribution::valid_tag(&tag) {
+        return Err(DistributionError::InvalidTag(tag).into());
+    }
+
+    let work_dir = std::env::temp_dir().join(format!("publish-{}", image_uuid.as_hyphenated()));
+    std::fs::create_dir_all(&work_dir)?;
+    info!("Publishing {} to {} as {}", image_uuid, endpoint, tag);
+    let mut client = distribution::Client::new(endpoint, credentials)?;
+    let published = match format {
+        PublishFormat::Oci => {
+            let container = manifest
+                .container
+                .clone()
+                .unwrap_or_else(|| oci::ContainerConfig::new(&OciConfig::default(), &manifest));
+            let root = final_root(image_uuid)?;
+            let layout_dir = work_dir.join(format!("{}.oci", image_uuid.as_hyphenated()));
+            oci::write_layout(&root, &layout_dir, &container, &manifest)
+                .map_err(ImageError::from)
+                .and_then(|_| {
+                    let image = oci::read_layout(&oci::LayoutReference {
+                        path: layout_dir,
+                        reference: None,
+                    })?;
+                    Ok(distribution::push_layout(&mut client, &image, &tag)?)
+                })
+        }
+        PublishFormat::Dataset => {
+            export_image_as_dataset_format(*image_uuid, &work_dir).and_then(|file| {
+                Ok(distribution::push_dataset(
+                    &mut client,
+                    &file,
+                    &manifest,
+                    &tag,
+                )?)
+            })
+        }
+    };
+    if let Err(e) = std::fs::remove_dir_all(&work_dir) {
+        info!("Could not remove {}: {}", work_dir.display(), e);
+    }
+    published
+}
+
+/// Upload the installed image below location in the object storage configured in
+/// /etc/opc/config.toml together with its manifest and add it to the catalog there.
+/// Returns the url of the uploaded image.
+pub fn publish_image_to_store(
+    image_uuid: &uuid::Uuid,
+    location: &S3Location,
+    format: PublishFormat,
+) -> Result<String> {
+    let mut manifest = ImageManifest::load(image_uuid)?
+        .ok_or(ImageError::ManifestMissing(image_uuid.to_string()))?;
+    let store = ObjectStore::open()?;
+
+    let work_dir = std::env::temp_dir().join(format!("publish-{}", image_uuid.as_hyphenated()));
+    std::fs::create_dir_all(&work_dir)?;
+    info!("Publishing {} to {}", image_uuid, location);
+    let uploaded = upload_to_store(&store, location, &mut manifest, format, &work_dir);
+    if let Err(e) = std::fs::remove_dir_all(&work_dir) {
+        info!("Could not remove {}: {}", work_dir.display(), e);
+    }
+    let url = uploaded?.to_string();
+
+    store::update_catalog(
+        &store,
+        location,
+        CatalogEntry {
+            uuid: manifest.uuid,
+            publisher: None,
+            name: manifest.name.clone(),
+            version: Some(manifest.version.clone()),
+            url: url.clone(),
+        },
+    )?;
+    Ok(url)
+}
+
+fn upload_to_store(
+    store: &ObjectStore,
+    location: &S3Location,
+    manifest: &mut ImageManifest,
+    format: PublishFormat,
+    work_dir: &Path,
+) -> Result<S3Location> {
+    match format {
+        PublishFormat::Oci => {
+            let container = manifest
+                .container
+                .clone()
+                .unwrap_or_else(|| oci::ContainerConfig::new(&OciConfig::default(), manifest));
+            let root = final_root(&manifest.uuid)?;
+            let layout_dir = work_dir.join(format!(
+                "{}{}",
+                manifest.uuid.as_hyphenated(),
+                store::LAYOUT_SUFFIX
+            ));
+            let layer = oci::write_layout(&root, &layout_dir, &container, manifest)?;
+            manifest.add_file(&layer.path)?;
+            let manifest_file = manifest.export_to(work_dir)?;
+            Ok(store::push_layout(
+                store,
+                location,
+                &layout_dir,
+                &manifest_file,
+            )?)
+        }
+        PublishFormat::Dataset => {
+            let file = export_image_as_dataset_format(manifest.uuid, work_dir)?;
+            manifest.add_file(&file)?;
+            let manifest_file = manifest.export_to(work_dir)?;
+            Ok(store::push_dataset(store, location, &file, &manifest_file)?)
+        }
+    }
+}
+
+/// Import an image published with publish_image_to_store. A <uuid>.zfs.gz is imported
+/// like import_dataset_image does and a <uuid>.oci like import_oci_image does, after
+/// the downloaded files matched the checksums recorded for them.
+pub fn import_s3_image(source: &str, name: Option<&str>) -> Result<uuid::Uuid> {
+    let location: S3Location = source.parse()?;
+    let store = ObjectStore::open()?;
+
+    let work_dir = std::env::temp_dir().join(format!("import-{}", location.file_name()));
+    std::fs::create_dir_all(&work_dir)?;
+    let imported = if location.key.ends_with(DATASET_EXPORT_SUFFIX) {
+        store::fetch_dataset(&store, &location, &work_dir)
+            .map_err(ImageError::from)
+            .and_then(|file| import_dataset_image(&file, name))
+    } else if location.key.ends_with(store::LAYOUT_SUFFIX) {
+        store::fetch_layout(&store, &location, &work_dir)
+            .map_err(ImageError::from)
+            .and_then(|layout_dir| {
+                let reference = format!("{}{}", oci::OCI_TRANSPORT, layout_dir.display());
+                let image_uuid = import_oci_image(&reference, name)?;
+                // The layout was only downloaded to a temporary directory
+                if let Some(mut manifest) = ImageManifest::load(&image_uuid)? {
+                    manifest.source = Some(location.to_string());
+                    manifest.store()?;
+                }
+                Ok(image_uuid)
+            })
+    } else {
+        Err(ImageError::NotAnExport(source.to_string()))
     };
-    let register_file_path = Path::new(ZONEIMAGE_DIR)
-        .join(encoded_name)
-        .with_extension("json");
-    let mut register_file = File::options()
-        .write(true)
-        .create_new(true)
-        .open(register_file_path)?;
-    Ok(serde_json::to_writer_pretty(
-        &mut register_file,
-        &register_file_content,
-    )?)
+    if let Err(e) = std::fs::remove_dir_all(&work_dir) {
+        info!("Could not remove {}: {}", work_dir.display(), e);
+    }
+    imported
 }
 
+/// Create the dataset of an image with its root and vroot children like a zone has.
+/// Returns where root is mounted.
+fn create_image_datasets(image_ds: &str) -> Result<PathBuf> {
+    let root_ds = format!("{}/root", image_ds);
+    crate::dataset_create_with(image_ds, false, &[])?;
+    crate::dataset_create_with(
+        &root_ds,
+        false,
+        &[("devices".to_string(), "off".to_string())],
+    )?;
+    crate::dataset_create_with(
+        &format!("{}/vroot", image_ds),
+        false,
+        &[("mountpoint".to_string(), "none".to_string())],
+    )?;
+
+    let mountpoint = crate::run_capture_stdout(
+        &[ZFS, "get", "-H", "-o", "value", "mountpoint", &root_ds],
+        None,
+    )?;
+    Ok(PathBuf::from(mountpoint.trim()))
+}
+
+/// Find an installed image by a plain name or an img:// FMRI, optionally followed by a
+/// version constraint like @1.2 or a tag like :stable. A plain name finds the latest
+/// image. Returns None if no image with that name is installed and fails if images
+/// with the name exist but none of them matches the publisher, version or tag asked for.
 pub fn find_image_by_name(name: &str) -> Result<Option<uuid::Uuid>> {
-    let encoded_name = name.replace("/", "_");
+    let reference: ImageReference = name.parse()?;
+    let found = Resolver::default().resolve_local(&reference)?;
+    Ok(found.map(|image| image.uuid))
+}
 
-    let register_file_path = Path::new(ZONEIMAGE_DIR)
-        .join(encoded_name)
-        .with_extension("json");
-    if !register_file_path.exists() {
-        return Ok(None);
+/// Import an image written by export_image_as_dataset_format. The manifest written
+/// next to it provides name, version and checksum of the image, without one the
+/// image must be given a name.
+pub fn import_dataset_image(file: &Path, name: Option<&str>) -> Result<uuid::Uuid> {
+    let not_an_export = || ImageError::NotAnExport(file.display().to_string());
+    let file_name = file
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(not_an_export)?;
+    let image_uuid = file_name
+        .strip_suffix(DATASET_EXPORT_SUFFIX)
+        .and_then(|uuid| uuid::Uuid::parse_str(uuid).ok())
+        .ok_or_else(not_an_export)?;
+
+    let manifest_path = file.with_file_name(format!(
+        "{}.{}",
+        image_uuid.as_hyphenated(),
+        MANIFEST_EXPORT_SUFFIX
+    ));
+    let manifest = if manifest_path.exists() {
+        Some(ImageManifest::read_from(&manifest_path)?)
+    } else {
+        None
+    };
+    let name = name
+        .map(String::from)
+        .or(manifest.as_ref().map(|m| m.name.clone()))
+        .ok_or(ImageError::NameRequired(file.display().to_string()))?;
+    let version = manifest.as_ref().map(|m| m.version.clone());
+
+    if let Some(recorded) = manifest
+        .as_ref()
+        .and_then(|m| m.files.iter().find(|f| f.name == file_name))
+    {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(file)?, &mut hasher)?;
+        let actual = hex::encode(hasher.finalize());
+        if actual != recorded.sha256 {
+            return Err(ImageError::ChecksumMismatch(
+                file.display().to_string(),
+                actual,
+                recorded.sha256.clone(),
+            ));
+        }
+    }
+
+    let image_ds = format!(
+        "{}/{}",
+        get_zone_dataset(IMAGES_PATH)?,
+        image_uuid.as_hyphenated()
+    );
+    if crate::run(&[ZFS, "list", "-H", "-o", "name", &image_ds], None).is_ok() {
+        return Err(ImageError::AlreadyInstalled(image_uuid.to_string()));
+    }
+
+    info!("Importing {} into {}", file.display(), image_ds);
+    let mut gzip = Command::new(GZIP)
+        .arg("-dc")
+        .arg(file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let zfs_receive = Command::new(ZFS)
+        .arg("receive")
+        .arg(&image_ds)
+        .stdin(Stdio::from(gzip.stdout.take().unwrap()))
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let output = zfs_receive.wait_with_output()?;
+    let unpacked = gzip.wait_with_output()?;
+    if !output.status.success() {
+        return Err(ImageError::ImageImportFailed(String::from_utf8(
+            output.stderr,
+        )?));
+    }
+
+    /*
+     * zfs may have received a complete stream from a file with a damaged end.
+     * Don't keep an image from a file we could not read completely.
+     */
+    if !unpacked.status.success() {
+        info!("Import failed, destroying {}", image_ds);
+        if let Err(destroy_err) = crate::run(&[ZFS, "destroy", "-r", &image_ds], None) {
+            info!("Could not destroy {}: {}", image_ds, destroy_err);
+        }
+        return Err(ImageError::ImageImportFailed(String::from_utf8(
+            unpacked.stderr,
+        )?));
+    }
+
+    let registered = register_image(&name, version.as_deref(), &image_uuid).and_then(|_| {
+        if let Some(mut manifest) = manifest {
+            manifest.name = name.clone();
+            manifest.store()?;
+        }
+        Ok(())
+    });
+    if let Err(e) = registered {
+        info!("Import failed, destroying {}", image_ds);
+        if let Err(destroy_err) = crate::run(&[ZFS, "destroy", "-r", &image_ds], None) {
+            info!("Could not destroy {}: {}", image_ds, destroy_err);
+        }
+        return Err(e);
     }
 
-    let register_file = File::open(&register_file_path)?;
-    let register_file_content: ImageRegisterFile = serde_json::from_reader(&register_file)?;
-    Ok(Some(register_file_content.uuid))
+    Ok(image_uuid)
 }
 
+/// Write the final snapshot of the image as gzip compressed zfs stream to output_dir.
+/// Returns the path of the written file.
 pub fn export_image_as_dataset_format<P: AsRef<Path>>(
     image_uuid: uuid::Uuid,
     output_dir: P,
-) -> Result<()> {
+) -> Result<PathBuf> {
     // zfs send dataset into output directory
     let image_path = format!("/zones/{}", image_uuid.as_hyphenated().to_string());
 
     let image_ds = get_zone_dataset(&image_path)?;
-    let snap_name = format!("{}@final", &image_ds);
+    let export_ds = format!("{}-export", &image_ds);
 
-    let image_filename = format!("{}.zfs.gz", image_uuid.as_hyphenated().to_string());
+    let image_filename = format!("{}{}", image_uuid.as_hyphenated(), DATASET_EXPORT_SUFFIX);
 
     let file_path = output_dir.as_ref().join(&image_filename);
 
-    let file = File::create(&file_path)?;
-
     info!(
         "Exporting zone to zfs image file {} with gzip compression",
         file_path.display()
     );
 
-    let zfs_send = Command::new(ZFS)
+    let exported = clone_final(&image_ds, &export_ds)
+        .and_then(|_| send_compressed(&format!("{}@final", &export_ds), &file_path));
+    if let Err(e) = crate::run(&[ZFS, "destroy", "-r", &export_ds], None) {
+        info!("Could not destroy {}: {}", export_ds, e);
+    }
+    exported?;
+
+    info!("Sucess");
+    Ok(file_path)
+}
+
+/*
+ * zfs send -R ships every snapshot of the image, the build cache layers taken
+ * before the final snapshot included. Clones of the final snapshots have no
+ * snapshot but the one taken for the export. Clones do not inherit the local
+ * properties of their origin so they are set on the clones again.
+ */
+fn clone_final(image_ds: &str, export_ds: &str) -> Result<()> {
+    let datasets = crate::run_capture_stdout(
+        &[
+            ZFS,
+            "list",
+            "-t",
+            "filesystem",
+            "-r",
+            "-H",
+            "-o",
+            "name",
+            image_ds,
+        ],
+        None,
+    )?;
+    for ds in datasets.lines() {
+        let local = crate::run_capture_stdout(
+            &[
+                ZFS,
+                "get",
+                "-H",
+                "-s",
+                "local",
+                "-o",
+                "property,value",
+                "all",
+                ds,
+            ],
+            None,
+        )?;
+        let mut args = vec![ZFS.to_string(), String::from("clone")];
+        for property in clone_properties(&local) {
+            args.push(String::from("-o"));
+            args.push(property);
+        }
+        args.push(format!("{}@final", ds));
+        args.push(ds.replacen(image_ds, export_ds, 1));
+        crate::run_with_timeout(&args, None, ZFS_TIMEOUT)?;
+    }
+    crate::run_with_timeout(
+        &[ZFS, "snap", "-r", &format!("{}@final", export_ds)],
+        None,
+        ZFS_TIMEOUT,
+    )?;
+    Ok(())
+}
+
+/// The local properties zfs get printed as property=value. Mountpoints are only kept
+/// if they do not mount anything, the clones must not mount over the image.
+fn clone_properties(local: &str) -> Vec<String> {
+    local
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(property, value)| {
+            *property != "mountpoint" || *value == "none" || *value == "legacy"
+        })
+        .map(|(property, value)| format!("{}={}", property, value))
+        .collect()
+}
+
+/// Write the replication stream of snapshot gzip compressed to path
+fn send_compressed(snapshot: &str, path: &Path) -> Result<()> {
+    let file = File::create(path)?;
+    let mut zfs_send = Command::new(ZFS)
         .arg("send")
         .arg("-R")
-        .arg(&snap_name)
+        .arg(snapshot)
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()?;
 
     let gzip = Command::new(GZIP)
-        .stdin(Stdio::from(zfs_send.stdout.unwrap()))
+        .stdin(Stdio::from(zfs_send.stdout.take().unwrap()))
         .stdout(file)
         .stderr(Stdio::piped())
         .spawn()?;
 
     let output = gzip.wait_with_output()?;
-
-    if output.status.success() {
-        info!("Sucess");
-        Ok(())
-    } else {
-        Err(ImageError::ImageExportFailed(String::from_utf8(
+    let sent = zfs_send.wait_with_output()?;
+    if !sent.status.success() {
+        return Err(ImageError::ImageExportFailed(String::from_utf8(
+            sent.stderr,
+        )?));
+    }
+    if !output.status.success() {
+        return Err(ImageError::ImageExportFailed(String::from_utf8(
             output.stderr,
-        )?))
+        )?));
     }
+    Ok(())
+}
+
+/// The root of the image as its final snapshot has it
+fn final_root(image_uuid: &uuid::Uuid) -> Result<PathBuf> {
+    let image_path = format!("/zones/{}", image_uuid.as_hyphenated());
+    let root_ds = format!("{}/root", get_zone_dataset(&image_path)?);
+    let mountpoint = crate::run_capture_stdout(
+        &[ZFS, "get", "-H", "-o", "value", "mountpoint", &root_ds],
+        None,
+    )?;
+    Ok(Path::new(mountpoint.trim()).join(".zfs/snapshot/final"))
 }
 
-#[allow(unused_variables)]
-pub fn export_zone_as_oci_format<P: AsRef<Path>>(zone: zone::Zone, output_dir: P) -> Result<()> {
-    todo!()
+/// Write the final snapshot of the image as OCI image layout <uuid>.oci to output_dir.
+/// Returns the layer of the image.
+pub fn export_image_as_oci_format<P: AsRef<Path>>(
+    output_dir: P,
+    config: &OciConfig,
+    manifest: &ImageManifest,
+) -> Result<oci::Layer> {
+    let root = final_root(&manifest.uuid)?;
+    let layout_dir = output_dir
+        .as_ref()
+        .join(format!("{}.oci", manifest.uuid.as_hyphenated()));
+    info!(
+        "Exporting image to oci image layout {}",
+        layout_dir.display()
+    );
+    let container = oci::ContainerConfig::new(config, manifest);
+    Ok(oci::write_layout(&root, &layout_dir, &container, manifest)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clone_properties, final_clones};
+
+    #[test]
+    fn test_final_clones() {
+        let zds = "rpool/zones/build";
+        let snapshots = "rpool/zones/build@buildcache-1a2b\n\
+                         rpool/zones/build@final\n\
+                         rpool/zones/build/root@buildcache-1a2b\n\
+                         rpool/zones/build/root@buildcache-3c4d\n\
+                         rpool/zones/build/root@final\n\
+                         rpool/zones/build/vroot@buildcache-1a2b\n\
+                         rpool/zones/build/vroot@final\n";
+        assert_eq!(
+            vec![
+                ("rpool/zones/build@final", "rpool/zones/img".to_string()),
+                (
+                    "rpool/zones/build/root@final",
+                    "rpool/zones/img/root".to_string()
+                ),
+                (
+                    "rpool/zones/build/vroot@final",
+                    "rpool/zones/img/vroot".to_string()
+                ),
+            ],
+            final_clones(snapshots, zds, "rpool/zones/img")
+        );
+    }
+
+    #[test]
+    fn test_clone_properties() {
+        assert_eq!(
+            vec!["devices=off".to_string()],
+            clone_properties("devices\toff\nmountpoint\t/zones/img/root\n")
+        );
+        assert_eq!(
+            vec!["mountpoint=none".to_string()],
+            clone_properties("mountpoint\tnone\n")
+        );
+    }
 }
@@ -0,0 +1,849 @@
+use super::manifest::ImageManifest;
+use super::oci::{
+    self, Descriptor, LayoutImage, Manifest, OciError, ANNOTATION_CREATED,
+    ANNOTATION_IMAGE_MANIFEST, ANNOTATION_IMAGE_UUID, ANNOTATION_TITLE, ANNOTATION_VERSION,
+    MEDIA_TYPE_IMAGE_MANIFEST, MEDIA_TYPE_MANIFEST, OCI_SCHEMA_VERSION,
+};
+use common::{debug, info};
+use miette::Diagnostic;
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fmt::Display, fs::File, io::Read, path::Path, time::Duration};
+use thiserror::Error;
+use url::Url;
+
+/// Scheme of endpoints pointing to an OCI distribution registry
+pub const OCI_SCHEME: &str = "oci";
+/// Artifact type of images pushed as zfs stream
+pub const MEDIA_TYPE_ZFS_ARTIFACT: &str = "application/vnd.openflowlabs.image.zfs.v1";
+/// The gzip compressed zfs stream of an image as export_image_as_dataset_format writes it
+pub const MEDIA_TYPE_ZFS_STREAM: &str = "application/vnd.openflowlabs.image.zfs.stream.v1+gzip";
+
+/// Blobs are uploaded in chunks of this many bytes
+const UPLOAD_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const OCTET_STREAM: &str = "application/octet-stream";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum DistributionError {
+    #[error("{0} is not a registry endpoint, use oci://<registry>/<repository>[:<tag>]")]
+    InvalidEndpoint(String),
+    #[error("{0} is not a valid tag")]
+    InvalidTag(String),
+    #[error("{0} {1} failed with {2}: {3}")]
+    RequestFailed(String, String, u16, String),
+    #[error("could not authenticate to {0}: {1}")]
+    AuthenticationFailed(String, String),
+    #[error("registry did not say where to continue the upload to {0}")]
+    MissingLocation(String),
+    #[error(transparent)]
+    OciError(#[from] OciError),
+    #[error(transparent)]
+    FetchError(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlError(#[from] url::ParseError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+}
+
+type Result<T> = miette::Result<T, DistributionError>;
+
+/// A repository of a registry images get pushed to like
+/// oci://registry.example.org/zones/web:1.0
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    /// Host of the registry with the port if it has one
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    /// Talk plain http to the registry like registries run locally for tests do
+    pub insecure: bool,
+}
+
+impl Endpoint {
+    pub fn from_url(url: &Url) -> Result<Self> {
+        let invalid = || DistributionError::InvalidEndpoint(url.to_string());
+        if url.scheme() != OCI_SCHEME {
+            return Err(invalid());
+        }
+        let host = url.host_str().ok_or_else(invalid)?;
+        let registry = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        let path = url.path().trim_matches('/');
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag.to_string())),
+            _ => (path, None),
+        };
+        if !valid_repository(repository) {
+            return Err(invalid());
+        }
+        if let Some(tag) = &tag {
+            if !valid_tag(tag) {
+                return Err(DistributionError::InvalidTag(tag.clone()));
+            }
+        }
+
+        Ok(Endpoint {
+            registry,
+            repository: repository.to_string(),
+            tag,
+            insecure: false,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        let scheme = if self.insecure { "http" } else { "https" };
+        format!(
+            "{}://{}/v2/{}/{}",
+            scheme, self.registry, self.repository, path
+        )
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}/{}", OCI_SCHEME, self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Components are lowercase alphanumerics separated by ., _ or -
+fn valid_repository(repository: &str) -> bool {
+    !repository.is_empty()
+        && repository.split('/').all(|component| {
+            component.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && component.ends_with(|c: char| c.is_ascii_alphanumeric())
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+        })
+}
+
+/// Tags are up to 128 alphanumerics, ., _ or - and do not start with . or -
+pub fn valid_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+/// How to authenticate to a registry. Tokens are sent as they are, a username and
+/// password is used for basic auth or to get a token from the registry.
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Token(String),
+}
+
+#[derive(Debug, Clone)]
+enum Authorization {
+    Basic(String, String),
+    Bearer(String),
+}
+
+/// What a registry answers with WWW-Authenticate
+#[derive(Debug, PartialEq)]
+struct Challenge {
+    scheme: String,
+    params: BTreeMap<String, String>,
+}
+
+/// Parse a challenge like Bearer realm="https://auth.example.org/token",service="registry".
+/// Quoted values can contain commas like scopes do.
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    let header = header.trim();
+    let (scheme, rest) = header.split_once(' ').unwrap_or((header, ""));
+    if scheme.is_empty() {
+        return None;
+    }
+
+    let mut params = BTreeMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.peek().map(|c| *c == ',' || c.is_whitespace()) == Some(true) {
+            chars.next();
+        }
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+        params.insert(key.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    Some(Challenge {
+        scheme: scheme.to_lowercase(),
+        params,
+    })
+}
+
+/// Registries return the token under either name
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+/// Pushes blobs and manifests to the repository of an endpoint
+pub struct Client {
+    http: reqwest::blocking::Client,
+    endpoint: Endpoint,
+    credentials: Option<Credentials>,
+    authorization: Option<Authorization>,
+    chunk_size: u64,
+}
+
+impl Client {
+    pub fn new(endpoint: Endpoint, credentials: Option<Credentials>) -> Result<Self> {
+        let http = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let authorization = match &credentials {
+            Some(Credentials::Token(token)) => Some(Authorization::Bearer(token.clone())),
+            _ => None,
+        };
+        Ok(Client {
+            http,
+            endpoint,
+            credentials,
+            authorization,
+            chunk_size: UPLOAD_CHUNK_SIZE,
+        })
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.authorization {
+            Some(Authorization::Basic(username, password)) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(Authorization::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn send<F>(&mut self, request: F) -> Result<Response>
+    where
+        F: Fn(&reqwest::blocking::Client) -> RequestBuilder,
+    {
+        /*
+         * Registries only say how to authenticate by rejecting a request. It is
+         * built again once authenticated as the body of the first one is gone.
+         */
+        let response = self.authorize(request(&self.http)).send()?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_challenge)
+            .ok_or_else(|| {
+                DistributionError::AuthenticationFailed(
+                    self.endpoint.registry.clone(),
+                    "the registry did not say how to authenticate".to_string(),
+                )
+            })?;
+        self.authenticate(&challenge)?;
+        Ok(self.authorize(request(&self.http)).send()?)
+    }
+
+    fn authenticate(&mut self, challenge: &Challenge) -> Result<()> {
+        let failed = |reason: &str| {
+            DistributionError::AuthenticationFailed(
+                self.endpoint.registry.clone(),
+                reason.to_string(),
+            )
+        };
+
+        match challenge.scheme.as_str() {
+            "basic" => match &self.credentials {
+                Some(Credentials::Basic { username, password }) => {
+                    self.authorization =
+                        Some(Authorization::Basic(username.clone(), password.clone()));
+                    Ok(())
+                }
+                _ => Err(failed("the registry wants a username and password")),
+            },
+            "bearer" => {
+                let realm = challenge
+                    .params
+                    .get("realm")
+                    .ok_or_else(|| failed("the token challenge has no realm"))?;
+                let scope = challenge
+                    .params
+                    .get("scope")
+                    .cloned()
+                    .unwrap_or(format!("repository:{}:pull,push", self.endpoint.repository));
+                let mut query = vec![("scope", scope)];
+                if let Some(service) = challenge.params.get("service") {
+                    query.push(("service", service.clone()));
+                }
+
+                debug!("requesting token from {}", realm);
+                let mut request = self.http.get(realm).query(&query);
+                if let Some(Credentials::Basic { username, password }) = &self.credentials {
+                    request = request.basic_auth(username, Some(password));
+                }
+                let response = expect(request.send()?, "GET", realm, StatusCode::OK)?;
+                let token: TokenResponse = serde_json::from_reader(response)?;
+                let token = token
+                    .token
+                    .or(token.access_token)
+                    .ok_or_else(|| failed("the token response has no token"))?;
+                self.authorization = Some(Authorization::Bearer(token));
+                Ok(())
+            }
+            scheme => Err(failed(&format!(
+                "{} authentication is not supported",
+                scheme
+            ))),
+        }
+    }
+
+    /// Where the registry wants the upload to continue. Registries may answer with a
+    /// path relative to themselves.
+    fn location(&self, response: &Response) -> Result<Url> {
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| DistributionError::MissingLocation(self.endpoint.to_string()))?;
+        Ok(Url::parse(&self.endpoint.url(""))?.join(location)?)
+    }
+
+    pub fn blob_exists(&mut self, digest: &str) -> Result<bool> {
+        let url = self.endpoint.url(&format!("blobs/{}", digest));
+        let response = self.send(|http| http.head(&url))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        expect(response, "HEAD", &url, StatusCode::OK)?;
+        Ok(true)
+    }
+
+    /// Upload the blob descriptor describes in chunks read from content. Returns false
+    /// if the registry has the blob already and nothing was uploaded.
+    pub fn push_blob<R: Read>(&mut self, descriptor: &Descriptor, mut content: R) -> Result<bool> {
+        if self.blob_exists(&descriptor.digest)? {
+            info!(
+                "{} exists in {}, skipping",
+                descriptor.digest, self.endpoint
+            );
+            return Ok(false);
+        }
+
+        info!(
+            "uploading {} ({} bytes) to {}",
+            descriptor.digest, descriptor.size, self.endpoint
+        );
+        let start = self.endpoint.url("blobs/uploads/");
+        let response = self.send(|http| http.post(&start))?;
+        let response = expect(response, "POST", &start, StatusCode::ACCEPTED)?;
+        let mut location = self.location(&response)?;
+
+        let mut offset: u64 = 0;
+        loop {
+            let mut chunk = vec![];
+            content
+                .by_ref()
+                .take(self.chunk_size)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            let range = format!("{}-{}", offset, offset + chunk.len() as u64 - 1);
+            let url = location.clone();
+            let response = self.send(|http| {
+                http.patch(url.clone())
+                    .header(CONTENT_TYPE, OCTET_STREAM)
+                    .header(CONTENT_RANGE, &range)
+                    .body(chunk.clone())
+            })?;
+            let response = expect(response, "PATCH", url.as_str(), StatusCode::ACCEPTED)?;
+            location = self.location(&response)?;
+            offset += chunk.len() as u64;
+        }
+
+        location
+            .query_pairs_mut()
+            .append_pair("digest", &descriptor.digest);
+        let response = self.send(|http| http.put(location.clone()).body(Vec::new()))?;
+        expect(response, "PUT", location.as_str(), StatusCode::CREATED)?;
+        Ok(true)
+    }
+
+    /// Put the manifest under reference, a tag or a digest. Returns the digest of the
+    /// manifest.
+    pub fn put_manifest(
+        &mut self,
+        reference: &str,
+        media_type: &str,
+        content: &[u8],
+    ) -> Result<String> {
+        let url = self.endpoint.url(&format!("manifests/{}", reference));
+        let response = self.send(|http| {
+            http.put(&url)
+                .header(CONTENT_TYPE, media_type)
+                .body(content.to_vec())
+        })?;
+        expect(response, "PUT", &url, StatusCode::CREATED)?;
+        Ok(format!("sha256:{}", hex::encode(Sha256::digest(content))))
+    }
+}
+
+fn expect(response: Response, method: &str, url: &str, status: StatusCode) -> Result<Response> {
+    if response.status() == status {
+        return Ok(response);
+    }
+    let code = response.status().as_u16();
+    let body = response.text().unwrap_or_default();
+    Err(DistributionError::RequestFailed(
+        method.to_string(),
+        url.to_string(),
+        code,
+        body,
+    ))
+}
+
+/// Digest and size of content
+fn descriptor<R: Read>(media_type: &str, mut content: R) -> Result<Descriptor> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut content, &mut hasher)?;
+    Ok(Descriptor {
+        media_type: media_type.to_string(),
+        digest: format!("sha256:{}", hex::encode(hasher.finalize())),
+        size,
+        platform: None,
+        annotations: BTreeMap::new(),
+    })
+}
+
+/// Push the image of an OCI layout under tag. Returns the digest of the manifest.
+pub fn push_layout(client: &mut Client, image: &LayoutImage, tag: &str) -> Result<String> {
+    let mut blobs = vec![image.manifest.config.clone()];
+    blobs.extend(image.manifest.layers.iter().cloned());
+    // Only an annotation refers to the image manifest, it has to be pushed as well
+    if let Some(digest) = image.manifest.annotations.get(ANNOTATION_IMAGE_MANIFEST) {
+        let mut image_manifest = Descriptor {
+            media_type: MEDIA_TYPE_IMAGE_MANIFEST.to_string(),
+            digest: digest.clone(),
+            size: 0,
+            platform: None,
+            annotations: BTreeMap::new(),
+        };
+        image_manifest.size =
+            std::fs::metadata(oci::blob_path(&image.layout_dir, &image_manifest))?.len();
+        blobs.push(image_manifest);
+    }
+
+    for blob in &blobs {
+        let content = File::open(oci::blob_path(&image.layout_dir, blob))?;
+        client.push_blob(blob, content)?;
+    }
+
+    let content = std::fs::read(oci::blob_path(&image.layout_dir, &image.descriptor))?;
+    client.put_manifest(tag, &image.descriptor.media_type, &content)
+}
+
+/// Push the zfs stream file of an image as artifact under tag. The image manifest is
+/// the config of the artifact. Returns the digest of the manifest.
+pub fn push_dataset(
+    client: &mut Client,
+    file: &Path,
+    manifest: &ImageManifest,
+    tag: &str,
+) -> Result<String> {
+    let config = serde_json::to_vec(manifest)?;
+    let config_descriptor = descriptor(MEDIA_TYPE_IMAGE_MANIFEST, config.as_slice())?;
+    let mut layer = descriptor(MEDIA_TYPE_ZFS_STREAM, File::open(file)?)?;
+    if let Some(name) = file.file_name() {
+        layer.annotations.insert(
+            ANNOTATION_TITLE.to_string(),
+            name.to_string_lossy().to_string(),
+        );
+    }
+
+    client.push_blob(&config_descriptor, config.as_slice())?;
+    client.push_blob(&layer, File::open(file)?)?;
+
+    let mut annotations = BTreeMap::new();
+    annotations.insert(ANNOTATION_TITLE.to_string(), manifest.name.clone());
+    annotations.insert(ANNOTATION_VERSION.to_string(), manifest.version.clone());
+    annotations.insert(
+        ANNOTATION_CREATED.to_string(),
+        oci::rfc3339(manifest.created),
+    );
+    annotations.insert(
+        ANNOTATION_IMAGE_UUID.to_string(),
+        manifest.uuid.as_hyphenated().to_string(),
+    );
+    let artifact = Manifest {
+        schema_version: OCI_SCHEMA_VERSION,
+        media_type: Some(MEDIA_TYPE_MANIFEST.to_string()),
+        artifact_type: Some(MEDIA_TYPE_ZFS_ARTIFACT.to_string()),
+        config: config_descriptor,
+        layers: vec![layer],
+        annotations,
+    };
+    client.put_manifest(tag, MEDIA_TYPE_MANIFEST, &serde_json::to_vec(&artifact)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_challenge, push_dataset, push_layout, Client, Credentials, DistributionError,
+        Endpoint, MEDIA_TYPE_ZFS_ARTIFACT,
+    };
+    use crate::image::manifest::ImageManifest;
+    use crate::image::oci::{
+        self, blob_path, ContainerConfig, ImageConfiguration, LayoutReference, Manifest, RootFs,
+    };
+    use crate::mock_http::{MockServer, Request, Response};
+    use base64::Engine;
+    use miette::{IntoDiagnostic, Result};
+    use sha2::{Digest, Sha256};
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    const REPOSITORY: &str = "zones/web";
+    const USERNAME: &str = "builder";
+    const PASSWORD: &str = "secret";
+    const TOKEN: &str = "mock-token";
+
+    #[derive(Clone, Copy)]
+    enum MockAuth {
+        Basic,
+        Bearer,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        blobs: BTreeMap<String, Vec<u8>>,
+        uploads: Vec<Vec<u8>>,
+        manifests: BTreeMap<String, (String, Vec<u8>)>,
+        requests: Vec<String>,
+    }
+
+    /// Just enough of a distribution registry to push to
+    struct MockRegistry {
+        server: MockServer,
+        state: Arc<Mutex<MockState>>,
+    }
+
+    impl MockRegistry {
+        fn start(auth: MockAuth) -> Self {
+            let state = Arc::new(Mutex::new(MockState::default()));
+            let server_state = state.clone();
+            let server = MockServer::start(move |request| respond(request, auth, &server_state));
+            MockRegistry { server, state }
+        }
+
+        fn endpoint(&self, tag: Option<&str>) -> Endpoint {
+            Endpoint {
+                registry: self.server.address().to_string(),
+                repository: REPOSITORY.to_string(),
+                tag: tag.map(String::from),
+                insecure: true,
+            }
+        }
+
+        fn count(&self, method: &str) -> usize {
+            let state = self.state.lock().unwrap();
+            state
+                .requests
+                .iter()
+                .filter(|r| r.starts_with(method))
+                .count()
+        }
+    }
+
+    fn respond(request: &Request, auth: MockAuth, state: &Mutex<MockState>) -> Response {
+        let method = request.method.as_str();
+        let path = request.path();
+        let query = request.query();
+        let headers = &request.headers;
+        let basic = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", USERNAME, PASSWORD))
+        );
+        let authorization = headers.get("authorization");
+
+        if path == "/token" {
+            let scope = format!("repository:{}:pull,push", REPOSITORY);
+            if authorization != Some(&basic) || query.get("scope") != Some(&scope) {
+                return (403, vec![], vec![]);
+            }
+            let token = format!(r#"{{"token": "{}"}}"#, TOKEN);
+            return (200, vec![], token.into_bytes());
+        }
+
+        let (authorized, challenge) = match auth {
+            MockAuth::Basic => (
+                authorization == Some(&basic),
+                r#"Basic realm="mock""#.into(),
+            ),
+            MockAuth::Bearer => (
+                authorization == Some(&format!("Bearer {}", TOKEN)),
+                format!(
+                    r#"Bearer realm="http://{}/token",service="mock",scope="repository:{}:pull,push""#,
+                    request.header("host").cloned().unwrap_or_default(),
+                    REPOSITORY
+                ),
+            ),
+        };
+        if !authorized {
+            return (401, vec![("WWW-Authenticate", challenge)], vec![]);
+        }
+
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{} {}", method, path));
+        let path = match path.strip_prefix(&format!("/v2/{}/", REPOSITORY)) {
+            Some(path) => path,
+            None => return (404, vec![], vec![]),
+        };
+        let upload_location = |id: usize| {
+            (
+                "Location",
+                format!("/v2/{}/blobs/uploads/{}", REPOSITORY, id),
+            )
+        };
+
+        match (method, path.split_once('/')) {
+            ("HEAD", Some(("blobs", digest))) if state.blobs.contains_key(digest) => {
+                (200, vec![], vec![])
+            }
+            ("HEAD", Some(("blobs", _))) => (404, vec![], vec![]),
+            ("POST", Some(("blobs", "uploads/"))) => {
+                state.uploads.push(vec![]);
+                (202, vec![upload_location(state.uploads.len() - 1)], vec![])
+            }
+            ("PATCH", Some(("blobs", upload))) | ("PUT", Some(("blobs", upload))) => {
+                let id: usize = upload.trim_start_matches("uploads/").parse().unwrap();
+                let start = state.uploads[id].len();
+                if method == "PATCH" {
+                    let range = format!("{}-{}", start, start + request.body.len() - 1);
+                    if headers.get("content-range") != Some(&range) {
+                        return (416, vec![], vec![]);
+                    }
+                }
+                state.uploads[id].extend(&request.body);
+                if method == "PATCH" {
+                    return (202, vec![upload_location(id)], vec![]);
+                }
+
+                let content = state.uploads[id].clone();
+                let digest = format!("sha256:{}", hex::encode(Sha256::digest(&content)));
+                if query.get("digest") != Some(&digest) {
+                    return (400, vec![], b"DIGEST_INVALID".to_vec());
+                }
+                state.blobs.insert(digest, content);
+                (201, vec![], vec![])
+            }
+            ("PUT", Some(("manifests", reference))) => {
+                let media_type = headers.get("content-type").cloned().unwrap_or_default();
+                state
+                    .manifests
+                    .insert(reference.to_string(), (media_type, request.body.clone()));
+                (201, vec![], vec![])
+            }
+            _ => (405, vec![], vec![]),
+        }
+    }
+
+    fn credentials() -> Option<Credentials> {
+        Some(Credentials::Basic {
+            username: USERNAME.into(),
+            password: PASSWORD.into(),
+        })
+    }
+
+    fn image_manifest() -> ImageManifest {
+        let config = ImageConfiguration {
+            created: None,
+            author: None,
+            architecture: "amd64".into(),
+            os: "illumos".into(),
+            config: ContainerConfig::default(),
+            rootfs: RootFs {
+                kind: "layers".into(),
+                diff_ids: vec![],
+            },
+        };
+        ImageManifest::from_oci_config("zones/web", "1.0", &config)
+    }
+
+    #[test]
+    fn test_endpoint() -> Result<()> {
+        let url = "oci://localhost:5000/zones/web:1.0"
+            .parse()
+            .into_diagnostic()?;
+        let endpoint = Endpoint::from_url(&url)?;
+        assert_eq!("localhost:5000", endpoint.registry);
+        assert_eq!("zones/web", endpoint.repository);
+        assert_eq!(Some("1.0".to_string()), endpoint.tag);
+        assert_eq!("oci://localhost:5000/zones/web:1.0", endpoint.to_string());
+
+        let url = "oci://ghcr.io/openflowlabs/images/base"
+            .parse()
+            .into_diagnostic()?;
+        let endpoint = Endpoint::from_url(&url)?;
+        assert_eq!("ghcr.io", endpoint.registry);
+        assert_eq!("openflowlabs/images/base", endpoint.repository);
+        assert_eq!(None, endpoint.tag);
+
+        for invalid in [
+            "https://ghcr.io/base",
+            "oci://ghcr.io/",
+            "oci://ghcr.io/Base",
+        ] {
+            let url = invalid.parse().into_diagnostic()?;
+            assert!(matches!(
+                Endpoint::from_url(&url),
+                Err(DistributionError::InvalidEndpoint(_))
+            ));
+        }
+        let url = "oci://ghcr.io/base:-1".parse().into_diagnostic()?;
+        assert!(matches!(
+            Endpoint::from_url(&url),
+            Err(DistributionError::InvalidTag(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let challenge = parse_challenge(
+            r#"Bearer realm="https://auth.example.org/token",service="registry",scope="repository:zones/web:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!("bearer", challenge.scheme);
+        assert_eq!(
+            Some(&"repository:zones/web:pull,push".to_string()),
+            challenge.params.get("scope")
+        );
+        assert_eq!(
+            Some(&"registry".to_string()),
+            challenge.params.get("service")
+        );
+
+        let challenge = parse_challenge("Basic realm=registry").unwrap();
+        assert_eq!("basic", challenge.scheme);
+        assert_eq!(Some(&"registry".to_string()), challenge.params.get("realm"));
+        assert_eq!(None, parse_challenge(""));
+    }
+
+    #[test]
+    fn test_push_layout() -> Result<()> {
+        let root = tempfile::tempdir().into_diagnostic()?;
+        std::fs::write(root.path().join("motd"), "hello ".repeat(100)).into_diagnostic()?;
+        let out = tempfile::tempdir().into_diagnostic()?;
+        let layout = out.path().join("web.oci");
+        let manifest = image_manifest();
+        oci::write_layout(root.path(), &layout, &ContainerConfig::default(), &manifest)?;
+        let image = oci::read_layout(&LayoutReference {
+            path: layout.clone(),
+            reference: None,
+        })?;
+
+        let registry = MockRegistry::start(MockAuth::Bearer);
+        let mut client = Client::new(registry.endpoint(None), credentials())?;
+        client.chunk_size = 64;
+        let digest = push_layout(&mut client, &image, "1.0")?;
+        assert_eq!(image.descriptor.digest, digest);
+
+        let state = registry.state.lock().unwrap();
+        let (media_type, content) = &state.manifests["1.0"];
+        assert_eq!(&image.descriptor.media_type, media_type);
+        let pushed: Manifest = serde_json::from_slice(content).into_diagnostic()?;
+        // Config, layer and the image manifest the annotation points to
+        assert_eq!(3, state.blobs.len());
+        assert!(state.blobs.contains_key(&pushed.config.digest));
+        assert!(state
+            .blobs
+            .contains_key(&pushed.annotations[oci::ANNOTATION_IMAGE_MANIFEST]));
+        for blob in &pushed.layers {
+            let content = std::fs::read(blob_path(&layout, blob)).into_diagnostic()?;
+            assert_eq!(Some(&content), state.blobs.get(&blob.digest));
+        }
+        drop(state);
+
+        // Blobs the registry has are not uploaded again
+        let uploads = registry.count("POST");
+        assert!(registry.count("PATCH") > uploads);
+        push_layout(&mut client, &image, "latest")?;
+        assert_eq!(uploads, registry.count("POST"));
+        assert!(registry
+            .state
+            .lock()
+            .unwrap()
+            .manifests
+            .contains_key("latest"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_dataset() -> Result<()> {
+        let out = tempfile::tempdir().into_diagnostic()?;
+        let manifest = image_manifest();
+        let file = out.path().join(format!("{}.zfs.gz", manifest.uuid));
+        std::fs::write(&file, vec![7u8; 1000]).into_diagnostic()?;
+
+        let registry = MockRegistry::start(MockAuth::Basic);
+        let mut anonymous = Client::new(registry.endpoint(None), None)?;
+        assert!(matches!(
+            push_dataset(&mut anonymous, &file, &manifest, "1.0"),
+            Err(DistributionError::AuthenticationFailed(_, _))
+        ));
+
+        let mut client = Client::new(registry.endpoint(None), credentials())?;
+        push_dataset(&mut client, &file, &manifest, "1.0")?;
+
+        let state = registry.state.lock().unwrap();
+        let pushed: Manifest =
+            serde_json::from_slice(&state.manifests["1.0"].1).into_diagnostic()?;
+        assert_eq!(
+            Some(MEDIA_TYPE_ZFS_ARTIFACT.to_string()),
+            pushed.artifact_type
+        );
+        assert_eq!(
+            Some(&vec![7u8; 1000]),
+            state.blobs.get(&pushed.layers[0].digest)
+        );
+        let config: ImageManifest =
+            serde_json::from_slice(&state.blobs[&pushed.config.digest]).into_diagnostic()?;
+        assert_eq!(manifest.uuid, config.uuid);
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use super::manifest::{ImageManifest, ManifestError};
+use super::reference::{ImageReference, ReferenceError};
+use super::registry::{Registry, RegistryError};
+use super::resolve::{ImageCandidate, ResolveError, Resolver};
+use super::{IMAGES_PATH, ZFS, ZFS_TIMEOUT, ZONEIMAGE_DIR};
+use crate::get_zone_dataset;
+use common::info;
+use miette::Diagnostic;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum InstalledError {
+    #[error("no installed image matches {0}")]
+    NotFound(String),
+    #[error("image {0} is used by {1}")]
+    InUse(String, String),
+    #[error("could not parse zfs output: {0}")]
+    ParseError(String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+    #[error(transparent)]
+    ReferenceError(#[from] ReferenceError),
+    #[error(transparent)]
+    ResolveError(#[from] ResolveError),
+    #[error(transparent)]
+    RegistryError(#[from] RegistryError),
+    #[error(transparent)]
+    ManifestError(#[from] ManifestError),
+    #[error(transparent)]
+    UtilError(#[from] crate::util::UtilError),
+    #[error(transparent)]
+    OPCZoneError(#[from] crate::OPCZoneError),
+}
+
+type Result<T> = miette::Result<T, InstalledError>;
+
+/// An image on this host with what zfs knows about its datasets. Images registered
+/// under more than one name or version show up once for each.
+#[derive(Debug, Clone)]
+pub struct InstalledImage {
+    pub uuid: uuid::Uuid,
+    pub publisher: Option<String>,
+    pub name: String,
+    pub version: Option<String>,
+    /// Tags like latest pointing to the image
+    pub tags: Vec<String>,
+    /// False for images no name, version or tag points to anymore, like versions
+    /// replaced by a rebuild
+    pub registered: bool,
+    pub dataset: String,
+    /// Bytes used by the image datasets. None if the dataset is gone
+    pub size: Option<u64>,
+    /// Datasets cloned from any snapshot of the image, zones installed from it, builds
+    /// based on it and builds started from its build cache
+    pub clones: Vec<String>,
+    pub manifest: Option<ImageManifest>,
+}
+
+impl InstalledImage {
+    /// The image this one was built on, only known if it has a manifest
+    pub fn origin(&self) -> Option<uuid::Uuid> {
+        self.manifest.as_ref().and_then(|m| m.origin)
+    }
+
+    /// Name with version like zones/web@1.0
+    pub fn reference(&self) -> String {
+        let reference = ImageReference {
+            publisher: self.publisher.clone(),
+            name: self.name.clone(),
+            version: None,
+            tag: None,
+        };
+        match &self.version {
+            Some(version) => format!("{}@{}", reference, version),
+            None => reference.to_string(),
+        }
+    }
+}
+
+/// All registered images sorted by name and version followed by the images with a
+/// stored manifest that are not registered anymore
+pub fn list() -> Result<Vec<InstalledImage>> {
+    let parent_ds = get_zone_dataset(IMAGES_PATH)?;
+    let mut images = vec![];
+    for candidate in Resolver::default().local_candidates()? {
+        images.push(installed_image(&parent_ds, candidate)?);
+    }
+
+    let mut unregistered = vec![];
+    for manifest in ImageManifest::list_stored()? {
+        if !images.iter().any(|i| i.uuid == manifest.uuid) {
+            unregistered.push(unregistered_image(&parent_ds, manifest)?);
+        }
+    }
+    unregistered.sort_by(|a, b| a.name.cmp(&b.name).then(a.uuid.cmp(&b.uuid)));
+    images.extend(unregistered);
+    Ok(images)
+}
+
+/// The registered images with the uuid or the best match of an image reference
+/// like zones/web@1
+pub fn find(image: &str) -> Result<Vec<InstalledImage>> {
+    let uuid = match uuid::Uuid::parse_str(image) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            let reference: ImageReference = image.parse()?;
+            Resolver::default()
+                .resolve_local(&reference)?
+                .ok_or(InstalledError::NotFound(image.to_string()))?
+                .uuid
+        }
+    };
+
+    let found: Vec<InstalledImage> = list()?.into_iter().filter(|i| i.uuid == uuid).collect();
+    if found.is_empty() {
+        return Err(InstalledError::NotFound(image.to_string()));
+    }
+    Ok(found)
+}
+
+fn installed_image(parent_ds: &str, candidate: ImageCandidate) -> Result<InstalledImage> {
+    let dataset = format!("{}/{}", parent_ds, candidate.uuid.as_hyphenated());
+    let (size, clones) = dataset_usage(&dataset)?;
+
+    Ok(InstalledImage {
+        uuid: candidate.uuid,
+        publisher: candidate.publisher,
+        name: candidate.name,
+        version: candidate.version.map(|v| v.to_string()),
+        tags: candidate.tags,
+        registered: true,
+        dataset,
+        size,
+        clones,
+        manifest: ImageManifest::load(&candidate.uuid)?,
+    })
+}
+
+fn unregistered_image(parent_ds: &str, manifest: ImageManifest) -> Result<InstalledImage> {
+    let dataset = format!("{}/{}", parent_ds, manifest.uuid.as_hyphenated());
+    let (size, clones) = dataset_usage(&dataset)?;
+
+    Ok(InstalledImage {
+        uuid: manifest.uuid,
+        publisher: None,
+        name: manifest.name.clone(),
+        version: Some(manifest.version.clone()),
+        tags: vec![],
+        registered: false,
+        dataset,
+        size,
+        clones,
+        manifest: Some(manifest),
+    })
+}
+
+/// Bytes used by dataset and the clones of its snapshots, no size if the dataset does
+/// not exist
+fn dataset_usage(dataset: &str) -> Result<(Option<u64>, Vec<String>)> {
+    let used = crate::run_capture_stdout(&[ZFS, "list", "-H", "-p", "-o", "used", dataset], None);
+    match used {
+        Ok(used) => {
+            let size = used
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| InstalledError::ParseError(used.clone()))?;
+            Ok((Some(size), clones(dataset)?))
+        }
+        Err(err) if is_missing_dataset(&err) => Ok((None, vec![])),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Whether zfs failed because the dataset does not exist. Other failures like
+/// missing permissions must not look like a missing dataset.
+fn is_missing_dataset(err: &crate::OPCZoneError) -> bool {
+    matches!(
+        err,
+        crate::OPCZoneError::ProcessFailed(_, _, stderr)
+            if stderr.trim_end().ends_with("dataset does not exist")
+    )
+}
+
+/// Datasets cloned from the snapshots of dataset and its children
+fn clones(dataset: &str) -> Result<Vec<String>> {
+    let props = "name,clones";
+    let output = crate::run_capture_stdout(
+        &[
+            ZFS, "list", "-H", "-r", "-t", "snapshot", "-o", props, dataset,
+        ],
+        None,
+    )?;
+    parse_clones(&output)
+}
+
+/// Parse the output of `zfs list -H -r -t snapshot -o name,clones`, zfs lists the
+/// clones comma separated. Clones of every snapshot count, not only of the final
+/// one. A zfs promote moves the build cache snapshots of the base image over to the
+/// image built on it and zones may be cloned from any of those.
+pub fn parse_clones(output: &str) -> Result<Vec<String>> {
+    let mut clones = vec![];
+    for line in output.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (_, snapshot_clones) = line
+            .split_once('\t')
+            .ok_or(InstalledError::ParseError(line.to_string()))?;
+        if snapshot_clones == "-" {
+            continue;
+        }
+
+        clones.extend(
+            snapshot_clones
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(String::from),
+        );
+    }
+    Ok(clones)
+}
+
+/// Destroy the datasets of the image and everything registering it. Fails while
+/// datasets are cloned from the image.
+pub fn delete(uuid: &uuid::Uuid) -> Result<()> {
+    let images = find(&uuid.as_hyphenated().to_string())?;
+    let image = &images[0];
+    if !image.clones.is_empty() {
+        return Err(InstalledError::InUse(
+            image.reference(),
+            image.clones.join(", "),
+        ));
+    }
+
+    if image.size.is_some() {
+        info!("Destroying {}", image.dataset);
+        crate::run_with_timeout(&[ZFS, "destroy", "-r", &image.dataset], None, ZFS_TIMEOUT)?;
+    }
+    if image.registered {
+        Registry::update(Path::new(ZONEIMAGE_DIR), |registry| {
+            registry.unregister(uuid);
+            Ok(())
+        })?;
+    }
+    ImageManifest::delete(uuid)?;
+    Ok(())
+}
+
+/// Images no zone or build uses and no installed image is based on
+pub fn unused(images: &[InstalledImage]) -> Vec<uuid::Uuid> {
+    let mut unused: Vec<uuid::Uuid> = vec![];
+    for image in images {
+        /*
+         * Nothing may be cloned from the base of another image anymore once
+         * the other image got promoted. Rebuilds of the other image still
+         * need it so it is kept.
+         */
+        let used =
+            !image.clones.is_empty() || images.iter().any(|i| i.origin() == Some(image.uuid));
+        if !used && !unused.contains(&image.uuid) {
+            unused.push(image.uuid);
+        }
+    }
+    unused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_missing_dataset, parse_clones, unused, InstalledImage};
+    use crate::image::manifest::{ImageKind, ImageManifest};
+    use miette::Result;
+
+    #[test]
+    fn test_parse_clones() -> Result<()> {
+        let output = "\
+rpool/zones/6f1c@final\t-
+rpool/zones/6f1c/root@final\trpool/zones/web01/root,rpool/zones/web02/root
+rpool/zones/6f1c/root@build\trpool/zones/build/root
+rpool/zones/6f1c/root@buildcache-3f2a\trpool/zones/build2/root
+rpool/zones/6f1c/vroot@final\t
+";
+        assert_eq!(
+            vec![
+                "rpool/zones/web01/root".to_string(),
+                "rpool/zones/web02/root".to_string(),
+                "rpool/zones/build/root".to_string(),
+                "rpool/zones/build2/root".to_string()
+            ],
+            parse_clones(output)?
+        );
+        assert!(parse_clones("no tabs here").is_err());
+        Ok(())
+    }
+
+    fn image(name: &str, clones: &[&str], origin: Option<uuid::Uuid>) -> InstalledImage {
+        let uuid = uuid::Uuid::new_v4();
+        InstalledImage {
+            uuid,
+            publisher: None,
+            name: name.into(),
+            version: None,
+            tags: vec![],
+            registered: true,
+            dataset: format!("rpool/zones/{}", uuid),
+            size: Some(1024),
+            clones: clones.iter().map(|c| c.to_string()).collect(),
+            manifest: origin.map(|origin| ImageManifest {
+                uuid,
+                name: name.into(),
+                version: "1".into(),
+                author: None,
+                brand: crate::brand::Brand::Image,
+                kind: ImageKind::Zone,
+                origin: Some(origin),
+                created: 0,
+                bundle_hash: None,
+                source: None,
+                container: None,
+                volumes: vec![],
+                packages: vec![],
+                services: vec![],
+                files: vec![],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_unused() {
+        let base = image("zones/base", &[], None);
+        let web = image("zones/web", &["rpool/zones/web01/root"], Some(base.uuid));
+        let old = image("zones/old", &[], None);
+        let mut tagged = old.clone();
+        tagged.tags = vec!["latest".into()];
+        let mut replaced = image("zones/web", &[], Some(base.uuid));
+        replaced.registered = false;
+
+        assert_eq!(
+            vec![old.uuid, replaced.uuid],
+            unused(&[base, web, old, tagged, replaced])
+        );
+    }
+
+    #[test]
+    fn test_is_missing_dataset() {
+        let failed = |stderr: &str| {
+            crate::OPCZoneError::ProcessFailed(
+                String::from("/usr/sbin/zfs list"),
+                crate::ExitStatus::Exited(1),
+                stderr.to_string(),
+            )
+        };
+
+        assert!(is_missing_dataset(&failed(
+            "cannot open 'rpool/zones/6f1c': dataset does not exist\n"
+        )));
+        assert!(!is_missing_dataset(&failed(
+            "cannot open 'rpool/zones/6f1c': permission denied\n"
+        )));
+        assert!(!is_missing_dataset(
+            &crate::OPCZoneError::ProcessOutputError(String::from("/usr/sbin/zfs list"))
+        ));
+    }
+}
@@ -0,0 +1,439 @@
+use super::oci::{ContainerConfig, ImageConfiguration};
+use super::ZONEIMAGE_DIR;
+use crate::brand::Brand;
+use crate::build::bundle::{Bundle, BundleError};
+use crate::build::Action;
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsStr,
+    fs::File,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+const PKG: &str = "/usr/bin/pkg";
+/// Manifests of installed images are kept in this directory of ZONEIMAGE_DIR
+const MANIFEST_DIR: &str = "manifests";
+/// Exported images get their manifest written next to them with this suffix
+pub const MANIFEST_EXPORT_SUFFIX: &str = "manifest.json";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ManifestError {
+    #[error("invalid image manifest {0}: {1}")]
+    InvalidManifest(String, String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+    #[error(transparent)]
+    BundleError(#[from] BundleError),
+    #[error(transparent)]
+    OPCZoneError(#[from] crate::OPCZoneError),
+}
+
+type Result<T> = miette::Result<T, ManifestError>;
+
+/// What gets deployed from an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageKind {
+    Zone,
+    Vm,
+}
+
+/// Where an image came from and what is in it. Every built image gets one, it is
+/// stored with the image and written next to every export of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageManifest {
+    pub uuid: uuid::Uuid,
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub brand: Brand,
+    #[serde(rename = "type")]
+    pub kind: ImageKind,
+    /// The image this one was built on. Base images have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<uuid::Uuid>,
+    /// Seconds since the epoch
+    pub created: u64,
+    /// Content hash of the build bundle, see Bundle::content_hash. Imported images
+    /// were not built from a bundle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle_hash: Option<String>,
+    /// Where an imported image was imported from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// What runs in zones of the image, from the oci node of the build or the
+    /// config of an imported OCI image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<ContainerConfig>,
+    #[serde(default)]
+    pub volumes: Vec<ManifestVolume>,
+    #[serde(default)]
+    pub packages: Vec<Package>,
+    /// Fmris of the services the build enabled
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Files the image was exported to
+    #[serde(default)]
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestVolume {
+    pub name: String,
+    pub mountpoint: String,
+}
+
+/// An installed IPS package
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Package {
+    pub publisher: String,
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// File name without directory, exports are moved around
+    pub name: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl ImageManifest {
+    /// The manifest of an image built from bundle. uuid and packages are only known
+    /// once the image exists and are set by the caller.
+    pub fn from_bundle(bundle: &Bundle, origin: Option<uuid::Uuid>) -> Result<Self> {
+        let document = &bundle.document;
+        let volumes = document
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Volume(volume) => Some(ManifestVolume {
+                    name: volume.name.clone(),
+                    // Like the volume action does when it creates the dataset
+                    mountpoint: volume
+                        .mountpoint
+                        .clone()
+                        .unwrap_or(format!("/{}", volume.name)),
+                }),
+                _ => None,
+            })
+            .collect();
+        let audit = bundle.get_audit_info();
+
+        let mut manifest = ImageManifest {
+            uuid: uuid::Uuid::nil(),
+            name: document.name.clone(),
+            version: document.version.to_string(),
+            author: document.author.clone(),
+            brand: audit.kind().brand(),
+            kind: if audit.is_vm() {
+                ImageKind::Vm
+            } else {
+                ImageKind::Zone
+            },
+            origin,
+            created: now(),
+            bundle_hash: Some(bundle.content_hash()?),
+            source: None,
+            container: None,
+            volumes,
+            packages: vec![],
+            services: document.enabled_services(),
+            files: vec![],
+        };
+        manifest.container = document
+            .oci
+            .as_ref()
+            .map(|config| ContainerConfig::new(config, &manifest));
+        Ok(manifest)
+    }
+
+    /// The manifest of an image imported from an OCI image with config. Volumes of
+    /// the OCI image are named after their mountpoint.
+    pub fn from_oci_config(name: &str, version: &str, config: &ImageConfiguration) -> Self {
+        let volumes = config
+            .config
+            .volumes
+            .keys()
+            .map(|mountpoint| ManifestVolume {
+                name: mountpoint.trim_matches('/').replace('/', "-"),
+                mountpoint: mountpoint.clone(),
+            })
+            .collect();
+
+        ImageManifest {
+            uuid: uuid::Uuid::nil(),
+            name: name.to_string(),
+            version: version.to_string(),
+            author: config.author.clone(),
+            brand: Brand::Image,
+            kind: ImageKind::Zone,
+            origin: None,
+            created: now(),
+            bundle_hash: None,
+            source: None,
+            container: Some(config.config.clone()),
+            volumes,
+            packages: vec![],
+            services: vec![],
+            files: vec![],
+        }
+    }
+
+    /// Record an exported file with its checksum and size
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.files.retain(|f| f.name != name);
+        self.files.push(ManifestFile {
+            name,
+            sha256: hex::encode(hasher.finalize()),
+            size,
+        });
+        Ok(())
+    }
+
+    /// Store the manifest with the installed image
+    pub fn store(&self) -> Result<PathBuf> {
+        let path = manifest_path(Path::new(ZONEIMAGE_DIR), &self.uuid);
+        self.write_to(&path)?;
+        Ok(path)
+    }
+
+    /// Write the manifest next to the exports in output_dir
+    pub fn export_to(&self, output_dir: &Path) -> Result<PathBuf> {
+        let path = output_dir.join(format!(
+            "{}.{}",
+            self.uuid.as_hyphenated(),
+            MANIFEST_EXPORT_SUFFIX
+        ));
+        self.write_to(&path)?;
+        Ok(path)
+    }
+
+    /// Readers never see a half written manifest
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let partial = path.with_extension("partial");
+        let file = File::create(&partial)?;
+        serde_json::to_writer_pretty(file, self)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// The manifest of an installed image. Images built before manifests existed
+    /// have none
+    pub fn load(uuid: &uuid::Uuid) -> Result<Option<Self>> {
+        Self::load_from(Path::new(ZONEIMAGE_DIR), uuid)
+    }
+
+    /// Like load with the manifests kept in image_dir
+    pub fn load_from(image_dir: &Path, uuid: &uuid::Uuid) -> Result<Option<Self>> {
+        let path = manifest_path(image_dir, uuid);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::read_from(&path).map(Some)
+    }
+
+    /// The manifests of all images on this host, registered or not
+    pub fn list_stored() -> Result<Vec<Self>> {
+        let dir = Path::new(ZONEIMAGE_DIR).join(MANIFEST_DIR);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut manifests = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("json")) {
+                manifests.push(Self::read_from(&path)?);
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Remove the stored manifest of an image that gets deleted
+    pub fn delete(uuid: &uuid::Uuid) -> Result<()> {
+        let path = manifest_path(Path::new(ZONEIMAGE_DIR), uuid);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        serde_json::from_reader(file)
+            .map_err(|e| ManifestError::InvalidManifest(path.display().to_string(), e.to_string()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn manifest_path(image_dir: &Path, uuid: &uuid::Uuid) -> PathBuf {
+    image_dir
+        .join(MANIFEST_DIR)
+        .join(uuid.as_hyphenated().to_string())
+        .with_extension("json")
+}
+
+/// The packages installed in the image at root
+pub fn installed_packages(root: &Path) -> Result<Vec<Package>> {
+    let root = root.to_string_lossy();
+    let output = crate::run_capture_stdout(&[PKG, "-R", &root, "list", "-H", "-v"], None)?;
+    Ok(parse_pkg_list(&output))
+}
+
+/// Parse the output of pkg list -H -v. Every line starts with the fmri of a package
+/// like pkg://openindiana.org/web/server/nginx@1.24.0,5.11-2023.0.0.0:20230601T101010Z
+pub fn parse_pkg_list(output: &str) -> Vec<Package> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|fmri| {
+            let rest = fmri.strip_prefix("pkg://")?;
+            let (publisher, rest) = rest.split_once('/')?;
+            let (name, version) = rest.split_once('@')?;
+            Some(Package {
+                publisher: publisher.to_string(),
+                name: name.to_string(),
+                version: version.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_pkg_list, ImageKind, ImageManifest, Package};
+    use crate::brand::Brand;
+    use crate::build::bundle::Bundle;
+    use crate::image::oci::ImageConfiguration;
+    use miette::{IntoDiagnostic, Result};
+
+    #[test]
+    fn test_manifest() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("build.kdl"),
+            r#"name "zones/web"
+author "ops"
+base-on "zones/base"
+volume "data" {
+    mountpoint "/var/www"
+}
+service "network/http/nginx"
+"#,
+        )
+        .into_diagnostic()?;
+        let bundle = Bundle::new(dir.path())?;
+        let origin = uuid::Uuid::new_v4();
+
+        let mut manifest = ImageManifest::from_bundle(&bundle, Some(origin))?;
+        manifest.uuid = uuid::Uuid::new_v4();
+        assert_eq!("zones/web", manifest.name);
+        assert_eq!(Some("ops".into()), manifest.author);
+        assert_eq!(Brand::Image, manifest.brand);
+        assert_eq!(ImageKind::Zone, manifest.kind);
+        assert_eq!(Some(origin), manifest.origin);
+        assert_eq!(Some(bundle.content_hash()?), manifest.bundle_hash);
+        assert_eq!(None, manifest.container);
+        assert_eq!("/var/www", manifest.volumes[0].mountpoint);
+        assert_eq!(
+            vec!["svc:/network/http/nginx:default".to_string()],
+            manifest.services
+        );
+
+        let export = dir.path().join("image.zfs.gz");
+        std::fs::write(&export, "zfs stream").into_diagnostic()?;
+        manifest.add_file(&export)?;
+        manifest.add_file(&export)?;
+        assert_eq!(1, manifest.files.len());
+        assert_eq!("image.zfs.gz", manifest.files[0].name);
+        assert_eq!(10, manifest.files[0].size);
+
+        let path = manifest.export_to(dir.path())?;
+        assert_eq!(
+            format!("{}.manifest.json", manifest.uuid.as_hyphenated()),
+            path.file_name().unwrap().to_string_lossy()
+        );
+        assert_eq!(manifest, ImageManifest::read_from(&path)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_oci_config() -> Result<()> {
+        let config: ImageConfiguration = serde_json::from_str(
+            r#"{
+                "architecture": "amd64",
+                "os": "illumos",
+                "author": "ops",
+                "config": {
+                    "Entrypoint": ["/usr/sbin/nginx"],
+                    "Env": ["PATH=/usr/bin"],
+                    "User": "webservd",
+                    "WorkingDir": "/var/www",
+                    "Volumes": {"/var/lib/nginx": {}}
+                },
+                "rootfs": {"type": "layers", "diff_ids": []}
+            }"#,
+        )
+        .into_diagnostic()?;
+
+        let manifest = ImageManifest::from_oci_config("nginx", "1.25", &config);
+        assert_eq!("1.25", manifest.version);
+        assert_eq!(Some("ops".into()), manifest.author);
+        assert_eq!(None, manifest.bundle_hash);
+        assert_eq!("var-lib-nginx", manifest.volumes[0].name);
+        let container = manifest.container.unwrap();
+        assert_eq!(vec!["/usr/sbin/nginx".to_string()], container.entrypoint);
+        assert_eq!(Some("webservd".into()), container.user);
+        assert_eq!(Some("/var/www".into()), container.working_dir);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pkg_list() {
+        let output = "\
+pkg://openindiana.org/web/server/nginx@1.24.0,5.11-2023.0.0.0:20230601T101010Z    i--
+pkg://openindiana.org/SUNWcs@0.5.11-2023.0.0.21000:20230401T000000Z               i--
+
+not a package
+";
+        assert_eq!(
+            vec![
+                Package {
+                    publisher: "openindiana.org".into(),
+                    name: "web/server/nginx".into(),
+                    version: "1.24.0,5.11-2023.0.0.0:20230601T101010Z".into(),
+                },
+                Package {
+                    publisher: "openindiana.org".into(),
+                    name: "SUNWcs".into(),
+                    version: "0.5.11-2023.0.0.21000:20230401T000000Z".into(),
+                },
+            ],
+            parse_pkg_list(output)
+        );
+    }
+}
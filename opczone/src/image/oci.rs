@@ -0,0 +1,1340 @@
+use super::manifest::ImageManifest;
+use crate::build::paths::{self, PathError};
+use crate::build::OciConfig;
+use common::{debug, info};
+use flate2::{write::GzEncoder, Compression};
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs::File,
+    io::{BufReader, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Prefix of import sources which are OCI image layouts
+pub const OCI_TRANSPORT: &str = "oci:";
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+pub const OCI_INDEX_FILE: &str = "index.json";
+pub const OCI_BLOBS_DIR: &str = "blobs";
+pub const OCI_LAYOUT_VERSION: &str = "1.0.0";
+pub const OCI_SCHEMA_VERSION: u32 = 2;
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MEDIA_TYPE_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+/// Our image manifest stored as blob of the layout
+pub const MEDIA_TYPE_IMAGE_MANIFEST: &str = "application/vnd.openflowlabs.image.manifest.v1+json";
+
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+pub const ANNOTATION_TITLE: &str = "org.opencontainers.image.title";
+pub const ANNOTATION_VERSION: &str = "org.opencontainers.image.version";
+pub const ANNOTATION_CREATED: &str = "org.opencontainers.image.created";
+/// Digest of the image manifest blob
+pub const ANNOTATION_IMAGE_MANIFEST: &str = "org.openflowlabs.image.manifest";
+pub const ANNOTATION_IMAGE_UUID: &str = "org.openflowlabs.image.uuid";
+
+/// Zone images only run on illumos
+const OCI_OS: &str = "illumos";
+const DEFAULT_PATH: &str = "/usr/bin:/usr/sbin:/sbin";
+/// A file named .wh.<name> in a layer removes <name> of the layers below
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Hides everything the layers below put into the directory of this file
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+/// Extended attributes are written as pax records with this prefix like GNU tar does
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum OciError {
+    #[error("{0} is not an oci image source, use oci:<layout>[:<tag>]")]
+    InvalidReference(String),
+    #[error("invalid oci layout {0}: {1}")]
+    InvalidLayout(String, String),
+    #[error("no image in {0} matches, tags in the layout are: {1}")]
+    NoMatchingManifest(String, String),
+    #[error("media type {0} is not supported")]
+    UnsupportedMediaType(String),
+    #[error("digest {0} is not supported, only sha256 digests are")]
+    UnsupportedDigest(String),
+    #[error("blob {0} has digest {1}")]
+    DigestMismatch(String, String),
+    #[error("hardlink {1} in layer {0} has no target")]
+    InvalidLayer(String, String),
+    #[error(transparent)]
+    PathError(#[from] PathError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+}
+
+type Result<T> = miette::Result<T, OciError>;
+
+/// A reference to a blob of the layout
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    /// Hex part of the digest which is also the file name of the blob
+    pub fn hex(&self) -> &str {
+        self.digest
+            .split_once(':')
+            .map(|(_, hex)| hex)
+            .unwrap_or(&self.digest)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+}
+
+impl Platform {
+    /// The platform of this host in the names OCI uses
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            x => x,
+        };
+        Platform {
+            architecture: architecture.to_string(),
+            os: OCI_OS.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciLayout {
+    pub image_layout_version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Index {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    /// Set for artifacts which are not container images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageConfiguration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub architecture: String,
+    pub os: String,
+    #[serde(default)]
+    pub config: ContainerConfig,
+    pub rootfs: RootFs,
+}
+
+/// Serialized as {} which is how OCI writes sets
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Empty {}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exposed_ports: BTreeMap<String, Empty>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entrypoint: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub volumes: BTreeMap<String, Empty>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+}
+
+impl ContainerConfig {
+    /// The container config of an image built with config. Volumes of the image become
+    /// OCI volumes, PATH is set unless config sets it.
+    pub fn new(config: &OciConfig, manifest: &ImageManifest) -> Self {
+        let mut env: Vec<String> = config
+            .env
+            .iter()
+            .map(|e| format!("{}={}", e.name, e.value))
+            .collect();
+        if !config.env.iter().any(|e| e.name == "PATH") {
+            env.insert(0, format!("PATH={}", DEFAULT_PATH));
+        }
+
+        ContainerConfig {
+            user: config.user.clone(),
+            exposed_ports: config
+                .ports
+                .iter()
+                .map(|p| (p.to_string(), Empty {}))
+                .collect(),
+            env,
+            entrypoint: config.entrypoint.clone(),
+            cmd: config.cmd.clone(),
+            volumes: manifest
+                .volumes
+                .iter()
+                .map(|v| (v.mountpoint.clone(), Empty {}))
+                .collect(),
+            working_dir: config.workdir.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub diff_ids: Vec<String>,
+}
+
+/// A layer written to the blobs of a layout
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub descriptor: Descriptor,
+    /// Digest of the uncompressed tar the config lists in rootfs
+    pub diff_id: String,
+    pub path: PathBuf,
+}
+
+/// Counts and hashes everything written through it
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> (W, String, u64) {
+        let digest = format!("sha256:{}", hex::encode(self.hasher.finalize()));
+        (self.inner, digest, self.size)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn blobs_dir(layout_dir: &Path) -> PathBuf {
+    layout_dir.join(OCI_BLOBS_DIR).join("sha256")
+}
+
+/// Path of the blob descriptor points to in layout_dir
+pub fn blob_path(layout_dir: &Path, descriptor: &Descriptor) -> PathBuf {
+    blobs_dir(layout_dir).join(descriptor.hex())
+}
+
+fn write_blob(layout_dir: &Path, media_type: &str, content: &[u8]) -> Result<Descriptor> {
+    let hex = hex::encode(Sha256::digest(content));
+    std::fs::write(blobs_dir(layout_dir).join(&hex), content)?;
+    Ok(Descriptor {
+        media_type: media_type.to_string(),
+        digest: format!("sha256:{}", hex),
+        size: content.len() as u64,
+        platform: None,
+        annotations: BTreeMap::new(),
+    })
+}
+
+/// Write the content of root as gzip compressed tar layer to the blobs of layout_dir.
+/// Owners, modes, hardlinks, device nodes and extended attributes are kept.
+pub fn write_layer(root: &Path, layout_dir: &Path) -> Result<Layer> {
+    let blobs = blobs_dir(layout_dir);
+    std::fs::create_dir_all(&blobs)?;
+    let partial = blobs.join(".layer.partial");
+
+    info!("writing layer of {}", root.display());
+    let file = HashWriter::new(File::create(&partial)?);
+    let tar = HashWriter::new(GzEncoder::new(file, Compression::default()));
+    let mut builder = tar::Builder::new(tar);
+    let mut links = HashMap::new();
+    append_dir(&mut builder, root, Path::new(""), &mut links)?;
+
+    let (gzip, diff_id, _) = builder.into_inner()?.finish();
+    let (file, digest, size) = gzip.finish()?.finish();
+    file.sync_all()?;
+
+    let descriptor = Descriptor {
+        media_type: MEDIA_TYPE_LAYER_GZIP.to_string(),
+        digest,
+        size,
+        platform: None,
+        annotations: BTreeMap::new(),
+    };
+    let path = blob_path(layout_dir, &descriptor);
+    std::fs::rename(&partial, &path)?;
+    debug!("layer {} has diff id {}", descriptor.digest, diff_id);
+
+    Ok(Layer {
+        descriptor,
+        diff_id,
+        path,
+    })
+}
+
+/*
+ * Entries are written sorted by name so the same tree always gives the same
+ * layer. Files with more than one link are written once, later paths become
+ * hardlinks to the first one.
+ */
+fn append_dir<W: Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    relative: &Path,
+    links: &mut HashMap<(u64, u64), PathBuf>,
+) -> Result<()> {
+    let dir = root.join(relative);
+    let mut entries = std::fs::read_dir(&dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = relative.join(entry.file_name());
+        let path = entry.path();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+
+        append_xattrs(builder, &path, &name, &metadata)?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+        // The mode field only holds the permission bits, the type has its own field
+        header.set_mode(metadata.mode() & 0o7777);
+
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            builder.append_link(&mut header, &name, target)?;
+        } else if file_type.is_dir() {
+            builder.append_data(&mut header, &name, std::io::empty())?;
+            append_dir(builder, root, &name, links)?;
+        } else if file_type.is_file() {
+            let key = (metadata.dev(), metadata.ino());
+            match links.get(&key) {
+                Some(first) if metadata.nlink() > 1 => {
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, &name, first)?;
+                }
+                _ => {
+                    if metadata.nlink() > 1 {
+                        links.insert(key, name.clone());
+                    }
+                    builder.append_data(&mut header, &name, File::open(&path)?)?;
+                }
+            }
+        } else if header.entry_type().is_character_special()
+            || header.entry_type().is_block_special()
+        {
+            let (major, minor) = device_numbers(metadata.rdev());
+            header.set_device_major(major)?;
+            header.set_device_minor(minor)?;
+            builder.append_data(&mut header, &name, std::io::empty())?;
+        } else if header.entry_type().is_fifo() {
+            builder.append_data(&mut header, &name, std::io::empty())?;
+        } else {
+            debug!("skipping socket {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn append_xattrs<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<()> {
+    let mut records = vec![];
+    for (attribute, value) in read_xattrs(path, metadata)? {
+        let key = format!("{}{}", PAX_XATTR_PREFIX, attribute);
+        records.extend(pax_record(&key, &value));
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    // The pax header applies to the entry written right after it
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(records.len() as u64);
+    let pax_name: String = format!("PaxHeaders/{}", name.display())
+        .chars()
+        .take(99)
+        .collect();
+    header.set_path(pax_name)?;
+    header.set_cksum();
+    builder.append(&header, records.as_slice())?;
+    Ok(())
+}
+
+/*
+ * On illumos extended attributes are files in a hidden attribute directory of
+ * the file, see fsattr(7). The directory is opened with O_XATTR relative to the
+ * file and its entries are read like regular files.
+ */
+#[cfg(target_os = "illumos")]
+fn read_xattrs(path: &Path, metadata: &std::fs::Metadata) -> Result<Vec<(String, Vec<u8>)>> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    // Opening devices and fifos has side effects, symlinks are never followed
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let file = File::open(path)?;
+    let attr_dir = unsafe {
+        libc::openat(
+            file.as_raw_fd(),
+            c".".as_ptr(),
+            libc::O_RDONLY | libc::O_XATTR,
+        )
+    };
+    if attr_dir < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            // The file system has no extended attributes
+            Some(libc::EINVAL) | Some(libc::ENOTSUP) | Some(libc::ENOENT) => Ok(vec![]),
+            _ => Err(err.into()),
+        };
+    }
+
+    let dir = unsafe { libc::fdopendir(attr_dir) };
+    if dir.is_null() {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::close(attr_dir) };
+        return Err(err.into());
+    }
+    let mut names = vec![];
+    loop {
+        let entry = unsafe { libc::readdir(dir) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        names.push(CString::from(name));
+    }
+    unsafe { libc::closedir(dir) };
+
+    // The system attribute views hold flags like immutable, not user data
+    names.retain(|name| {
+        !matches!(
+            name.to_bytes(),
+            b"." | b".." | b"SUNWattr_ro" | b"SUNWattr_rw"
+        )
+    });
+    names.sort();
+
+    let mut attributes = vec![];
+    for name in names {
+        let fd = unsafe {
+            libc::openat(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_XATTR,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut value = vec![];
+        unsafe { File::from_raw_fd(fd) }.read_to_end(&mut value)?;
+        attributes.push((name.to_string_lossy().to_string(), value));
+    }
+    Ok(attributes)
+}
+
+#[cfg(not(target_os = "illumos"))]
+fn read_xattrs(path: &Path, _metadata: &std::fs::Metadata) -> Result<Vec<(String, Vec<u8>)>> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(vec![]);
+    }
+
+    let mut attributes = vec![];
+    for attribute in xattr::list(path)? {
+        if let Some(value) = xattr::get(path, &attribute)? {
+            attributes.push((attribute.to_string_lossy().to_string(), value));
+        }
+    }
+    Ok(attributes)
+}
+
+/// A pax record is "<length> <key>=<value>\n" where length counts the whole record
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + rest.to_string().len();
+    // Adding the digits of the length can add another digit
+    if length.to_string().len() + rest > length {
+        length += 1;
+    }
+
+    let mut record = format!("{} {}=", length, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+#[cfg(target_os = "illumos")]
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    ((rdev >> 32) as u32, rdev as u32)
+}
+
+#[cfg(not(target_os = "illumos"))]
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Write root as OCI image layout to layout_dir. The layout has a single manifest with
+/// one layer, containers run from it are configured by container and the image
+/// manifest is stored as blob referenced by an annotation of the manifest.
+pub fn write_layout(
+    root: &Path,
+    layout_dir: &Path,
+    container: &ContainerConfig,
+    manifest: &ImageManifest,
+) -> Result<Layer> {
+    if layout_dir.exists() {
+        std::fs::remove_dir_all(layout_dir)?;
+    }
+    let layer = write_layer(root, layout_dir)?;
+
+    let image_manifest = write_blob(
+        layout_dir,
+        MEDIA_TYPE_IMAGE_MANIFEST,
+        &serde_json::to_vec_pretty(manifest)?,
+    )?;
+
+    let platform = Platform::host();
+    let created = rfc3339(manifest.created);
+    let image_config = ImageConfiguration {
+        created: Some(created.clone()),
+        author: manifest.author.clone(),
+        architecture: platform.architecture.clone(),
+        os: platform.os.clone(),
+        config: container.clone(),
+        rootfs: RootFs {
+            kind: "layers".to_string(),
+            diff_ids: vec![layer.diff_id.clone()],
+        },
+    };
+    let config_descriptor = write_blob(
+        layout_dir,
+        MEDIA_TYPE_CONFIG,
+        &serde_json::to_vec(&image_config)?,
+    )?;
+
+    let mut annotations = BTreeMap::new();
+    annotations.insert(ANNOTATION_TITLE.to_string(), manifest.name.clone());
+    annotations.insert(ANNOTATION_VERSION.to_string(), manifest.version.clone());
+    annotations.insert(ANNOTATION_CREATED.to_string(), created);
+    annotations.insert(
+        ANNOTATION_IMAGE_UUID.to_string(),
+        manifest.uuid.as_hyphenated().to_string(),
+    );
+    annotations.insert(
+        ANNOTATION_IMAGE_MANIFEST.to_string(),
+        image_manifest.digest.clone(),
+    );
+    let oci_manifest = Manifest {
+        schema_version: OCI_SCHEMA_VERSION,
+        media_type: Some(MEDIA_TYPE_MANIFEST.to_string()),
+        artifact_type: None,
+        config: config_descriptor,
+        layers: vec![layer.descriptor.clone()],
+        annotations,
+    };
+    let mut manifest_descriptor = write_blob(
+        layout_dir,
+        MEDIA_TYPE_MANIFEST,
+        &serde_json::to_vec(&oci_manifest)?,
+    )?;
+    manifest_descriptor.platform = Some(platform);
+    manifest_descriptor
+        .annotations
+        .insert(ANNOTATION_REF_NAME.to_string(), manifest.version.clone());
+
+    let index = Index {
+        schema_version: OCI_SCHEMA_VERSION,
+        media_type: Some(MEDIA_TYPE_INDEX.to_string()),
+        manifests: vec![manifest_descriptor],
+    };
+    std::fs::write(
+        layout_dir.join(OCI_INDEX_FILE),
+        serde_json::to_vec_pretty(&index)?,
+    )?;
+    std::fs::write(
+        layout_dir.join(OCI_LAYOUT_FILE),
+        serde_json::to_vec(&OciLayout {
+            image_layout_version: OCI_LAYOUT_VERSION.to_string(),
+        })?,
+    )?;
+
+    Ok(layer)
+}
+
+/// Where to import an OCI image from. Like skopeo the path of the layout can be
+/// followed by the tag of the image: oci:/var/tmp/nginx.oci:1.25
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutReference {
+    pub path: PathBuf,
+    pub reference: Option<String>,
+}
+
+impl FromStr for LayoutReference {
+    type Err = OciError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix(OCI_TRANSPORT)
+            .ok_or(OciError::InvalidReference(s.to_string()))?;
+
+        // Tags can not contain a / so a colon followed by a path belongs to the path
+        let (path, reference) = match rest.rsplit_once(':') {
+            Some((path, reference)) if !reference.contains('/') => (path, Some(reference)),
+            _ => (rest, None),
+        };
+        if path.is_empty() || reference == Some("") {
+            return Err(OciError::InvalidReference(s.to_string()));
+        }
+
+        Ok(LayoutReference {
+            path: PathBuf::from(path),
+            reference: reference.map(String::from),
+        })
+    }
+}
+
+impl Display for LayoutReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", OCI_TRANSPORT, self.path.display())?;
+        if let Some(reference) = &self.reference {
+            write!(f, ":{}", reference)?;
+        }
+        Ok(())
+    }
+}
+
+/// An image of a layout with its manifest and config read and verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutImage {
+    pub layout_dir: PathBuf,
+    /// The entry of index.json pointing to the manifest
+    pub descriptor: Descriptor,
+    pub manifest: Manifest,
+    pub config: ImageConfiguration,
+}
+
+impl LayoutImage {
+    /// The tag of the image in index.json
+    pub fn reference(&self) -> Option<&str> {
+        self.descriptor
+            .annotations
+            .get(ANNOTATION_REF_NAME)
+            .map(|r| r.as_str())
+    }
+
+    /// The image manifest write_layout stores in the layout. Copies of the layout
+    /// made by other tools may have dropped it as no layer references it.
+    pub fn image_manifest(&self) -> Result<Option<ImageManifest>> {
+        let descriptor = match self.manifest.annotations.get(ANNOTATION_IMAGE_MANIFEST) {
+            Some(digest) => Descriptor {
+                media_type: MEDIA_TYPE_IMAGE_MANIFEST.to_string(),
+                digest: digest.clone(),
+                size: 0,
+                platform: None,
+                annotations: BTreeMap::new(),
+            },
+            None => return Ok(None),
+        };
+        if !blob_path(&self.layout_dir, &descriptor).exists() {
+            return Ok(None);
+        }
+        let content = read_blob(&self.layout_dir, &descriptor)?;
+        Ok(Some(serde_json::from_slice(&content)?))
+    }
+}
+
+/// Read the image reference points to. Layouts with a single image need no tag,
+/// otherwise the image for the platform of this host is taken.
+pub fn read_layout(reference: &LayoutReference) -> Result<LayoutImage> {
+    let layout_dir = &reference.path;
+    let invalid = |reason: &str| {
+        OciError::InvalidLayout(layout_dir.display().to_string(), reason.to_string())
+    };
+
+    let layout: OciLayout = serde_json::from_slice(
+        &std::fs::read(layout_dir.join(OCI_LAYOUT_FILE))
+            .map_err(|_| invalid("no oci-layout file"))?,
+    )?;
+    if !layout.image_layout_version.starts_with("1.") {
+        return Err(invalid(&format!(
+            "unsupported layout version {}",
+            layout.image_layout_version
+        )));
+    }
+    let index: Index = serde_json::from_slice(&std::fs::read(layout_dir.join(OCI_INDEX_FILE))?)?;
+
+    let references = || {
+        index
+            .manifests
+            .iter()
+            .filter_map(|m| m.annotations.get(ANNOTATION_REF_NAME).cloned())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let candidates: Vec<&Descriptor> = match &reference.reference {
+        Some(tag) => index
+            .manifests
+            .iter()
+            .filter(|m| m.annotations.get(ANNOTATION_REF_NAME) == Some(tag))
+            .collect(),
+        None if index.manifests.len() == 1 => index.manifests.iter().collect(),
+        None => {
+            let host = Platform::host();
+            index
+                .manifests
+                .iter()
+                .filter(|m| m.platform.as_ref() == Some(&host))
+                .collect()
+        }
+    };
+    let descriptor = match candidates.as_slice() {
+        [descriptor] => (*descriptor).clone(),
+        _ => {
+            return Err(OciError::NoMatchingManifest(
+                reference.to_string(),
+                references(),
+            ))
+        }
+    };
+    if descriptor.media_type != MEDIA_TYPE_MANIFEST {
+        return Err(OciError::UnsupportedMediaType(descriptor.media_type));
+    }
+
+    let manifest: Manifest = serde_json::from_slice(&read_blob(layout_dir, &descriptor)?)?;
+    let config: ImageConfiguration =
+        serde_json::from_slice(&read_blob(layout_dir, &manifest.config)?)?;
+
+    Ok(LayoutImage {
+        layout_dir: layout_dir.clone(),
+        descriptor,
+        manifest,
+        config,
+    })
+}
+
+/// Content of a blob after checking it against its digest
+fn read_blob(layout_dir: &Path, descriptor: &Descriptor) -> Result<Vec<u8>> {
+    let content = std::fs::read(blob_path(layout_dir, descriptor))?;
+    check_digest(descriptor, &content[..])?;
+    Ok(content)
+}
+
+/// Fails unless content hashes to the digest of descriptor
+pub fn check_digest<R: Read>(descriptor: &Descriptor, mut content: R) -> Result<()> {
+    if !descriptor.digest.starts_with("sha256:") {
+        return Err(OciError::UnsupportedDigest(descriptor.digest.clone()));
+    }
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut content, &mut hasher)?;
+    let actual = format!("sha256:{}", hex::encode(hasher.finalize()));
+    if actual != descriptor.digest {
+        return Err(OciError::DigestMismatch(descriptor.digest.clone(), actual));
+    }
+    Ok(())
+}
+
+fn layer_reader(path: &Path, media_type: &str) -> Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    // Docker uses .tar.gzip and tar.gzip instead of tar+gzip
+    Ok(if media_type.ends_with("gzip") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if media_type.ends_with("zstd") {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else if media_type.ends_with("tar") {
+        Box::new(file)
+    } else {
+        return Err(OciError::UnsupportedMediaType(media_type.to_string()));
+    })
+}
+
+/// A tar archive which unpacks like tar -p as root: mode, owner, mtime and the
+/// extended attributes of the entries are kept.
+pub(crate) fn unpack_archive<R: Read>(reader: R) -> tar::Archive<R> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    /*
+     * tar fails the entry when an attribute can not be set, which is every
+     * attribute on illumos. They are dropped there like on export.
+     */
+    archive.set_unpack_xattrs(xattr::SUPPORTED_PLATFORM);
+    archive
+}
+
+/// Apply the layer of layout_dir described by layer on top of target. Whiteouts
+/// remove what lower layers put there before the entries of the layer get unpacked.
+/// Every path is resolved inside target, symlinks of lower layers can not be used to
+/// write outside of it.
+pub fn apply_layer(layout_dir: &Path, layer: &Descriptor, target: &Path) -> Result<()> {
+    let blob = blob_path(layout_dir, layer);
+    check_digest(layer, File::open(&blob)?)?;
+    info!("applying layer {}", layer.digest);
+
+    /*
+     * Whiteouts only hide what lower layers contain. An opaque directory keeps
+     * the entries the same layer adds to it no matter in which order they
+     * appear in the tar. Removing first and unpacking second handles both.
+     */
+    let mut archive = tar::Archive::new(layer_reader(&blob, &layer.media_type)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+
+        if name == WHITEOUT_OPAQUE {
+            let dir = paths::resolve(target, parent)?;
+            if dir.is_dir() {
+                for child in std::fs::read_dir(&dir)? {
+                    remove_path(&child?.path())?;
+                }
+            }
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let victim = paths::resolve_parent(target, parent.join(hidden))?;
+            remove_path(&victim)?;
+        }
+    }
+
+    let mut archive = unpack_archive(layer_reader(&blob, &layer.media_type)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        match path.file_name() {
+            Some(name) if name.to_string_lossy().starts_with(WHITEOUT_PREFIX) => continue,
+            Some(_) => {}
+            // The root directory itself
+            None => continue,
+        }
+
+        let dst = paths::resolve_parent(target, &path)?;
+        let entry_type = entry.header().entry_type();
+        if let Ok(existing) = std::fs::symlink_metadata(&dst) {
+            // Directories are merged, anything else replaces what is there
+            if !(existing.is_dir() && entry_type.is_dir()) {
+                remove_path(&dst)?;
+            }
+        }
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if entry_type.is_hard_link() {
+            let link = entry.link_name()?.ok_or(OciError::InvalidLayer(
+                layer.digest.clone(),
+                path.display().to_string(),
+            ))?;
+            std::fs::hard_link(paths::resolve_parent(target, link)?, &dst)?;
+        } else {
+            entry.unpack(&dst)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Seconds since the epoch as UTC timestamp like 2023-11-14T22:13:20Z
+pub fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_layer, blob_path, pax_record, read_layout, rfc3339, write_blob, write_layout,
+        ContainerConfig, Descriptor, ImageConfiguration, Index, LayoutReference, Manifest,
+        OciError, ANNOTATION_IMAGE_MANIFEST, MEDIA_TYPE_IMAGE_MANIFEST,
+    };
+    use crate::build::{OciConfig, OciPort};
+    use crate::image::manifest::{ImageKind, ImageManifest, ManifestVolume};
+    use miette::{IntoDiagnostic, Result};
+    use sha2::{Digest, Sha256};
+    use std::{
+        collections::HashMap,
+        io::Read,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::{Path, PathBuf},
+    };
+
+    fn image_manifest() -> ImageManifest {
+        ImageManifest {
+            uuid: uuid::Uuid::new_v4(),
+            name: "zones/web".into(),
+            version: "1".into(),
+            author: None,
+            brand: crate::brand::Brand::Image,
+            kind: ImageKind::Zone,
+            origin: None,
+            created: 1700000000,
+            bundle_hash: Some("0".repeat(64)),
+            source: None,
+            container: None,
+            volumes: vec![ManifestVolume {
+                name: "data".into(),
+                mountpoint: "/var/www".into(),
+            }],
+            packages: vec![],
+            services: vec![],
+            files: vec![],
+        }
+    }
+
+    #[test]
+    fn test_write_layout() -> Result<()> {
+        let root = tempfile::tempdir().into_diagnostic()?;
+        let etc = root.path().join("etc");
+        std::fs::create_dir(&etc).into_diagnostic()?;
+        std::fs::write(etc.join("motd"), "hello").into_diagnostic()?;
+        std::fs::set_permissions(etc.join("motd"), std::fs::Permissions::from_mode(0o640))
+            .into_diagnostic()?;
+        std::fs::hard_link(etc.join("motd"), etc.join("issue")).into_diagnostic()?;
+        std::os::unix::fs::symlink("motd", etc.join("motd.link")).into_diagnostic()?;
+        let has_xattr = xattr::set(etc.join("motd"), "user.origin", b"imgbuild").is_ok();
+        let uid = std::fs::metadata(etc.join("motd")).into_diagnostic()?.uid();
+
+        let out = tempfile::tempdir().into_diagnostic()?;
+        let layout = out.path().join("web.oci");
+        let config = OciConfig {
+            entrypoint: vec!["/usr/sbin/nginx".into()],
+            ports: vec![OciPort {
+                port: 80,
+                protocol: None,
+            }],
+            ..Default::default()
+        };
+        let manifest = image_manifest();
+        let layer = write_layout(
+            root.path(),
+            &layout,
+            &ContainerConfig::new(&config, &manifest),
+            &manifest,
+        )?;
+
+        let read_json = |path: PathBuf| -> Result<serde_json::Value> {
+            serde_json::from_slice(&std::fs::read(path).into_diagnostic()?).into_diagnostic()
+        };
+        assert_eq!(
+            "1.0.0",
+            read_json(layout.join("oci-layout"))?["imageLayoutVersion"]
+        );
+
+        let index: Index =
+            serde_json::from_value(read_json(layout.join("index.json"))?).into_diagnostic()?;
+        assert_eq!(1, index.manifests.len());
+        let oci_manifest: Manifest =
+            serde_json::from_value(read_json(blob_path(&layout, &index.manifests[0]))?)
+                .into_diagnostic()?;
+        let image_config: ImageConfiguration =
+            serde_json::from_value(read_json(blob_path(&layout, &oci_manifest.config))?)
+                .into_diagnostic()?;
+
+        assert_eq!(Some("2023-11-14T22:13:20Z".into()), image_config.created);
+        assert_eq!(
+            vec!["/usr/sbin/nginx".to_string()],
+            image_config.config.entrypoint
+        );
+        assert!(image_config.config.exposed_ports.contains_key("80/tcp"));
+        assert!(image_config.config.volumes.contains_key("/var/www"));
+        assert!(image_config.config.env[0].starts_with("PATH="));
+        assert_eq!(vec![layer.diff_id.clone()], image_config.rootfs.diff_ids);
+
+        // The annotation points to the image manifest blob
+        let digest = &oci_manifest.annotations[ANNOTATION_IMAGE_MANIFEST];
+        let blob = std::fs::read(
+            layout
+                .join("blobs/sha256")
+                .join(digest.trim_start_matches("sha256:")),
+        )
+        .into_diagnostic()?;
+        assert_eq!(
+            manifest,
+            serde_json::from_slice::<ImageManifest>(&blob).into_diagnostic()?
+        );
+        assert!(MEDIA_TYPE_IMAGE_MANIFEST.ends_with("+json"));
+
+        // Digests match the content of the blob and of the uncompressed tar
+        let compressed = std::fs::read(&layer.path).into_diagnostic()?;
+        assert_eq!(
+            layer.descriptor.digest,
+            format!("sha256:{}", hex::encode(Sha256::digest(&compressed)))
+        );
+        let mut tar = vec![];
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut tar)
+            .into_diagnostic()?;
+        assert_eq!(
+            layer.diff_id,
+            format!("sha256:{}", hex::encode(Sha256::digest(&tar)))
+        );
+
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let mut entries = HashMap::new();
+        for entry in archive.entries().into_diagnostic()? {
+            let mut entry = entry.into_diagnostic()?;
+            let path = entry.path().into_diagnostic()?.display().to_string();
+            let xattrs: Vec<String> = match entry.pax_extensions().into_diagnostic()? {
+                Some(extensions) => extensions
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.key().ok().map(String::from))
+                    .collect(),
+                None => vec![],
+            };
+            let header = entry.header();
+            let link = header
+                .link_name()
+                .into_diagnostic()?
+                .map(|l| l.display().to_string());
+            entries.insert(
+                path,
+                (
+                    header.entry_type(),
+                    header.mode().into_diagnostic()?,
+                    header.uid().into_diagnostic()?,
+                    link,
+                    xattrs,
+                ),
+            );
+        }
+
+        assert_eq!(vec!["etc", "etc/issue", "etc/motd", "etc/motd.link"], {
+            let mut paths: Vec<&str> = entries.keys().map(|k| k.as_str()).collect();
+            paths.sort();
+            paths
+        });
+        let (kind, mode, owner, _, xattrs) = &entries["etc/issue"];
+        assert!(kind.is_file());
+        assert_eq!(0o640, *mode);
+        assert_eq!(u64::from(uid), *owner);
+        if has_xattr {
+            assert_eq!(vec!["SCHILY.xattr.user.origin".to_string()], *xattrs);
+        }
+        let (kind, _, _, link, _) = &entries["etc/motd"];
+        assert!(kind.is_hard_link());
+        assert_eq!(Some("etc/issue".into()), *link);
+        let (kind, _, _, link, _) = &entries["etc/motd.link"];
+        assert!(kind.is_symlink());
+        assert_eq!(Some("motd".into()), *link);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_layout() -> Result<()> {
+        let root = tempfile::tempdir().into_diagnostic()?;
+        std::fs::write(root.path().join("motd"), "hello").into_diagnostic()?;
+        let out = tempfile::tempdir().into_diagnostic()?;
+        let layout = out.path().join("web.oci");
+        let config = OciConfig {
+            user: Some("webservd".into()),
+            ..Default::default()
+        };
+        let manifest = image_manifest();
+        write_layout(
+            root.path(),
+            &layout,
+            &ContainerConfig::new(&config, &manifest),
+            &manifest,
+        )?;
+
+        let source = format!("oci:{}", layout.display());
+        let image = read_layout(&source.parse()?)?;
+        assert_eq!(Some("1"), image.reference());
+        assert_eq!(Some("webservd".into()), image.config.config.user);
+        assert_eq!(Some(manifest), image.image_manifest()?);
+        assert!(read_layout(&format!("{}:1", source).parse()?).is_ok());
+        assert!(matches!(
+            read_layout(&format!("{}:2", source).parse()?),
+            Err(OciError::NoMatchingManifest(..))
+        ));
+
+        // Blobs are checked against their digest
+        let config_blob = blob_path(&layout, &image.manifest.config);
+        std::fs::write(&config_blob, "{}").into_diagnostic()?;
+        assert!(matches!(
+            read_layout(&source.parse()?),
+            Err(OciError::DigestMismatch(..))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_reference() -> Result<()> {
+        let reference: LayoutReference = "oci:/var/tmp/nginx.oci:1.25".parse()?;
+        assert_eq!(PathBuf::from("/var/tmp/nginx.oci"), reference.path);
+        assert_eq!(Some("1.25".into()), reference.reference);
+        assert_eq!("oci:/var/tmp/nginx.oci:1.25", reference.to_string());
+
+        let reference: LayoutReference = "oci:nginx.oci".parse()?;
+        assert_eq!(PathBuf::from("nginx.oci"), reference.path);
+        assert_eq!(None, reference.reference);
+
+        // The colon belongs to the path if a slash follows it
+        let reference: LayoutReference = "oci:/var/tmp/a:b/nginx.oci".parse()?;
+        assert_eq!(PathBuf::from("/var/tmp/a:b/nginx.oci"), reference.path);
+        assert_eq!(None, reference.reference);
+
+        assert!("/var/tmp/nginx.oci".parse::<LayoutReference>().is_err());
+        assert!("oci:".parse::<LayoutReference>().is_err());
+        assert!("oci:nginx.oci:".parse::<LayoutReference>().is_err());
+        Ok(())
+    }
+
+    enum LayerEntry<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+        Hardlink(&'a str, &'a str),
+    }
+
+    fn layer(layout: &Path, entries: &[LayerEntry]) -> Result<Descriptor> {
+        // Owned by whoever runs the tests so unpacking can keep the owner
+        let owner = std::fs::metadata(layout).into_diagnostic()?;
+        let mut builder = tar::Builder::new(vec![]);
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(1700000000);
+            header.set_uid(owner.uid().into());
+            header.set_gid(owner.gid().into());
+            match entry {
+                LayerEntry::Dir(path) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, std::io::empty())
+                }
+                LayerEntry::File(path, content) => {
+                    header.set_mode(0o644);
+                    header.set_size(content.len() as u64);
+                    builder.append_data(&mut header, path, content.as_bytes())
+                }
+                LayerEntry::Symlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target)
+                }
+                LayerEntry::Hardlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target)
+                }
+            }
+            .into_diagnostic()?;
+        }
+        let tar = builder.into_inner().into_diagnostic()?;
+        std::fs::create_dir_all(layout.join("blobs/sha256")).into_diagnostic()?;
+        Ok(write_blob(
+            layout,
+            "application/vnd.oci.image.layer.v1.tar",
+            &tar,
+        )?)
+    }
+
+    #[test]
+    fn test_apply_layer() -> Result<()> {
+        use LayerEntry::*;
+
+        let layout = tempfile::tempdir().into_diagnostic()?;
+        let lower = layer(
+            layout.path(),
+            &[
+                Dir("etc"),
+                File("etc/motd", "lower"),
+                File("etc/passwd", "root"),
+                Dir("var/cache"),
+                File("var/cache/a", "a"),
+                Dir("var/cache/b"),
+                File("var/cache/b/c", "c"),
+                Symlink("escape", "/"),
+                Symlink("lib", "usr/lib"),
+            ],
+        )?;
+        let upper = layer(
+            layout.path(),
+            &[
+                File("etc/.wh.passwd", ""),
+                File("var/cache/d", "d"),
+                File("var/cache/.wh..wh..opq", ""),
+                File("etc/motd", "upper"),
+                Hardlink("etc/issue", "etc/motd"),
+                File("escape/etc/shadow", "inside"),
+                Dir("lib"),
+                File("lib/libc.so", "libc"),
+            ],
+        )?;
+
+        let target = tempfile::tempdir().into_diagnostic()?;
+        let root = target.path().join("root");
+        std::fs::create_dir(&root).into_diagnostic()?;
+        apply_layer(layout.path(), &lower, &root)?;
+        assert!(root.join("etc/passwd").exists());
+        assert!(root.join("var/cache/b/c").exists());
+
+        apply_layer(layout.path(), &upper, &root)?;
+        assert!(!root.join("etc/passwd").exists());
+        let mut cache: Vec<String> = std::fs::read_dir(root.join("var/cache"))
+            .into_diagnostic()?
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        cache.sort();
+        assert_eq!(vec!["d".to_string()], cache);
+
+        let motd = std::fs::metadata(root.join("etc/motd")).into_diagnostic()?;
+        assert_eq!(
+            "upper",
+            std::fs::read_to_string(root.join("etc/motd")).into_diagnostic()?
+        );
+        assert_eq!(0o644, motd.mode() & 0o7777);
+        assert_eq!(1700000000, motd.mtime());
+        assert_eq!(
+            motd.ino(),
+            std::fs::metadata(root.join("etc/issue"))
+                .into_diagnostic()?
+                .ino()
+        );
+
+        // Symlinks of lower layers resolve inside the root
+        assert_eq!(
+            "inside",
+            std::fs::read_to_string(root.join("etc/shadow")).into_diagnostic()?
+        );
+        assert!(!target.path().join("etc").exists());
+
+        // A directory replaces a symlink instead of being written through it
+        assert!(root
+            .join("lib")
+            .symlink_metadata()
+            .into_diagnostic()?
+            .is_dir());
+        assert!(root.join("lib/libc.so").exists());
+        assert!(!root.join("usr/lib").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(
+            b"29 SCHILY.xattr.user.a=hello\n".to_vec(),
+            pax_record("SCHILY.xattr.user.a", b"hello")
+        );
+        assert_eq!(b"8 ab=cd\n".to_vec(), pax_record("ab", b"cd"));
+        // 98 bytes and two digits would be 100 which needs a third digit
+        let record = pax_record("k", &[b'v'; 94]);
+        assert_eq!(
+            record.len().to_string(),
+            String::from_utf8_lossy(&record[..3])
+        );
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!("1970-01-01T00:00:00Z", rfc3339(0));
+        assert_eq!("2000-02-29T12:30:05Z", rfc3339(951827405));
+        assert_eq!("2023-11-14T22:13:20Z", rfc3339(1700000000));
+    }
+}
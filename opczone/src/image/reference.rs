@@ -0,0 +1,386 @@
+use miette::Diagnostic;
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
+use thiserror::Error;
+
+pub const IMAGE_SCHEME: &str = "img";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ReferenceError {
+    #[error("image reference is empty")]
+    Empty,
+    #[error("image reference {0} uses scheme {1}, only img:// references can be resolved")]
+    UnsupportedScheme(String, String),
+    #[error("image reference {0} has no publisher, use img://<publisher>/<name>")]
+    MissingPublisher(String),
+    #[error("image reference {0} has no name")]
+    MissingName(String),
+    #[error("image reference {0} contains an empty path segment")]
+    EmptySegment(String),
+    #[error("image reference {0} contains whitespace")]
+    Whitespace(String),
+    #[error("version constraint {0} has no version")]
+    MissingVersion(String),
+    #[error("image reference {0} has an empty tag")]
+    MissingTag(String),
+}
+
+type Result<T> = miette::Result<T, ReferenceError>;
+
+/// A reference to an image like base-on uses it. Either a plain name like zones/base
+/// or an FMRI like img://openindiana.org/hipster@2023. Both can carry a version
+/// constraint after the @ or a tag after a : like zones/base:stable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageReference {
+    pub publisher: Option<String>,
+    pub name: String,
+    pub version: Option<VersionConstraint>,
+    pub tag: Option<String>,
+}
+
+impl ImageReference {
+    /// Whether an image with publisher, name and version satisfies this reference.
+    /// Images without a publisher were built or imported locally without one and
+    /// match any publisher.
+    pub fn matches(&self, publisher: Option<&str>, name: &str, version: Option<&Version>) -> bool {
+        if encode_name(&self.name) != encode_name(name) {
+            return false;
+        }
+
+        if let (Some(wanted), Some(publisher)) = (&self.publisher, publisher) {
+            if wanted != publisher {
+                return false;
+            }
+        }
+
+        match (&self.version, version) {
+            (None, _) => true,
+            (Some(constraint), Some(version)) => constraint.matches(version),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+impl FromStr for ImageReference {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(ReferenceError::Empty);
+        }
+        if s.chars().any(char::is_whitespace) {
+            return Err(ReferenceError::Whitespace(s.to_string()));
+        }
+
+        let (path, version) = match s.split_once('@') {
+            Some((path, version)) => (path, Some(version.parse::<VersionConstraint>()?)),
+            None => (s, None),
+        };
+
+        let (publisher, name) = match path.split_once("://") {
+            Some((scheme, rest)) if scheme == IMAGE_SCHEME => match rest.split_once('/') {
+                Some((publisher, name)) if !publisher.is_empty() => {
+                    (Some(publisher.to_string()), name)
+                }
+                Some(_) => return Err(ReferenceError::MissingPublisher(s.to_string())),
+                None if rest.is_empty() => {
+                    return Err(ReferenceError::MissingPublisher(s.to_string()))
+                }
+                None => return Err(ReferenceError::MissingName(s.to_string())),
+            },
+            Some((scheme, _)) => {
+                return Err(ReferenceError::UnsupportedScheme(
+                    s.to_string(),
+                    scheme.to_string(),
+                ))
+            }
+            None => (None, path),
+        };
+
+        // Versions may contain colons, a tag can only follow a name without version
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if version.is_none() && !tag.contains('/') => {
+                if tag.is_empty() {
+                    return Err(ReferenceError::MissingTag(s.to_string()));
+                }
+                (name, Some(tag.to_string()))
+            }
+            _ => (name, None),
+        };
+
+        if name.is_empty() {
+            return Err(ReferenceError::MissingName(s.to_string()));
+        }
+        if name.split('/').any(|segment| segment.is_empty()) {
+            return Err(ReferenceError::EmptySegment(s.to_string()));
+        }
+
+        Ok(ImageReference {
+            publisher,
+            name: name.to_string(),
+            version,
+            tag,
+        })
+    }
+}
+
+impl Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(publisher) = &self.publisher {
+            write!(f, "{}://{}/", IMAGE_SCHEME, publisher)?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some(version) = &self.version {
+            write!(f, "@{}", version)?;
+        }
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Names are stored with / replaced by _ in /etc/zimages. Compare names in that form
+/// so images registered before their name was recorded still match.
+pub(crate) fn encode_name(name: &str) -> String {
+    name.replace('/', "_")
+}
+
+/// A dotted version like 2023.04 or 1.2.3. Numeric components compare as numbers,
+/// everything else as text.
+#[derive(Debug, Clone)]
+pub struct Version {
+    components: Vec<String>,
+}
+
+impl Version {
+    /// Whether the leading components of self are the ones of prefix. 1.2.3 starts
+    /// with 1.2 but not with 1.23
+    pub fn starts_with(&self, prefix: &Version) -> bool {
+        self.components.len() >= prefix.components.len()
+            && self
+                .components
+                .iter()
+                .zip(prefix.components.iter())
+                .all(|(a, b)| compare_component(a, b) == Ordering::Equal)
+    }
+}
+
+fn compare_component(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+impl FromStr for Version {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || s.split('.').any(|c| c.is_empty()) {
+            return Err(ReferenceError::MissingVersion(s.to_string()));
+        }
+        Ok(Version {
+            components: s.split('.').map(String::from).collect(),
+        })
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.components.join("."))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for (a, b) in self.components.iter().zip(other.components.iter()) {
+            let ordering = compare_component(a, b);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        self.components.len().cmp(&other.components.len())
+    }
+}
+
+/*
+ * Equality has to agree with the ordering, 2023.04 and 2023.4 are the same
+ * version.
+ */
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The part of a reference after the @. A bare version matches every version starting
+/// with it like pkg(5) does, so @2023 matches 2023.04 and 2023.10.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionConstraint {
+    Prefix(Version),
+    Exact(Version),
+    Greater(Version),
+    GreaterOrEqual(Version),
+    Less(Version),
+    LessOrEqual(Version),
+}
+
+impl VersionConstraint {
+    pub fn matches(&self, version: &Version) -> bool {
+        match self {
+            VersionConstraint::Prefix(v) => version.starts_with(v),
+            VersionConstraint::Exact(v) => version == v,
+            VersionConstraint::Greater(v) => version > v,
+            VersionConstraint::GreaterOrEqual(v) => version >= v,
+            VersionConstraint::Less(v) => version < v,
+            VersionConstraint::LessOrEqual(v) => version <= v,
+        }
+    }
+}
+
+impl FromStr for VersionConstraint {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Two character operators first so >= is not read as > with version =1
+        let constraint = if let Some(v) = s.strip_prefix(">=") {
+            VersionConstraint::GreaterOrEqual(v.parse()?)
+        } else if let Some(v) = s.strip_prefix("<=") {
+            VersionConstraint::LessOrEqual(v.parse()?)
+        } else if let Some(v) = s.strip_prefix('>') {
+            VersionConstraint::Greater(v.parse()?)
+        } else if let Some(v) = s.strip_prefix('<') {
+            VersionConstraint::Less(v.parse()?)
+        } else if let Some(v) = s.strip_prefix('=') {
+            VersionConstraint::Exact(v.parse()?)
+        } else {
+            VersionConstraint::Prefix(s.parse()?)
+        };
+        Ok(constraint)
+    }
+}
+
+impl Display for VersionConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionConstraint::Prefix(v) => write!(f, "{}", v),
+            VersionConstraint::Exact(v) => write!(f, "={}", v),
+            VersionConstraint::Greater(v) => write!(f, ">{}", v),
+            VersionConstraint::GreaterOrEqual(v) => write!(f, ">={}", v),
+            VersionConstraint::Less(v) => write!(f, "<{}", v),
+            VersionConstraint::LessOrEqual(v) => write!(f, "<={}", v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageReference, Version, VersionConstraint};
+    use miette::Result;
+
+    #[test]
+    fn test_parse_reference() -> Result<()> {
+        let reference: ImageReference = "img://openindiana.org/hipster".parse()?;
+        assert_eq!(Some("openindiana.org".into()), reference.publisher);
+        assert_eq!("hipster", reference.name);
+        assert_eq!(None, reference.version);
+
+        let reference: ImageReference = "img://openindiana.org/hvm/hipster@>=2023.04".parse()?;
+        assert_eq!("hvm/hipster", reference.name);
+        assert_eq!(
+            Some(VersionConstraint::GreaterOrEqual("2023.04".parse()?)),
+            reference.version
+        );
+        assert_eq!(
+            "img://openindiana.org/hvm/hipster@>=2023.04",
+            reference.to_string()
+        );
+
+        let reference: ImageReference = "zones/base@1.2".parse()?;
+        assert_eq!(None, reference.publisher);
+        assert_eq!("zones/base", reference.name);
+        assert_eq!("zones/base@1.2", reference.to_string());
+
+        let reference: ImageReference = "img://openindiana.org/hipster:latest".parse()?;
+        assert_eq!("hipster", reference.name);
+        assert_eq!(Some("latest".into()), reference.tag);
+        assert_eq!(None, reference.version);
+        assert_eq!(
+            "img://openindiana.org/hipster:latest",
+            reference.to_string()
+        );
+
+        let reference: ImageReference = "zones/base@1.2:20230601T101010Z".parse()?;
+        assert_eq!(None, reference.tag);
+
+        for invalid in [
+            "",
+            "img://",
+            "img:///hipster",
+            "img://openindiana.org",
+            "img://openindiana.org/",
+            "oci://ghcr.io/app",
+            "zones//base",
+            "zones/base@",
+            "zones/base@>=",
+            "zones/base@1..2",
+            "zones/base:",
+            ":latest",
+            "zones base",
+        ] {
+            assert!(
+                invalid.parse::<ImageReference>().is_err(),
+                "{} should not parse",
+                invalid
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_constraints() -> Result<()> {
+        let version: Version = "2023.10.1".parse()?;
+        assert!(version > "2023.9".parse()?);
+        assert!(version > "2023.10".parse()?);
+        assert!(version < "2024".parse()?);
+
+        let matching = [
+            "2023",
+            "2023.10",
+            ">2023.9",
+            ">=2023.10.1",
+            "<2024",
+            "=2023.10.1",
+        ];
+        for constraint in matching {
+            let constraint: VersionConstraint = constraint.parse()?;
+            assert!(constraint.matches(&version), "{} should match", constraint);
+        }
+        for constraint in ["2023.1", "=2023.10", "<2023.10.1", ">2023.10.1"] {
+            let constraint: VersionConstraint = constraint.parse()?;
+            assert!(
+                !constraint.matches(&version),
+                "{} should not match",
+                constraint
+            );
+        }
+
+        let reference: ImageReference = "img://openindiana.org/hipster@2023".parse()?;
+        assert!(reference.matches(Some("openindiana.org"), "hipster", Some(&version)));
+        assert!(reference.matches(None, "hipster", Some(&version)));
+        assert!(!reference.matches(Some("omnios.org"), "hipster", Some(&version)));
+        assert!(!reference.matches(Some("openindiana.org"), "hipster", None));
+        assert!(!reference.matches(Some("openindiana.org"), "minimal", Some(&version)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+use super::manifest::{ImageManifest, ManifestError};
+use super::reference::{encode_name, ReferenceError, Version};
+use super::resolve::{CandidateOrigin, ImageCandidate};
+use common::{debug, info};
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::File,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// The registry lives next to the register files it replaces
+pub const REGISTRY_FILE: &str = "registry.json";
+/// Moves to every image registered under a name
+pub const LATEST_TAG: &str = "latest";
+const REGISTRY_FORMAT: u32 = 1;
+/// Held while the registry gets updated
+const LOCK_FILE: &str = "registry.lock";
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const LOCK_RETRY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum RegistryError {
+    #[error("could not read {0}: {1}")]
+    InvalidRegistry(String, String),
+    #[error("could not read {0}: {1}")]
+    RegisterFileError(String, String),
+    #[error("{0} is not a valid tag")]
+    InvalidTag(String),
+    #[error("registry is locked by {0}, remove it if no image is being registered")]
+    Locked(String),
+    #[error(transparent)]
+    ReferenceError(#[from] ReferenceError),
+    #[error(transparent)]
+    ManifestError(#[from] ManifestError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+}
+
+type Result<T> = miette::Result<T, RegistryError>;
+
+/// A file of /etc/zimages registering a single image before the registry existed
+#[derive(Debug, Deserialize, Serialize)]
+struct ImageRegisterFile {
+    uuid: uuid::Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    publisher: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+/// The images installed on this host by name. Every name can have several versions
+/// and tags, each pointing to an image.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Registry {
+    #[serde(skip)]
+    dir: PathBuf,
+    /// Register files merged into the registry, removed once it is saved
+    #[serde(skip)]
+    legacy_files: Vec<PathBuf>,
+    pub format: u32,
+    #[serde(default)]
+    pub images: BTreeMap<String, RegisteredName>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RegisteredName {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(default)]
+    pub versions: BTreeMap<String, RegistryEntry>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, RegistryEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegistryEntry {
+    pub uuid: uuid::Uuid,
+    /// Seconds since the epoch
+    pub registered: u64,
+}
+
+impl RegistryEntry {
+    fn new(uuid: &uuid::Uuid) -> Self {
+        RegistryEntry {
+            uuid: *uuid,
+            registered: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Registry {
+    /// The registry of dir with the register files written before it existed merged in
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(REGISTRY_FILE);
+        let mut registry = if path.exists() {
+            serde_json::from_reader(File::open(&path)?).map_err(|e| {
+                RegistryError::InvalidRegistry(path.display().to_string(), e.to_string())
+            })?
+        } else {
+            Registry {
+                format: REGISTRY_FORMAT,
+                ..Default::default()
+            }
+        };
+        registry.dir = dir.to_path_buf();
+
+        if dir.exists() {
+            let mut legacy_files = vec![];
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension() == Some(OsStr::new("json")) && path != dir.join(REGISTRY_FILE) {
+                    legacy_files.push(path);
+                }
+            }
+            legacy_files.sort();
+            for path in legacy_files {
+                registry.migrate(&path)?;
+                registry.legacy_files.push(path);
+            }
+        }
+
+        Ok(registry)
+    }
+
+    /// Open the registry of dir, apply change and save it. Nobody else updates the
+    /// registry in between.
+    pub fn update<T, F>(dir: &Path, change: F) -> Result<T>
+    where
+        F: FnOnce(&mut Registry) -> Result<T>,
+    {
+        std::fs::create_dir_all(dir)?;
+        let _lock = RegistryLock::acquire(&dir.join(LOCK_FILE))?;
+        let mut registry = Registry::open(dir)?;
+        let result = change(&mut registry)?;
+        registry.save()?;
+        Ok(result)
+    }
+
+    /*
+     * Register files have no tags. Unversioned images were looked up by their
+     * name only so they become latest, versioned ones keep resolving to the
+     * highest version.
+     */
+    fn migrate(&mut self, path: &Path) -> Result<()> {
+        let register_file: ImageRegisterFile =
+            serde_json::from_reader(File::open(path)?).map_err(|e| {
+                RegistryError::RegisterFileError(path.display().to_string(), e.to_string())
+            })?;
+
+        // Images registered before the name was recorded only have it in the file name
+        let name = register_file.name.unwrap_or_else(|| {
+            path.file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+        debug!("migrating {} to the image registry", path.display());
+
+        let entry = RegistryEntry::new(&register_file.uuid);
+        let registered = self.entry(&name, register_file.publisher.as_deref());
+        match register_file.version {
+            Some(version) => {
+                registered.versions.insert(version, entry);
+            }
+            None => {
+                registered.tags.insert(LATEST_TAG.to_string(), entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// The name as registered. Names compare like encode_name does so names of old
+    /// register files with _ for / still match.
+    fn key(&self, name: &str) -> Option<String> {
+        self.images
+            .keys()
+            .find(|k| encode_name(k) == encode_name(name))
+            .cloned()
+    }
+
+    fn entry(&mut self, name: &str, publisher: Option<&str>) -> &mut RegisteredName {
+        let existing = self.key(name);
+        // Prefer the name with slashes over the encoded one of old register files
+        let key = match &existing {
+            Some(key) if !name.contains('/') => key.clone(),
+            _ => name.to_string(),
+        };
+        let mut registered = existing
+            .and_then(|key| self.images.remove(&key))
+            .unwrap_or_default();
+        if publisher.is_some() {
+            registered.publisher = publisher.map(String::from);
+        }
+        self.images.entry(key).or_insert(registered)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisteredName> {
+        self.key(name).and_then(|key| self.images.get(&key))
+    }
+
+    /// Register the image under name and version and make it the latest. Returns the
+    /// image the version pointed to before if it was another one.
+    pub fn register(
+        &mut self,
+        name: &str,
+        publisher: Option<&str>,
+        version: Option<&str>,
+        uuid: &uuid::Uuid,
+    ) -> Result<Option<uuid::Uuid>> {
+        if let Some(version) = version {
+            version.parse::<Version>()?;
+        }
+
+        let entry = RegistryEntry::new(uuid);
+        let registered = self.entry(name, publisher);
+        registered
+            .tags
+            .insert(LATEST_TAG.to_string(), entry.clone());
+        let replaced = match version {
+            Some(version) => registered.versions.insert(version.to_string(), entry),
+            None => None,
+        };
+        Ok(replaced.map(|e| e.uuid).filter(|old| old != uuid))
+    }
+
+    /// Point tag of name to the image
+    pub fn tag(&mut self, name: &str, tag: &str, uuid: &uuid::Uuid) -> Result<()> {
+        if tag.is_empty() || tag.contains(|c: char| c == '/' || c == '@' || c.is_whitespace()) {
+            return Err(RegistryError::InvalidTag(tag.to_string()));
+        }
+        self.entry(name, None)
+            .tags
+            .insert(tag.to_string(), RegistryEntry::new(uuid));
+        Ok(())
+    }
+
+    /// Remove every version and tag pointing to the image. latest moves to the
+    /// highest version left, names go away with their last image.
+    pub fn unregister(&mut self, uuid: &uuid::Uuid) {
+        for registered in self.images.values_mut() {
+            registered.versions.retain(|_, e| &e.uuid != uuid);
+            let had_latest = registered.tags.contains_key(LATEST_TAG);
+            registered.tags.retain(|_, e| &e.uuid != uuid);
+
+            if had_latest && !registered.tags.contains_key(LATEST_TAG) {
+                let highest = registered
+                    .versions
+                    .iter()
+                    .filter_map(|(v, e)| v.parse::<Version>().ok().map(|v| (v, e)))
+                    .max_by(|a, b| a.0.cmp(&b.0))
+                    .map(|(_, e)| e.clone());
+                if let Some(entry) = highest {
+                    registered.tags.insert(LATEST_TAG.to_string(), entry);
+                }
+            }
+        }
+        self.images
+            .retain(|_, r| !r.versions.is_empty() || !r.tags.is_empty());
+    }
+
+    /// Write the registry, readers never see a half written one. Register files merged
+    /// into it are removed afterwards.
+    pub fn save(&mut self) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(REGISTRY_FILE);
+        let partial = path.with_extension("partial");
+        let file = File::create(&partial)?;
+        serde_json::to_writer_pretty(&file, self)?;
+        file.sync_all()?;
+        std::fs::rename(&partial, &path)?;
+
+        for legacy_file in self.legacy_files.drain(..) {
+            info!("Migrated {} to {}", legacy_file.display(), path.display());
+            std::fs::remove_file(&legacy_file)?;
+        }
+        Ok(())
+    }
+
+    /// Every registered image once per name and version. Tags go with the image they
+    /// point to, images only reachable by a tag have no version.
+    pub fn candidates(&self) -> Result<Vec<ImageCandidate>> {
+        let mut candidates = vec![];
+        for (name, registered) in &self.images {
+            let tags_of = |uuid: &uuid::Uuid| -> Vec<String> {
+                registered
+                    .tags
+                    .iter()
+                    .filter(|(_, e)| &e.uuid == uuid)
+                    .map(|(t, _)| t.clone())
+                    .collect()
+            };
+            let candidate = |uuid: &uuid::Uuid, version: Option<Version>| ImageCandidate {
+                uuid: *uuid,
+                publisher: registered.publisher.clone(),
+                name: name.clone(),
+                version,
+                tags: tags_of(uuid),
+                origin: CandidateOrigin::Local,
+            };
+
+            for (version, entry) in &registered.versions {
+                candidates.push(candidate(&entry.uuid, Some(version.parse()?)));
+            }
+            for entry in registered.tags.values() {
+                let versioned = registered.versions.values().any(|e| e.uuid == entry.uuid);
+                let listed = candidates
+                    .iter()
+                    .any(|c| c.name == *name && c.uuid == entry.uuid);
+                if !versioned && !listed {
+                    candidates.push(candidate(&entry.uuid, None));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+        Ok(candidates)
+    }
+
+    /// The manifest stored for a registered image
+    pub fn manifest(&self, uuid: &uuid::Uuid) -> Result<Option<ImageManifest>> {
+        Ok(ImageManifest::load_from(&self.dir, uuid)?)
+    }
+}
+
+/// Removed again when dropped
+struct RegistryLock {
+    path: PathBuf,
+}
+
+impl RegistryLock {
+    fn acquire(path: &Path) -> Result<Self> {
+        let started = Instant::now();
+        loop {
+            match File::options().write(true).create_new(true).open(path) {
+                Ok(_) => {
+                    return Ok(RegistryLock {
+                        path: path.to_path_buf(),
+                    })
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if started.elapsed() > LOCK_TIMEOUT {
+                        return Err(RegistryError::Locked(path.display().to_string()));
+                    }
+                    thread::sleep(LOCK_RETRY);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for RegistryLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, LATEST_TAG, REGISTRY_FILE};
+    use miette::{IntoDiagnostic, Result};
+
+    #[test]
+    fn test_registry() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let v1 = uuid::Uuid::new_v4();
+        let v2 = uuid::Uuid::new_v4();
+        let rebuilt = uuid::Uuid::new_v4();
+
+        let mut registry = Registry::open(dir.path())?;
+        assert_eq!(None, registry.register("zones/web", None, Some("1"), &v1)?);
+        assert_eq!(None, registry.register("zones/web", None, Some("2"), &v2)?);
+        registry.tag("zones/web", "stable", &v1)?;
+        assert!(registry.tag("zones/web", "a/b", &v1).is_err());
+        assert!(registry
+            .register("zones/web", None, Some("1..2"), &v1)
+            .is_err());
+
+        // Rebuilding a version replaces it
+        assert_eq!(
+            Some(v2),
+            registry.register("zones/web", None, Some("2"), &rebuilt)?
+        );
+        registry.save()?;
+
+        let other = uuid::Uuid::new_v4();
+        let replaced = Registry::update(dir.path(), |registry| {
+            registry.register("zones/app", None, None, &other)
+        })?;
+        assert_eq!(None, replaced);
+        assert!(!dir.path().join("registry.lock").exists());
+
+        let mut registry = Registry::open(dir.path())?;
+        assert_eq!(
+            other,
+            registry.get("zones/app").unwrap().tags[LATEST_TAG].uuid
+        );
+        let web = registry.get("zones/web").unwrap();
+        assert_eq!(rebuilt, web.versions["2"].uuid);
+        assert_eq!(rebuilt, web.tags[LATEST_TAG].uuid);
+        assert_eq!(v1, web.tags["stable"].uuid);
+
+        let candidates = registry.candidates()?;
+        assert_eq!(3, candidates.len());
+        assert_eq!(other, candidates[0].uuid);
+        assert_eq!(None, candidates[0].version);
+        assert_eq!(vec!["stable".to_string()], candidates[1].tags);
+        assert_eq!(vec![LATEST_TAG.to_string()], candidates[2].tags);
+
+        // latest falls back to the highest version left
+        registry.unregister(&rebuilt);
+        let web = registry.get("zones/web").unwrap();
+        assert_eq!(v1, web.tags[LATEST_TAG].uuid);
+        assert!(!web.versions.contains_key("2"));
+
+        registry.unregister(&v1);
+        assert!(registry.get("zones/web").is_none());
+        registry.unregister(&other);
+        assert!(registry.images.is_empty());
+        assert!(!dir.path().join("registry.partial").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let base = uuid::Uuid::new_v4();
+        let hipster = uuid::Uuid::new_v4();
+        std::fs::write(
+            dir.path().join("zones_base.json"),
+            format!(r#"{{"uuid": "{}"}}"#, base),
+        )
+        .into_diagnostic()?;
+        std::fs::write(
+            dir.path().join("hipster.json"),
+            format!(
+                r#"{{"uuid": "{}", "name": "hipster", "publisher": "openindiana.org", "version": "2024.04"}}"#,
+                hipster
+            ),
+        )
+        .into_diagnostic()?;
+        std::fs::create_dir(dir.path().join("manifests")).into_diagnostic()?;
+
+        let mut registry = Registry::open(dir.path())?;
+        assert_eq!(
+            base,
+            registry.get("zones/base").unwrap().tags[LATEST_TAG].uuid
+        );
+        let hipster_entry = registry.get("hipster").unwrap();
+        assert_eq!(Some("openindiana.org".into()), hipster_entry.publisher);
+        assert_eq!(hipster, hipster_entry.versions["2024.04"].uuid);
+        assert!(hipster_entry.tags.is_empty());
+
+        // The real name replaces the one from the file name
+        let rebuilt = uuid::Uuid::new_v4();
+        registry.register("zones/base", None, Some("2"), &rebuilt)?;
+        registry.save()?;
+        assert!(registry.images.contains_key("zones/base"));
+        assert!(!registry.images.contains_key("zones_base"));
+
+        let mut left: Vec<String> = std::fs::read_dir(dir.path())
+            .into_diagnostic()?
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(
+            vec!["manifests".to_string(), REGISTRY_FILE.to_string()],
+            left
+        );
+
+        let registry = Registry::open(dir.path())?;
+        let base_entry = registry.get("zones_base").unwrap();
+        assert_eq!(rebuilt, base_entry.tags[LATEST_TAG].uuid);
+        assert_eq!(rebuilt, base_entry.versions["2"].uuid);
+        Ok(())
+    }
+}
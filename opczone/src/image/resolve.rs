@@ -0,0 +1,384 @@
+use super::reference::{encode_name, ImageReference, ReferenceError, Version};
+use super::registry::{Registry, RegistryError, LATEST_TAG};
+use super::store::{self, ObjectStore};
+use super::ZONEIMAGE_DIR;
+use common::debug;
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, fs::File, path::PathBuf, time::Duration};
+use thiserror::Error;
+
+const CATALOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ResolveError {
+    #[error("no image matches {0}. Candidates considered: {1}")]
+    NoMatchingImage(String, String),
+    #[error("could not read image catalog {0}: {1}")]
+    CatalogError(String, String),
+    #[error(transparent)]
+    RegistryError(#[from] RegistryError),
+    #[error(transparent)]
+    ReferenceError(#[from] ReferenceError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
+
+type Result<T> = miette::Result<T, ResolveError>;
+
+/// Where a candidate was found
+#[derive(Debug, Clone, PartialEq)]
+pub enum CandidateOrigin {
+    /// Installed on this host
+    Local,
+    /// Listed in a remote catalog. The image can be downloaded from url
+    Catalog { catalog: String, url: String },
+}
+
+/// An image a reference could resolve to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageCandidate {
+    pub uuid: uuid::Uuid,
+    pub publisher: Option<String>,
+    pub name: String,
+    pub version: Option<Version>,
+    /// Tags pointing to the image, catalogs have none
+    pub tags: Vec<String>,
+    pub origin: CandidateOrigin,
+}
+
+impl ImageCandidate {
+    pub fn is_local(&self) -> bool {
+        self.origin == CandidateOrigin::Local
+    }
+}
+
+impl Display for ImageCandidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reference = ImageReference {
+            publisher: self.publisher.clone(),
+            name: self.name.clone(),
+            version: None,
+            tag: None,
+        };
+        write!(f, "{}", reference)?;
+        if let Some(version) = &self.version {
+            write!(f, "@{}", version)?;
+        }
+        match &self.origin {
+            CandidateOrigin::Local => write!(f, " ({}, local)", self.uuid),
+            CandidateOrigin::Catalog { catalog, .. } => {
+                write!(f, " ({}, catalog {})", self.uuid, catalog)
+            }
+        }
+    }
+}
+
+/// The document a remote catalog serves
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Catalog {
+    pub images: Vec<CatalogEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CatalogEntry {
+    pub uuid: uuid::Uuid,
+    pub publisher: Option<String>,
+    pub name: String,
+    pub version: Option<String>,
+    pub url: String,
+}
+
+/// Finds the image a reference points to. Installed images are preferred, the catalog
+/// is only asked when no installed image matches.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    /// Directory with the registry of installed images
+    pub image_dir: PathBuf,
+    /// Url or path of the remote catalog
+    pub catalog: Option<String>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self {
+            image_dir: PathBuf::from(ZONEIMAGE_DIR),
+            catalog: None,
+        }
+    }
+}
+
+impl Resolver {
+    /// The best matching image. Fails with the candidates considered when none matches.
+    pub fn resolve(&self, reference: &ImageReference) -> Result<ImageCandidate> {
+        let mut considered = self.local_candidates()?;
+        if let Some(found) = best_match(reference, &considered) {
+            return Ok(found);
+        }
+
+        if let Some(catalog) = &self.catalog {
+            let remote = self.catalog_candidates(catalog)?;
+            if let Some(found) = best_match(reference, &remote) {
+                return Ok(found);
+            }
+            considered.extend(remote);
+        }
+
+        Err(no_match(reference, &considered))
+    }
+
+    /// The best matching installed image. None if no installed image has the name of
+    /// reference, an error listing the installed versions if none of them matches.
+    pub fn resolve_local(&self, reference: &ImageReference) -> Result<Option<ImageCandidate>> {
+        let candidates = self.local_candidates()?;
+        if let Some(found) = best_match(reference, &candidates) {
+            return Ok(Some(found));
+        }
+
+        if candidates.iter().any(|c| same_name(reference, c)) {
+            return Err(no_match(reference, &candidates));
+        }
+
+        Ok(None)
+    }
+
+    /// The image to build on. Like resolve_local without a catalog. With one, installed
+    /// images of the name which do not match are no error since the catalog can have
+    /// the version asked for.
+    pub fn resolve_base(&self, reference: &ImageReference) -> Result<Option<ImageCandidate>> {
+        match self.catalog {
+            Some(_) => Ok(Some(self.resolve(reference)?)),
+            None => self.resolve_local(reference),
+        }
+    }
+
+    /// All installed images
+    pub fn local_candidates(&self) -> Result<Vec<ImageCandidate>> {
+        Ok(Registry::open(&self.image_dir)?.candidates()?)
+    }
+
+    /// All images of the catalog at location. Locations without a scheme or with
+    /// file:// are read from disk.
+    pub fn catalog_candidates(&self, location: &str) -> Result<Vec<ImageCandidate>> {
+        let catalog = fetch_catalog(location)
+            .map_err(|e| ResolveError::CatalogError(location.to_string(), e))?;
+
+        let mut candidates = vec![];
+        for entry in catalog.images {
+            let version = match &entry.version {
+                Some(version) => Some(version.parse().map_err(|e: ReferenceError| {
+                    ResolveError::CatalogError(location.to_string(), e.to_string())
+                })?),
+                None => None,
+            };
+            candidates.push(ImageCandidate {
+                uuid: entry.uuid,
+                publisher: entry.publisher,
+                name: entry.name,
+                version,
+                tags: vec![],
+                origin: CandidateOrigin::Catalog {
+                    catalog: location.to_string(),
+                    url: entry.url,
+                },
+            });
+        }
+        Ok(candidates)
+    }
+}
+
+fn fetch_catalog(location: &str) -> std::result::Result<Catalog, String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        debug!("fetching image catalog {}", location);
+        let client = reqwest::blocking::Client::builder()
+            .timeout(CATALOG_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        return client
+            .get(location)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .map_err(|e| e.to_string());
+    }
+
+    if location.starts_with("s3://") {
+        debug!("fetching image catalog {}", location);
+        return ObjectStore::open()
+            .and_then(|store| store::fetch_catalog(&store, &location.parse()?))
+            .map_err(|e| e.to_string());
+    }
+
+    let path = location.strip_prefix("file://").unwrap_or(location);
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    serde_json::from_reader(file).map_err(|e| e.to_string())
+}
+
+fn same_name(reference: &ImageReference, candidate: &ImageCandidate) -> bool {
+    encode_name(&reference.name) == encode_name(&candidate.name)
+}
+
+/*
+ * Among the matching candidates one with exactly the publisher asked for beats
+ * one without a publisher. A plain name means the latest image, without one
+ * the highest version wins. Unversioned images only win if no versioned one
+ * matches.
+ */
+fn best_match(reference: &ImageReference, candidates: &[ImageCandidate]) -> Option<ImageCandidate> {
+    let wants_latest = reference.version.is_none() && reference.tag.is_none();
+    candidates
+        .iter()
+        .filter(|c| reference.matches(c.publisher.as_deref(), &c.name, c.version.as_ref()))
+        .filter(|c| match &reference.tag {
+            Some(tag) => c.tags.contains(tag),
+            None => true,
+        })
+        .max_by_key(|c| {
+            let same_publisher =
+                reference.publisher.is_some() && reference.publisher == c.publisher;
+            let latest = wants_latest && c.tags.iter().any(|t| t == LATEST_TAG);
+            (same_publisher, latest, c.version.clone())
+        })
+        .cloned()
+}
+
+fn no_match(reference: &ImageReference, candidates: &[ImageCandidate]) -> ResolveError {
+    let considered: Vec<String> = candidates
+        .iter()
+        .filter(|c| same_name(reference, c))
+        .map(|c| c.to_string())
+        .collect();
+    let considered = if considered.is_empty() {
+        format!("none, no image is named {}", reference.name)
+    } else {
+        considered.join(", ")
+    };
+    ResolveError::NoMatchingImage(reference.to_string(), considered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CandidateOrigin, Resolver};
+    use crate::image::reference::ImageReference;
+    use crate::image::registry::Registry;
+    use miette::{IntoDiagnostic, Result};
+
+    const OI_2023: &str = "3c1b2f5e-0b7a-4d51-9d2e-6a3f1c9b8e01";
+    const OI_2024: &str = "7f4e8a2d-5c1b-4e3a-8b9f-0d2c6e1a4b02";
+    const BASE: &str = "a2b9c8d7-e6f5-4a3b-9c2d-1e0f9a8b7c03";
+    const REMOTE: &str = "d4c3b2a1-9f8e-4d7c-8b6a-5f4e3d2c1b04";
+    const WEB_1: &str = "b1e2d3c4-a5f6-4b7c-8d9e-0f1a2b3c4d05";
+    const WEB_2: &str = "c2f3e4d5-b6a7-4c8d-9e0f-1a2b3c4d5e06";
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let images = dir.path().join("zimages");
+        std::fs::create_dir_all(&images).into_diagnostic()?;
+        std::fs::write(
+            images.join("hipster-2023.json"),
+            format!(
+                r#"{{"uuid": "{}", "name": "hipster", "publisher": "openindiana.org", "version": "2023.10"}}"#,
+                OI_2023
+            ),
+        )
+        .into_diagnostic()?;
+        std::fs::write(
+            images.join("hipster-2024.json"),
+            format!(
+                r#"{{"uuid": "{}", "name": "hipster", "publisher": "openindiana.org", "version": "2024.04"}}"#,
+                OI_2024
+            ),
+        )
+        .into_diagnostic()?;
+        // Registered before names were recorded
+        std::fs::write(
+            images.join("zones_base.json"),
+            format!(r#"{{"uuid": "{}"}}"#, BASE),
+        )
+        .into_diagnostic()?;
+
+        let catalog = dir.path().join("catalog.json");
+        std::fs::write(
+            &catalog,
+            format!(
+                r#"{{"images": [{{"uuid": "{}", "publisher": "openindiana.org", "name": "hipster", "version": "2025.04", "url": "https://images.example.org/hipster-2025.04.zfs.gz"}}]}}"#,
+                REMOTE
+            ),
+        )
+        .into_diagnostic()?;
+
+        let resolver = Resolver {
+            image_dir: images,
+            ..Default::default()
+        };
+
+        let resolve = |r: &Resolver, reference: &str| -> Result<String> {
+            let reference: ImageReference = reference.parse()?;
+            Ok(r.resolve(&reference)?.uuid.to_string())
+        };
+
+        assert_eq!(
+            OI_2024,
+            resolve(&resolver, "img://openindiana.org/hipster")?
+        );
+        assert_eq!(OI_2023, resolve(&resolver, "hipster@2023")?);
+        assert_eq!(OI_2023, resolve(&resolver, "hipster@<2024")?);
+        assert_eq!(BASE, resolve(&resolver, "zones/base")?);
+
+        let err = resolve(&resolver, "img://openindiana.org/hipster@2025").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains(OI_2023), "{}", message);
+        assert!(message.contains(OI_2024), "{}", message);
+        assert!(!message.contains(BASE), "{}", message);
+
+        let reference: ImageReference = "hipster@2025".parse()?;
+        assert!(resolver.resolve_local(&reference).is_err());
+        let reference: ImageReference = "unknown".parse()?;
+        assert_eq!(None, resolver.resolve_local(&reference)?);
+
+        let with_catalog = Resolver {
+            catalog: Some(format!("file://{}", catalog.display())),
+            ..resolver.clone()
+        };
+        let reference: ImageReference = "img://openindiana.org/hipster@2025".parse()?;
+        let found = with_catalog.resolve(&reference)?;
+        assert_eq!(REMOTE, found.uuid.to_string());
+        assert!(matches!(found.origin, CandidateOrigin::Catalog { .. }));
+
+        // Installed images win even if the catalog has a newer version
+        assert_eq!(OI_2024, resolve(&with_catalog, "hipster")?);
+
+        // Only the catalog has the version a build is based on
+        let reference: ImageReference = "hipster@2025".parse()?;
+        assert!(resolver.resolve_base(&reference).is_err());
+        let base = with_catalog
+            .resolve_base(&reference)?
+            .map(|c| c.uuid.to_string());
+        assert_eq!(Some(REMOTE.to_string()), base);
+        let reference: ImageReference = "hipster@2023".parse()?;
+        let base = with_catalog
+            .resolve_base(&reference)?
+            .map(|c| c.uuid.to_string());
+        assert_eq!(Some(OI_2023.to_string()), base);
+        let reference: ImageReference = "unknown".parse()?;
+        assert_eq!(None, resolver.resolve_base(&reference)?);
+        assert!(with_catalog.resolve_base(&reference).is_err());
+
+        // A rebuild of an older version is latest until another image is registered
+        let web_1 = WEB_1.parse().into_diagnostic()?;
+        let web_2 = WEB_2.parse().into_diagnostic()?;
+        Registry::update(&resolver.image_dir, |registry| {
+            registry.register("zones/web", None, Some("2.0"), &web_2)?;
+            registry.register("zones/web", None, Some("1.0"), &web_1)?;
+            registry.tag("zones/web", "stable", &web_2)
+        })?;
+        assert_eq!(WEB_1, resolve(&resolver, "zones/web")?);
+        assert_eq!(WEB_2, resolve(&resolver, "zones/web@2")?);
+        assert_eq!(WEB_2, resolve(&resolver, "zones/web:stable")?);
+        assert!(resolve(&resolver, "zones/web:beta").is_err());
+        assert_eq!(BASE, resolve(&resolver, "zones/base")?);
+
+        Ok(())
+    }
+}
@@ -1,17 +1,21 @@
 pub mod brand;
 pub mod build;
 pub mod dladm;
+pub mod exec;
 pub mod image;
 pub mod machine;
+#[cfg(test)]
+mod mock_http;
 pub mod smf;
 mod util;
 pub mod vmext;
 
 use miette::Diagnostic;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::time::Duration;
 use thiserror::Error;
 
+pub use exec::{execute, execute_checked, CancelToken, ExecOptions, ExecOutput, ExitStatus};
 pub use util::*;
 
 use common::*;
@@ -28,6 +32,10 @@ pub enum OPCZoneError {
     ProcessOutputError(String),
     #[error("process {0} failed with: {1}")]
     ProcessOutputErrorWithOutput(String, String),
+    #[error("process {0} {1}: {2}")]
+    ProcessFailed(String, ExitStatus, String),
+    #[error("failed to read {0} of process {1}: {2}")]
+    ProcessReadError(String, String, #[source] std::io::Error),
     #[error("zone {0} does not exist")]
     ZoneDoesNotExist(String),
     #[error(transparent)]
@@ -46,45 +54,6 @@ pub fn get_zone(zonename: &str) -> Result<zone::Zone> {
     Err(OPCZoneError::ZoneDoesNotExist(zonename.clone().into()))
 }
 
-fn spawn_reader<T>(name: &str, stream: Option<T>) -> Option<std::thread::JoinHandle<()>>
-where
-    T: Read + Send + 'static,
-{
-    let name = name.to_string();
-    let stream = match stream {
-        Some(stream) => stream,
-        None => return None,
-    };
-
-    Some(std::thread::spawn(move || {
-        let mut r = BufReader::new(stream);
-
-        loop {
-            let mut buf = String::new();
-
-            match r.read_line(&mut buf) {
-                Ok(0) => {
-                    /*
-                     * EOF.
-                     */
-                    return;
-                }
-                Ok(_) => {
-                    let s = buf.trim();
-
-                    if !s.is_empty() {
-                        info!(target: "illumos-rs", "{}| {}", name, s);
-                    }
-                }
-                Err(e) => {
-                    error!(target: "illumos-rs", "failed to read {}: {}", name, e);
-                    std::process::exit(100);
-                }
-            }
-        }
-    }))
-}
-
 fn build_env<S: AsRef<str>>(env: Option<&[(S, S)]>) -> Option<Vec<(&str, &str)>> {
     if let Some(env) = env {
         let env: Vec<(&str, &str)> = env.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect();
@@ -123,100 +92,56 @@ pub fn run_with_stdin<S: AsRef<str>>(
     env: Option<&[(S, S)]>,
     stdin: String,
 ) -> Result<()> {
-    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-    let env = build_env(env);
-    let mut cmd = build_cmd(args.clone(), env);
-
-    cmd.stdin(Stdio::piped());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
-
-    let mut child = cmd.spawn().map_err(|e| {
-        OPCZoneError::ProcessErrorWithContext(
-            format!("could not spawn process {}", args[0].clone().to_owned()),
-            e,
-        )
-    })?;
-    let mut child_stdin = child.stdin.take().unwrap();
-    std::thread::spawn(move || {
-        child_stdin.write_all(stdin.as_bytes()).unwrap();
-    });
-
-    let readout = spawn_reader("O", child.stdout.take());
-    let readerr = spawn_reader("E", child.stderr.take());
-
-    if let Some(t) = readout {
-        t.join().expect("join stdout thread");
-    }
-    if let Some(t) = readerr {
-        t.join().expect("join stderr thread");
-    }
-
-    match child.wait() {
-        Err(e) => Err(OPCZoneError::ProcessError(e)),
-        Ok(es) => {
-            if !es.success() {
-                Err(OPCZoneError::ProcessOutputError(args.join(" ")))
-            } else {
-                Ok(())
-            }
-        }
-    }
+    let opts = ExecOptions {
+        env: owned_env(env),
+        stdin: Some(stdin),
+        ..Default::default()
+    };
+    execute_checked(args, &opts)?;
+    Ok(())
 }
 
 pub fn run<S: AsRef<str>>(args: &[S], env: Option<&[(S, S)]>) -> Result<()> {
-    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-    let env = build_env(env);
-    let mut cmd = build_cmd(args.clone(), env);
-
-    cmd.stdin(Stdio::null());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
-
-    let mut child = cmd.spawn().map_err(|e| {
-        OPCZoneError::ProcessErrorWithContext(format!("could not spawn process {}", args[0]), e)
-    })?;
-
-    let readout = spawn_reader("O", child.stdout.take());
-    let readerr = spawn_reader("E", child.stderr.take());
-
-    if let Some(t) = readout {
-        t.join().expect("join stdout thread");
-    }
-    if let Some(t) = readerr {
-        t.join().expect("join stderr thread");
-    }
+    let opts = ExecOptions {
+        env: owned_env(env),
+        ..Default::default()
+    };
+    execute_checked(args, &opts)?;
+    Ok(())
+}
 
-    match child.wait() {
-        Err(e) => Err(e.into()),
-        Ok(es) => {
-            if !es.success() {
-                Err(OPCZoneError::ProcessOutputError(args.join(" ")))
-            } else {
-                Ok(())
-            }
-        }
-    }
+/// Like run but kills the process if it does not finish within timeout
+pub fn run_with_timeout<S: AsRef<str>>(
+    args: &[S],
+    env: Option<&[(S, S)]>,
+    timeout: Duration,
+) -> Result<()> {
+    let opts = ExecOptions {
+        env: owned_env(env),
+        timeout: Some(timeout),
+        ..Default::default()
+    };
+    execute_checked(args, &opts)?;
+    Ok(())
 }
 
+/// Run a process and return its stdout exactly as written. Fails if it is not UTF-8
 pub fn run_capture_stdout<S: AsRef<str>>(args: &[S], env: Option<&[(S, S)]>) -> Result<String> {
-    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-    let env = build_env(env);
-    let mut cmd = build_cmd(args.clone(), env);
-
-    cmd.stdin(Stdio::null());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+    let opts = ExecOptions {
+        env: owned_env(env),
+        capture_stdout: true,
+        live_log: false,
+        ..Default::default()
+    };
+    let output = execute_checked(args, &opts)?;
+    Ok(String::from_utf8(output.stdout_bytes)?)
+}
 
-    let output = cmd.output().map_err(|e| {
-        OPCZoneError::ProcessErrorWithContext(format!("could not spawn process {}", args[0]), e)
-    })?;
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout)?)
-    } else {
-        Err(OPCZoneError::ProcessOutputErrorWithOutput(
-            args.join(" "),
-            String::from_utf8(output.stderr)?,
-        ))
-    }
+fn owned_env<S: AsRef<str>>(env: Option<&[(S, S)]>) -> Vec<(String, String)> {
+    env.map(|env| {
+        env.iter()
+            .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+            .collect()
+    })
+    .unwrap_or_default()
 }
@@ -1,8 +1,20 @@
 use hard_xml::{XmlRead, XmlWrite};
 use miette::Diagnostic;
-use std::{fmt::Display, path::Path, str::FromStr};
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
 use thiserror::Error;
 
+mod status;
+
+pub use status::{
+    parse_svcs_explanation, parse_svcs_output, wait_for_online, ServiceExplanation, ServiceState,
+    ServiceStatus, Svcs,
+};
+
 type Result<T> = miette::Result<T, SMFError>;
 
 const SITE_MANIFEST_HEADER: &str = r#"<?xml version='1.0'?>
@@ -12,15 +24,25 @@ const SITE_MANIFEST_HEADER: &str = r#"<?xml version='1.0'?>
 pub enum SMFError {
     #[error("could not parse {0} as {1}")]
     ParseError(String, String),
+    #[error("value {1} of property {0} is not a valid {2}")]
+    InvalidPropertyValue(String, String, PropValType),
     #[error(transparent)]
     XMLError(#[from] hard_xml::XmlError),
     #[error(transparent)]
     IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    ExecError(#[from] crate::OPCZoneError),
+    #[error("service {0} is in maintenance: {1} (log: {2})")]
+    ServiceInMaintenance(String, String, String),
+    #[error("service {0} did not come online within {1:?}, last state: {2}")]
+    ServiceTimeout(String, std::time::Duration, String),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum BundleType {
     Profile,
+    Manifest,
+    Archive,
 }
 
 impl Default for BundleType {
@@ -35,7 +57,12 @@ impl FromStr for BundleType {
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "profile" => Ok(Self::Profile),
-            x => Err(SMFError::ParseError(x.to_owned(), String::from("profile"))),
+            "manifest" => Ok(Self::Manifest),
+            "archive" => Ok(Self::Archive),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("bundle type"),
+            )),
         }
     }
 }
@@ -44,6 +71,8 @@ impl Display for BundleType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             BundleType::Profile => write!(f, "profile"),
+            BundleType::Manifest => write!(f, "manifest"),
+            BundleType::Archive => write!(f, "archive"),
         }
     }
 }
@@ -51,6 +80,8 @@ impl Display for BundleType {
 #[derive(Debug, PartialEq, Clone)]
 pub enum ServiceType {
     Service,
+    Restarter,
+    Milestone,
 }
 
 impl Default for ServiceType {
@@ -65,6 +96,8 @@ impl FromStr for ServiceType {
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "service" => Ok(Self::Service),
+            "restarter" => Ok(Self::Restarter),
+            "milestone" => Ok(Self::Milestone),
             x => Err(SMFError::ParseError(x.to_owned(), String::from("service"))),
         }
     }
@@ -74,6 +107,8 @@ impl Display for ServiceType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ServiceType::Service => write!(f, "service"),
+            ServiceType::Restarter => write!(f, "restarter"),
+            ServiceType::Milestone => write!(f, "milestone"),
         }
     }
 }
@@ -89,6 +124,7 @@ pub struct ServiceBundle {
     pub services: Vec<Service>,
 }
 
+/// The order of the fields follows service_bundle.dtd.1 as the writer emits children in field order
 #[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
 #[xml(tag = "service")]
 pub struct Service {
@@ -98,8 +134,28 @@ pub struct Service {
     pub version: i32,
     #[xml(attr = "type")]
     pub service_type: ServiceType,
+    #[xml(child = "create_default_instance")]
+    pub create_default_instance: Option<CreateDefaultInstance>,
+    #[xml(child = "single_instance")]
+    pub single_instance: Option<SingleInstance>,
+    #[xml(child = "restarter")]
+    pub restarter: Option<Restarter>,
+    #[xml(child = "dependency")]
+    pub dependencies: Vec<Dependency>,
+    #[xml(child = "dependent")]
+    pub dependents: Vec<Dependent>,
+    #[xml(child = "method_context")]
+    pub method_context: Option<MethodContext>,
+    #[xml(child = "exec_method")]
+    pub exec_methods: Vec<ExecMethod>,
+    #[xml(child = "property_group")]
+    pub property_groups: Vec<PropertyGroup>,
     #[xml(child = "instance")]
     pub instances: Vec<Instance>,
+    #[xml(child = "stability")]
+    pub stability: Option<Stability>,
+    #[xml(child = "template")]
+    pub template: Option<Template>,
 }
 
 #[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
@@ -109,13 +165,487 @@ pub struct Instance {
     pub name: String,
     #[xml(attr = "enabled")]
     pub enabled: Option<bool>,
+    #[xml(child = "restarter")]
+    pub restarter: Option<Restarter>,
+    #[xml(child = "dependency")]
+    pub dependencies: Vec<Dependency>,
+    #[xml(child = "dependent")]
+    pub dependents: Vec<Dependent>,
+    #[xml(child = "method_context")]
+    pub method_context: Option<MethodContext>,
+    #[xml(child = "exec_method")]
+    pub exec_methods: Vec<ExecMethod>,
     #[xml(child = "property_group")]
     pub property_groups: Vec<PropertyGroup>,
+    #[xml(child = "template")]
+    pub template: Option<Template>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "create_default_instance")]
+pub struct CreateDefaultInstance {
+    #[xml(attr = "enabled")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "single_instance")]
+pub struct SingleInstance {}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "restarter")]
+pub struct Restarter {
+    #[xml(child = "service_fmri")]
+    pub service_fmri: ServiceFmri,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "service_fmri")]
+pub struct ServiceFmri {
+    #[xml(attr = "value")]
+    pub value: String,
+}
+
+impl ServiceFmri {
+    pub fn new(value: &str) -> Self {
+        Self {
+            value: value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DependencyGrouping {
+    RequireAll,
+    RequireAny,
+    OptionalAll,
+    ExcludeAll,
+}
+
+impl Default for DependencyGrouping {
+    fn default() -> Self {
+        Self::RequireAll
+    }
+}
+
+impl FromStr for DependencyGrouping {
+    type Err = SMFError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "require_all" => Ok(Self::RequireAll),
+            "require_any" => Ok(Self::RequireAny),
+            "optional_all" => Ok(Self::OptionalAll),
+            "exclude_all" => Ok(Self::ExcludeAll),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("dependency grouping"),
+            )),
+        }
+    }
+}
+
+impl Display for DependencyGrouping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyGrouping::RequireAll => write!(f, "require_all"),
+            DependencyGrouping::RequireAny => write!(f, "require_any"),
+            DependencyGrouping::OptionalAll => write!(f, "optional_all"),
+            DependencyGrouping::ExcludeAll => write!(f, "exclude_all"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RestartOn {
+    None,
+    Error,
+    Restart,
+    Refresh,
+}
+
+impl Default for RestartOn {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl FromStr for RestartOn {
+    type Err = SMFError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "error" => Ok(Self::Error),
+            "restart" => Ok(Self::Restart),
+            "refresh" => Ok(Self::Refresh),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("restart_on"),
+            )),
+        }
+    }
+}
+
+impl Display for RestartOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartOn::None => write!(f, "none"),
+            RestartOn::Error => write!(f, "error"),
+            RestartOn::Restart => write!(f, "restart"),
+            RestartOn::Refresh => write!(f, "refresh"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DependencyType {
+    Service,
+    Path,
+}
+
+impl Default for DependencyType {
+    fn default() -> Self {
+        Self::Service
+    }
+}
+
+impl FromStr for DependencyType {
+    type Err = SMFError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "service" => Ok(Self::Service),
+            "path" => Ok(Self::Path),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("dependency type"),
+            )),
+        }
+    }
+}
+
+impl Display for DependencyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyType::Service => write!(f, "service"),
+            DependencyType::Path => write!(f, "path"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "dependency")]
+pub struct Dependency {
+    #[xml(attr = "name")]
+    pub name: String,
+    #[xml(attr = "grouping")]
+    pub grouping: DependencyGrouping,
+    #[xml(attr = "restart_on")]
+    pub restart_on: RestartOn,
+    #[xml(attr = "type")]
+    pub dependency_type: DependencyType,
+    #[xml(attr = "delete")]
+    pub delete: Option<bool>,
+    #[xml(child = "service_fmri")]
+    pub service_fmris: Vec<ServiceFmri>,
+    #[xml(child = "stability")]
+    pub stability: Option<Stability>,
+    #[xml(child = "propval")]
+    pub values: Vec<PropVal>,
+    #[xml(child = "property")]
+    pub properties: Vec<Property>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "dependent")]
+pub struct Dependent {
+    #[xml(attr = "name")]
+    pub name: String,
+    #[xml(attr = "grouping")]
+    pub grouping: DependencyGrouping,
+    #[xml(attr = "restart_on")]
+    pub restart_on: RestartOn,
+    #[xml(attr = "delete")]
+    pub delete: Option<bool>,
+    #[xml(attr = "override")]
+    pub override_: Option<bool>,
+    #[xml(child = "service_fmri")]
+    pub service_fmri: ServiceFmri,
+    #[xml(child = "stability")]
+    pub stability: Option<Stability>,
+    #[xml(child = "propval")]
+    pub values: Vec<PropVal>,
+    #[xml(child = "property")]
+    pub properties: Vec<Property>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "method_context")]
+pub struct MethodContext {
+    #[xml(attr = "working_directory")]
+    pub working_directory: Option<String>,
+    #[xml(attr = "project")]
+    pub project: Option<String>,
+    #[xml(attr = "resource_pool")]
+    pub resource_pool: Option<String>,
+    #[xml(attr = "security_flags")]
+    pub security_flags: Option<String>,
+    #[xml(child = "method_profile")]
+    pub method_profile: Option<MethodProfile>,
+    #[xml(child = "method_credential")]
+    pub method_credential: Option<MethodCredential>,
+    #[xml(child = "method_environment")]
+    pub method_environment: Option<MethodEnvironment>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "method_profile")]
+pub struct MethodProfile {
+    #[xml(attr = "name")]
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "method_credential")]
+pub struct MethodCredential {
+    #[xml(attr = "user")]
+    pub user: String,
+    #[xml(attr = "group")]
+    pub group: Option<String>,
+    #[xml(attr = "supp_groups")]
+    pub supp_groups: Option<String>,
+    #[xml(attr = "privileges")]
+    pub privileges: Option<String>,
+    #[xml(attr = "limit_privileges")]
+    pub limit_privileges: Option<String>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "method_environment")]
+pub struct MethodEnvironment {
+    #[xml(child = "envvar")]
+    pub variables: Vec<EnvVar>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "envvar")]
+pub struct EnvVar {
+    #[xml(attr = "name")]
+    pub name: String,
+    #[xml(attr = "value")]
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "exec_method")]
+pub struct ExecMethod {
+    #[xml(attr = "type")]
+    pub method_type: ExecMethodType,
+    #[xml(attr = "name")]
+    pub name: String,
+    #[xml(attr = "exec")]
+    pub exec: String,
+    #[xml(attr = "timeout_seconds")]
+    pub timeout_seconds: i64,
+    #[xml(attr = "delete")]
+    pub delete: Option<bool>,
+    #[xml(child = "method_context")]
+    pub method_context: Option<MethodContext>,
+    #[xml(child = "stability")]
+    pub stability: Option<Stability>,
+    #[xml(child = "propval")]
+    pub values: Vec<PropVal>,
+    #[xml(child = "property")]
+    pub properties: Vec<Property>,
+}
+
+impl ExecMethod {
+    pub fn new(name: &str, exec: &str, timeout_seconds: i64) -> Self {
+        Self {
+            name: name.to_string(),
+            exec: exec.to_string(),
+            timeout_seconds,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExecMethodType {
+    Method,
+    Monitor,
+}
+
+impl Default for ExecMethodType {
+    fn default() -> Self {
+        Self::Method
+    }
+}
+
+impl FromStr for ExecMethodType {
+    type Err = SMFError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "method" => Ok(Self::Method),
+            "monitor" => Ok(Self::Monitor),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("exec_method type"),
+            )),
+        }
+    }
+}
+
+impl Display for ExecMethodType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecMethodType::Method => write!(f, "method"),
+            ExecMethodType::Monitor => write!(f, "monitor"),
+        }
+    }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum StabilityValue {
+    Standard,
+    Stable,
+    Evolving,
+    Unstable,
+    External,
+    Obsolete,
+}
+
+impl Default for StabilityValue {
+    fn default() -> Self {
+        Self::Unstable
+    }
+}
+
+impl FromStr for StabilityValue {
+    type Err = SMFError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "Standard" => Ok(Self::Standard),
+            "Stable" => Ok(Self::Stable),
+            "Evolving" => Ok(Self::Evolving),
+            "Unstable" => Ok(Self::Unstable),
+            "External" => Ok(Self::External),
+            "Obsolete" => Ok(Self::Obsolete),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("stability"),
+            )),
+        }
+    }
+}
+
+impl Display for StabilityValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StabilityValue::Standard => write!(f, "Standard"),
+            StabilityValue::Stable => write!(f, "Stable"),
+            StabilityValue::Evolving => write!(f, "Evolving"),
+            StabilityValue::Unstable => write!(f, "Unstable"),
+            StabilityValue::External => write!(f, "External"),
+            StabilityValue::Obsolete => write!(f, "Obsolete"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "stability")]
+pub struct Stability {
+    #[xml(attr = "value")]
+    pub value: StabilityValue,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "template")]
+pub struct Template {
+    #[xml(child = "common_name")]
+    pub common_name: Option<CommonName>,
+    #[xml(child = "description")]
+    pub description: Option<Description>,
+    #[xml(child = "documentation")]
+    pub documentation: Option<Documentation>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "common_name")]
+pub struct CommonName {
+    #[xml(child = "loctext")]
+    pub texts: Vec<LocText>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "description")]
+pub struct Description {
+    #[xml(child = "loctext")]
+    pub texts: Vec<LocText>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "loctext")]
+pub struct LocText {
+    #[xml(attr = "xml:lang")]
+    pub lang: String,
+    #[xml(text)]
+    pub text: String,
+}
+
+impl LocText {
+    /// Text in the C locale which is what svcs(1) falls back to.
+    pub fn c(text: &str) -> Self {
+        Self {
+            lang: String::from("C"),
+            text: text.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "documentation")]
+pub struct Documentation {
+    #[xml(child = "manpage")]
+    pub manpages: Vec<Manpage>,
+    #[xml(child = "doc_link")]
+    pub doc_links: Vec<DocLink>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "manpage")]
+pub struct Manpage {
+    #[xml(attr = "title")]
+    pub title: String,
+    #[xml(attr = "section")]
+    pub section: String,
+    #[xml(attr = "manpath")]
+    pub manpath: Option<String>,
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "doc_link")]
+pub struct DocLink {
+    #[xml(attr = "name")]
+    pub name: String,
+    #[xml(attr = "uri")]
+    pub uri: String,
+}
+
+/// SMF does not restrict property group types but these are the ones the system knows about.
+/// Anything else ends up in Other
 #[derive(Debug, PartialEq, Clone)]
 pub enum PropertyGroupType {
     System,
+    Application,
+    Framework,
+    Method,
+    Dependency,
+    Template,
+    Other(String),
 }
 
 impl Default for PropertyGroupType {
@@ -130,10 +660,16 @@ impl FromStr for PropertyGroupType {
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "system" => Ok(Self::System),
-            x => Err(SMFError::ParseError(
-                x.to_owned(),
+            "application" => Ok(Self::Application),
+            "framework" => Ok(Self::Framework),
+            "method" => Ok(Self::Method),
+            "dependency" => Ok(Self::Dependency),
+            "template" => Ok(Self::Template),
+            "" => Err(SMFError::ParseError(
+                s.to_owned(),
                 String::from("property group"),
             )),
+            x => Ok(Self::Other(x.to_owned())),
         }
     }
 }
@@ -142,6 +678,12 @@ impl Display for PropertyGroupType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             PropertyGroupType::System => write!(f, "system"),
+            PropertyGroupType::Application => write!(f, "application"),
+            PropertyGroupType::Framework => write!(f, "framework"),
+            PropertyGroupType::Method => write!(f, "method"),
+            PropertyGroupType::Dependency => write!(f, "dependency"),
+            PropertyGroupType::Template => write!(f, "template"),
+            PropertyGroupType::Other(x) => write!(f, "{}", x),
         }
     }
 }
@@ -153,13 +695,31 @@ pub struct PropertyGroup {
     pub name: String,
     #[xml(attr = "type")]
     pub pg_type: PropertyGroupType,
+    #[xml(child = "stability")]
+    pub stability: Option<Stability>,
     #[xml(child = "propval")]
     pub values: Vec<PropVal>,
+    #[xml(child = "property")]
+    pub properties: Vec<Property>,
 }
 
+/// All value types SMF properties can have. See scf_value_create(3SCF)
 #[derive(Debug, PartialEq, Clone)]
 pub enum PropValType {
+    Count,
+    Integer,
+    Opaque,
+    Host,
+    Hostname,
+    NetAddress,
+    NetAddressV4,
+    NetAddressV6,
+    Time,
     AString,
+    UString,
+    Boolean,
+    Fmri,
+    Uri,
 }
 
 impl Default for PropValType {
@@ -173,7 +733,20 @@ impl FromStr for PropValType {
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
+            "count" => Ok(Self::Count),
+            "integer" => Ok(Self::Integer),
+            "opaque" => Ok(Self::Opaque),
+            "host" => Ok(Self::Host),
+            "hostname" => Ok(Self::Hostname),
+            "net_address" => Ok(Self::NetAddress),
+            "net_address_v4" => Ok(Self::NetAddressV4),
+            "net_address_v6" => Ok(Self::NetAddressV6),
+            "time" => Ok(Self::Time),
             "astring" | "string" => Ok(Self::AString),
+            "ustring" => Ok(Self::UString),
+            "boolean" => Ok(Self::Boolean),
+            "fmri" => Ok(Self::Fmri),
+            "uri" => Ok(Self::Uri),
             x => Err(SMFError::ParseError(
                 x.to_owned(),
                 String::from("propval type"),
@@ -185,11 +758,88 @@ impl FromStr for PropValType {
 impl Display for PropValType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            PropValType::Count => write!(f, "count"),
+            PropValType::Integer => write!(f, "integer"),
+            PropValType::Opaque => write!(f, "opaque"),
+            PropValType::Host => write!(f, "host"),
+            PropValType::Hostname => write!(f, "hostname"),
+            PropValType::NetAddress => write!(f, "net_address"),
+            PropValType::NetAddressV4 => write!(f, "net_address_v4"),
+            PropValType::NetAddressV6 => write!(f, "net_address_v6"),
+            PropValType::Time => write!(f, "time"),
             PropValType::AString => write!(f, "astring"),
+            PropValType::UString => write!(f, "ustring"),
+            PropValType::Boolean => write!(f, "boolean"),
+            PropValType::Fmri => write!(f, "fmri"),
+            PropValType::Uri => write!(f, "uri"),
+        }
+    }
+}
+
+impl PropValType {
+    /// Check that value is acceptable for this type. svccfg would reject the manifest
+    /// on import otherwise and we rather know that while building.
+    pub fn is_valid(&self, value: &str) -> bool {
+        match self {
+            PropValType::Count => value.parse::<u64>().is_ok(),
+            PropValType::Integer => value.parse::<i64>().is_ok(),
+            PropValType::Opaque => {
+                value.len().is_multiple_of(2) && value.chars().all(|c| c.is_ascii_hexdigit())
+            }
+            PropValType::Host => {
+                is_valid_net_address::<IpAddr>(value, 128) || is_valid_hostname(value)
+            }
+            PropValType::Hostname => is_valid_hostname(value),
+            PropValType::NetAddress => is_valid_net_address::<IpAddr>(value, 128),
+            PropValType::NetAddressV4 => is_valid_net_address::<Ipv4Addr>(value, 32),
+            PropValType::NetAddressV6 => is_valid_net_address::<Ipv6Addr>(value, 128),
+            PropValType::Time => {
+                let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+                secs.parse::<i64>().is_ok()
+                    && nanos.len() <= 9
+                    && nanos.chars().all(|c| c.is_ascii_digit())
+            }
+            PropValType::AString | PropValType::UString => true,
+            PropValType::Boolean => value == "true" || value == "false",
+            PropValType::Fmri => {
+                value.starts_with("svc:/")
+                    || value.starts_with("file:/")
+                    || value.starts_with("lrc:/")
+            }
+            PropValType::Uri => match value.split_once(':') {
+                Some((scheme, _)) => {
+                    !scheme.is_empty()
+                        && scheme
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+                }
+                None => false,
+            },
         }
     }
 }
 
+fn is_valid_hostname(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 255
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn is_valid_net_address<A: FromStr>(value: &str, max_prefix: u8) -> bool {
+    match value.split_once('/') {
+        Some((addr, prefix)) => {
+            addr.parse::<A>().is_ok() && prefix.parse::<u8>().is_ok_and(|p| p <= max_prefix)
+        }
+        None => value.parse::<A>().is_ok(),
+    }
+}
+
 #[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
 #[xml(tag = "propval")]
 pub struct PropVal {
@@ -201,24 +851,235 @@ pub struct PropVal {
     pub value: String,
 }
 
-pub fn parse_site_manifest<P: AsRef<Path>>(path: P) -> Result<ServiceBundle> {
+impl PropVal {
+    /// Create a propval making sure the value fits the type
+    pub fn new(name: &str, pb_type: PropValType, value: &str) -> Result<Self> {
+        if !pb_type.is_valid(value) {
+            return Err(SMFError::InvalidPropertyValue(
+                name.to_string(),
+                value.to_string(),
+                pb_type,
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            pb_type,
+            value: value.to_string(),
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.pb_type.is_valid(&self.value) {
+            return Err(SMFError::InvalidPropertyValue(
+                self.name.clone(),
+                self.value.clone(),
+                self.pb_type.clone(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A property with possibly multiple values. Unlike propval the values live
+/// in a list element named after the type e.g. `<astring_list>`
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "property")]
+pub struct Property {
+    #[xml(attr = "name")]
+    pub name: String,
+    #[xml(attr = "type")]
+    pub prop_type: PropValType,
+    #[xml(attr = "override")]
+    pub override_: Option<bool>,
+    #[xml(
+        child = "count_list",
+        child = "integer_list",
+        child = "opaque_list",
+        child = "host_list",
+        child = "hostname_list",
+        child = "net_address_list",
+        child = "net_address_v4_list",
+        child = "net_address_v6_list",
+        child = "time_list",
+        child = "astring_list",
+        child = "ustring_list",
+        child = "boolean_list",
+        child = "fmri_list",
+        child = "uri_list"
+    )]
+    pub values: Option<PropertyValueList>,
+}
+
+impl Property {
+    pub fn new(name: &str, prop_type: PropValType, values: &[&str]) -> Result<Self> {
+        for value in values {
+            if !prop_type.is_valid(value) {
+                return Err(SMFError::InvalidPropertyValue(
+                    name.to_string(),
+                    value.to_string(),
+                    prop_type,
+                ));
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            values: Some(PropertyValueList::new(&prop_type, values)),
+            prop_type,
+            override_: None,
+        })
+    }
+
+    pub fn get_values(&self) -> Vec<String> {
+        match &self.values {
+            Some(list) => list.value_nodes().iter().map(|v| v.value.clone()).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for value in self.get_values() {
+            if !self.prop_type.is_valid(&value) {
+                return Err(SMFError::InvalidPropertyValue(
+                    self.name.clone(),
+                    value,
+                    self.prop_type.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+#[xml(tag = "value_node")]
+pub struct ValueNode {
+    #[xml(attr = "value")]
+    pub value: String,
+}
+
+/*
+ * Every property type has its own list element which only differs by name.
+ */
+macro_rules! value_list {
+    ($name:ident, $tag:tt) => {
+        #[derive(Debug, PartialEq, XmlRead, XmlWrite, Default, Clone)]
+        #[xml(tag = $tag)]
+        pub struct $name {
+            #[xml(child = "value_node")]
+            pub values: Vec<ValueNode>,
+        }
+    };
+}
+
+value_list!(CountList, "count_list");
+value_list!(IntegerList, "integer_list");
+value_list!(OpaqueList, "opaque_list");
+value_list!(HostList, "host_list");
+value_list!(HostnameList, "hostname_list");
+value_list!(NetAddressList, "net_address_list");
+value_list!(NetAddressV4List, "net_address_v4_list");
+value_list!(NetAddressV6List, "net_address_v6_list");
+value_list!(TimeList, "time_list");
+value_list!(AStringList, "astring_list");
+value_list!(UStringList, "ustring_list");
+value_list!(BooleanList, "boolean_list");
+value_list!(FmriList, "fmri_list");
+value_list!(UriList, "uri_list");
+
+#[derive(Debug, PartialEq, XmlRead, XmlWrite, Clone)]
+pub enum PropertyValueList {
+    #[xml(tag = "count_list")]
+    Count(CountList),
+    #[xml(tag = "integer_list")]
+    Integer(IntegerList),
+    #[xml(tag = "opaque_list")]
+    Opaque(OpaqueList),
+    #[xml(tag = "host_list")]
+    Host(HostList),
+    #[xml(tag = "hostname_list")]
+    Hostname(HostnameList),
+    #[xml(tag = "net_address_list")]
+    NetAddress(NetAddressList),
+    #[xml(tag = "net_address_v4_list")]
+    NetAddressV4(NetAddressV4List),
+    #[xml(tag = "net_address_v6_list")]
+    NetAddressV6(NetAddressV6List),
+    #[xml(tag = "time_list")]
+    Time(TimeList),
+    #[xml(tag = "astring_list")]
+    AString(AStringList),
+    #[xml(tag = "ustring_list")]
+    UString(UStringList),
+    #[xml(tag = "boolean_list")]
+    Boolean(BooleanList),
+    #[xml(tag = "fmri_list")]
+    Fmri(FmriList),
+    #[xml(tag = "uri_list")]
+    Uri(UriList),
+}
+
+impl PropertyValueList {
+    pub fn new(prop_type: &PropValType, values: &[&str]) -> Self {
+        let values: Vec<ValueNode> = values
+            .iter()
+            .map(|v| ValueNode {
+                value: v.to_string(),
+            })
+            .collect();
+        match prop_type {
+            PropValType::Count => Self::Count(CountList { values }),
+            PropValType::Integer => Self::Integer(IntegerList { values }),
+            PropValType::Opaque => Self::Opaque(OpaqueList { values }),
+            PropValType::Host => Self::Host(HostList { values }),
+            PropValType::Hostname => Self::Hostname(HostnameList { values }),
+            PropValType::NetAddress => Self::NetAddress(NetAddressList { values }),
+            PropValType::NetAddressV4 => Self::NetAddressV4(NetAddressV4List { values }),
+            PropValType::NetAddressV6 => Self::NetAddressV6(NetAddressV6List { values }),
+            PropValType::Time => Self::Time(TimeList { values }),
+            PropValType::AString => Self::AString(AStringList { values }),
+            PropValType::UString => Self::UString(UStringList { values }),
+            PropValType::Boolean => Self::Boolean(BooleanList { values }),
+            PropValType::Fmri => Self::Fmri(FmriList { values }),
+            PropValType::Uri => Self::Uri(UriList { values }),
+        }
+    }
+
+    pub fn value_nodes(&self) -> &[ValueNode] {
+        match self {
+            PropertyValueList::Count(l) => &l.values,
+            PropertyValueList::Integer(l) => &l.values,
+            PropertyValueList::Opaque(l) => &l.values,
+            PropertyValueList::Host(l) => &l.values,
+            PropertyValueList::Hostname(l) => &l.values,
+            PropertyValueList::NetAddress(l) => &l.values,
+            PropertyValueList::NetAddressV4(l) => &l.values,
+            PropertyValueList::NetAddressV6(l) => &l.values,
+            PropertyValueList::Time(l) => &l.values,
+            PropertyValueList::AString(l) => &l.values,
+            PropertyValueList::UString(l) => &l.values,
+            PropertyValueList::Boolean(l) => &l.values,
+            PropertyValueList::Fmri(l) => &l.values,
+            PropertyValueList::Uri(l) => &l.values,
+        }
+    }
+}
+
+pub fn parse_manifest<P: AsRef<Path>>(path: P) -> Result<ServiceBundle> {
     use std::fs::read_to_string;
     let content = read_to_string(path.as_ref())?;
     Ok(ServiceBundle::from_str(&content)?)
 }
 
-pub fn write_site_manifest<P: AsRef<Path>>(path: P, bundle: &ServiceBundle) -> Result<()> {
+pub fn write_manifest<P: AsRef<Path>>(path: P, bundle: &ServiceBundle) -> Result<()> {
     use std::fs::write;
-    let content = vec![
-        SITE_MANIFEST_HEADER.clone().to_string(),
-        bundle.to_string()?,
-    ]
-    .join("\n");
+    let content = manifest_to_string(bundle)?;
     write(path.as_ref(), &content.into_bytes())?;
     Ok(())
 }
 
-pub fn site_manifest_to_string(bundle: &ServiceBundle) -> Result<String> {
+pub fn manifest_to_string(bundle: &ServiceBundle) -> Result<String> {
     let content = vec![
         SITE_MANIFEST_HEADER.clone().to_string(),
         bundle.to_string()?,
@@ -226,6 +1087,18 @@ pub fn site_manifest_to_string(bundle: &ServiceBundle) -> Result<String> {
     Ok(content.join("\n"))
 }
 
+pub fn parse_site_manifest<P: AsRef<Path>>(path: P) -> Result<ServiceBundle> {
+    parse_manifest(path)
+}
+
+pub fn write_site_manifest<P: AsRef<Path>>(path: P, bundle: &ServiceBundle) -> Result<()> {
+    write_manifest(path, bundle)
+}
+
+pub fn site_manifest_to_string(bundle: &ServiceBundle) -> Result<String> {
+    manifest_to_string(bundle)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::read_to_string;
@@ -235,13 +1108,27 @@ mod tests {
     use crate::smf::{PropValType, PropertyGroupType, Service};
 
     use super::{
-        site_manifest_to_string, BundleType, Instance, PropVal, PropertyGroup, ServiceType,
+        manifest_to_string, site_manifest_to_string, BundleType, CommonName, CreateDefaultInstance,
+        Dependency, DependencyGrouping, DependencyType, Dependent, Documentation, EnvVar,
+        ExecMethod, Instance, LocText, Manpage, MethodContext, MethodCredential, MethodEnvironment,
+        PropVal, Property, PropertyGroup, RestartOn, ServiceFmri, ServiceType, SingleInstance,
+        Stability, StabilityValue, Template,
     };
 
     use super::ServiceBundle;
 
     use hard_xml::XmlRead;
 
+    /// Collapse a pretty printed golden file into what the writer produces.
+    fn collapse_golden(content: &str) -> String {
+        content
+            .split("\n")
+            .map(|line| line.trim().to_owned())
+            .collect::<Vec<String>>()
+            .join("")
+            .replace("<!--br-->", "\n")
+    }
+
     #[test]
     fn test_site_xml_parse() -> Result<()> {
         let expected = ServiceBundle {
@@ -264,10 +1151,13 @@ mod tests {
                                 value: "US-English".into(),
                             }]
                             .to_vec(),
+                            ..Default::default()
                         }]
                         .to_vec(),
+                        ..Default::default()
                     }]
                     .to_vec(),
+                    ..Default::default()
                 },
                 Service {
                     name: "application/graphical-login/gdm".into(),
@@ -277,8 +1167,10 @@ mod tests {
                         name: "default".into(),
                         enabled: Some(true),
                         property_groups: [].to_vec(),
+                        ..Default::default()
                     }]
                     .to_vec(),
+                    ..Default::default()
                 },
             ]
             .to_vec(),
@@ -292,16 +1184,181 @@ mod tests {
     #[test]
     fn test_full_cycle() -> Result<()> {
         let content = read_to_string("testdata/site.xml").into_diagnostic()?;
-        let expected = content
-            .split("\n")
-            .into_iter()
-            .map(|line| line.trim().to_owned())
-            .collect::<Vec<String>>()
-            .join("")
-            .replace("<!--br-->", "\n");
+        let expected = collapse_golden(&content);
         let test = ServiceBundle::from_str(&expected).into_diagnostic()?;
         let actual = site_manifest_to_string(&test)?;
         assert_eq!(expected, actual);
         Ok(())
     }
+
+    fn expected_manifest() -> Result<ServiceBundle> {
+        Ok(ServiceBundle {
+            bundle_type: BundleType::Manifest,
+            name: "garage".into(),
+            services: vec![Service {
+                name: "network/storage/garage".into(),
+                version: 1,
+                service_type: ServiceType::Service,
+                create_default_instance: Some(CreateDefaultInstance { enabled: false }),
+                single_instance: Some(SingleInstance {}),
+                dependencies: vec![
+                    Dependency {
+                        name: "network".into(),
+                        grouping: DependencyGrouping::RequireAll,
+                        restart_on: RestartOn::Error,
+                        dependency_type: DependencyType::Service,
+                        service_fmris: vec![ServiceFmri::new("svc:/milestone/network:default")],
+                        ..Default::default()
+                    },
+                    Dependency {
+                        name: "config_file".into(),
+                        grouping: DependencyGrouping::RequireAll,
+                        restart_on: RestartOn::None,
+                        dependency_type: DependencyType::Path,
+                        service_fmris: vec![ServiceFmri::new("file://localhost/etc/garage.toml")],
+                        ..Default::default()
+                    },
+                ],
+                dependents: vec![Dependent {
+                    name: "garage_multi-user".into(),
+                    grouping: DependencyGrouping::OptionalAll,
+                    restart_on: RestartOn::None,
+                    service_fmri: ServiceFmri::new("svc:/milestone/multi-user"),
+                    ..Default::default()
+                }],
+                method_context: Some(MethodContext {
+                    working_directory: Some("/var/lib/garage".into()),
+                    method_credential: Some(MethodCredential {
+                        user: "garage".into(),
+                        group: Some("garage".into()),
+                        privileges: Some("basic,net_privaddr".into()),
+                        ..Default::default()
+                    }),
+                    method_environment: Some(MethodEnvironment {
+                        variables: vec![EnvVar {
+                            name: "RUST_LOG".into(),
+                            value: "info".into(),
+                        }],
+                    }),
+                    ..Default::default()
+                }),
+                exec_methods: vec![
+                    ExecMethod::new("start", "/usr/bin/garage -c %{config/file} server &", 60),
+                    ExecMethod::new("stop", ":kill", 60),
+                    ExecMethod::new("refresh", ":kill -HUP", 60),
+                ],
+                property_groups: vec![
+                    PropertyGroup {
+                        name: "config".into(),
+                        pg_type: PropertyGroupType::Application,
+                        values: vec![
+                            PropVal::new("file", PropValType::AString, "/etc/garage.toml")?,
+                            PropVal::new("threads", PropValType::Count, "4")?,
+                            PropVal::new("offset", PropValType::Integer, "-3")?,
+                            PropVal::new("debug", PropValType::Boolean, "false")?,
+                            PropVal::new("listen", PropValType::NetAddress, "0.0.0.0/0")?,
+                            PropVal::new("upstream", PropValType::Fmri, "svc:/network/dns/client")?,
+                        ],
+                        properties: vec![Property::new(
+                            "peers",
+                            PropValType::Host,
+                            &["10.0.0.1", "garage2.example.com"],
+                        )?],
+                        ..Default::default()
+                    },
+                    PropertyGroup {
+                        name: "startd".into(),
+                        pg_type: PropertyGroupType::Framework,
+                        values: vec![PropVal::new("duration", PropValType::AString, "child")?],
+                        ..Default::default()
+                    },
+                ],
+                stability: Some(Stability {
+                    value: StabilityValue::Unstable,
+                }),
+                template: Some(Template {
+                    common_name: Some(CommonName {
+                        texts: vec![LocText::c("Garage object storage")],
+                    }),
+                    documentation: Some(Documentation {
+                        manpages: vec![Manpage {
+                            title: "garage".into(),
+                            section: "1".into(),
+                            manpath: None,
+                        }],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+        })
+    }
+
+    #[test]
+    fn test_manifest_xml_parse() -> Result<()> {
+        let content = read_to_string("testdata/smf/manifest.xml").into_diagnostic()?;
+        let actual = ServiceBundle::from_str(&content).into_diagnostic()?;
+        assert_eq!(expected_manifest()?, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_golden_write() -> Result<()> {
+        let content = read_to_string("testdata/smf/manifest.xml").into_diagnostic()?;
+        let expected = collapse_golden(&content);
+        let actual = manifest_to_string(&expected_manifest()?)?;
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_full_cycle() -> Result<()> {
+        let content = read_to_string("testdata/smf/manifest.xml").into_diagnostic()?;
+        let expected = collapse_golden(&content);
+        let test = ServiceBundle::from_str(&expected).into_diagnostic()?;
+        let actual = manifest_to_string(&test)?;
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let valid = [
+            (PropValType::Count, "42"),
+            (PropValType::Integer, "-42"),
+            (PropValType::Boolean, "true"),
+            (PropValType::Opaque, "deadbeef"),
+            (PropValType::NetAddress, "fe80::1/10"),
+            (PropValType::NetAddressV4, "192.168.1.1/24"),
+            (PropValType::NetAddressV6, "::1"),
+            (PropValType::Host, "10.0.0.1"),
+            (PropValType::Hostname, "pkg.openindiana.org"),
+            (PropValType::Time, "1681900000.500"),
+            (PropValType::Fmri, "svc:/network/ssh:default"),
+            (PropValType::Uri, "https://openindiana.org"),
+            (PropValType::AString, "anything goes"),
+        ];
+        for (t, v) in valid {
+            assert!(t.is_valid(v), "{} should be a valid {}", v, t);
+        }
+
+        let invalid = [
+            (PropValType::Count, "-1"),
+            (PropValType::Integer, "one"),
+            (PropValType::Boolean, "yes"),
+            (PropValType::Opaque, "abc"),
+            (PropValType::NetAddressV4, "::1"),
+            (PropValType::NetAddressV4, "10.0.0.1/33"),
+            (PropValType::Hostname, "-bad-.example.com"),
+            (PropValType::Time, "soon"),
+            (PropValType::Fmri, "network/ssh"),
+            (PropValType::Uri, "no scheme"),
+        ];
+        for (t, v) in invalid {
+            assert!(!t.is_valid(v), "{} should not be a valid {}", v, t);
+        }
+
+        assert!(PropVal::new("threads", PropValType::Count, "many").is_err());
+    }
 }
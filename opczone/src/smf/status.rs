@@ -0,0 +1,400 @@
+use super::{Result, SMFError};
+use crate::{execute, ExecOptions};
+use common::debug;
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const SVCS: &str = "/usr/bin/svcs";
+
+/// svcs only talks to the repository so anything taking longer than this is stuck
+const SVCS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often wait_for_online polls the service state
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of a service instance as reported by svcs(1)
+#[derive(Debug, PartialEq, Clone)]
+pub enum ServiceState {
+    Uninitialized,
+    Offline,
+    Online,
+    Degraded,
+    Maintenance,
+    Disabled,
+    LegacyRun,
+}
+
+impl FromStr for ServiceState {
+    type Err = SMFError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "uninitialized" => Ok(Self::Uninitialized),
+            "offline" => Ok(Self::Offline),
+            "online" => Ok(Self::Online),
+            "degraded" => Ok(Self::Degraded),
+            "maintenance" => Ok(Self::Maintenance),
+            "disabled" => Ok(Self::Disabled),
+            "legacy_run" => Ok(Self::LegacyRun),
+            x => Err(SMFError::ParseError(
+                x.to_owned(),
+                String::from("service state"),
+            )),
+        }
+    }
+}
+
+impl Display for ServiceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceState::Uninitialized => write!(f, "uninitialized"),
+            ServiceState::Offline => write!(f, "offline"),
+            ServiceState::Online => write!(f, "online"),
+            ServiceState::Degraded => write!(f, "degraded"),
+            ServiceState::Maintenance => write!(f, "maintenance"),
+            ServiceState::Disabled => write!(f, "disabled"),
+            ServiceState::LegacyRun => write!(f, "legacy_run"),
+        }
+    }
+}
+
+/// One line of `svcs -H -o state,fmri`
+#[derive(Debug, PartialEq, Clone)]
+pub struct ServiceStatus {
+    pub fmri: String,
+    pub state: ServiceState,
+    /// svcs marks instances which are moving to another state with a `*`
+    pub transitioning: bool,
+}
+
+/// One block of `svcs -x` output
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ServiceExplanation {
+    pub fmri: String,
+    pub name: Option<String>,
+    pub state: Option<ServiceState>,
+    pub since: Option<String>,
+    pub reason: String,
+    pub see: Vec<String>,
+    pub impact: Vec<String>,
+}
+
+impl ServiceExplanation {
+    /// The log file of the service if svcs mentioned one
+    pub fn log_file(&self) -> Option<&str> {
+        self.see
+            .iter()
+            .find(|s| s.starts_with("/"))
+            .map(|s| s.as_str())
+    }
+}
+
+/// Parse the output of `svcs -H -o state,fmri`
+pub fn parse_svcs_output(output: &str) -> Result<Vec<ServiceStatus>> {
+    let mut states = vec![];
+    for line in output.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (state, fmri) = line
+            .split_once(char::is_whitespace)
+            .ok_or(SMFError::ParseError(
+                line.to_owned(),
+                String::from("svcs output line"),
+            ))?;
+
+        let (state, transitioning) = if let Some(state) = state.strip_suffix("*") {
+            (state, true)
+        } else {
+            (state, false)
+        };
+
+        states.push(ServiceStatus {
+            fmri: fmri.trim().to_string(),
+            state: ServiceState::from_str(state)?,
+            transitioning,
+        });
+    }
+
+    Ok(states)
+}
+
+/// Split a line of svcs -x output into its key and value. Keys are right aligned
+/// single words like `State:` or `Reason:`. Continuation lines have no key.
+fn split_explanation_key(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.trim_start().split_once(": ")?;
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some((key, value.trim()))
+    } else {
+        None
+    }
+}
+
+/// Parse the output of `svcs -x`
+pub fn parse_svcs_explanation(output: &str) -> Result<Vec<ServiceExplanation>> {
+    let mut explanations = vec![];
+    let mut current: Option<ServiceExplanation> = None;
+    let mut last_key = String::new();
+
+    for line in output.lines() {
+        if line.trim().is_empty() {
+            if let Some(explanation) = current.take() {
+                explanations.push(explanation);
+            }
+            continue;
+        }
+
+        let explanation = match current.as_mut() {
+            Some(explanation) => explanation,
+            None => {
+                // The first line of a block is the fmri followed by the service name
+                let line = line.trim();
+                let (fmri, name) = if let Some((fmri, name)) = line.split_once(" (") {
+                    (fmri, Some(name.trim_end_matches(")").to_string()))
+                } else {
+                    (line, None)
+                };
+                current = Some(ServiceExplanation {
+                    fmri: fmri.to_string(),
+                    name,
+                    ..Default::default()
+                });
+                last_key = String::new();
+                continue;
+            }
+        };
+
+        let (key, value) = if let Some((key, value)) = split_explanation_key(line) {
+            last_key = key.to_string();
+            (key, value)
+        } else {
+            (last_key.as_str(), line.trim())
+        };
+
+        match key {
+            "State" => {
+                let (state, since) = if let Some((state, since)) = value.split_once(" since ") {
+                    (state, Some(since.to_string()))
+                } else {
+                    (value, None)
+                };
+                explanation.state = Some(ServiceState::from_str(state)?);
+                explanation.since = since;
+            }
+            "Reason" => {
+                if !explanation.reason.is_empty() {
+                    explanation.reason.push(' ');
+                }
+                explanation.reason.push_str(value);
+            }
+            "See" => explanation.see.push(value.to_string()),
+            "Impact" => explanation.impact.push(value.to_string()),
+            _ => debug!("ignoring svcs -x line: {}", line),
+        }
+    }
+
+    if let Some(explanation) = current.take() {
+        explanations.push(explanation);
+    }
+
+    Ok(explanations)
+}
+
+/// Query service states with svcs(1). Without a zone the queries run against the
+/// current zone. With a zone they run from the global zone with `svcs -z`.
+#[derive(Debug, Clone, Default)]
+pub struct Svcs {
+    pub zone: Option<String>,
+}
+
+impl Svcs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn zone(zonename: &str) -> Self {
+        Self {
+            zone: Some(zonename.to_string()),
+        }
+    }
+
+    fn svcs(&self, args: &[&str]) -> Result<crate::ExecOutput> {
+        let mut cmd = vec![SVCS];
+        if let Some(zone) = &self.zone {
+            cmd.push("-z");
+            cmd.push(zone);
+        }
+        cmd.extend_from_slice(args);
+
+        let opts = ExecOptions {
+            timeout: Some(SVCS_TIMEOUT),
+            capture_stdout: true,
+            live_log: false,
+            ..Default::default()
+        };
+
+        Ok(execute(&cmd, &opts)?)
+    }
+
+    /// States of the instances matching the fmris. svcs fails if a pattern does
+    /// not match any instance, the instances it did find are returned anyway.
+    pub fn states(&self, fmris: &[&str]) -> Result<Vec<ServiceStatus>> {
+        let mut args = vec!["-H", "-o", "state,fmri"];
+        args.extend_from_slice(fmris);
+        let output = self.svcs(&args)?;
+        let states = parse_svcs_output(&output.stdout_string())?;
+        if states.is_empty() && !output.success() {
+            debug!("svcs found no instances: {}", output.stderr_string());
+        }
+        Ok(states)
+    }
+
+    /// State of a single instance. Returns None if the instance does not exist (yet)
+    pub fn state(&self, fmri: &str) -> Result<Option<ServiceStatus>> {
+        Ok(self.states(&[fmri])?.into_iter().next())
+    }
+
+    /// Explanations for the given fmris or all services with problems if fmris is empty
+    pub fn explain(&self, fmris: &[&str]) -> Result<Vec<ServiceExplanation>> {
+        let mut args = vec!["-x"];
+        args.extend_from_slice(fmris);
+        let output = self.svcs(&args)?.into_result()?;
+        parse_svcs_explanation(&output.stdout_string())
+    }
+
+    /// Wait until all instances matching fmri are online. Fails as soon as one
+    /// instance enters maintenance or when the timeout is reached.
+    pub fn wait_for_online(&self, fmri: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let states = self.states(&[fmri])?;
+
+            if let Some(broken) = states.iter().find(|s| s.state == ServiceState::Maintenance) {
+                let explanation = self
+                    .explain(&[&broken.fmri])?
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default();
+                let log_file = explanation
+                    .log_file()
+                    .unwrap_or("unknown log file")
+                    .to_string();
+                return Err(SMFError::ServiceInMaintenance(
+                    broken.fmri.clone(),
+                    explanation.reason,
+                    log_file,
+                ));
+            }
+
+            if !states.is_empty()
+                && states
+                    .iter()
+                    .all(|s| s.state == ServiceState::Online && !s.transitioning)
+            {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                let last_state = if states.is_empty() {
+                    String::from("not present")
+                } else {
+                    states
+                        .iter()
+                        .map(|s| format!("{} {}", s.fmri, s.state))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                };
+                return Err(SMFError::ServiceTimeout(
+                    fmri.to_string(),
+                    timeout,
+                    last_state,
+                ));
+            }
+
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+/// Wait for a service in the current zone to come online
+pub fn wait_for_online(fmri: &str, timeout: Duration) -> Result<()> {
+    Svcs::new().wait_for_online(fmri, timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_svcs_explanation, parse_svcs_output, ServiceState, ServiceStatus};
+    use miette::Result;
+
+    #[test]
+    fn test_parse_svcs_output() -> Result<()> {
+        let output = "\
+legacy_run     lrc:/etc/rc2_d/S20sysetup
+online         svc:/system/svc/restarter:default
+maintenance    svc:/network/myapp:default
+offline*       svc:/network/ssh:default
+";
+        let states = parse_svcs_output(output)?;
+        assert_eq!(4, states.len());
+        assert_eq!(ServiceState::LegacyRun, states[0].state);
+        assert_eq!(
+            ServiceStatus {
+                fmri: "svc:/network/ssh:default".into(),
+                state: ServiceState::Offline,
+                transitioning: true,
+            },
+            states[3]
+        );
+        assert!(parse_svcs_output("bogus svc:/foo:default").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_svcs_explanation() -> Result<()> {
+        let output = "\
+svc:/network/myapp:default (My Application)
+ State: maintenance since Mon Oct 19 10:00:00 2026
+Reason: Start method exited with $SMF_EXIT_ERR_FATAL.
+   See: http://illumos.org/msg/SMF-8000-KS
+   See: /var/svc/log/network-myapp:default.log
+Impact: 2 dependent services are not running:
+        svc:/network/frontend:default
+        svc:/network/worker:default
+
+svc:/network/other:default (Other)
+ State: offline since Mon Oct 19 10:00:01 2026
+Reason: Dependency svc:/network/myapp:default is absent.
+";
+        let explanations = parse_svcs_explanation(output)?;
+        assert_eq!(2, explanations.len());
+
+        let myapp = &explanations[0];
+        assert_eq!("svc:/network/myapp:default", myapp.fmri);
+        assert_eq!(Some("My Application".into()), myapp.name);
+        assert_eq!(Some(ServiceState::Maintenance), myapp.state);
+        assert_eq!(Some("Mon Oct 19 10:00:00 2026".into()), myapp.since);
+        assert_eq!(
+            "Start method exited with $SMF_EXIT_ERR_FATAL.",
+            myapp.reason
+        );
+        assert_eq!(
+            Some("/var/svc/log/network-myapp:default.log"),
+            myapp.log_file()
+        );
+        assert_eq!(3, myapp.impact.len());
+        assert_eq!("svc:/network/worker:default", myapp.impact[2]);
+
+        assert_eq!(Some(ServiceState::Offline), explanations[1].state);
+        assert_eq!(None, explanations[1].log_file());
+
+        Ok(())
+    }
+}